@@ -0,0 +1,162 @@
+use std::boxed::Box;
+use std::collections::HashMap;
+
+use knormal::{FuncDef, KNormal};
+use node::Handler;
+use id::IdGen;
+
+// alpha conversion: gives every bound variable a unique name
+
+fn find(x: &String, env: &HashMap<String, String>) -> String {
+    env.get(x).cloned().unwrap_or(x.clone())
+}
+
+pub fn g(e: KNormal, env: &HashMap<String, String>, idgen: &mut IdGen) -> KNormal {
+    macro_rules! find_seq {
+        ($xs:expr) => ($xs.iter().map(|x| find(x, env)).collect::<Vec<_>>());
+    }
+    match e {
+        KNormal::Var(x) => KNormal::Var(find(&x, env)),
+        KNormal::Tuple(xs) => KNormal::Tuple(find_seq!(xs)),
+        KNormal::IntUnaryOp(op, x) => KNormal::IntUnaryOp(op, find(&x, env)),
+        KNormal::FloatUnaryOp(op, x) => KNormal::FloatUnaryOp(op, find(&x, env)),
+        KNormal::IntBinaryOp(op, x, y) => KNormal::IntBinaryOp(op, find(&x, env), find(&y, env)),
+        KNormal::FloatBinaryOp(op, x, y) => {
+            KNormal::FloatBinaryOp(op, find(&x, env), find(&y, env))
+        }
+        KNormal::CompBinaryOp(op, x, y) => {
+            KNormal::CompBinaryOp(op, find(&x, env), find(&y, env))
+        }
+        KNormal::If(c, then, els) => KNormal::If(
+            find(&c, env),
+            Box::new(g(*then, env, idgen)),
+            Box::new(g(*els, env, idgen)),
+        ),
+        KNormal::LetExpr((x, t), e1, e2) => {
+            let newx = idgen.get_fresh_name(&x);
+            let e1 = g(*e1, env, idgen);
+            let mut newenv = env.clone();
+            newenv.insert(x, newx.clone());
+            KNormal::LetExpr((newx, t), Box::new(e1), Box::new(g(*e2, &newenv, idgen)))
+        }
+        KNormal::LetTupleExpr(xts, y, e) => {
+            let mut newenv = env.clone();
+            let mut newxts = vec![];
+            for (x, t) in xts {
+                let newx = idgen.get_fresh_name(&x);
+                newenv.insert(x, newx.clone());
+                newxts.push((newx, t));
+            }
+            KNormal::LetTupleExpr(newxts, find(&y, env), Box::new(g(*e, &newenv, idgen)))
+        }
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name: (x, t),
+                params,
+                body,
+            },
+            e,
+        ) => {
+            let mut newenv = env.clone();
+            let newx = idgen.get_fresh_name(&x);
+            newenv.insert(x, newx.clone());
+            let mut newenv_body = newenv.clone();
+            let mut newparams = vec![];
+            for (y, t) in params {
+                let newy = idgen.get_fresh_name(&y);
+                newenv_body.insert(y, newy.clone());
+                newparams.push((newy, t));
+            }
+            KNormal::LetFuncExpr(
+                FuncDef {
+                    name: (newx, t),
+                    params: newparams,
+                    body: Box::new(g(*body, &newenv_body, idgen)),
+                },
+                Box::new(g(*e, &newenv, idgen)),
+            )
+        }
+        KNormal::App(f, args) => KNormal::App(find(&f, env), find_seq!(args)),
+        KNormal::MakeArray(x, y) => KNormal::MakeArray(find(&x, env), find(&y, env)),
+        KNormal::Get(x, y) => KNormal::Get(find(&x, env), find(&y, env)),
+        KNormal::Put(x, y, z) => KNormal::Put(find(&x, env), find(&y, env), find(&z, env)),
+        KNormal::MakeRef(x) => KNormal::MakeRef(find(&x, env)),
+        KNormal::Deref(x) => KNormal::Deref(find(&x, env)),
+        KNormal::Assign(x, y) => KNormal::Assign(find(&x, env), find(&y, env)),
+        KNormal::While(cond, body) => {
+            KNormal::While(Box::new(g(*cond, env, idgen)), Box::new(g(*body, env, idgen)))
+        }
+        // a global keeps its name, so the following module items can refer to it
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(g(*e, env, idgen))),
+        KNormal::For(x, a, b, dir, body) => {
+            let newx = idgen.get_fresh_name(&x);
+            let mut newenv = env.clone();
+            newenv.insert(x, newx.clone());
+            KNormal::For(
+                newx,
+                find(&a, env),
+                find(&b, env),
+                dir,
+                Box::new(g(*body, &newenv, idgen)),
+            )
+        }
+        KNormal::MakeExn(name, x) => KNormal::MakeExn(name, x.map(|x| find(&x, env))),
+        KNormal::Raise(x, t) => KNormal::Raise(find(&x, env), t),
+        KNormal::TryWith(body, handlers) => {
+            let body = g(*body, env, idgen);
+            let handlers = handlers
+                .into_iter()
+                .map(|Handler { constr, var, body }| {
+                    let mut newenv = env.clone();
+                    let var = var.map(|(x, t)| {
+                        let newx = idgen.get_fresh_name(&x);
+                        newenv.insert(x, newx.clone());
+                        (newx, t)
+                    });
+                    Handler {
+                        constr: constr,
+                        var: var,
+                        body: Box::new(g(*body, &newenv, idgen)),
+                    }
+                })
+                .collect();
+            KNormal::TryWith(Box::new(body), handlers)
+        }
+        e => e,
+    }
+}
+
+pub fn f(e: KNormal, idgen: &mut IdGen) -> KNormal {
+    g(e, &HashMap::new(), idgen)
+}
+
+// converts a top-level function made by knormal::f but leaves its name alone
+pub fn toplevel_fun(e: KNormal, idgen: &mut IdGen) -> KNormal {
+    match e {
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name,
+                params,
+                body,
+            },
+            e,
+        ) => {
+            let mut env = HashMap::new();
+            let mut newparams = vec![];
+            for (y, t) in params {
+                let newy = idgen.get_fresh_name(&y);
+                env.insert(y, newy.clone());
+                newparams.push((newy, t));
+            }
+            KNormal::LetFuncExpr(
+                FuncDef {
+                    name: name,
+                    params: newparams,
+                    body: Box::new(g(*body, &env, idgen)),
+                },
+                e,
+            )
+        }
+        e => panic!(format!("not a top-level function: {:?}", e)),
+    }
+}
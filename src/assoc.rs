@@ -0,0 +1,51 @@
+use std::boxed::Box;
+
+use knormal::{FuncDef, KNormal};
+use typing::Type;
+
+// flattens nested lets: 'let x = (let y = e1 in e2) in e3' becomes 'let y = e1 in let x = e2 in e3'.
+// only valid on alpha-converted terms.
+
+fn insert(e: KNormal, xt: (String, Type), body: KNormal) -> KNormal {
+    match e {
+        KNormal::LetExpr(yt, e3, e4) => KNormal::LetExpr(yt, e3, Box::new(insert(*e4, xt, body))),
+        KNormal::LetFuncExpr(fundef, e) => {
+            KNormal::LetFuncExpr(fundef, Box::new(insert(*e, xt, body)))
+        }
+        KNormal::LetTupleExpr(yts, z, e) => {
+            KNormal::LetTupleExpr(yts, z, Box::new(insert(*e, xt, body)))
+        }
+        e => KNormal::LetExpr(xt, Box::new(e), Box::new(body)),
+    }
+}
+
+pub fn f(e: KNormal) -> KNormal {
+    match e {
+        KNormal::If(c, then, els) => KNormal::If(c, Box::new(f(*then)), Box::new(f(*els))),
+        KNormal::LetExpr(xt, e1, e2) => insert(f(*e1), xt, f(*e2)),
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name,
+                params,
+                body,
+            },
+            e,
+        ) => KNormal::LetFuncExpr(
+            FuncDef {
+                name: name,
+                params: params,
+                body: Box::new(f(*body)),
+            },
+            Box::new(f(*e)),
+        ),
+        KNormal::LetTupleExpr(xts, y, e) => KNormal::LetTupleExpr(xts, y, Box::new(f(*e))),
+        KNormal::While(cond, body) => KNormal::While(Box::new(f(*cond)), Box::new(f(*body))),
+        KNormal::For(x, a, b, dir, body) => KNormal::For(x, a, b, dir, Box::new(f(*body))),
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(f(*e))),
+        KNormal::TryWith(body, handlers) => KNormal::TryWith(
+            Box::new(f(*body)),
+            handlers.into_iter().map(|handler| handler.map_body(f)).collect(),
+        ),
+        e => e,
+    }
+}
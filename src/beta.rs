@@ -0,0 +1,89 @@
+use std::boxed::Box;
+use std::collections::HashMap;
+
+use knormal::{FuncDef, KNormal};
+
+// beta reduction: 'let x = y in e' is replaced by e with x renamed to y
+
+fn find(x: String, env: &HashMap<String, String>) -> String {
+    match env.get(&x) {
+        Some(y) => y.clone(),
+        None => x,
+    }
+}
+
+fn g(e: KNormal, env: &HashMap<String, String>) -> KNormal {
+    macro_rules! find_seq {
+        ($xs:expr) => ($xs.into_iter().map(|x| find(x, env)).collect::<Vec<_>>());
+    }
+    match e {
+        KNormal::Var(x) => KNormal::Var(find(x, env)),
+        KNormal::Tuple(xs) => KNormal::Tuple(find_seq!(xs)),
+        KNormal::IntUnaryOp(op, x) => KNormal::IntUnaryOp(op, find(x, env)),
+        KNormal::FloatUnaryOp(op, x) => KNormal::FloatUnaryOp(op, find(x, env)),
+        KNormal::IntBinaryOp(op, x, y) => KNormal::IntBinaryOp(op, find(x, env), find(y, env)),
+        KNormal::FloatBinaryOp(op, x, y) => {
+            KNormal::FloatBinaryOp(op, find(x, env), find(y, env))
+        }
+        KNormal::CompBinaryOp(op, x, y) => KNormal::CompBinaryOp(op, find(x, env), find(y, env)),
+        KNormal::If(c, then, els) => KNormal::If(
+            find(c, env),
+            Box::new(g(*then, env)),
+            Box::new(g(*els, env)),
+        ),
+        KNormal::LetExpr((x, t), e1, e2) => match g(*e1, env) {
+            KNormal::Var(y) => {
+                let mut newenv = env.clone();
+                newenv.insert(x, y);
+                g(*e2, &newenv)
+            }
+            e1 => KNormal::LetExpr((x, t), Box::new(e1), Box::new(g(*e2, env))),
+        },
+        KNormal::LetTupleExpr(xts, y, e) => {
+            KNormal::LetTupleExpr(xts, find(y, env), Box::new(g(*e, env)))
+        }
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name,
+                params,
+                body,
+            },
+            e,
+        ) => KNormal::LetFuncExpr(
+            FuncDef {
+                name: name,
+                params: params,
+                body: Box::new(g(*body, env)),
+            },
+            Box::new(g(*e, env)),
+        ),
+        KNormal::App(f, args) => KNormal::App(find(f, env), find_seq!(args)),
+        KNormal::MakeArray(x, y) => KNormal::MakeArray(find(x, env), find(y, env)),
+        KNormal::Get(x, y) => KNormal::Get(find(x, env), find(y, env)),
+        KNormal::Put(x, y, z) => KNormal::Put(find(x, env), find(y, env), find(z, env)),
+        KNormal::MakeRef(x) => KNormal::MakeRef(find(x, env)),
+        KNormal::Deref(x) => KNormal::Deref(find(x, env)),
+        KNormal::Assign(x, y) => KNormal::Assign(find(x, env), find(y, env)),
+        KNormal::While(cond, body) => {
+            KNormal::While(Box::new(g(*cond, env)), Box::new(g(*body, env)))
+        }
+        KNormal::For(x, a, b, dir, body) => {
+            KNormal::For(x, find(a, env), find(b, env), dir, Box::new(g(*body, env)))
+        }
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(g(*e, env))),
+        KNormal::MakeExn(name, x) => KNormal::MakeExn(name, x.map(|x| find(x, env))),
+        KNormal::Raise(x, t) => KNormal::Raise(find(x, env), t),
+        KNormal::TryWith(body, handlers) => KNormal::TryWith(
+            Box::new(g(*body, env)),
+            handlers
+                .into_iter()
+                .map(|handler| handler.map_body(|e| g(e, env)))
+                .collect(),
+        ),
+        e => e,
+    }
+}
+
+pub fn f(e: KNormal) -> KNormal {
+    g(e, &HashMap::new())
+}
@@ -0,0 +1,259 @@
+extern crate libc;
+
+use std::ffi::CStr;
+
+use codegen::emit;
+use codegen::emit::Emit;
+use typing::{Type, TypeScheme};
+
+// the functions every program can use without declaring them. the typing environment
+// (typing::Globals::new) and the code generated for a call (CodeGen::new) are both made from this
+// table, so they always agree
+
+pub struct Builtin {
+    pub name: String,
+    pub scheme: TypeScheme,
+    pub imp: Impl,
+}
+
+// how a call to a builtin is generated
+pub enum Impl {
+    Native(Native),
+    // the emitter generates the call inline (see CodeGen::gen_dir)
+    Inline(Emit),
+}
+
+// a Rust function called through its C symbol. its parameters and return value are laid out
+// as Type::to_llvmty_sub gives them
+pub struct Native {
+    pub symbol: &'static str,
+    pub addr: *mut libc::c_void,
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_print_int(i: i64) -> i32 {
+    print!("{}", i);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_print_float(f: f64) -> i32 {
+    print!("{}", f);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_print_string(s: *const libc::c_char) -> i32 {
+    print!("{}", unsafe { CStr::from_ptr(s) }.to_string_lossy());
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_print_newline(_: i32) -> i32 {
+    print!("\n");
+    0
+}
+
+// the symbol is the name of the Rust function, so the two can't disagree
+macro_rules! native {
+    ($name:expr, $ty:expr, $fun:ident) => (Builtin {
+        name: $name.to_string(),
+        scheme: TypeScheme::new(vec![], $ty),
+        imp: Impl::Native(Native {
+            symbol: stringify!($fun),
+            addr: $fun as *mut libc::c_void,
+        }),
+    });
+}
+
+fn inline(name: &str, tyvars: Vec<Type>, ty: Type, emit: Emit) -> Builtin {
+    Builtin {
+        name: name.to_string(),
+        scheme: TypeScheme::new(tyvars, ty),
+        imp: Impl::Inline(emit),
+    }
+}
+
+fn func(params: Vec<Type>, ret: Type) -> Type {
+    Type::Func(params, Box::new(ret))
+}
+
+fn array(t: &Type) -> Type {
+    Type::Array(Box::new(t.clone()))
+}
+
+pub fn all() -> Vec<Builtin> {
+    let mut builtins = vec![
+        native!("print_int", func(vec![Type::Int], Type::Unit), rcaml_print_int),
+        native!("print_float", func(vec![Type::Float], Type::Unit), rcaml_print_float),
+        native!("print_string", func(vec![Type::String], Type::Unit), rcaml_print_string),
+        native!("print_newline", func(vec![Type::Unit], Type::Unit), rcaml_print_newline),
+    ];
+
+    // the Array functions are polymorphic in 'a = Var(0) and 'b = Var(1)
+    let (a, b) = (Type::Var(0), Type::Var(1));
+    let a_ = vec![a.clone()];
+    let ab = vec![a.clone(), b.clone()];
+    builtins.extend(vec![
+        inline(
+            "Array.length",
+            a_.clone(),
+            func(vec![array(&a)], Type::Int),
+            emit::array_length,
+        ),
+        inline(
+            "Array.init",
+            a_.clone(),
+            func(vec![Type::Int, func(vec![Type::Int], a.clone())], array(&a)),
+            emit::array_init,
+        ),
+        inline(
+            "Array.iter",
+            a_.clone(),
+            func(vec![func(vec![a.clone()], Type::Unit), array(&a)], Type::Unit),
+            emit::array_iter,
+        ),
+        inline(
+            "Array.map",
+            ab.clone(),
+            func(vec![func(vec![a.clone()], b.clone()), array(&a)], array(&b)),
+            emit::array_map,
+        ),
+        inline(
+            "Array.fold_left",
+            ab.clone(),
+            func(
+                vec![func(vec![a.clone(), b.clone()], a.clone()), a.clone(), array(&b)],
+                a.clone(),
+            ),
+            emit::array_fold_left,
+        ),
+        inline(
+            "Array.blit",
+            a_.clone(),
+            func(
+                vec![array(&a), Type::Int, array(&a), Type::Int, Type::Int],
+                Type::Unit,
+            ),
+            emit::array_blit,
+        ),
+        inline(
+            "Array.copy",
+            a_.clone(),
+            func(vec![array(&a)], array(&a)),
+            emit::array_copy,
+        ),
+    ]);
+
+    // Int32 and Int64 share their signatures and emitters
+    for &(module, ref t) in &[("Int32", Type::Int32), ("Int64", Type::Int64)] {
+        let mut add = |name: &str, ty: Type, emit: Emit| {
+            builtins.push(inline(&format!("{}.{}", module, name), vec![], ty, emit))
+        };
+        let binops: &[(&str, Emit)] = &[
+            ("add", emit::intn_add),
+            ("sub", emit::intn_sub),
+            ("mul", emit::intn_mul),
+            ("div", emit::intn_div),
+            ("rem", emit::intn_rem),
+            ("logand", emit::intn_logand),
+            ("logor", emit::intn_logor),
+            ("logxor", emit::intn_logxor),
+        ];
+        for &(name, emit) in binops {
+            add(name, func(vec![t.clone(), t.clone()], t.clone()), emit);
+        }
+        let unops: &[(&str, Emit)] = &[
+            ("neg", emit::intn_neg),
+            ("abs", emit::intn_abs),
+            ("lognot", emit::intn_lognot),
+        ];
+        for &(name, emit) in unops {
+            add(name, func(vec![t.clone()], t.clone()), emit);
+        }
+        let shifts: &[(&str, Emit)] = &[
+            ("shift_left", emit::intn_shift_left),
+            ("shift_right", emit::intn_shift_right),
+            ("shift_right_logical", emit::intn_shift_right_logical),
+        ];
+        for &(name, emit) in shifts {
+            add(name, func(vec![t.clone(), Type::Int], t.clone()), emit);
+        }
+        add("of_int", func(vec![Type::Int], t.clone()), emit::intn_of_int);
+        add("to_int", func(vec![t.clone()], Type::Int), emit::intn_to_int);
+        add("of_float", func(vec![Type::Float], t.clone()), emit::intn_of_float);
+        add("to_float", func(vec![t.clone()], Type::Float), emit::intn_to_float);
+        add("to_string", func(vec![t.clone()], Type::String), emit::intn_to_string);
+    }
+
+    // MinCaml's float functions are available besides OCaml's
+    let float_funs: Vec<(&[(&str, Emit)], Type)> = vec![
+        (
+            &[
+                ("sqrt", emit::sqrt),
+                ("sin", emit::sin),
+                ("cos", emit::cos),
+                ("tan", emit::tan),
+                ("asin", emit::asin),
+                ("acos", emit::acos),
+                ("atan", emit::atan),
+                ("exp", emit::exp),
+                ("log", emit::log),
+                ("log10", emit::log10),
+                ("floor", emit::floor),
+                ("ceil", emit::ceil),
+                ("abs_float", emit::fabs),
+                ("fabs", emit::fabs),
+                ("fsqr", emit::fsqr),
+                ("fhalf", emit::fhalf),
+                ("fneg", emit::fneg),
+            ],
+            func(vec![Type::Float], Type::Float),
+        ),
+        (
+            &[("atan2", emit::atan2)],
+            func(vec![Type::Float, Type::Float], Type::Float),
+        ),
+        (
+            &[("fispos", emit::fispos), ("fisneg", emit::fisneg), ("fiszero", emit::fiszero)],
+            func(vec![Type::Float], Type::Bool),
+        ),
+        (
+            &[("fless", emit::fless), ("fequal", emit::fequal)],
+            func(vec![Type::Float, Type::Float], Type::Bool),
+        ),
+        (
+            &[("float", emit::float_of_int), ("float_of_int", emit::float_of_int)],
+            func(vec![Type::Int], Type::Float),
+        ),
+        (
+            &[("int_of_float", emit::int_of_float), ("truncate", emit::int_of_float)],
+            func(vec![Type::Float], Type::Int),
+        ),
+    ];
+    for (funs, ty) in float_funs {
+        for &(name, emit) in funs {
+            builtins.push(inline(name, vec![], ty.clone(), emit));
+        }
+    }
+
+    builtins
+}
+
+#[test]
+pub fn test_builtins() {
+    use std::collections::HashSet;
+
+    let builtins = all();
+    let names: HashSet<_> = builtins.iter().map(|builtin| builtin.name.clone()).collect();
+    assert_eq!(names.len(), builtins.len());
+    for builtin in &builtins {
+        match builtin.scheme.body {
+            Type::Func(_, _) => (),
+            ref t => panic!(format!("'{}' has type {:?}", builtin.name, t)),
+        }
+        if let Impl::Native(ref native) = builtin.imp {
+            assert!(native.symbol.starts_with("rcaml_"));
+        }
+    }
+}
@@ -1,15 +1,15 @@
-use node::NodeKind;
+use knormal::KNormal;
+use knormal;
+use typing::{Globals, Type};
+use node::{BinOps, CompBinOps, Direction, Handler, UnaryOps};
 use node;
-use typing::Type;
-use node::{BinOps, CompBinOps};
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 extern crate ordered_float;
 use self::ordered_float::OrderedFloat;
 
-use parser::EXTENV;
-
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Cls {
     pub entry: String,
@@ -20,10 +20,15 @@ pub struct Cls {
 pub enum Closure {
     Unit,
     Bool(bool),
-    Int(i32),
+    Int(i64),
+    Int32(i32),
+    Int64(i64),
     Float(OrderedFloat<f64>),
+    String(String),
     Var(String),
     Tuple(Vec<Closure>),
+    IntUnaryOp(UnaryOps, Box<Closure>),
+    FloatUnaryOp(UnaryOps, Box<Closure>),
     IntBinaryOp(BinOps, Box<Closure>, Box<Closure>),
     FloatBinaryOp(BinOps, Box<Closure>, Box<Closure>),
     CompBinaryOp(CompBinOps, Box<Closure>, Box<Closure>),
@@ -36,6 +41,15 @@ pub enum Closure {
     MakeArray(Box<Closure>, Box<Closure>),
     Get(Box<Closure>, Box<Closure>),
     Put(Box<Closure>, Box<Closure>, Box<Closure>),
+    MakeRef(Box<Closure>),
+    Deref(Box<Closure>),
+    Assign(Box<Closure>, Box<Closure>),
+    While(Box<Closure>, Box<Closure>),                   // cond, body
+    For(String, Box<Closure>, Box<Closure>, Direction, Box<Closure>), // var, from, to, direction, body
+    MakeExn(String, Option<Box<Closure>>),               // constructor, argument
+    Raise(Box<Closure>, Type),                           // exception, type of the 'raise' expression
+    TryWith(Box<Closure>, Vec<Handler<Closure>>),
+    LetDef((String, Type), Box<Closure>),                // a global variable, bound expr
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -56,7 +70,7 @@ macro_rules! build_set {
             h
     })
 }
-fn fv(e: &Closure) -> HashSet<String> {
+pub fn fv(e: &Closure) -> HashSet<String> {
     use self::Closure::*;
     macro_rules! seq {
         ($e:expr) => { {
@@ -70,13 +84,15 @@ fn fv(e: &Closure) -> HashSet<String> {
         } }
     }
     match *e {
-        Unit | Bool(_) | Int(_) | Float(_) => HashSet::new(),
-        // Neg(ref x) | FNeg(ref x) => build_set!(x),
+        Unit | Bool(_) | Int(_) | Int32(_) | Int64(_) | Float(_) | String(_) | MakeExn(_, None) => {
+            HashSet::new()
+        }
+        IntUnaryOp(_, ref x) | FloatUnaryOp(_, ref x) => fv(x),
         IntBinaryOp(_, ref x, ref y)
         | FloatBinaryOp(_, ref x, ref y)
         | CompBinaryOp(_, ref x, ref y) => {
             let mut set = HashSet::new();
-            for e in fv(x).union(&fv(y)).collect::<Vec<&String>>() {
+            for e in fv(x).union(&fv(y)).collect::<Vec<_>>() {
                 set.insert(e.clone());
             }
             set
@@ -114,57 +130,55 @@ fn fv(e: &Closure) -> HashSet<String> {
             .map(|y| (*y).clone())
             .collect::<HashSet<_>>(),
         LetTupleExpr(ref es, ref expr, ref body) => {
-            let tmp: HashSet<String> = es.iter().map(|e| e.0.clone()).collect();
+            let tmp: HashSet<_> = es.iter().map(|e| e.0.clone()).collect();
             &fv(expr) | &(&fv(body) - &tmp)
         }
         MakeArray(ref x, ref y) => &fv(x) | &fv(y),
         Get(ref x, ref y) => &fv(x) | &fv(y),
         Put(ref x, ref y, ref z) => &(&fv(x) | &fv(y)) | &fv(z),
+        MakeRef(ref x) | Deref(ref x) | MakeExn(_, Some(ref x)) | Raise(ref x, _) => fv(x),
+        Assign(ref x, ref y) | While(ref x, ref y) => &fv(x) | &fv(y),
+        For(ref x, ref a, ref b, _, ref body) => {
+            &(&fv(a) | &fv(b)) | &(&fv(body) - &build_set!(x))
+        }
+        TryWith(ref body, ref handlers) => handlers.iter().fold(fv(body), |acc, handler| {
+            let bound = handler.var.iter().map(|&(ref x, _)| x.clone()).collect();
+            &acc | &(&fv(&handler.body) - &bound)
+        }),
+        LetDef(_, ref e) => fv(e),
     }
 }
 
 fn g(
-    node: NodeKind,
+    e: KNormal,
     env: &HashMap<String, Type>,
     known: &HashSet<String>,
     toplevel: &mut Vec<FuncDef>,
 ) -> Closure {
-    macro_rules! seq { ($e:expr) => { {
-        let mut a = Vec::new();
-        for c in $e {
-          a.push(  g(c, env, known , toplevel))
-        }
-        a }
-    }};
-    match node {
-        NodeKind::Unit => Closure::Unit,
-        NodeKind::Bool(b) => Closure::Bool(b),
-        NodeKind::Int(i) => Closure::Int(i),
-        NodeKind::Float(f) => Closure::Float(OrderedFloat::from(f)),
-        NodeKind::Ident(name) => Closure::Var(name),
-        NodeKind::Tuple(es) => Closure::Tuple(seq!(es)),
-        NodeKind::IntBinaryOp(op, lhs, rhs) => Closure::IntBinaryOp(
-            op,
-            Box::new(g(*lhs, env, known, toplevel)),
-            Box::new(g(*rhs, env, known, toplevel)),
-        ),
-        NodeKind::FloatBinaryOp(op, lhs, rhs) => Closure::FloatBinaryOp(
-            op,
-            Box::new(g(*lhs, env, known, toplevel)),
-            Box::new(g(*rhs, env, known, toplevel)),
-        ),
-        NodeKind::CompBinaryOp(op, lhs, rhs) => Closure::CompBinaryOp(
-            op,
-            Box::new(g(*lhs, env, known, toplevel)),
-            Box::new(g(*rhs, env, known, toplevel)),
-        ),
-        NodeKind::IfExpr(cond, then, els) => Closure::If(
-            Box::new(g(*cond, env, known, toplevel)),
+    macro_rules! var {
+        ($x:expr) => (Box::new(Closure::Var($x)))
+    }
+    match e {
+        KNormal::Unit => Closure::Unit,
+        KNormal::Bool(b) => Closure::Bool(b),
+        KNormal::Int(i) => Closure::Int(i),
+        KNormal::Int32(i) => Closure::Int32(i),
+        KNormal::Int64(i) => Closure::Int64(i),
+        KNormal::Float(f) => Closure::Float(OrderedFloat::from(f)),
+        KNormal::String(s) => Closure::String(s),
+        KNormal::Var(name) => Closure::Var(name),
+        KNormal::Tuple(xs) => Closure::Tuple(xs.into_iter().map(Closure::Var).collect()),
+        KNormal::IntUnaryOp(op, x) => Closure::IntUnaryOp(op, var!(x)),
+        KNormal::FloatUnaryOp(op, x) => Closure::FloatUnaryOp(op, var!(x)),
+        KNormal::IntBinaryOp(op, x, y) => Closure::IntBinaryOp(op, var!(x), var!(y)),
+        KNormal::FloatBinaryOp(op, x, y) => Closure::FloatBinaryOp(op, var!(x), var!(y)),
+        KNormal::CompBinaryOp(op, x, y) => Closure::CompBinaryOp(op, var!(x), var!(y)),
+        KNormal::If(cond, then, els) => Closure::If(
+            var!(cond),
             Box::new(g(*then, env, known, toplevel)),
             Box::new(g(*els, env, known, toplevel)),
         ),
-        // LetExpr((String, typing::Type), Box<NodeKind>, Box<NodeKind>), // (name, ty), bound expr, body
-        NodeKind::LetExpr((name, ty), expr, body) => {
+        KNormal::LetExpr((name, ty), expr, body) => {
             let mut cp_env = env.clone();
             cp_env.insert(name.clone(), ty.clone());
             Closure::LetExpr(
@@ -173,13 +187,12 @@ fn g(
                 Box::new(g(*body, &cp_env, known, toplevel)),
             )
         }
-
-        NodeKind::LetFuncExpr(
-            node::FuncDef {
+        KNormal::LetFuncExpr(
+            knormal::FuncDef {
                 name: (x, t),
                 params,
+                body: expr,
             },
-            expr,
             body,
         ) => {
             let mut toplevel_cp = toplevel.clone();
@@ -193,7 +206,12 @@ fn g(
             }
             let e1p = g((*expr).clone(), &env_p2, &known_p, &mut toplevel_cp);
             /* Check if e1p contains free variables */
+            // a variable that is neither local nor a known function is a global variable, which
+            // is read directly rather than captured
             let zs = &fv(&e1p) - &params.iter().map(|&(ref y, _)| y.clone()).collect();
+            let zs = zs.into_iter()
+                .filter(|z| env_p.contains_key(z) || known_p.contains(z))
+                .collect::<HashSet<_>>();
             let (known_p, e1p) = if zs.is_empty() {
                 *toplevel = toplevel_cp;
                 (&known_p, e1p)
@@ -201,9 +219,14 @@ fn g(
                 let e1p = g(*expr, &env_p2, known, toplevel);
                 (known, e1p)
             };
-            let zs: Vec<String> = (&zs - &build_set!(x)).into_iter().collect();
+            // sorted so that the environment layout doesn't depend on hashing
+            let mut zs: Vec<String> = (&zs - &build_set!(x)).into_iter().collect();
+            zs.sort();
             let zts: Vec<(String, Type)> = zs.iter()
-                .map(|&ref z| (z.clone(), env.get(z).unwrap().clone()))
+                .map(|z| match env.get(z) {
+                    Some(t) => (z.clone(), t.clone()),
+                    None => panic!(format!("external function '{}' can't be used as a value", z)),
+                })
                 .collect();
             toplevel.push(FuncDef {
                 name: (x.clone(), t.clone()),
@@ -226,56 +249,478 @@ fn g(
                 e2p
             }
         }
-        NodeKind::LetTupleExpr(es, expr, body) => {
+        KNormal::LetTupleExpr(xs, y, body) => {
             let mut newenv = env.clone();
-            for &(ref x, ref t) in es.iter() {
+            for &(ref x, ref t) in xs.iter() {
                 newenv.insert(x.clone(), t.clone());
             }
-            Closure::LetTupleExpr(
-                es,
-                Box::new(g(*expr, &newenv, known, toplevel)),
-                Box::new(g(*body, &newenv, known, toplevel)),
-            )
+            Closure::LetTupleExpr(xs, var!(y), Box::new(g(*body, &newenv, known, toplevel)))
         }
-
-        NodeKind::Call(callee, args) => {
-            let name = if let NodeKind::Ident(name) = *callee.clone() {
-                name
+        KNormal::App(f, args) => {
+            let args = args.into_iter().map(Closure::Var).collect();
+            if known.contains(&f) {
+                Closure::AppDir(var!(f), args)
             } else {
-                panic!()
-            };
-            if known.contains(&name) {
-                Closure::AppDir(Box::new(g(*callee, env, known, toplevel)), seq!(args))
-            } else {
-                Closure::AppCls(Box::new(g(*callee, env, known, toplevel)), seq!(args))
+                Closure::AppCls(var!(f), args)
             }
         }
-
-        NodeKind::MakeArray(e1, e2) => Closure::MakeArray(
-            Box::new(g(*e1, env, known, toplevel)),
-            Box::new(g(*e2, env, known, toplevel)),
-        ),
-        NodeKind::Get(e1, e2) => Closure::Get(
-            Box::new(g(*e1, env, known, toplevel)),
-            Box::new(g(*e2, env, known, toplevel)),
+        KNormal::MakeArray(x, y) => Closure::MakeArray(var!(x), var!(y)),
+        KNormal::Get(x, y) => Closure::Get(var!(x), var!(y)),
+        KNormal::Put(x, y, z) => Closure::Put(var!(x), var!(y), var!(z)),
+        KNormal::MakeRef(x) => Closure::MakeRef(var!(x)),
+        KNormal::Deref(x) => Closure::Deref(var!(x)),
+        KNormal::Assign(x, y) => Closure::Assign(var!(x), var!(y)),
+        KNormal::While(cond, body) => Closure::While(
+            Box::new(g(*cond, env, known, toplevel)),
+            Box::new(g(*body, env, known, toplevel)),
         ),
-        NodeKind::Put(e1, e2, e3) => Closure::Put(
-            Box::new(g(*e1, env, known, toplevel)),
-            Box::new(g(*e2, env, known, toplevel)),
-            Box::new(g(*e3, env, known, toplevel)),
+        KNormal::For(x, a, b, dir, body) => {
+            let mut newenv = env.clone();
+            newenv.insert(x.clone(), Type::Int);
+            Closure::For(
+                x,
+                var!(a),
+                var!(b),
+                dir,
+                Box::new(g(*body, &newenv, known, toplevel)),
+            )
+        }
+        KNormal::MakeExn(name, x) => Closure::MakeExn(name, x.map(|x| var!(x))),
+        KNormal::Raise(x, t) => Closure::Raise(var!(x), t),
+        KNormal::TryWith(body, handlers) => Closure::TryWith(
+            Box::new(g(*body, env, known, toplevel)),
+            handlers
+                .into_iter()
+                .map(|handler| {
+                    let mut newenv = env.clone();
+                    if let Some((ref x, ref t)) = handler.var {
+                        newenv.insert(x.clone(), t.clone());
+                    }
+                    handler.map_body(|e| g(e, &newenv, known, toplevel))
+                })
+                .collect(),
         ),
-
-        _ => panic!(),
+        KNormal::LetDef(xt, e) => Closure::LetDef(xt, Box::new(g(*e, env, known, toplevel))),
     }
 }
 
-pub fn f(e: NodeKind) -> Prog {
+pub fn f(e: KNormal, globals: &Globals) -> Prog {
     let mut toplevel = Vec::new();
     // TODO: better code needed
     let mut known = HashSet::new();
-    for (fun_name, _) in EXTENV.lock().unwrap().iter() {
-        known.insert(fun_name.to_owned());
+    for (fun_name, _) in globals.values.iter() {
+        if !globals.vars.contains(fun_name) {
+            known.insert(fun_name.to_owned());
+        }
     }
     let e = g(e, &HashMap::new(), &known, &mut toplevel);
     Prog(toplevel, e)
 }
+
+impl Closure {
+    fn fmt_sub(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = " ".repeat(indent);
+        macro_rules! seq {
+            ($xs:expr) => ($xs.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        }
+        match *self {
+            Closure::Unit => write!(f, "()"),
+            Closure::Bool(b) => write!(f, "{}", b),
+            Closure::Int(i) => write!(f, "{}", i),
+            Closure::Int32(i) => write!(f, "{}l", i),
+            Closure::Int64(i) => write!(f, "{}L", i),
+            Closure::Float(x) => write!(f, "{:?}", x.into_inner()),
+            Closure::String(ref s) => write!(f, "{:?}", s),
+            Closure::Var(ref x) => write!(f, "{}", x),
+            Closure::Tuple(ref xs) => write!(f, "({})", seq!(xs).join(", ")),
+            Closure::IntUnaryOp(ref op, ref x) | Closure::FloatUnaryOp(ref op, ref x) => {
+                write!(f, "{}{}", node::unaryop_to_str(op), x)
+            }
+            Closure::IntBinaryOp(ref op, ref x, ref y)
+            | Closure::FloatBinaryOp(ref op, ref x, ref y) => {
+                write!(f, "{} {} {}", x, node::binop_to_str(op), y)
+            }
+            Closure::CompBinaryOp(ref op, ref x, ref y) => {
+                write!(f, "{} {} {}", x, node::comp_binop_to_str(op), y)
+            }
+            Closure::AppCls(ref x, ref ys) => {
+                write!(f, "apply_closure {} {}", x, seq!(ys).join(" "))
+            }
+            Closure::AppDir(ref x, ref ys) => {
+                write!(f, "apply_direct {} {}", x, seq!(ys).join(" "))
+            }
+            Closure::If(ref c, ref then, ref els) => {
+                try!(write!(f, "if {} then\n{}  ", c, pad));
+                try!(then.fmt_sub(f, indent + 2));
+                try!(write!(f, "\n{}else\n{}  ", pad, pad));
+                els.fmt_sub(f, indent + 2)
+            }
+            Closure::LetExpr((ref x, ref t), ref e1, ref e2) => {
+                try!(write!(f, "let {} : {} = ", x, t.to_string()));
+                try!(e1.fmt_sub(f, indent + 2));
+                try!(write!(f, " in\n{}", pad));
+                e2.fmt_sub(f, indent)
+            }
+            Closure::LetTupleExpr(ref xs, ref y, ref e) => {
+                try!(write!(
+                    f,
+                    "let ({}) = {} in\n{}",
+                    xs.iter()
+                        .map(|&(ref x, ref t)| format!("{} : {}", x, t.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    y,
+                    pad
+                ));
+                e.fmt_sub(f, indent)
+            }
+            Closure::MakeCls(ref x, ref t, ref cls, ref e) => {
+                try!(write!(
+                    f,
+                    "let {} : {} = make_closure {} ({}) in\n{}",
+                    x,
+                    t.to_string(),
+                    cls.entry,
+                    cls.actual_fv.join(", "),
+                    pad
+                ));
+                e.fmt_sub(f, indent)
+            }
+            Closure::MakeArray(ref x, ref y) => write!(f, "Array.create {} {}", x, y),
+            Closure::Get(ref x, ref y) => write!(f, "{}.({})", x, y),
+            Closure::Put(ref x, ref y, ref z) => write!(f, "{}.({}) <- {}", x, y, z),
+            Closure::MakeRef(ref x) => write!(f, "ref {}", x),
+            Closure::Deref(ref x) => write!(f, "!{}", x),
+            Closure::Assign(ref x, ref y) => write!(f, "{} := {}", x, y),
+            Closure::While(ref cond, ref body) => {
+                try!(write!(f, "while "));
+                try!(cond.fmt_sub(f, indent + 2));
+                try!(write!(f, " do\n{}  ", pad));
+                try!(body.fmt_sub(f, indent + 2));
+                write!(f, "\n{}done", pad)
+            }
+            Closure::For(ref x, ref a, ref b, ref dir, ref body) => {
+                try!(write!(
+                    f,
+                    "for {} = {} {} {} do\n{}  ",
+                    x,
+                    a,
+                    node::direction_to_str(dir),
+                    b,
+                    pad
+                ));
+                try!(body.fmt_sub(f, indent + 2));
+                write!(f, "\n{}done", pad)
+            }
+            Closure::MakeExn(ref name, None) => write!(f, "{}", name),
+            Closure::MakeExn(ref name, Some(ref x)) => write!(f, "{} {}", name, x),
+            Closure::Raise(ref x, _) => write!(f, "raise {}", x),
+            Closure::TryWith(ref body, ref handlers) => {
+                try!(write!(f, "try\n{}  ", pad));
+                try!(body.fmt_sub(f, indent + 2));
+                try!(write!(f, "\n{}with", pad));
+                for handler in handlers {
+                    try!(write!(f, "\n{}| {} ->\n{}  ", pad, node::handler_pat_to_str(handler), pad));
+                    try!(handler.body.fmt_sub(f, indent + 2));
+                }
+                Ok(())
+            }
+            Closure::LetDef((ref x, ref t), ref e) => {
+                try!(write!(f, "let {} : {} = ", x, t.to_string()));
+                e.fmt_sub(f, indent + 2)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_sub(f, 0)
+    }
+}
+
+// 'let rec f [free variables] (params) : return type = body'
+impl fmt::Display for FuncDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (ref x, ref t) = self.name;
+        try!(write!(f, "let rec {} [", x));
+        try!(write!(
+            f,
+            "{}]",
+            self.formal_fv
+                .iter()
+                .map(|&(ref z, ref t)| format!("{} : {}", z, t.to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        for &(ref y, ref t) in &self.params {
+            try!(write!(f, " ({} : {})", y, t.to_string()));
+        }
+        let ret_ty = match *t {
+            Type::Func(_, ref r) => r.to_string(),
+            _ => t.to_string(),
+        };
+        try!(write!(f, " : {} =\n  ", ret_ty));
+        self.body.fmt_sub(f, 2)
+    }
+}
+
+impl fmt::Display for Prog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for fundef in &self.0 {
+            try!(write!(f, "{}\n", fundef));
+        }
+        write!(f, "{}", self.1)
+    }
+}
+
+fn validate_sub(
+    e: &Closure,
+    env: &HashMap<String, Type>,
+    funs: &HashMap<String, &FuncDef>,
+    ext: &HashSet<String>,
+) -> Result<(), String> {
+    macro_rules! seq {
+        ($xs:expr) => ({
+            for x in $xs {
+                try!(validate_sub(x, env, funs, ext));
+            }
+        })
+    }
+    match *e {
+        Closure::Unit
+        | Closure::Bool(_)
+        | Closure::Int(_)
+        | Closure::Int32(_)
+        | Closure::Int64(_)
+        | Closure::Float(_)
+        | Closure::String(_)
+        | Closure::MakeExn(_, None) => Ok(()),
+        Closure::Var(ref x) => if env.contains_key(x) {
+            Ok(())
+        } else {
+            Err(format!("unbound variable '{}'", x))
+        },
+        Closure::Tuple(ref xs) => {
+            seq!(xs);
+            Ok(())
+        }
+        Closure::IntBinaryOp(_, ref x, ref y)
+        | Closure::FloatBinaryOp(_, ref x, ref y)
+        | Closure::CompBinaryOp(_, ref x, ref y)
+        | Closure::MakeArray(ref x, ref y)
+        | Closure::Get(ref x, ref y)
+        | Closure::Assign(ref x, ref y)
+        | Closure::While(ref x, ref y) => {
+            seq!(vec![x, y]);
+            Ok(())
+        }
+        Closure::IntUnaryOp(_, ref x)
+        | Closure::FloatUnaryOp(_, ref x)
+        | Closure::MakeRef(ref x)
+        | Closure::Deref(ref x)
+        | Closure::MakeExn(_, Some(ref x))
+        | Closure::Raise(ref x, _)
+        | Closure::LetDef(_, ref x) => validate_sub(x, env, funs, ext),
+        Closure::Put(ref x, ref y, ref z) | Closure::If(ref x, ref y, ref z) => {
+            seq!(vec![x, y, z]);
+            Ok(())
+        }
+        Closure::AppCls(ref x, ref ys) => {
+            try!(validate_sub(x, env, funs, ext));
+            seq!(ys);
+            Ok(())
+        }
+        Closure::AppDir(ref x, ref ys) => {
+            match **x {
+                Closure::Var(ref name) if funs.contains_key(name) || ext.contains(name) => (),
+                _ => return Err(format!("direct call to unknown function '{}'", x)),
+            }
+            seq!(ys);
+            Ok(())
+        }
+        Closure::LetExpr((ref x, ref t), ref e1, ref e2) => {
+            try!(validate_sub(e1, env, funs, ext));
+            let mut newenv = env.clone();
+            newenv.insert(x.clone(), t.clone());
+            validate_sub(e2, &newenv, funs, ext)
+        }
+        Closure::LetTupleExpr(ref xs, ref y, ref e) => {
+            try!(validate_sub(y, env, funs, ext));
+            let mut newenv = env.clone();
+            for &(ref x, ref t) in xs {
+                newenv.insert(x.clone(), t.clone());
+            }
+            validate_sub(e, &newenv, funs, ext)
+        }
+        Closure::For(ref x, ref a, ref b, _, ref body) => {
+            seq!(vec![a, b]);
+            let mut newenv = env.clone();
+            newenv.insert(x.clone(), Type::Int);
+            validate_sub(body, &newenv, funs, ext)
+        }
+        Closure::TryWith(ref body, ref handlers) => {
+            try!(validate_sub(body, env, funs, ext));
+            for handler in handlers {
+                let mut newenv = env.clone();
+                if let Some((ref x, ref t)) = handler.var {
+                    newenv.insert(x.clone(), t.clone());
+                }
+                try!(validate_sub(&handler.body, &newenv, funs, ext));
+            }
+            Ok(())
+        }
+        Closure::MakeCls(ref x, ref t, ref cls, ref e) => {
+            let fundef = match funs.get(&cls.entry) {
+                Some(fundef) => fundef,
+                None => return Err(format!("closure of unknown function '{}'", cls.entry)),
+            };
+            if fundef.formal_fv.len() != cls.actual_fv.len() {
+                return Err(format!(
+                    "closure of '{}' has {} free variables but {} are expected",
+                    cls.entry,
+                    cls.actual_fv.len(),
+                    fundef.formal_fv.len()
+                ));
+            }
+            for (z, &(ref formal, ref formal_ty)) in cls.actual_fv.iter().zip(&fundef.formal_fv) {
+                match env.get(z) {
+                    Some(ty) if ty == formal_ty => (),
+                    Some(ty) => {
+                        return Err(format!(
+                            "free variable '{}' of '{}' has type {} but '{}' expects {}",
+                            z,
+                            cls.entry,
+                            ty.to_string(),
+                            formal,
+                            formal_ty.to_string()
+                        ))
+                    }
+                    None => return Err(format!("unbound variable '{}'", z)),
+                }
+            }
+            let mut newenv = env.clone();
+            newenv.insert(x.clone(), t.clone());
+            validate_sub(e, &newenv, funs, ext)
+        }
+    }
+}
+
+// checks that every variable is bound, every direct call targets a known function and every
+// closure captures variables of the types its function expects
+pub fn validate(prog: &Prog, globals: &Globals) -> Result<(), String> {
+    let &Prog(ref fundefs, ref main) = prog;
+    let ext = globals.values.keys().cloned().collect::<HashSet<_>>();
+    // the global variables are bound everywhere
+    let global_env = globals
+        .vars
+        .iter()
+        .map(|x| (x.clone(), globals.values[x].body.clone()))
+        .collect::<HashMap<_, _>>();
+    let funs = fundefs
+        .iter()
+        .map(|fundef| (fundef.name.0.clone(), fundef))
+        .collect::<HashMap<_, _>>();
+
+    for fundef in fundefs {
+        let mut env = global_env.clone();
+        env.insert(fundef.name.0.clone(), fundef.name.1.clone());
+        for &(ref x, ref t) in fundef.formal_fv.iter().chain(&fundef.params) {
+            env.insert(x.clone(), t.clone());
+        }
+        try!(
+            validate_sub(&fundef.body, &env, &funs, &ext)
+                .map_err(|msg| format!("in '{}': {}", fundef.name.0, msg))
+        );
+    }
+    validate_sub(main, &global_env, &funs, &ext)
+}
+
+#[test]
+pub fn test_closure_conv() {
+    use nom::IResult;
+    use parser::{module_item, tokens, uniquify};
+    use typing;
+    use id::IdGen;
+    use alpha;
+
+    let mut idgen = IdGen::new();
+    let mut tyenv = HashMap::new();
+    let mut globals = Globals::new();
+    let mut f = |e: &str| match module_item(&tokens(e).0) {
+        IResult::Done(_, node) => {
+            let uniquified = uniquify(node, &mut idgen);
+            let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+            let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+            self::f(knormalized, &globals)
+        }
+        IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
+        IResult::Error(err) => panic!(format!("error: {:?}", err)),
+    };
+
+    let prog = f("let make_adder x = let adder y = x + y in adder in let a = make_adder 3 in print_int (a 7)");
+    assert_eq!(validate(&prog, &Globals::new()), Ok(()));
+    assert_eq!(
+        prog.to_string(),
+        "let rec adder.19 [x.18 : int] (y.20 : int) : int =\n  \
+         x.18 + y.20\n\
+         let rec make_adder.17 [] (x.18 : int) : (int -> int) =\n  \
+         let adder.19 : (int -> int) = make_closure adder.19 (x.18) in\n  \
+         adder.19\n\
+         let a.21 : (int -> int) = let tmp.22 : int = 3 in\n  \
+         apply_direct make_adder.17 tmp.22 in\n\
+         let tmp.23 : int = let tmp.24 : int = 7 in\n  \
+         apply_closure a.21 tmp.24 in\n\
+         apply_direct print_int tmp.23"
+    );
+
+    let adder = FuncDef {
+        name: (
+            "adder".to_string(),
+            Type::Func(vec![Type::Int], Box::new(Type::Int)),
+        ),
+        params: vec![("y".to_string(), Type::Int)],
+        formal_fv: vec![("x".to_string(), Type::Int)],
+        body: Box::new(Closure::IntBinaryOp(
+            BinOps::IAdd,
+            Box::new(Closure::Var("x".to_string())),
+            Box::new(Closure::Var("y".to_string())),
+        )),
+    };
+    let make_cls = |x: Closure| {
+        Closure::LetExpr(
+            ("x".to_string(), Type::Float),
+            Box::new(Closure::Float(OrderedFloat::from(1.0))),
+            Box::new(Closure::MakeCls(
+                "a".to_string(),
+                adder.name.1.clone(),
+                Cls {
+                    entry: "adder".to_string(),
+                    actual_fv: vec!["x".to_string()],
+                },
+                Box::new(x),
+            )),
+        )
+    };
+    assert_eq!(
+        validate(
+            &Prog(vec![adder.clone()], make_cls(Closure::Unit)),
+            &Globals::new()
+        ),
+        Err("free variable 'x' of 'adder' has type float but 'x' expects int".to_string())
+    );
+    assert_eq!(
+        validate(
+            &Prog(
+                vec![adder.clone()],
+                Closure::AppDir(Box::new(Closure::Var("a".to_string())), vec![Closure::Int(1)])
+            ),
+            &Globals::new()
+        ),
+        Err("direct call to unknown function 'a'".to_string())
+    );
+    assert_eq!(
+        validate(&Prog(vec![], Closure::Var("y".to_string())), &Globals::new()),
+        Err("unbound variable 'y'".to_string())
+    );
+}
@@ -1,26 +1,43 @@
 extern crate llvm_sys as llvm;
 
-use self::llvm::core::*;
 use self::llvm::prelude::*;
 
 extern crate libc;
 
-use std::ffi::CString;
-use std::ptr;
-use std::boxed::Box;
+use std::cell::RefCell;
+use std::mem;
 use std::collections::HashMap;
 
-use node::{BinOps, CompBinOps};
+use node;
+use node::{BinOps, CompBinOps, Direction, Handler};
 
 use closure::{Closure, Prog};
 use closure;
 
 use typing::Type;
+use typing;
+
+use ir;
+use runtime;
+use builtin;
 
 #[derive(Eq, PartialEq, Hash)]
 pub struct ExtFunc {
     llvm_val: LLVMValueRef,
     ty: Type,
+    // passed before the arguments to a host function (see session.rs)
+    env: Option<LLVMValueRef>,
+}
+
+// a function defined in the program. its closure is { fun, fv1, ..., fvn } and is passed to
+// the function itself as the environment pointer
+pub struct LocalFunc {
+    llvm_val: LLVMValueRef,
+    ty: Type,
+    // free variables stored after the function pointer in the closure
+    formal_fv: Vec<(String, Type)>,
+    // the body reads its closure, so the function can't be called with a null environment
+    reads_env: bool,
 }
 
 #[derive(Eq, PartialEq, Clone, Hash)]
@@ -30,29 +47,72 @@ pub enum ValKind {
 }
 
 impl ValKind {
-    unsafe fn get(&self, builder: LLVMBuilderRef) -> LLVMValueRef {
+    fn get(&self, builder: &ir::Builder) -> LLVMValueRef {
         match self {
-            &ValKind::Load(v) => LLVMBuildLoad(builder, v, CString::new("").unwrap().as_ptr()),
+            &ValKind::Load(v) => builder.load(v, ""),
             &ValKind::Other(v) => v,
         }
     }
-    unsafe fn retrieve(&self) -> LLVMValueRef {
+    fn retrieve(&self) -> LLVMValueRef {
         match self {
             &ValKind::Load(v) | &ValKind::Other(v) => v,
         }
     }
 }
 
-pub struct CodeGen<'a> {
-    context: LLVMContextRef,
-    module: LLVMModuleRef,
-    builder: LLVMBuilderRef,
-    exec_engine: llvm::execution_engine::LLVMExecutionEngineRef,
+// makes the symbols of a shared library available to 'external'
+pub fn load_library(path: &str) -> Result<(), String> {
+    if !ir::load_library(Some(path)) {
+        return Err(format!("cannot load '{}'", path));
+    }
+    Ok(())
+}
+
+// whether 'external' can bind the symbol, in the process or a library loaded by load_library
+pub fn symbol_defined(symbol: &str) -> bool {
+    ir::load_library(None);
+    ir::search_symbol(symbol).is_some()
+}
+
+// the LLVM objects are disposed of in the order of the fields: the builder, then the engine with
+// the modules, and the context they were made in last
+pub struct CodeGen {
+    builder: ir::Builder,
+    exec_engine: ir::ExecutionEngine,
+    module: ir::Module,
+    context: ir::Context,
     llvm_main_fun: Option<LLVMValueRef>,
-    _tyenv: &'a mut HashMap<usize, Type>,
     ext_funcmap: HashMap<String, ExtFunc>,
+    // the builtins generated inline, with their types
+    inline_builtins: HashMap<String, (emit::Emit, Type)>,
+    funcmap: HashMap<String, LocalFunc>,
     global_varmap: HashMap<String, (Type, LLVMTypeRef, LLVMValueRef)>,
     malloc: LLVMValueRef,
+    rt: Runtime,
+    // number of enclosing 'try' bodies. a call inside one isn't in tail position
+    try_depth: usize,
+    // 'a.(i)' raises Invalid_argument when i is out of bounds. turned off by '-unsafe'
+    bounds_check: bool,
+    // the exceptions and externals the program declares
+    exns: Vec<typing::ExnDef>,
+    externals: HashMap<String, typing::External>,
+    // the entries gen_entry made, by function name
+    entries: RefCell<HashMap<String, *mut libc::c_void>>,
+}
+
+// the runtime functions used by generated code (see runtime.rs)
+struct Runtime {
+    setjmp: LLVMValueRef,
+    push_handler: LLVMValueRef,
+    pop_handler: LLVMValueRef,
+    current_exn: LLVMValueRef,
+    raise: LLVMValueRef,
+    invalid_argument: LLVMValueRef,
+    division_by_zero: LLVMValueRef,
+    array_make: LLVMValueRef,
+    array_blit: LLVMValueRef,
+    array_copy: LLVMValueRef,
+    int64_to_string: LLVMValueRef,
 }
 
 #[derive(Debug)]
@@ -62,204 +122,257 @@ pub enum CodeGenError {
 
 type CodeGenResult<T> = Result<T, CodeGenError>;
 
-#[no_mangle]
-pub extern "C" fn print_int(i: i32) -> i32 {
-    print!("{}", i);
-    0
-}
-#[no_mangle]
-pub extern "C" fn print_float(f: f64) -> i32 {
-    print!("{}", f);
-    0
-}
-#[no_mangle]
-pub extern "C" fn print_newline(_: i32) -> i32 {
-    print!("\n");
-    0
-}
-#[no_mangle]
-pub extern "C" fn float_of_int(i: i32) -> f64 {
-    i as f64
+// declares a function whose calls jump to addr. addr must be a function of that type
+unsafe fn add_runtime_fun(
+    module: ir::Module,
+    ee: &ir::ExecutionEngine,
+    name: &str,
+    ret_ty: LLVMTypeRef,
+    param_tys: Vec<LLVMTypeRef>,
+    addr: *mut libc::c_void,
+) -> LLVMValueRef {
+    let fun = module.add_function(name, ir::function_type(ret_ty, &param_tys));
+    ee.add_global_mapping(fun, addr);
+    fun
 }
 
-unsafe fn cur_bb_has_no_terminator(builder: LLVMBuilderRef) -> bool {
-    LLVMIsATerminatorInst(LLVMGetLastInstruction(LLVMGetInsertBlock(builder))) == ptr::null_mut()
+// { i64 length, [0 x elem] }, laid out as runtime.rs expects
+fn array_block_ty(ctx: &ir::Context, elem_ty: LLVMTypeRef) -> LLVMTypeRef {
+    ctx.struct_type(&[ctx.i64_type(), ir::array_type(elem_ty, 0)])
 }
 
-impl<'a> CodeGen<'a> {
-    pub unsafe fn new(tyenv: &'a mut HashMap<usize, Type>) -> Self {
-        llvm::execution_engine::LLVMLinkInMCJIT();
-        llvm::target::LLVM_InitializeAllTargetMCs();
-        llvm::target::LLVM_InitializeNativeTarget();
-        llvm::target::LLVM_InitializeNativeAsmPrinter();
-        llvm::target::LLVM_InitializeNativeAsmParser();
-
-        let context = LLVMContextCreate();
-
-        let c_mod_name = CString::new("rcaml").unwrap();
-        let module = LLVMModuleCreateWithNameInContext(c_mod_name.as_ptr(), context);
-
-        let mut ee = 0 as llvm::execution_engine::LLVMExecutionEngineRef;
-        let mut error = 0 as *mut i8;
-        if llvm::execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module, &mut error)
-            != 0
-        {
-            panic!("err");
-        }
+fn array_elem_ty(ary_ty: LLVMTypeRef) -> LLVMTypeRef {
+    ir::element_type(ir::field_type(ir::element_type(ary_ty), 1))
+}
 
-        let mut ext_funcmap = HashMap::new();
-        // initialize standard functions
-        // {
-        let f_print_int_ty = LLVMFunctionType(
-            LLVMInt32Type(),
-            vec![LLVMInt32Type()].as_mut_slice().as_mut_ptr(),
-            1,
-            0,
-        );
-        let f_print_int = LLVMAddFunction(
-            module,
-            CString::new("print_int").unwrap().as_ptr(),
-            f_print_int_ty,
-        );
-        ext_funcmap.insert(
-            "print_int".to_string(),
-            ExtFunc {
-                ty: Type::Func(vec![Type::Int], Box::new(Type::Unit)),
-                llvm_val: f_print_int,
-            },
-        );
-        llvm::execution_engine::LLVMAddGlobalMapping(
-            ee,
-            f_print_int,
-            print_int as *mut libc::c_void,
-        );
+// the return type of the function in a closure
+fn cls_ret_ty(cls_ty: LLVMTypeRef) -> LLVMTypeRef {
+    let fun_ptr_ty = ir::field_type(ir::element_type(cls_ty), 0);
+    ir::return_type(ir::element_type(fun_ptr_ty))
+}
 
-        let f_print_float_ty = LLVMFunctionType(
-            LLVMInt32Type(),
-            vec![LLVMDoubleType()].as_mut_slice().as_mut_ptr(),
-            1,
-            0,
-        );
-        let f_print_float = LLVMAddFunction(
-            module,
-            CString::new("print_float").unwrap().as_ptr(),
-            f_print_float_ty,
-        );
-        ext_funcmap.insert(
-            "print_float".to_string(),
-            ExtFunc {
-                ty: Type::Func(vec![Type::Float], Box::new(Type::Unit)),
-                llvm_val: f_print_float,
-            },
-        );
-        llvm::execution_engine::LLVMAddGlobalMapping(
-            ee,
-            f_print_float,
-            print_float as *mut libc::c_void,
-        );
+impl CodeGen {
+    pub fn new(globals: &typing::Globals, bounds_check: bool) -> Self {
+        let context = ir::Context::new();
+        let module = context.module("rcaml");
+        let ee = ir::ExecutionEngine::new(module);
 
-        let f_print_newline_ty =
-            LLVMFunctionType(LLVMInt32Type(), vec![].as_mut_slice().as_mut_ptr(), 0, 0);
-        let f_print_newline = LLVMAddFunction(
-            module,
-            CString::new("print_newline").unwrap().as_ptr(),
-            f_print_newline_ty,
-        );
-        ext_funcmap.insert(
-            "print_newline".to_string(),
-            ExtFunc {
-                ty: Type::Func(vec![Type::Unit], Box::new(Type::Unit)),
-                llvm_val: f_print_newline,
-            },
-        );
-        llvm::execution_engine::LLVMAddGlobalMapping(
-            ee,
-            f_print_newline,
-            print_newline as *mut libc::c_void,
-        );
+        // an external's symbol is looked up in the process and the libraries loaded by
+        // load_library
+        ir::load_library(None);
 
-        let f_float_of_int_ty = LLVMFunctionType(
-            LLVMDoubleType(),
-            vec![LLVMInt32Type()].as_mut_slice().as_mut_ptr(),
-            1,
-            0,
-        );
-        let f_float_of_int = LLVMAddFunction(
-            module,
-            CString::new("float_of_int").unwrap().as_ptr(),
-            f_float_of_int_ty,
-        );
-        ext_funcmap.insert(
-            "float_of_int".to_string(),
-            ExtFunc {
-                ty: Type::Func(vec![Type::Int], Box::new(Type::Float)),
-                llvm_val: f_float_of_int,
-            },
-        );
-        llvm::execution_engine::LLVMAddGlobalMapping(
-            ee,
-            f_float_of_int,
-            float_of_int as *mut libc::c_void,
-        );
+        let mut ext_funcmap = HashMap::new();
+        let mut inline_builtins = HashMap::new();
+        for builtin in builtin::all() {
+            let native = match builtin.imp {
+                builtin::Impl::Native(native) => native,
+                builtin::Impl::Inline(emit) => {
+                    inline_builtins.insert(builtin.name, (emit, builtin.scheme.body));
+                    continue;
+                }
+            };
+            let (params, ret) = match builtin.scheme.body {
+                Type::Func(ref params, ref ret) => (params.clone(), ret.clone()),
+                ref t => panic!(format!("builtin '{}' has type {:?}", builtin.name, t)),
+            };
+            let llvm_val = unsafe {
+                add_runtime_fun(
+                    module,
+                    &ee,
+                    native.symbol,
+                    ret.to_llvmty_sub(&context),
+                    params.iter().map(|t| t.to_llvmty_sub(&context)).collect(),
+                    native.addr,
+                )
+            };
+            ext_funcmap.insert(
+                builtin.name,
+                ExtFunc {
+                    ty: builtin.scheme.body,
+                    llvm_val: llvm_val,
+                    env: None,
+                },
+            );
+        }
 
-        let f_malloc_ty = LLVMFunctionType(
-            LLVMPointerType(LLVMInt8Type(), 0),
-            vec![LLVMInt64Type()].as_mut_slice().as_mut_ptr(),
-            1,
-            0,
-        );
-        let f_malloc = LLVMAddFunction(
-            module,
-            CString::new("malloc").unwrap().as_ptr(),
-            f_malloc_ty,
-        );
+        let ptr_ty = ir::pointer_type(context.i8_type());
+        let i32_ty = context.i32_type();
+        let i64_ty = context.i64_type();
+        let void_ty = context.void_type();
+        let f_malloc = module.add_function("malloc", ir::function_type(ptr_ty, &[i64_ty]));
+
+        // resolved from the C library. it must be marked so that LLVM knows it returns twice
+        let setjmp = module.add_function("_setjmp", ir::function_type(i32_ty, &[ptr_ty]));
+        context.add_fun_attr(setjmp, "returns_twice");
+        let rt = unsafe {
+            Runtime {
+                setjmp: setjmp,
+                push_handler: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_push_handler",
+                    i32_ty,
+                    vec![ptr_ty],
+                    runtime::rcaml_push_handler as *mut libc::c_void,
+                ),
+                pop_handler: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_pop_handler",
+                    i32_ty,
+                    vec![],
+                    runtime::rcaml_pop_handler as *mut libc::c_void,
+                ),
+                current_exn: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_current_exn",
+                    ptr_ty,
+                    vec![],
+                    runtime::rcaml_current_exn as *mut libc::c_void,
+                ),
+                raise: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_raise",
+                    void_ty,
+                    vec![ptr_ty],
+                    runtime::rcaml_raise as *mut libc::c_void,
+                ),
+                invalid_argument: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_invalid_argument",
+                    void_ty,
+                    vec![ptr_ty],
+                    runtime::rcaml_invalid_argument as *mut libc::c_void,
+                ),
+                division_by_zero: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_division_by_zero",
+                    void_ty,
+                    vec![],
+                    runtime::rcaml_division_by_zero as *mut libc::c_void,
+                ),
+                array_make: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_array_make",
+                    ptr_ty,
+                    vec![i64_ty, i64_ty],
+                    runtime::rcaml_array_make as *mut libc::c_void,
+                ),
+                array_blit: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_array_blit",
+                    i32_ty,
+                    vec![ptr_ty, i64_ty, ptr_ty, i64_ty, i64_ty, i64_ty],
+                    runtime::rcaml_array_blit as *mut libc::c_void,
+                ),
+                array_copy: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_array_copy",
+                    ptr_ty,
+                    vec![ptr_ty, i64_ty],
+                    runtime::rcaml_array_copy as *mut libc::c_void,
+                ),
+                int64_to_string: add_runtime_fun(
+                    module,
+                    &ee,
+                    "rcaml_int64_to_string",
+                    ptr_ty,
+                    vec![i64_ty],
+                    runtime::rcaml_int64_to_string as *mut libc::c_void,
+                ),
+            }
+        };
 
         CodeGen {
-            context: context,
-            module: module,
-            builder: LLVMCreateBuilderInContext(context),
+            builder: context.builder(),
             exec_engine: ee,
+            module: module,
+            context: context,
             llvm_main_fun: None,
-            _tyenv: tyenv,
             ext_funcmap: ext_funcmap,
+            inline_builtins: inline_builtins,
+            funcmap: HashMap::new(),
             global_varmap: HashMap::new(),
             malloc: f_malloc,
+            rt: rt,
+            try_depth: 0,
+            bounds_check: bounds_check,
+            exns: globals.exns.clone(),
+            externals: globals.externals.clone(),
+            entries: RefCell::new(HashMap::new()),
         }
     }
 
-    pub unsafe fn gen(
+    // declares a Rust closure registered by Session::register_fn. the trampoline takes the
+    // closure's address followed by the arguments. env must live as long as the module
+    pub unsafe fn add_host_fn(
+        &mut self,
+        name: &str,
+        ty: &Type,
+        trampoline: *mut libc::c_void,
+        env: *mut libc::c_void,
+    ) {
+        let (params, ret) = match *ty {
+            Type::Func(ref params, ref ret) => (params, ret),
+            ref t => panic!(format!("host function '{}' has type {:?}", name, t)),
+        };
+        let i8ptr = ir::pointer_type(self.context.i8_type());
+        let mut param_tys = vec![i8ptr];
+        param_tys.extend(params.iter().map(|t| t.to_llvmty_sub(&self.context)));
+        let llvm_val = add_runtime_fun(
+            self.module,
+            &self.exec_engine,
+            &format!("rcaml_host_{}", name),
+            ret.to_llvmty_sub(&self.context),
+            param_tys,
+            trampoline,
+        );
+        let env = ir::const_int_to_ptr(ir::const_int(self.context.i64_type(), env as i64), i8ptr);
+        self.ext_funcmap.insert(
+            name.to_string(),
+            ExtFunc {
+                llvm_val: llvm_val,
+                ty: ty.clone(),
+                env: Some(env),
+            },
+        );
+    }
+
+    pub fn gen(
         &mut self,
         mod_dump_to_stderr: bool,
         run_module_for_debugging: bool,
         progs: Vec<Prog>,
-    ) -> CodeGenResult<LLVMValueRef> {
-        let pm = LLVMCreatePassManager();
-        llvm::transforms::scalar::LLVMAddTailCallEliminationPass(pm);
-        llvm::transforms::scalar::LLVMAddReassociatePass(pm);
-        llvm::transforms::scalar::LLVMAddGVNPass(pm);
-        llvm::transforms::scalar::LLVMAddInstructionCombiningPass(pm);
-        llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
-        llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
-        llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
-
-        let main_ty = LLVMFunctionType(LLVMInt32Type(), vec![].as_mut_slice().as_mut_ptr(), 0, 0);
-        let main = LLVMAddFunction(self.module, CString::new("main").unwrap().as_ptr(), main_ty);
-        let bb_entry = LLVMAppendBasicBlock(main, CString::new("entry").unwrap().as_ptr());
-        LLVMPositionBuilderAtEnd(self.builder, bb_entry);
+    ) -> CodeGenResult<()> {
+        let main_ty = ir::function_type(self.context.i32_type(), &[]);
+        let main = self.module.add_function("main", main_ty);
+        let bb_entry = self.context.append_block(main, "entry");
+        self.builder.position_at_end(bb_entry);
         self.llvm_main_fun = Some(main);
         // let mut funcs = Vec::new();
 
         for Prog(funs, expr) in progs {
             // TODO: consider how to impl poly ty
-            let mut env = HashMap::new();
-            for fun in funs {
-                try!(self.gen_fun(&mut env, &fun));
+            // declare all functions first since a nested function may call an enclosing one
+            for fun in &funs {
+                self.declare_fun(fun);
+            }
+            for fun in &funs {
+                try!(self.gen_fun(fun));
             }
 
-            try!(self.gen_expr(&env, Some(main), &expr));
+            try!(self.gen_expr(&HashMap::new(), Some(main), &expr));
         }
 
-        LLVMBuildRet(self.builder, try!(self.gen_int(0)));
+        let unit = try!(self.gen_unit());
+        self.builder.ret(unit);
 
         // llvm::analysis::LLVMVerifyModule(
         //     self.module,
@@ -273,10 +386,10 @@ impl<'a> CodeGen<'a> {
         // );
 
         if mod_dump_to_stderr {
-            LLVMDumpModule(self.module);
+            self.module.dump();
         }
 
-        LLVMRunPassManager(pm, self.module);
+        self.module.optimize();
 
         if run_module_for_debugging {
             println!("*** running main ***");
@@ -284,92 +397,203 @@ impl<'a> CodeGen<'a> {
             println!("*** end of main ***");
         }
 
-        Ok(ptr::null_mut())
+        Ok(())
     }
 
-    pub unsafe fn run_module(&mut self) {
-        let main = LLVMGetNamedFunction(self.module, CString::new("main").unwrap().as_ptr());
-        llvm::execution_engine::LLVMRunFunction(
-            self.exec_engine,
-            main,
-            0,
-            vec![].as_mut_slice().as_mut_ptr(),
-        );
+    pub fn run_module(&self) {
+        runtime::set_exns(self.exns.clone());
+        let main = self.module.get_function("main").unwrap();
+        self.exec_engine.run_function(main);
     }
 
-    unsafe fn gen_fun(
-        &mut self,
-        env: &mut HashMap<String, ValKind>,
-        cls: &closure::FuncDef,
-    ) -> CodeGenResult<LLVMValueRef> {
-        let tmp_builder = self.builder;
-        self.builder = LLVMCreateBuilderInContext(self.context);
+    // the tag of the latest declaration of an exception
+    fn exn_tag(&self, name: &str) -> Option<usize> {
+        self.exns.iter().rposition(|exn| exn.name == name)
+    }
+
+    pub fn exns(&self) -> &[typing::ExnDef] {
+        &self.exns
+    }
+
+    // the type of a function defined at the top level, or None if there is no such function
+    pub fn toplevel_fun_ty(&self, name: &str) -> Option<Type> {
+        self.funcmap.get(name).map(|fun| fun.ty.clone())
+    }
+
+    // makes 'i8* entry(i8* args, i8* ret)' that calls the top-level function name with the
+    // parameters stored in args as a struct, and stores the result in ret. this is easier to call
+    // from Rust than the function itself, whose tuples are passed as LLVM structs. an exception
+    // the function doesn't handle is caught and returned, and null is returned otherwise. the
+    // module is compiled by then, so the entry is put in a module of its own, made once per name
+    pub fn gen_entry(&self, name: &str) -> *mut libc::c_void {
+        if let Some(&entry) = self.entries.borrow().get(name) {
+            return entry;
+        }
+        let (fun, params, ret) = match self.funcmap.get(name) {
+            Some(&LocalFunc {
+                llvm_val,
+                ty: Type::Func(ref params, ref ret),
+                reads_env: false,
+                ..
+            }) => (llvm_val, params.clone(), (**ret).clone()),
+            _ => panic!(format!("'{}' isn't a closed top-level function", name)),
+        };
+
+        let entry_name = format!("rcaml.entry.{}", name);
+        let module = self.context.module(&entry_name);
+        let fun = module.add_function(&ir::name_of(fun), ir::element_type(ir::type_of(fun)));
+        let i8ptr = ir::pointer_type(self.context.i8_type());
+        let i32_ty = self.context.i32_type();
+        let setjmp = module.add_function("_setjmp", ir::function_type(i32_ty, &[i8ptr]));
+        self.context.add_fun_attr(setjmp, "returns_twice");
+        let (push_handler, pop_handler, current_exn) = unsafe {
+            (
+                add_runtime_fun(
+                    module,
+                    &self.exec_engine,
+                    "rcaml_push_handler",
+                    i32_ty,
+                    vec![i8ptr],
+                    runtime::rcaml_push_handler as *mut libc::c_void,
+                ),
+                add_runtime_fun(
+                    module,
+                    &self.exec_engine,
+                    "rcaml_pop_handler",
+                    i32_ty,
+                    vec![],
+                    runtime::rcaml_pop_handler as *mut libc::c_void,
+                ),
+                add_runtime_fun(
+                    module,
+                    &self.exec_engine,
+                    "rcaml_current_exn",
+                    i8ptr,
+                    vec![],
+                    runtime::rcaml_current_exn as *mut libc::c_void,
+                ),
+            )
+        };
+        let entry_ty = ir::function_type(i8ptr, &[i8ptr, i8ptr]);
+        let entry = module.add_function(&entry_name, entry_ty);
+        let builder = self.context.builder();
+        builder.position_at_end(self.context.append_block(entry, "entry"));
+        let bb_body = self.context.append_block(entry, "body");
+        let bb_handler = self.context.append_block(entry, "handler");
+
+        // the same as the 'try' of gen_try_with
+        let jmp_buf = builder.alloca(
+            ir::array_type(self.context.i8_type(), runtime::JMP_BUF_SIZE),
+            "jmp_buf",
+        );
+        ir::set_alignment(jmp_buf, 16);
+        let jmp_buf = builder.pointer_cast(jmp_buf, i8ptr, "");
+        let jumped = builder.call(setjmp, &[jmp_buf], "");
+        let zero = ir::const_int(i32_ty, 0);
+        let entered = builder.icmp(llvm::LLVMIntPredicate::LLVMIntEQ, jumped, zero, "entered");
+        builder.cond_br(entered, bb_body, bb_handler);
+
+        builder.position_at_end(bb_body);
+        builder.call(push_handler, &[jmp_buf], "");
+        let args_ty = Type::Tuple(params).to_llvmty_sub(&self.context);
+        let args_ptr = builder.pointer_cast(ir::param(entry, 0), ir::pointer_type(args_ty), "");
+        let args = builder.load(args_ptr, "");
+        let mut args_val = vec![ir::const_null(i8ptr)];
+        for i in 0..ir::field_count(args_ty) {
+            args_val.push(builder.extract_value(args, i, ""));
+        }
+        let ret_val = builder.call(fun, &args_val, "");
+        builder.call(pop_handler, &[], "");
+        let ret_ty = ret.to_llvmty_sub(&self.context);
+        let ret_ptr = builder.pointer_cast(ir::param(entry, 1), ir::pointer_type(ret_ty), "");
+        builder.store(ret_val, ret_ptr);
+        builder.ret(ir::const_null(i8ptr));
+
+        builder.position_at_end(bb_handler);
+        let exn = builder.call(current_exn, &[], "exn");
+        builder.ret(exn);
+
+        self.exec_engine.add_module(module);
+        let entry = self.exec_engine.function_address(&entry_name);
+        self.entries.borrow_mut().insert(name.to_string(), entry);
+        entry
+    }
 
+    fn declare_fun(&mut self, cls: &closure::FuncDef) {
         let (ref name, ref fun_ty) = cls.name;
         assert!(match fun_ty {
             &Type::Func(_, _) => true,
             _ => false,
         });
 
-        let llvm_fun_ty = fun_ty.to_llvmty();
-        let llvm_fun = LLVMAddFunction(
-            self.module,
-            CString::new(name.as_str()).unwrap().as_ptr(),
-            llvm_fun_ty,
+        let llvm_fun = self.module.add_function(name, fun_ty.to_llvmty(&self.context));
+        self.funcmap.insert(
+            name.clone(),
+            LocalFunc {
+                llvm_val: llvm_fun,
+                ty: fun_ty.clone(),
+                formal_fv: cls.formal_fv.clone(),
+                reads_env: cls.formal_fv.len() > 0 || closure::fv(&cls.body).contains(name),
+            },
         );
+    }
 
-        let bb_entry = LLVMAppendBasicBlock(llvm_fun, CString::new("entry").unwrap().as_ptr());
-        LLVMPositionBuilderAtEnd(self.builder, bb_entry);
-
-        if cls.formal_fv.len() > 0 {
-            let p = LLVMGetParam(llvm_fun, 0);
-            let pty = LLVMPointerType(
-                LLVMStructType(
-                    cls.formal_fv
-                        .iter()
-                        .map(|ref x| x.1.to_llvmty())
-                        .collect::<Vec<_>>()
-                        .as_mut_ptr(),
-                    cls.formal_fv.len() as u32,
-                    0,
-                ),
-                0,
-            );
-            let p = LLVMBuildPointerCast(self.builder, p, pty, CString::new("").unwrap().as_ptr());
-
-            for (i, &(ref param_name, ref param_ty)) in cls.formal_fv.iter().enumerate() {
-                let param_val = LLVMBuildStructGEP(
-                    self.builder,
-                    p,
-                    i as u32,
-                    CString::new("").unwrap().as_ptr(),
-                );
-                env.insert(param_name.clone(), ValKind::Load(param_val));
+    fn gen_fun(&mut self, cls: &closure::FuncDef) -> CodeGenResult<LLVMValueRef> {
+        let fun_builder = self.context.builder();
+        let tmp_builder = mem::replace(&mut self.builder, fun_builder);
+
+        let (ref name, ref fun_ty) = cls.name;
+        let (llvm_fun, reads_env) = {
+            let fun = self.funcmap.get(name).unwrap();
+            (fun.llvm_val, fun.reads_env)
+        };
+
+        let bb_entry = self.context.append_block(llvm_fun, "entry");
+        self.builder.position_at_end(bb_entry);
+
+        let mut env = HashMap::new();
+
+        if reads_env {
+            let p = ir::param(llvm_fun, 0);
+            let mut field_tys = vec![ir::pointer_type(fun_ty.to_llvmty(&self.context))];
+            for &(_, ref fv_ty) in &cls.formal_fv {
+                field_tys.push(fv_ty.to_llvmty_sub(&self.context));
             }
+            let pty = ir::pointer_type(self.context.struct_type(&field_tys));
+            let cls_val =
+                self.builder.pointer_cast(p, pty, "");
+
+            for (i, &(ref fv_name, _)) in cls.formal_fv.iter().enumerate() {
+                //                                           '1' is for function pointer
+                let fv_val = self.builder.struct_gep(cls_val, (i + 1) as u32, "");
+                env.insert(fv_name.clone(), ValKind::Load(fv_val));
+            }
+
+            // the function refers to itself through the closure it was called with
+            let self_cls = self.builder.pointer_cast(p, fun_ty.to_llvmty_sub(&self.context), "");
+            env.insert(name.clone(), ValKind::Other(self_cls));
         }
 
         for (i, &(ref param_name, ref param_ty)) in cls.params.iter().enumerate() {
             //                                         '1' is for free variable
-            let param_val = LLVMGetParam(llvm_fun, (i + 1) as u32);
+            let param_val = ir::param(llvm_fun, (i + 1) as u32);
             let var = try!(self.declare_local_var(
-                env,
+                &mut env,
                 Some(llvm_fun),
                 &param_name,
-                param_ty.to_llvmty(),
+                param_ty.to_llvmty_sub(&self.context),
             ));
-            LLVMBuildStore(self.builder, param_val, var);
+            self.builder.store(param_val, var);
         }
 
-        env.insert(name.clone(), ValKind::Other(llvm_fun));
-
         let ret_val = try!(self.gen_expr(&env, Some(llvm_fun), &*cls.body));
-        LLVMBuildRet(self.builder, ret_val);
+        self.builder.ret(ret_val);
 
         self.builder = tmp_builder;
-        Ok(ptr::null_mut())
+        Ok(llvm_fun)
     }
 
-    unsafe fn gen_expr(
+    fn gen_expr(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -387,8 +611,10 @@ impl<'a> CodeGen<'a> {
             // LetDef((String, typing::Type), Box<NodeKind>), // name, bound expr
             // LetFuncDef(FuncDef, Box<NodeKind>), // name, bound expr
 
+            &Closure::LetDef((ref name, ref ty), ref expr) => {
+                self.gen_letdef(env, cur_fun, name, ty, &*expr)
+            }
             // TODO: below
-            // &NodeKind::LetDef((ref name, ref ty), ref expr) => self.gen_letdef(name, ty, &*expr),
             // &NodeKind::LetFuncDef(ref funcdef, ref expr) => self.gen_letfuncdef(&*funcdef, &*expr),
 
             // Call(Box<NodeKind>, Vec<NodeKind>),
@@ -405,6 +631,15 @@ impl<'a> CodeGen<'a> {
             // &NodeKind::IntBinaryOp(ref op, ref lhs, ref rhs) => {
             //     self.gen_int_binop(op, &*lhs, &*rhs)
             // }
+            // -min_int is min_int, as the negation wraps around at 63 bits
+            &Closure::IntUnaryOp(_, ref e) => {
+                let val = try!(self.gen_expr(env, cur_fun, e));
+                Ok(self.wrap_int(self.builder.neg(val, "neg")))
+            }
+            &Closure::FloatUnaryOp(_, ref e) => {
+                let val = try!(self.gen_expr(env, cur_fun, e));
+                Ok(self.builder.fneg(val, "fneg"))
+            }
             &Closure::IntBinaryOp(ref op, ref lhs, ref rhs) => {
                 self.gen_int_binop(env, cur_fun, &*op, &*lhs, &*rhs)
             }
@@ -417,43 +652,110 @@ impl<'a> CodeGen<'a> {
             &Closure::If(ref cond, ref then, ref els) => {
                 self.gen_if_expr(env, cur_fun, &*cond, &*then, &*els)
             }
+            &Closure::While(ref cond, ref body) => self.gen_while(env, cur_fun, &*cond, &*body),
+            &Closure::MakeArray(ref len, ref init) => {
+                let len = try!(self.gen_expr(env, cur_fun, len));
+                let init = try!(self.gen_expr(env, cur_fun, init));
+                let ary = try!(self.array_make(len, ir::type_of(init)));
+                try!(self.build_loop(cur_fun, len, |cg, i| {
+                    cg.builder.store(init, cg.array_elem_ptr(ary, i));
+                    Ok(())
+                }));
+                Ok(ary)
+            }
+            &Closure::Get(ref ary, ref idx) => {
+                let ary = try!(self.gen_expr(env, cur_fun, ary));
+                let idx = try!(self.gen_expr(env, cur_fun, idx));
+                try!(self.check_index(cur_fun, ary, idx));
+                Ok(self.builder.load(self.array_elem_ptr(ary, idx), ""))
+            }
+            &Closure::Put(ref ary, ref idx, ref val) => {
+                let ary = try!(self.gen_expr(env, cur_fun, ary));
+                let idx = try!(self.gen_expr(env, cur_fun, idx));
+                let val = try!(self.gen_expr(env, cur_fun, val));
+                try!(self.check_index(cur_fun, ary, idx));
+                self.builder.store(val, self.array_elem_ptr(ary, idx));
+                self.gen_unit()
+            }
+            &Closure::For(ref var, ref from, ref to, ref dir, ref body) => {
+                self.gen_for(env, cur_fun, var, &*from, &*to, dir, &*body)
+            }
             // &Closure::MakeArray(ref len, ref val) => {
             //     self.gen_make_array(env, cur_fun, &*len, &*val)
             // }
-            // &NodeKind:: FloatUnaryOp(UnaryOps, Box<NodeKind>)
             &Closure::Var(ref name) => self.gen_var_load(env, name),
             &Closure::Int(ref i) => self.gen_int(*i),
+            &Closure::Int32(ref i) => Ok(ir::const_int(self.context.i32_type(), *i as i64)),
+            &Closure::Int64(ref i) => Ok(ir::const_int(self.context.i64_type(), *i as i64)),
             &Closure::Bool(ref b) => self.gen_bool(*b),
             &Closure::Float(ref f) => self.gen_float(f.into_inner()),
             &Closure::Tuple(ref es) => self.gen_tuple(env, cur_fun, &*es),
-            &Closure::Unit => self.gen_int(0), // tmp
+            &Closure::Unit => self.gen_unit(),
+            &Closure::String(ref s) => Ok(self.builder.global_string_ptr(s, "str")),
+            &Closure::MakeExn(ref name, ref arg) => {
+                let tag = match self.exn_tag(name) {
+                    Some(tag) => tag,
+                    None => panic!(format!("not found exception '{}'", name)),
+                };
+                let mut fields = vec![ir::const_int(self.context.i64_type(), tag as i64)];
+                if let Some(ref arg) = *arg {
+                    fields.push(try!(self.gen_expr(env, cur_fun, arg)));
+                }
+                let exn = try!(self.llvm_struct_alloc(fields));
+                Ok(self.builder.pointer_cast(exn, Type::Exn.to_llvmty_sub(&self.context), ""))
+            }
+            &Closure::Raise(ref e, ref ty) => {
+                let exn = try!(self.gen_expr(env, cur_fun, e));
+                self.builder.call(self.rt.raise, &[exn], "");
+                // never used. a 'raise' whose type is still unknown is treated as unit
+                let llvm_ty = match *ty {
+                    Type::Var(_) => self.context.i32_type(),
+                    ref ty => ty.to_llvmty_sub(&self.context),
+                };
+                Ok(ir::undef(llvm_ty))
+            }
+            &Closure::TryWith(ref body, ref handlers) => {
+                self.gen_try_with(env, cur_fun, &*body, handlers)
+            }
+            &Closure::MakeRef(ref e) => {
+                let val = try!(self.gen_expr(env, cur_fun, e));
+                self.llvm_struct_alloc(vec![val])
+            }
+            &Closure::Deref(ref e) => {
+                let r = try!(self.gen_expr(env, cur_fun, e));
+                self.llvm_struct_elem_load(r, 0)
+            }
+            &Closure::Assign(ref e1, ref e2) => {
+                let r = try!(self.gen_expr(env, cur_fun, e1));
+                let val = try!(self.gen_expr(env, cur_fun, e2));
+                self.builder.store(val, self.builder.struct_gep(r, 0, ""));
+                self.gen_unit()
+            }
             _ => panic!(format!("not implemented {:?}", closure)),
         }
     }
 
-    unsafe fn declare_local_var(
+    fn declare_local_var(
         &mut self,
         env: &mut HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
         name: &String,
         ty: LLVMTypeRef,
     ) -> CodeGenResult<LLVMValueRef> {
-        let builder = LLVMCreateBuilderInContext(self.context);
+        let builder = self.context.builder();
 
-        let entry_bb = LLVMGetEntryBasicBlock(cur_fun.unwrap());
-        let first_inst = LLVMGetFirstInstruction(entry_bb);
+        let entry_bb = ir::entry_block(cur_fun.unwrap());
         // let var is always declared at the first of entry block
-        if first_inst == ptr::null_mut() {
-            LLVMPositionBuilderAtEnd(builder, entry_bb);
-        } else {
-            LLVMPositionBuilderBefore(builder, first_inst);
+        match ir::first_instruction(entry_bb) {
+            Some(first_inst) => builder.position_before(first_inst),
+            None => builder.position_at_end(entry_bb),
         }
-        let var = LLVMBuildAlloca(builder, ty, CString::new(name.as_str()).unwrap().as_ptr());
+        let var = builder.alloca(ty, name);
         env.insert(name.to_owned(), ValKind::Load(var));
         Ok(var)
     }
 
-    pub unsafe fn gen_letexpr(
+    pub fn gen_letexpr(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -464,13 +766,18 @@ impl<'a> CodeGen<'a> {
     ) -> CodeGenResult<LLVMValueRef> {
         let mut newenv = env.clone();
         let llvm_expr_val = try!(self.gen_expr(env, cur_fun, expr));
+        // a constant needs no slot, and its uses can see the value (see gen_int_div)
+        if ir::is_constant(llvm_expr_val) {
+            newenv.insert(name.to_owned(), ValKind::Other(llvm_expr_val));
+            return self.gen_expr(&newenv, cur_fun, body);
+        }
         let var =
-            try!(self.declare_local_var(&mut newenv, cur_fun, name, LLVMTypeOf(llvm_expr_val),));
-        LLVMBuildStore(self.builder, llvm_expr_val, var);
+            try!(self.declare_local_var(&mut newenv, cur_fun, name, ir::type_of(llvm_expr_val),));
+        self.builder.store(llvm_expr_val, var);
         self.gen_expr(&newenv, cur_fun, body)
     }
 
-    pub unsafe fn gen_lettupleexpr(
+    pub fn gen_lettupleexpr(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -484,40 +791,31 @@ impl<'a> CodeGen<'a> {
             let llvm_elem_val = try!(self.llvm_struct_elem_extract(llvm_expr_val, i as u32));
             let var =
                 try!(
-                    self.declare_local_var(&mut newenv, cur_fun, name, LLVMTypeOf(llvm_elem_val),)
+                    self.declare_local_var(&mut newenv, cur_fun, name, ir::type_of(llvm_elem_val),)
                 );
-            LLVMBuildStore(self.builder, llvm_elem_val, var);
+            self.builder.store(llvm_elem_val, var);
         }
         self.gen_expr(&newenv, cur_fun, body)
     }
 
-    // pub unsafe fn gen_letdef(
-    //     &mut self,
-    //     name: &String,
-    //     ty: &Type,
-    //     expr: &NodeKind,
-    // ) -> CodeGenResult<LLVMValueRef> {
-    //     let llvm_ty = ty.to_llvmty();
-    //     let llvm_val = LLVMAddGlobal(
-    //         self.module,
-    //         llvm_ty,
-    //         CString::new(name.as_str()).unwrap().as_ptr(),
-    //     );
-    //     LLVMSetInitializer(llvm_val, LLVMConstNull(llvm_ty));
-    //
-    //     self.global_varmap.insert(name.clone(), (
-    //         ty.clone(),
-    //         llvm_ty,
-    //         llvm_val,
-    //     ));
-    //
-    //     let llvm_expr = try!(self.gen_expr(expr));
-    //
-    //     LLVMBuildStore(self.builder, llvm_expr, llvm_val);
-    //
-    //     Ok(llvm_val)
-    // }
-    //
+    // the value of a top-level 'let x = e' lives in a global variable, so that the functions and
+    // phrases after it can read it
+    fn gen_letdef(
+        &mut self,
+        env: &HashMap<String, ValKind>,
+        cur_fun: Option<LLVMValueRef>,
+        name: &String,
+        ty: &Type,
+        expr: &Closure,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let val = try!(self.gen_expr(env, cur_fun, expr));
+        let llvm_ty = ir::type_of(val);
+        let global = self.module.add_global(name, llvm_ty);
+        self.builder.store(val, global);
+        self.global_varmap.insert(name.clone(), (ty.clone(), llvm_ty, global));
+        self.gen_unit()
+    }
+
     // pub unsafe fn gen_letfuncdef(
     //     &mut self,
     //     funcdef: &FuncDef,
@@ -527,113 +825,97 @@ impl<'a> CodeGen<'a> {
     //     Ok(ptr::null_mut())
     // }
 
-    unsafe fn llvm_ty_alloc(&mut self, ty: LLVMTypeRef) -> CodeGenResult<LLVMValueRef> {
-        let sz = LLVMSizeOf(ty);
-        let ty = LLVMPointerType(ty, 0);
-        Ok(LLVMBuildPointerCast(
-            self.builder,
-            LLVMBuildCall(
-                self.builder,
-                self.malloc,
-                vec![sz].as_mut_slice().as_mut_ptr(),
-                1,
-                CString::new("").unwrap().as_ptr(),
-            ),
-            ty,
-            CString::new("").unwrap().as_ptr(),
-        ))
+    fn llvm_ty_alloc(&self, ty: LLVMTypeRef) -> CodeGenResult<LLVMValueRef> {
+        let sz = ir::size_of(ty);
+        let ty = ir::pointer_type(ty);
+        Ok(self.builder.pointer_cast(self.builder.call(self.malloc, &[sz], ""), ty, ""))
     }
-    unsafe fn llvm_struct_alloc(&mut self, vals: Vec<LLVMValueRef>) -> CodeGenResult<LLVMValueRef> {
+    fn llvm_struct_alloc(&self, vals: Vec<LLVMValueRef>) -> CodeGenResult<LLVMValueRef> {
         let x = try!(
-            self.llvm_ty_alloc(LLVMStructType(
-                vals.iter()
-                    .map(|&v| LLVMTypeOf(v))
-                    .collect::<Vec<_>>()
-                    .as_mut_slice()
-                    .as_mut_ptr(),
-                vals.len() as u32,
-                0,
-            ))
+            self.llvm_ty_alloc(self.context.struct_type(&vals.iter()
+                    .map(|&v| ir::type_of(v))
+                    .collect::<Vec<_>>()))
         );
         for (i, val) in vals.iter().enumerate() {
-            LLVMBuildStore(
-                self.builder,
-                *val,
-                LLVMBuildStructGEP(
-                    self.builder,
-                    x,
-                    i as u32,
-                    CString::new("").unwrap().as_ptr(),
-                ),
-            );
+            self.builder.store(*val, self.builder.struct_gep(x, i as u32, ""));
         }
         Ok(x)
     }
-    unsafe fn llvm_struct_elem_load(
-        &mut self,
+    fn llvm_struct_elem_load(
+        &self,
         p: LLVMValueRef,
         idx: u32,
     ) -> CodeGenResult<LLVMValueRef> {
-        Ok(LLVMBuildLoad(
-            self.builder,
-            LLVMBuildStructGEP(self.builder, p, idx, CString::new("").unwrap().as_ptr()),
-            CString::new("").unwrap().as_ptr(),
-        ))
+        Ok(self.builder.load(self.builder.struct_gep(p, idx, ""), ""))
     }
-    unsafe fn llvm_struct_elem_extract(
-        &mut self,
+    fn llvm_struct_elem_extract(
+        &self,
         p: LLVMValueRef,
         idx: u32,
     ) -> CodeGenResult<LLVMValueRef> {
-        Ok(LLVMBuildExtractValue(
-            self.builder,
-            p,
-            idx,
-            CString::new("").unwrap().as_ptr(),
-        ))
+        Ok(self.builder.extract_value(p, idx, ""))
     }
 
-    unsafe fn make_cls(
+    fn make_cls(
         &mut self,
         env: &HashMap<String, ValKind>,
         name: String,
+        cls_ty: LLVMTypeRef,
         fun: LLVMValueRef,
         actual_fv: Vec<LLVMValueRef>,
     ) -> CodeGenResult<(HashMap<String, ValKind>, LLVMValueRef)> {
-        let actual_fv = if actual_fv.len() == 0 {
-            LLVMConstPointerNull(LLVMPointerType(LLVMInt8Type(), 0))
-        } else {
-            try!(self.llvm_struct_alloc(actual_fv))
-        };
-        let newcls = try!(self.llvm_struct_alloc(vec![fun, actual_fv]));
+        let mut fields = vec![fun];
+        fields.extend(actual_fv);
+        let newcls = try!(self.llvm_struct_alloc(fields));
+        let newcls =
+            self.builder.pointer_cast(newcls, cls_ty, "");
         let mut newenv = env.clone();
         newenv.insert(name, ValKind::Other(newcls));
         Ok((newenv, newcls))
     }
 
-    unsafe fn gen_makecls(
+    fn gen_makecls(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
         name: &String,
-        _ty: &Type,
+        ty: &Type,
         cls: &closure::Cls,
         body: &Closure,
     ) -> CodeGenResult<LLVMValueRef> {
-        let fun = try!(self.lookup_var(env, &cls.entry)).retrieve();
+        let (fun, formal_fv) = match self.funcmap.get(&cls.entry) {
+            Some(fun) => (fun.llvm_val, fun.formal_fv.clone()),
+            None => panic!(format!("not found function '{}'", cls.entry)),
+        };
+        if formal_fv.len() != cls.actual_fv.len() {
+            panic!(format!(
+                "closure of '{}' needs {} free variables but got {}",
+                cls.entry,
+                formal_fv.len(),
+                cls.actual_fv.len()
+            ))
+        }
         let fv = {
             let mut v = vec![];
-            for name in &cls.actual_fv {
-                v.push(try!(self.lookup_var(env, name)).get(self.builder))
+            for (name, &(_, ref fv_ty)) in cls.actual_fv.iter().zip(formal_fv.iter()) {
+                let val = try!(self.lookup_var(env, name)).get(&self.builder);
+                if ir::type_of(val) != fv_ty.to_llvmty_sub(&self.context) {
+                    panic!(format!(
+                        "free variable '{}' of '{}' doesn't match its environment layout",
+                        name, cls.entry
+                    ))
+                }
+                v.push(val)
             }
             v
         };
-        let (new_env, _) = try!(self.make_cls(env, name.clone(), fun, fv));
+        let cls_ty = ty.to_llvmty_sub(&self.context);
+        let (new_env, _) = try!(self.make_cls(env, name.clone(), cls_ty, fun, fv));
         let x = try!(self.gen_expr(&new_env, cur_fun, body));
         Ok(x)
     }
 
-    unsafe fn gen_cls(
+    fn gen_cls(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -646,37 +928,31 @@ impl<'a> CodeGen<'a> {
             panic!()
         };
 
-        let x = try!(self.lookup_var(env, name)).get(self.builder);
-        let fun = try!(self.llvm_struct_elem_load(x, 0));
-        let fv = try!(self.llvm_struct_elem_load(x, 1));
-
-        let mut args_val = vec![fv];
+        let x = try!(self.lookup_var(env, name)).get(&self.builder);
+        let mut args_val = vec![];
         for arg in args {
             let llvm_arg = try!(self.gen_expr(env, cur_fun, &arg));
             args_val.push(llvm_arg);
         }
+        self.call_cls(x, args_val)
+    }
 
-        // TODO: is this need?
-        // if let Some(fun) = self.ext_funcmap.get(name) {
-        //     return Ok(LLVMBuildCall(
-        //         self.builder,
-        //         fun.llvm_val,
-        //         args_val.as_mut_slice().as_mut_ptr(),
-        //         args_val.len() as u32,
-        //         CString::new("").unwrap().as_ptr(),
-        //     ));
-        // }
+    fn call_cls(
+        &mut self,
+        cls: LLVMValueRef,
+        args: Vec<LLVMValueRef>,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let fun = try!(self.llvm_struct_elem_load(cls, 0));
+        // the closure itself is the environment
+        let cls_env = self.builder.pointer_cast(cls, ir::pointer_type(self.context.i8_type()), "");
 
-        Ok(LLVMBuildCall(
-            self.builder,
-            fun,
-            args_val.as_mut_slice().as_mut_ptr(),
-            args_val.len() as u32,
-            CString::new("").unwrap().as_ptr(),
-        ))
+        let mut args_val = vec![cls_env];
+        args_val.extend(args);
+
+        Ok(self.builder.call(fun, &args_val, ""))
     }
 
-    unsafe fn gen_dir(
+    fn gen_dir(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -694,30 +970,37 @@ impl<'a> CodeGen<'a> {
             args_val.push(try!(self.gen_expr(env, cur_fun, &arg)))
         }
 
+        // an external may shadow a builtin
+        if let Some(ext) = self.externals.get(name).cloned() {
+            return Ok(self.gen_external(name, &ext, args_val));
+        }
+        if let Some(&(emit, ref ty)) = self.inline_builtins.get(name) {
+            let ty = ty.clone();
+            return emit(self, cur_fun, &ty, &args_val);
+        }
+
         // TODO: ?
         if let Some(fun) = self.ext_funcmap.get(name) {
-            return Ok(LLVMBuildCall(
-                self.builder,
-                fun.llvm_val,
-                args_val.as_mut_slice().as_mut_ptr(),
-                args_val.len() as u32,
-                CString::new("").unwrap().as_ptr(),
-            ));
+            if let Some(env) = fun.env {
+                args_val.insert(0, env);
+            }
+            return Ok(self.builder.call(fun.llvm_val, &args_val, ""));
         }
 
-        let fun = try!(self.lookup_var(env, name)).retrieve();
-        args_val.insert(0, LLVMConstNull(LLVMPointerType(LLVMInt8Type(), 0)));
+        let fun = match self.funcmap.get(name) {
+            Some(fun) if fun.reads_env => panic!(format!(
+                "'{}' reads its closure and can't be called directly",
+                name
+            )),
+            Some(fun) => fun.llvm_val,
+            None => panic!(format!("not found function '{}'", name)),
+        };
+        args_val.insert(0, ir::const_null(ir::pointer_type(self.context.i8_type())));
 
-        Ok(LLVMBuildCall(
-            self.builder,
-            fun,
-            args_val.as_mut_slice().as_mut_ptr(),
-            args_val.len() as u32,
-            CString::new("").unwrap().as_ptr(),
-        ))
+        Ok(self.builder.call(fun, &args_val, ""))
     }
 
-    unsafe fn gen_int_binop(
+    fn gen_int_binop(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -725,45 +1008,92 @@ impl<'a> CodeGen<'a> {
         lhs: &Closure,
         rhs: &Closure,
     ) -> CodeGenResult<LLVMValueRef> {
-        let inst_name = |s: &str| CString::new(s).unwrap().as_ptr();
         let lhs_val = try!(self.gen_expr(env, cur_fun, lhs));
         let rhs_val = try!(self.gen_expr(env, cur_fun, rhs));
+        let val = try!(self.build_int_binop(cur_fun, op, lhs_val, rhs_val));
         match op {
-            &BinOps::IAdd => Ok(LLVMBuildAdd(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("add"),
-            )),
-            &BinOps::ISub => Ok(LLVMBuildSub(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("sub"),
-            )),
-            &BinOps::IMul => Ok(LLVMBuildMul(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("mul"),
-            )),
-            &BinOps::IDiv => Ok(LLVMBuildSDiv(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("div"),
-            )),
-            &BinOps::IMod => Ok(LLVMBuildSRem(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("rem"),
-            )),
+            &BinOps::IMod => Ok(val),
+            _ => Ok(self.wrap_int(val)),
+        }
+    }
+
+    // also used by Int32 and Int64, whose arithmetic wraps around at their own width
+    fn build_int_binop(
+        &self,
+        cur_fun: Option<LLVMValueRef>,
+        op: &BinOps,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+    ) -> CodeGenResult<LLVMValueRef> {
+        match op {
+            &BinOps::IAdd => Ok(self.builder.add(lhs, rhs, "add")),
+            &BinOps::ISub => Ok(self.builder.sub(lhs, rhs, "sub")),
+            &BinOps::IMul => Ok(self.builder.mul(lhs, rhs, "mul")),
+            &BinOps::IDiv | &BinOps::IMod => self.gen_int_div(cur_fun, op, lhs, rhs),
             _ => panic!("not implemented"),
         }
     }
 
-    unsafe fn gen_float_binop(
+    // brings the i64 result of an int operation back into the 63-bit range
+    fn wrap_int(&self, val: LLVMValueRef) -> LLVMValueRef {
+        let shift = ir::const_int(self.context.i64_type(), (64 - node::INT_BITS) as i64);
+        let shl = self.builder.shl(val, shift, "");
+        self.builder.ashr(shl, shift, "")
+    }
+
+    // sdiv and srem are undefined on zero and on min_int / -1. a zero divisor raises
+    // Division_by_zero, and -1 is handled without dividing. both checks are left out when the
+    // divisor is a known constant
+    fn gen_int_div(
+        &self,
+        cur_fun: Option<LLVMValueRef>,
+        op: &BinOps,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let is_div = *op == BinOps::IDiv;
+        let build_div = |builder: &ir::Builder, rhs| if is_div {
+            builder.sdiv(lhs, rhs, "div")
+        } else {
+            builder.srem(lhs, rhs, "rem")
+        };
+        // x / -1 is -x, and x mod -1 is 0
+        let by_minus_one = |builder: &ir::Builder| if is_div {
+            builder.neg(lhs, "neg")
+        } else {
+            ir::const_int(ir::type_of(lhs), 0)
+        };
+
+        match ir::const_int_value(rhs) {
+            Some(0) | None => (),
+            Some(-1) => return Ok(by_minus_one(&self.builder)),
+            Some(_) => return Ok(build_div(&self.builder, rhs)),
+        }
+
+        let fun = cur_fun.unwrap();
+        let zero = ir::const_int(ir::type_of(lhs), 0);
+        let is_zero = self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntEQ, rhs, zero, "is_zero");
+        let bb_error = self.context.append_block(fun, "div.error");
+        let bb_ok = self.context.append_block(fun, "div.ok");
+        self.builder.cond_br(is_zero, bb_error, bb_ok);
+
+        self.builder.position_at_end(bb_error);
+        self.builder.call(self.rt.division_by_zero, &[], "");
+        self.builder.unreachable();
+
+        self.builder.position_at_end(bb_ok);
+        let minus_one = ir::const_int(ir::type_of(lhs), -1);
+        let is_minus_one =
+            self.builder
+                .icmp(llvm::LLVMIntPredicate::LLVMIntEQ, rhs, minus_one, "is_minus_one");
+        // divide by 1 instead of -1 so that the division itself is always defined
+        let one = ir::const_int(ir::type_of(lhs), 1);
+        let safe_rhs = self.builder.select(is_minus_one, one, rhs, "");
+        let quot = build_div(&self.builder, safe_rhs);
+        Ok(self.builder.select(is_minus_one, by_minus_one(&self.builder), quot, ""))
+    }
+
+    fn gen_float_binop(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -771,39 +1101,18 @@ impl<'a> CodeGen<'a> {
         lhs: &Closure,
         rhs: &Closure,
     ) -> CodeGenResult<LLVMValueRef> {
-        let inst_name = |s: &str| CString::new(s).unwrap().as_ptr();
         let lhs_val = try!(self.gen_expr(env, cur_fun, lhs));
         let rhs_val = try!(self.gen_expr(env, cur_fun, rhs));
         match op {
-            &BinOps::FAdd => Ok(LLVMBuildFAdd(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("fadd"),
-            )),
-            &BinOps::FSub => Ok(LLVMBuildFSub(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("fsub"),
-            )),
-            &BinOps::FMul => Ok(LLVMBuildFMul(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("fmul"),
-            )),
-            &BinOps::FDiv => Ok(LLVMBuildFDiv(
-                self.builder,
-                lhs_val,
-                rhs_val,
-                inst_name("fdiv"),
-            )),
+            &BinOps::FAdd => Ok(self.builder.fadd(lhs_val, rhs_val, "fadd")),
+            &BinOps::FSub => Ok(self.builder.fsub(lhs_val, rhs_val, "fsub")),
+            &BinOps::FMul => Ok(self.builder.fmul(lhs_val, rhs_val, "fmul")),
+            &BinOps::FDiv => Ok(self.builder.fdiv(lhs_val, rhs_val, "fdiv")),
             _ => panic!("not implemented"),
         }
     }
 
-    unsafe fn gen_comp_binop(
+    fn gen_comp_binop(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -811,57 +1120,23 @@ impl<'a> CodeGen<'a> {
         lhs: &Closure,
         rhs: &Closure,
     ) -> CodeGenResult<LLVMValueRef> {
-        let inst_name = |s: &str| CString::new(s).unwrap().as_ptr();
         let lhs_val = try!(self.gen_expr(env, cur_fun, lhs));
         let rhs_val = try!(self.gen_expr(env, cur_fun, rhs));
-        match op {
-            &CompBinOps::SEq => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntEQ,
-                lhs_val,
-                rhs_val,
-                inst_name("eq"),
-            )),
-            &CompBinOps::SNe => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntNE,
-                lhs_val,
-                rhs_val,
-                inst_name("ne"),
-            )),
-            &CompBinOps::Lt => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntSLT,
-                lhs_val,
-                rhs_val,
-                inst_name("lt"),
-            )),
-            &CompBinOps::Le => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntSLE,
-                lhs_val,
-                rhs_val,
-                inst_name("le"),
-            )),
-            &CompBinOps::Gt => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntSGT,
-                lhs_val,
-                rhs_val,
-                inst_name("gt"),
-            )),
-            &CompBinOps::Ge => Ok(LLVMBuildICmp(
-                self.builder,
-                llvm::LLVMIntPredicate::LLVMIntSGE,
-                lhs_val,
-                rhs_val,
-                inst_name("ge"),
-            )),
+        let pred = match op {
+            &CompBinOps::SEq => llvm::LLVMIntPredicate::LLVMIntEQ,
+            &CompBinOps::SNe => llvm::LLVMIntPredicate::LLVMIntNE,
+            &CompBinOps::Lt => llvm::LLVMIntPredicate::LLVMIntSLT,
+            &CompBinOps::Le => llvm::LLVMIntPredicate::LLVMIntSLE,
+            &CompBinOps::Gt => llvm::LLVMIntPredicate::LLVMIntSGT,
+            &CompBinOps::Ge => llvm::LLVMIntPredicate::LLVMIntSGE,
             // TODO: more ops!
             _ => panic!("not supported"),
-        }
+        };
+        let cmp = self.builder.icmp(pred, lhs_val, rhs_val, "cmp");
+        // bool is an i32 everywhere else (see Type::to_llvmty)
+        Ok(self.builder.zext(cmp, self.context.i32_type(), ""))
     }
-    unsafe fn gen_if_expr(
+    fn gen_if_expr(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -871,64 +1146,454 @@ impl<'a> CodeGen<'a> {
     ) -> CodeGenResult<LLVMValueRef> {
         macro_rules! tailcall {
             ($closure:expr, $val:expr) => (
-                LLVMSetTailCall(
-                    $val,
-                    match $closure {
-                        &Closure::AppCls(_, _) |
-                        &Closure::AppDir(_, _) => 1,
-                        _ => 0,
-                    },
-                );
+                match $closure {
+                    &Closure::AppCls(_, _) |
+                    &Closure::AppDir(_, _) if self.try_depth == 0 => ir::set_tail_call($val),
+                    _ => (),
+                }
             );
         };
         let cond_val = try!(self.gen_expr(env, cur_fun, cond));
+        let zero = ir::const_int(self.context.i32_type(), 0);
+        let cond_val =
+            self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntNE, cond_val, zero, "cond");
 
         let fun = cur_fun.unwrap();
-        let bb_then = LLVMAppendBasicBlock(fun, CString::new("then").unwrap().as_ptr());
-        let bb_else = LLVMAppendBasicBlock(fun, CString::new("else").unwrap().as_ptr());
-        let bb_merge = LLVMAppendBasicBlock(fun, CString::new("merge").unwrap().as_ptr());
+        let bb_then = self.context.append_block(fun, "then");
+        let bb_else = self.context.append_block(fun, "else");
+        let bb_merge = self.context.append_block(fun, "merge");
 
-        LLVMBuildCondBr(self.builder, cond_val, bb_then, bb_else);
+        self.builder.cond_br(cond_val, bb_then, bb_else);
 
-        LLVMPositionBuilderAtEnd(self.builder, bb_then);
+        self.builder.position_at_end(bb_then);
 
         let then_val = try!(self.gen_expr(env, cur_fun, then));
         tailcall!(then, then_val);
         // if cur_bb_has_no_terminator(self.builder) {
-        let actual_bb_then = LLVMGetInsertBlock(self.builder);
-        LLVMBuildBr(self.builder, bb_merge);
+        let actual_bb_then = self.builder.insert_block();
+        self.builder.br(bb_merge);
         // }
 
-        LLVMPositionBuilderAtEnd(self.builder, bb_else);
+        self.builder.position_at_end(bb_else);
 
         let else_val = try!(self.gen_expr(env, cur_fun, els));
         tailcall!(els, else_val);
         // if cur_bb_has_no_terminator(self.builder) {
-        let actual_bb_else = LLVMGetInsertBlock(self.builder);
-        LLVMBuildBr(self.builder, bb_merge);
+        let actual_bb_else = self.builder.insert_block();
+        self.builder.br(bb_merge);
         // }
 
-        LLVMPositionBuilderAtEnd(self.builder, bb_merge);
+        self.builder.position_at_end(bb_merge);
 
-        let phi = LLVMBuildPhi(
-            self.builder,
-            LLVMTypeOf(then_val),
-            CString::new("phi").unwrap().as_ptr(),
-        );
-        LLVMAddIncoming(
-            phi,
-            vec![then_val].as_mut_slice().as_mut_ptr(),
-            vec![actual_bb_then].as_mut_slice().as_mut_ptr(),
-            1,
+        Ok(self.builder.phi(
+            ir::type_of(then_val),
+            &[(then_val, actual_bb_then), (else_val, actual_bb_else)],
+        ))
+    }
+
+    fn gen_while(
+        &mut self,
+        env: &HashMap<String, ValKind>,
+        cur_fun: Option<LLVMValueRef>,
+        cond: &Closure,
+        body: &Closure,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let fun = cur_fun.unwrap();
+        let bb_cond = self.context.append_block(fun, "while.cond");
+        let bb_body = self.context.append_block(fun, "while.body");
+        let bb_end = self.context.append_block(fun, "while.end");
+
+        self.builder.br(bb_cond);
+
+        self.builder.position_at_end(bb_cond);
+        let cond_val = try!(self.gen_expr(env, cur_fun, cond));
+        let zero = ir::const_int(self.context.i32_type(), 0);
+        let cond_val =
+            self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntNE, cond_val, zero, "cond");
+        self.builder.cond_br(cond_val, bb_body, bb_end);
+
+        self.builder.position_at_end(bb_body);
+        try!(self.gen_expr(env, cur_fun, body));
+        self.builder.br(bb_cond);
+
+        self.builder.position_at_end(bb_end);
+        self.gen_unit()
+    }
+
+    // the bound is compared before the counter is stepped, so 'to max_int' doesn't overflow
+    fn gen_for(
+        &mut self,
+        env: &HashMap<String, ValKind>,
+        cur_fun: Option<LLVMValueRef>,
+        var: &String,
+        from: &Closure,
+        to: &Closure,
+        dir: &Direction,
+        body: &Closure,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let fun = cur_fun.unwrap();
+        let (enter_pred, step) = match *dir {
+            Direction::Upto => (llvm::LLVMIntPredicate::LLVMIntSLE, 1),
+            Direction::Downto => (llvm::LLVMIntPredicate::LLVMIntSGE, -1),
+        };
+
+        let from_val = try!(self.gen_expr(env, cur_fun, from));
+        let to_val = try!(self.gen_expr(env, cur_fun, to));
+        let mut newenv = env.clone();
+        let counter =
+            try!(self.declare_local_var(&mut newenv, cur_fun, var, self.context.i64_type()));
+        self.builder.store(from_val, counter);
+
+        let bb_body = self.context.append_block(fun, "for.body");
+        let bb_step = self.context.append_block(fun, "for.step");
+        let bb_end = self.context.append_block(fun, "for.end");
+
+        let enter = self.builder.icmp(enter_pred, from_val, to_val, "enter");
+        self.builder.cond_br(enter, bb_body, bb_end);
+
+        self.builder.position_at_end(bb_body);
+        try!(self.gen_expr(&newenv, cur_fun, body));
+        let i = self.builder.load(counter, "");
+        let last = self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntEQ, i, to_val, "last");
+        self.builder.cond_br(last, bb_end, bb_step);
+
+        self.builder.position_at_end(bb_step);
+        let next = self.builder.add(i, ir::const_int(self.context.i64_type(), step as i64), "");
+        self.builder.store(next, counter);
+        self.builder.br(bb_body);
+
+        self.builder.position_at_end(bb_end);
+        self.gen_unit()
+    }
+
+    // the body runs in a function of its own, which returns 0 after it and 1 when a raised
+    // exception comes back to its '_setjmp'. a function that calls '_setjmp' makes no tail calls,
+    // so the recursive calls around a 'try' would use up the stack otherwise. for the same reason
+    // the variables are passed by value and the result comes back in a global: the address of a
+    // local variable passed to a call keeps the later calls from being tail calls
+    fn gen_try_with(
+        &mut self,
+        env: &HashMap<String, ValKind>,
+        cur_fun: Option<LLVMValueRef>,
+        body: &Closure,
+        handlers: &Vec<Handler<Closure>>,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let fun = cur_fun.unwrap();
+        let ptr_ty = ir::pointer_type(self.context.i8_type());
+        let zero = ir::const_int(self.context.i32_type(), 0);
+
+        // the body gets the variables of env as parameters
+        let vars = env.iter().collect::<Vec<_>>();
+        let param_tys = vars
+            .iter()
+            .map(|&(_, val)| match *val {
+                ValKind::Load(ptr) => ir::element_type(ir::type_of(ptr)),
+                ValKind::Other(val) => ir::type_of(val),
+            })
+            .collect::<Vec<_>>();
+        let try_fun = self.module.add_function(
+            &format!("{}.try", ir::name_of(fun)),
+            ir::function_type(self.context.i32_type(), &param_tys),
         );
-        LLVMAddIncoming(
-            phi,
-            vec![else_val].as_mut_slice().as_mut_ptr(),
-            vec![actual_bb_else].as_mut_slice().as_mut_ptr(),
-            1,
+        self.context.add_fun_attr(try_fun, "noinline");
+        let mut try_env = HashMap::new();
+        for (i, &(name, _)) in vars.iter().enumerate() {
+            try_env.insert(name.clone(), ValKind::Other(ir::param(try_fun, i as u32)));
+        }
+
+        let bb_cur = self.builder.insert_block();
+        let bb_entry = self.context.append_block(try_fun, "entry");
+        let bb_body = self.context.append_block(try_fun, "try.body");
+        let bb_raised = self.context.append_block(try_fun, "try.raised");
+
+        self.builder.position_at_end(bb_entry);
+        let jmp_buf = try!(self.declare_local_var(
+            &mut try_env.clone(),
+            Some(try_fun),
+            &"jmp_buf".to_string(),
+            ir::array_type(self.context.i8_type(), runtime::JMP_BUF_SIZE),
+        ));
+        ir::set_alignment(jmp_buf, 16);
+        let jmp_buf = self.builder.pointer_cast(jmp_buf, ptr_ty, "");
+        let jumped = self.builder.call(self.rt.setjmp, &[jmp_buf], "");
+        let entered = self
+            .builder
+            .icmp(llvm::LLVMIntPredicate::LLVMIntEQ, jumped, zero, "entered");
+        self.builder.cond_br(entered, bb_body, bb_raised);
+
+        self.builder.position_at_end(bb_body);
+        self.builder.call(self.rt.push_handler, &[jmp_buf], "");
+        self.try_depth += 1;
+        let body_val = try!(self.gen_expr(&try_env, Some(try_fun), body));
+        self.try_depth -= 1;
+        self.builder.call(self.rt.pop_handler, &[], "");
+        let ret = self.module.add_global(
+            &format!("{}.ret", ir::name_of(try_fun)),
+            ir::type_of(body_val),
         );
+        self.builder.store(body_val, ret);
+        self.builder.ret(zero);
+
+        self.builder.position_at_end(bb_raised);
+        self.builder.ret(ir::const_int(self.context.i32_type(), 1));
+
+        self.builder.position_at_end(bb_cur);
+        let args = vars
+            .iter()
+            .map(|&(_, val)| val.get(&self.builder))
+            .collect::<Vec<_>>();
+        let raised = self.builder.call(try_fun, &args, "");
+        let raised = self
+            .builder
+            .icmp(llvm::LLVMIntPredicate::LLVMIntNE, raised, zero, "raised");
+        let bb_done = self.context.append_block(fun, "try.done");
+        let bb_handler = self.context.append_block(fun, "try.handler");
+        let bb_end = self.context.append_block(fun, "try.end");
+        self.builder.cond_br(raised, bb_handler, bb_done);
+
+        self.builder.position_at_end(bb_done);
+        let body_val = self.builder.load(ret, "");
+        let mut incoming = vec![(body_val, bb_done)];
+        self.builder.br(bb_end);
+
+        self.builder.position_at_end(bb_handler);
+        let exn = self.builder.call(self.rt.current_exn, &[], "exn");
+        let tag_ptr = self
+            .builder
+            .pointer_cast(exn, ir::pointer_type(self.context.i64_type()), "");
+        let tag = self.builder.load(tag_ptr, "tag");
+        let mut exhaustive = false;
+        for handler in handlers {
+            let mut newenv = env.clone();
+            match handler.constr {
+                Some(ref name) => {
+                    let expected = match self.exn_tag(name) {
+                        Some(tag) => tag,
+                        None => panic!(format!("not found exception '{}'", name)),
+                    };
+                    let expected = ir::const_int(self.context.i64_type(), expected as i64);
+                    let matched = self.builder.icmp(
+                        llvm::LLVMIntPredicate::LLVMIntEQ,
+                        tag,
+                        expected,
+                        "matched",
+                    );
+                    let bb_case = self.context.append_block(fun, "try.case");
+                    let bb_next = self.context.append_block(fun, "try.next");
+                    self.builder.cond_br(matched, bb_case, bb_next);
+
+                    self.builder.position_at_end(bb_case);
+                    if let Some((ref x, ref ty)) = handler.var {
+                        let field_tys =
+                            vec![self.context.i64_type(), ty.to_llvmty_sub(&self.context)];
+                        let block_ty = ir::pointer_type(self.context.struct_type(&field_tys));
+                        let block = self.builder.pointer_cast(exn, block_ty, "");
+                        let arg = try!(self.llvm_struct_elem_load(block, 1));
+                        let var =
+                            try!(self.declare_local_var(&mut newenv, cur_fun, x, ir::type_of(arg)));
+                        self.builder.store(arg, var);
+                    }
+                    let val = try!(self.gen_expr(&newenv, cur_fun, &handler.body));
+                    incoming.push((val, self.builder.insert_block()));
+                    self.builder.br(bb_end);
+
+                    self.builder.position_at_end(bb_next);
+                }
+                None => {
+                    if let Some((ref x, _)) = handler.var {
+                        newenv.insert(x.clone(), ValKind::Other(exn));
+                    }
+                    let val = try!(self.gen_expr(&newenv, cur_fun, &handler.body));
+                    incoming.push((val, self.builder.insert_block()));
+                    self.builder.br(bb_end);
+                    exhaustive = true;
+                    break;
+                }
+            }
+        }
+        // no handler matched: pass the exception to the enclosing one
+        if !exhaustive {
+            self.builder.call(self.rt.raise, &[exn], "");
+            self.builder.unreachable();
+        }
+
+        self.builder.position_at_end(bb_end);
+        Ok(self.builder.phi(ir::type_of(body_val), &incoming))
+    }
 
-        Ok(phi)
+    fn array_make(
+        &self,
+        len: LLVMValueRef,
+        elem_ty: LLVMTypeRef,
+    ) -> CodeGenResult<LLVMValueRef> {
+        let ary = self.builder.call(self.rt.array_make, &[len, ir::size_of(elem_ty)], "");
+        let ary_ty = ir::pointer_type(array_block_ty(&self.context, elem_ty));
+        Ok(self.builder.pointer_cast(ary, ary_ty, "ary"))
+    }
+
+    fn array_len(&self, ary: LLVMValueRef) -> LLVMValueRef {
+        self.builder.load(self.builder.struct_gep(ary, 0, ""), "len")
+    }
+
+    fn array_elem_ptr(&self, ary: LLVMValueRef, idx: LLVMValueRef) -> LLVMValueRef {
+        self.builder.gep(ary, &[
+                ir::const_int(self.context.i32_type(), 0),
+                ir::const_int(self.context.i32_type(), 1),
+                idx,
+            ], "")
+    }
+
+    // a negative index is caught by the unsigned comparison
+    fn check_index(
+        &self,
+        cur_fun: Option<LLVMValueRef>,
+        ary: LLVMValueRef,
+        idx: LLVMValueRef,
+    ) -> CodeGenResult<()> {
+        if !self.bounds_check {
+            return Ok(());
+        }
+        let fun = cur_fun.unwrap();
+        let len = try!(self.llvm_struct_elem_load(ary, 0));
+        let out_of_bounds =
+            self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntUGE, idx, len, "out_of_bounds");
+        let bb_error = self.context.append_block(fun, "bounds.error");
+        let bb_ok = self.context.append_block(fun, "bounds.ok");
+        self.builder.cond_br(out_of_bounds, bb_error, bb_ok);
+
+        self.builder.position_at_end(bb_error);
+        let msg = self.builder.global_string_ptr("index out of bounds", "str");
+        self.builder.call(self.rt.invalid_argument, &[msg], "");
+        self.builder.unreachable();
+
+        self.builder.position_at_end(bb_ok);
+        Ok(())
+    }
+
+    // runs body with i = 0, 1, ..., n - 1
+    fn build_loop<F>(
+        &mut self,
+        cur_fun: Option<LLVMValueRef>,
+        n: LLVMValueRef,
+        mut body: F,
+    ) -> CodeGenResult<()>
+    where
+        F: FnMut(&mut Self, LLVMValueRef) -> CodeGenResult<()>,
+    {
+        let fun = cur_fun.unwrap();
+        let counter = try!(self.declare_local_var(
+            &mut HashMap::new(),
+            cur_fun,
+            &"i".to_string(),
+            self.context.i64_type(),
+        ));
+        self.builder.store(ir::const_int(self.context.i64_type(), 0), counter);
+
+        let bb_cond = self.context.append_block(fun, "loop.cond");
+        let bb_body = self.context.append_block(fun, "loop.body");
+        let bb_end = self.context.append_block(fun, "loop.end");
+        self.builder.br(bb_cond);
+
+        self.builder.position_at_end(bb_cond);
+        let i = self.builder.load(counter, "i");
+        let cond = self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntSLT, i, n, "cond");
+        self.builder.cond_br(cond, bb_body, bb_end);
+
+        self.builder.position_at_end(bb_body);
+        try!(body(self, i));
+        let next = self.builder.add(i, ir::const_int(self.context.i64_type(), 1), "");
+        self.builder.store(next, counter);
+        self.builder.br(bb_cond);
+
+        self.builder.position_at_end(bb_end);
+        Ok(())
+    }
+
+    // calls a function declared by 'external'. unit parameters are left out and a unit result is
+    // void, so 'unit -> int' is 'int64_t f(void)'. bool is a C int, true when nonzero
+    fn gen_external(
+        &mut self,
+        name: &str,
+        ext: &typing::External,
+        args: Vec<LLVMValueRef>,
+    ) -> LLVMValueRef {
+        let (params, ret) = match ext.ty {
+            Type::Func(ref params, ref ret) => (params, &**ret),
+            _ => panic!(),
+        };
+        let mut c_args = vec![];
+        for (param, arg) in params.iter().zip(args) {
+            if *param != Type::Unit {
+                c_args.push(arg)
+            }
+        }
+        let fun = match self.ext_funcmap.get(name) {
+            Some(fun) => fun.llvm_val,
+            None => {
+                // Session::compile has checked that the symbol is defined
+                let addr = match ir::search_symbol(&ext.symbol) {
+                    Some(addr) => addr,
+                    None => panic!(format!(
+                        "external '{}': undefined symbol '{}'",
+                        name, ext.symbol
+                    )),
+                };
+                let ret_ty = if *ret == Type::Unit {
+                    self.context.void_type()
+                } else {
+                    ret.to_llvmty_sub(&self.context)
+                };
+                let param_tys = params
+                    .iter()
+                    .filter(|t| **t != Type::Unit)
+                    .map(|t| t.to_llvmty_sub(&self.context))
+                    .collect();
+                // the program vouches for the C type of the symbol, as in OCaml
+                let fun = unsafe {
+                    add_runtime_fun(
+                        self.module,
+                        &self.exec_engine,
+                        &ext.symbol,
+                        ret_ty,
+                        param_tys,
+                        addr,
+                    )
+                };
+                self.ext_funcmap.insert(
+                    name.to_string(),
+                    ExtFunc {
+                        llvm_val: fun,
+                        ty: ext.ty.clone(),
+                        env: None,
+                    },
+                );
+                fun
+            }
+        };
+        let val = self.builder.call(fun, &c_args, "");
+        match *ret {
+            Type::Unit => ir::const_int(self.context.i32_type(), 0),
+            Type::Bool => {
+                let zero = ir::const_int(self.context.i32_type(), 0);
+                let b = self.builder.icmp(llvm::LLVMIntPredicate::LLVMIntNE, val, zero, "");
+                self.builder.zext(b, self.context.i32_type(), "")
+            }
+            Type::Int => self.wrap_int(val),
+            _ => val,
+        }
+    }
+
+    // declares an LLVM intrinsic or a C library function on its first use
+    fn get_or_add_fun(
+        &self,
+        name: &str,
+        ret_ty: LLVMTypeRef,
+        param_tys: Vec<LLVMTypeRef>,
+    ) -> LLVMValueRef {
+        match self.module.get_function(name) {
+            Some(fun) => fun,
+            None => self.module.add_function(name, ir::function_type(ret_ty, &param_tys)),
+        }
     }
 
     // unsafe fn gen_make_array(
@@ -941,7 +1606,7 @@ impl<'a> CodeGen<'a> {
     //
     // }
 
-    unsafe fn lookup_var(
+    fn lookup_var(
         &mut self,
         env: &HashMap<String, ValKind>,
         name: &String,
@@ -955,16 +1620,16 @@ impl<'a> CodeGen<'a> {
         }
     }
 
-    unsafe fn gen_var_load(
+    fn gen_var_load(
         &mut self,
         env: &HashMap<String, ValKind>,
         name: &String,
     ) -> CodeGenResult<LLVMValueRef> {
         let val = try!(self.lookup_var(env, name));
-        Ok(val.get(self.builder))
+        Ok(val.get(&self.builder))
     }
 
-    unsafe fn gen_tuple(
+    fn gen_tuple(
         &mut self,
         env: &HashMap<String, ValKind>,
         cur_fun: Option<LLVMValueRef>,
@@ -977,106 +1642,353 @@ impl<'a> CodeGen<'a> {
             }
             v
         };
-        Ok(LLVMBuildLoad(
-            self.builder,
-            try!(self.llvm_struct_alloc(es)),
-            CString::new("").unwrap().as_ptr(),
-        ))
+        Ok(self.builder.load(try!(self.llvm_struct_alloc(es)), ""))
+    }
+
+    fn gen_int(&self, i: i64) -> CodeGenResult<LLVMValueRef> {
+        Ok(ir::const_int(self.context.i64_type(), i as i64))
     }
 
-    unsafe fn gen_int(&mut self, i: i32) -> CodeGenResult<LLVMValueRef> {
-        Ok(LLVMConstInt(LLVMInt32Type(), i as u64, 0))
+    fn gen_unit(&self) -> CodeGenResult<LLVMValueRef> {
+        Ok(ir::const_int(self.context.i32_type(), 0))
     }
 
-    unsafe fn gen_bool(&mut self, b: bool) -> CodeGenResult<LLVMValueRef> {
-        Ok(LLVMConstInt(LLVMInt32Type(), if b { 1 } else { 0 }, 0))
+    fn gen_bool(&self, b: bool) -> CodeGenResult<LLVMValueRef> {
+        Ok(ir::const_int(self.context.i32_type(), if b { 1 } else { 0 }))
     }
 
-    unsafe fn gen_float(&mut self, f: f64) -> CodeGenResult<LLVMValueRef> {
-        Ok(LLVMConstReal(LLVMDoubleType(), f))
+    fn gen_float(&self, f: f64) -> CodeGenResult<LLVMValueRef> {
+        Ok(ir::const_real(self.context.double_type(), f))
     }
 }
 
+// the builtins generated inline (see builtin.rs). an emitter gets the function the call is in,
+// the type of the builtin and the arguments
+pub mod emit {
+    use super::llvm;
+    use super::llvm::prelude::*;
+    use super::{array_elem_ty, cls_ret_ty, CodeGen, CodeGenResult};
+
+    use std::collections::HashMap;
+
+    use ir;
+    use node::BinOps;
+    use typing::Type;
+
+    pub type Emit =
+        fn(&mut CodeGen, Option<LLVMValueRef>, &Type, &[LLVMValueRef])
+            -> CodeGenResult<LLVMValueRef>;
+
+    macro_rules! emitter {
+        ($name:ident, |$cg:ident, $cur_fun:ident, $ty:ident, $args:ident| $body:expr) => {
+            #[allow(unused_variables)]
+            pub fn $name(
+                $cg: &mut CodeGen,
+                $cur_fun: Option<LLVMValueRef>,
+                $ty: &Type,
+                $args: &[LLVMValueRef],
+            ) -> CodeGenResult<LLVMValueRef> {
+                $body
+            }
+        };
+    }
+
+    // the higher-order functions call closures, so they are generated inline rather than
+    // provided by the runtime
+    emitter!(array_length, |cg, cur_fun, ty, args| Ok(cg.array_len(args[0])));
+
+    emitter!(array_init, |cg, cur_fun, ty, args| {
+        let (len, f) = (args[0], args[1]);
+        let ary = try!(cg.array_make(len, cls_ret_ty(ir::type_of(f))));
+        try!(cg.build_loop(cur_fun, len, |cg, i| {
+            let val = try!(cg.call_cls(f, vec![i]));
+            cg.builder.store(val, cg.array_elem_ptr(ary, i));
+            Ok(())
+        }));
+        Ok(ary)
+    });
+
+    emitter!(array_iter, |cg, cur_fun, ty, args| {
+        let (f, ary) = (args[0], args[1]);
+        let len = cg.array_len(ary);
+        try!(cg.build_loop(cur_fun, len, |cg, i| {
+            let elem = cg.builder.load(cg.array_elem_ptr(ary, i), "");
+            try!(cg.call_cls(f, vec![elem]));
+            Ok(())
+        }));
+        cg.gen_unit()
+    });
+
+    emitter!(array_map, |cg, cur_fun, ty, args| {
+        let (f, ary) = (args[0], args[1]);
+        let len = cg.array_len(ary);
+        let result = try!(cg.array_make(len, cls_ret_ty(ir::type_of(f))));
+        try!(cg.build_loop(cur_fun, len, |cg, i| {
+            let elem = cg.builder.load(cg.array_elem_ptr(ary, i), "");
+            let val = try!(cg.call_cls(f, vec![elem]));
+            cg.builder.store(val, cg.array_elem_ptr(result, i));
+            Ok(())
+        }));
+        Ok(result)
+    });
+
+    emitter!(array_fold_left, |cg, cur_fun, ty, args| {
+        let (f, init, ary) = (args[0], args[1], args[2]);
+        let acc = try!(cg.declare_local_var(
+            &mut HashMap::new(),
+            cur_fun,
+            &"acc".to_string(),
+            ir::type_of(init),
+        ));
+        cg.builder.store(init, acc);
+        let len = cg.array_len(ary);
+        try!(cg.build_loop(cur_fun, len, |cg, i| {
+            let elem = cg.builder.load(cg.array_elem_ptr(ary, i), "");
+            let cur = cg.builder.load(acc, "");
+            let val = try!(cg.call_cls(f, vec![cur, elem]));
+            cg.builder.store(val, acc);
+            Ok(())
+        }));
+        Ok(cg.builder.load(acc, ""))
+    });
+
+    emitter!(array_blit, |cg, cur_fun, ty, args| {
+        let ptr_ty = ir::pointer_type(cg.context.i8_type());
+        let elem_size = ir::size_of(array_elem_ty(ir::type_of(args[0])));
+        let args_val = vec![
+            cg.builder.pointer_cast(args[0], ptr_ty, ""),
+            args[1],
+            cg.builder.pointer_cast(args[2], ptr_ty, ""),
+            args[3],
+            args[4],
+            elem_size,
+        ];
+        Ok(cg.builder.call(cg.rt.array_blit, &args_val, ""))
+    });
+
+    emitter!(array_copy, |cg, cur_fun, ty, args| {
+        let ptr_ty = ir::pointer_type(cg.context.i8_type());
+        let ary_ty = ir::type_of(args[0]);
+        let elem_size = ir::size_of(array_elem_ty(ary_ty));
+        let ary = cg.builder.pointer_cast(args[0], ptr_ty, "");
+        let copy = cg.builder.call(cg.rt.array_copy, &[ary, elem_size], "");
+        Ok(cg.builder.pointer_cast(copy, ary_ty, ""))
+    });
+
+    // Int32 and Int64 values are plain i32 and i64, so unlike int they wrap around at their
+    // full width. the same emitters serve both modules, told apart by the type of the builtin
+    fn is_int32(ty: &Type) -> bool {
+        match *ty {
+            Type::Func(ref params, ref ret) => {
+                **ret == Type::Int32 || params.first() == Some(&Type::Int32)
+            }
+            _ => false,
+        }
+    }
+
+    // an int argument (a shift amount or of_int) as an i32 or i64
+    fn from_int(cg: &CodeGen, ty: &Type, x: LLVMValueRef) -> LLVMValueRef {
+        if is_int32(ty) {
+            cg.builder.trunc(x, cg.context.i32_type(), "")
+        } else {
+            x
+        }
+    }
+
+    fn intn_ty(cg: &CodeGen, ty: &Type) -> LLVMTypeRef {
+        if is_int32(ty) {
+            cg.context.i32_type()
+        } else {
+            cg.context.i64_type()
+        }
+    }
+
+    emitter!(intn_add, |cg, cur_fun, ty, args| {
+        cg.build_int_binop(cur_fun, &BinOps::IAdd, args[0], args[1])
+    });
+    emitter!(intn_sub, |cg, cur_fun, ty, args| {
+        cg.build_int_binop(cur_fun, &BinOps::ISub, args[0], args[1])
+    });
+    emitter!(intn_mul, |cg, cur_fun, ty, args| {
+        cg.build_int_binop(cur_fun, &BinOps::IMul, args[0], args[1])
+    });
+    emitter!(intn_div, |cg, cur_fun, ty, args| {
+        cg.build_int_binop(cur_fun, &BinOps::IDiv, args[0], args[1])
+    });
+    emitter!(intn_rem, |cg, cur_fun, ty, args| {
+        cg.build_int_binop(cur_fun, &BinOps::IMod, args[0], args[1])
+    });
+    emitter!(intn_logand, |cg, cur_fun, ty, args| Ok(cg.builder.and(args[0], args[1], "")));
+    emitter!(intn_logor, |cg, cur_fun, ty, args| Ok(cg.builder.or(args[0], args[1], "")));
+    emitter!(intn_logxor, |cg, cur_fun, ty, args| Ok(cg.builder.xor(args[0], args[1], "")));
+    emitter!(intn_neg, |cg, cur_fun, ty, args| Ok(cg.builder.neg(args[0], "")));
+    emitter!(intn_lognot, |cg, cur_fun, ty, args| Ok(cg.builder.not(args[0], "")));
+
+    emitter!(intn_abs, |cg, cur_fun, ty, args| {
+        let zero = ir::const_int(intn_ty(cg, ty), 0);
+        let is_neg = cg.builder.icmp(llvm::LLVMIntPredicate::LLVMIntSLT, args[0], zero, "");
+        let neg = cg.builder.neg(args[0], "");
+        Ok(cg.builder.select(is_neg, neg, args[0], ""))
+    });
+
+    emitter!(intn_shift_left, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.shl(args[0], from_int(cg, ty, args[1]), ""))
+    });
+    emitter!(intn_shift_right, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.ashr(args[0], from_int(cg, ty, args[1]), ""))
+    });
+    emitter!(intn_shift_right_logical, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.lshr(args[0], from_int(cg, ty, args[1]), ""))
+    });
+    emitter!(intn_of_int, |cg, cur_fun, ty, args| Ok(from_int(cg, ty, args[0])));
+
+    // an int64 loses its top bit like in OCaml
+    emitter!(intn_to_int, |cg, cur_fun, ty, args| if is_int32(ty) {
+        Ok(cg.builder.sext(args[0], cg.context.i64_type(), ""))
+    } else {
+        Ok(cg.wrap_int(args[0]))
+    });
+
+    emitter!(intn_of_float, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.fp_to_si(args[0], intn_ty(cg, ty), ""))
+    });
+    emitter!(intn_to_float, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.si_to_fp(args[0], cg.context.double_type(), ""))
+    });
+
+    emitter!(intn_to_string, |cg, cur_fun, ty, args| {
+        let i = cg.builder.sext(args[0], cg.context.i64_type(), "");
+        Ok(cg.builder.call(cg.rt.int64_to_string, &[i], ""))
+    });
+
+    // the math functions LLVM has intrinsics for are optimized like arithmetic (e.g. 'sqrt 4.0'
+    // is folded), and the rest call the C library
+    macro_rules! math {
+        ($($name:ident => $fun:expr),*) => ($(
+            emitter!($name, |cg, cur_fun, ty, args| {
+                let double = cg.context.double_type();
+                let fun = cg.get_or_add_fun($fun, double, vec![double; args.len()]);
+                Ok(cg.builder.call(fun, args, ""))
+            });
+        )*);
+    }
+
+    math!(
+        sqrt => "llvm.sqrt.f64",
+        sin => "llvm.sin.f64",
+        cos => "llvm.cos.f64",
+        exp => "llvm.exp.f64",
+        log => "llvm.log.f64",
+        log10 => "llvm.log10.f64",
+        floor => "llvm.floor.f64",
+        ceil => "llvm.ceil.f64",
+        fabs => "llvm.fabs.f64",
+        tan => "tan",
+        asin => "asin",
+        acos => "acos",
+        atan => "atan",
+        atan2 => "atan2"
+    );
+
+    emitter!(fsqr, |cg, cur_fun, ty, args| Ok(cg.builder.fmul(args[0], args[0], "")));
+
+    emitter!(fhalf, |cg, cur_fun, ty, args| {
+        let half = ir::const_real(cg.context.double_type(), 0.5);
+        Ok(cg.builder.fmul(args[0], half, ""))
+    });
+
+    emitter!(fneg, |cg, cur_fun, ty, args| Ok(cg.builder.fneg(args[0], "")));
+
+    // compares x with y and gives a bool
+    fn fcmp(cg: &CodeGen, pred: llvm::LLVMRealPredicate, x: LLVMValueRef, y: LLVMValueRef)
+        -> LLVMValueRef {
+        let cmp = cg.builder.fcmp(pred, x, y, "");
+        cg.builder.zext(cmp, cg.context.i32_type(), "")
+    }
+
+    emitter!(fispos, |cg, cur_fun, ty, args| {
+        let zero = ir::const_real(cg.context.double_type(), 0.0);
+        Ok(fcmp(cg, llvm::LLVMRealPredicate::LLVMRealOGT, args[0], zero))
+    });
+    emitter!(fisneg, |cg, cur_fun, ty, args| {
+        let zero = ir::const_real(cg.context.double_type(), 0.0);
+        Ok(fcmp(cg, llvm::LLVMRealPredicate::LLVMRealOLT, args[0], zero))
+    });
+    emitter!(fiszero, |cg, cur_fun, ty, args| {
+        let zero = ir::const_real(cg.context.double_type(), 0.0);
+        Ok(fcmp(cg, llvm::LLVMRealPredicate::LLVMRealOEQ, args[0], zero))
+    });
+    emitter!(fless, |cg, cur_fun, ty, args| {
+        Ok(fcmp(cg, llvm::LLVMRealPredicate::LLVMRealOLT, args[0], args[1]))
+    });
+    emitter!(fequal, |cg, cur_fun, ty, args| {
+        Ok(fcmp(cg, llvm::LLVMRealPredicate::LLVMRealOEQ, args[0], args[1]))
+    });
+
+    emitter!(float_of_int, |cg, cur_fun, ty, args| {
+        Ok(cg.builder.si_to_fp(args[0], cg.context.double_type(), ""))
+    });
+
+    // saturates instead of giving poison when the float is out of range or nan
+    emitter!(int_of_float, |cg, cur_fun, ty, args| {
+        let (i64_ty, double) = (cg.context.i64_type(), cg.context.double_type());
+        let fun = cg.get_or_add_fun("llvm.fptosi.sat.i64.f64", i64_ty, vec![double]);
+        let i = cg.builder.call(fun, &[args[0]], "");
+        Ok(cg.wrap_int(i))
+    });
+}
+
 impl Type {
-    pub unsafe fn to_llvmty(&self) -> LLVMTypeRef {
+    fn to_llvmty(&self, ctx: &ir::Context) -> LLVMTypeRef {
         match self {
-            &Type::Unit => LLVMInt32Type(),
-            &Type::Bool => LLVMInt32Type(),
-            &Type::Char => LLVMInt8Type(),
-            &Type::Int => LLVMInt32Type(),
-            &Type::Float => LLVMDoubleType(),
-            &Type::Tuple(ref xs) => LLVMStructType(
-                xs.iter()
-                    .map(|ref x| x.to_llvmty_sub())
-                    .collect::<Vec<_>>()
-                    .as_mut_slice()
-                    .as_mut_ptr(),
-                xs.len() as u32,
-                0,
-            ),
+            &Type::Unit => ctx.i32_type(),
+            &Type::Bool => ctx.i32_type(),
+            &Type::Char => ctx.i8_type(),
+            &Type::Int | &Type::Int64 => ctx.i64_type(),
+            &Type::Int32 => ctx.i32_type(),
+            &Type::Float => ctx.double_type(),
+            &Type::String | &Type::Exn => ir::pointer_type(ctx.i8_type()),
+            &Type::Array(ref t) => ir::pointer_type(array_block_ty(ctx, t.to_llvmty_sub(ctx))),
+            &Type::Tuple(ref xs) => ctx.struct_type(&xs.iter()
+                .map(|ref x| x.to_llvmty_sub(ctx))
+                .collect::<Vec<_>>()),
+            // a single-field heap cell
+            &Type::Ref(ref t) => ir::pointer_type(ctx.struct_type(&[t.to_llvmty_sub(ctx)])),
             &Type::Func(ref params_ty, ref ret_ty) => {
-                LLVMFunctionType(
-                    ret_ty.to_llvmty_sub(),
-                    || -> *mut LLVMTypeRef {
-                        let mut param_llvm_types: Vec<LLVMTypeRef> =
-                            vec![LLVMPointerType(LLVMInt8Type(), 0)];
-                        for param_ty in params_ty {
-                            param_llvm_types.push(param_ty.to_llvmty_sub());
-                        }
-                        param_llvm_types.as_mut_slice().as_mut_ptr()
-                    }(),
-                    // '1' is for free variable
-                    (1 + params_ty.len()) as u32,
-                    0,
-                )
+                // '1' is for free variable
+                let mut param_llvm_types: Vec<LLVMTypeRef> = vec![ir::pointer_type(ctx.i8_type())];
+                for param_ty in params_ty {
+                    param_llvm_types.push(param_ty.to_llvmty_sub(ctx));
+                }
+                ir::function_type(ret_ty.to_llvmty_sub(ctx), &param_llvm_types)
             }
             _ => panic!(format!("{:?}", self)),
         }
     }
-    pub unsafe fn to_llvmty_sub(&self) -> LLVMTypeRef {
+    fn to_llvmty_sub(&self, ctx: &ir::Context) -> LLVMTypeRef {
         match self {
-            &Type::Unit => LLVMInt32Type(),
-            &Type::Bool => LLVMInt32Type(),
-            &Type::Char => LLVMInt8Type(),
-            &Type::Int => LLVMInt32Type(),
-            &Type::Float => LLVMDoubleType(),
-            &Type::Tuple(ref xs) => LLVMStructType(
-                xs.iter()
-                    .map(|ref x| x.to_llvmty_sub())
-                    .collect::<Vec<_>>()
-                    .as_mut_slice()
-                    .as_mut_ptr(),
-                xs.len() as u32,
-                0,
-            ),
+            &Type::Unit => ctx.i32_type(),
+            &Type::Bool => ctx.i32_type(),
+            &Type::Char => ctx.i8_type(),
+            &Type::Int | &Type::Int64 => ctx.i64_type(),
+            &Type::Int32 => ctx.i32_type(),
+            &Type::Float => ctx.double_type(),
+            &Type::String | &Type::Exn => ir::pointer_type(ctx.i8_type()),
+            &Type::Array(ref t) => ir::pointer_type(array_block_ty(ctx, t.to_llvmty_sub(ctx))),
+            &Type::Tuple(ref xs) => ctx.struct_type(&xs.iter()
+                .map(|ref x| x.to_llvmty_sub(ctx))
+                .collect::<Vec<_>>()),
+            &Type::Ref(ref t) => ir::pointer_type(ctx.struct_type(&[t.to_llvmty_sub(ctx)])),
             &Type::Func(ref params_ty, ref ret_ty) => {
-                let fty = LLVMPointerType(
-                    LLVMFunctionType(
-                        ret_ty.to_llvmty_sub(),
-                        || -> *mut LLVMTypeRef {
-                            let mut param_llvm_types: Vec<LLVMTypeRef> =
-                                vec![LLVMPointerType(LLVMInt8Type(), 0)];
-                            for param_ty in params_ty {
-                                param_llvm_types.push(param_ty.to_llvmty_sub());
-                            }
-                            param_llvm_types.as_mut_slice().as_mut_ptr()
-                        }(),
-                        // '1' is for free variable
-                        (1 + params_ty.len()) as u32,
-                        0,
-                    ),
-                    0,
-                );
-                LLVMPointerType(
-                    LLVMStructType(
-                        vec![fty, LLVMPointerType(LLVMInt8Type(), 0)]
-                            .as_mut_slice()
-                            .as_mut_ptr(),
-                        2,
-                        0,
-                    ),
-                    0,
-                )
+                // '1' is for free variable
+                let mut param_llvm_types: Vec<LLVMTypeRef> = vec![ir::pointer_type(ctx.i8_type())];
+                for param_ty in params_ty {
+                    param_llvm_types.push(param_ty.to_llvmty_sub(ctx));
+                }
+                let ret_ty = ret_ty.to_llvmty_sub(ctx);
+                let fty = ir::pointer_type(ir::function_type(ret_ty, &param_llvm_types));
+                // a closure: its free variables follow the function pointer
+                ir::pointer_type(ctx.struct_type(&[fty]))
             }
             _ => panic!(format!("{:?}", self)),
         }
@@ -0,0 +1,169 @@
+use std::boxed::Box;
+use std::collections::HashMap;
+
+use knormal::{FuncDef, KNormal};
+use node;
+use node::{BinOps, CompBinOps};
+
+// constant folding of arithmetic, comparisons and 'if' on known conditions
+
+fn fold_int_binop(op: &BinOps, x: i64, y: i64) -> Option<i64> {
+    let i = match *op {
+        BinOps::IAdd => x.wrapping_add(y),
+        BinOps::ISub => x.wrapping_sub(y),
+        BinOps::IMul => x.wrapping_mul(y),
+        // division by zero is left to run time. min_int / -1 wraps around as in OCaml
+        BinOps::IDiv if y != 0 => x.wrapping_div(y),
+        BinOps::IMod if y != 0 => x.wrapping_rem(y),
+        _ => return None,
+    };
+    Some(node::wrap_int(i))
+}
+
+fn fold_float_binop(op: &BinOps, x: f64, y: f64) -> Option<f64> {
+    match *op {
+        BinOps::FAdd => Some(x + y),
+        BinOps::FSub => Some(x - y),
+        BinOps::FMul => Some(x * y),
+        BinOps::FDiv => Some(x / y),
+        _ => None,
+    }
+}
+
+fn fold_comp<T: PartialOrd>(op: &CompBinOps, x: T, y: T) -> bool {
+    match *op {
+        CompBinOps::SEq | CompBinOps::PEq => x == y,
+        CompBinOps::SNe | CompBinOps::PNe => x != y,
+        CompBinOps::Lt => x < y,
+        CompBinOps::Gt => x > y,
+        CompBinOps::Le => x <= y,
+        CompBinOps::Ge => x >= y,
+    }
+}
+
+fn g(e: KNormal, env: &mut HashMap<String, KNormal>) -> KNormal {
+    match e {
+        KNormal::Var(x) => match env.get(&x) {
+            Some(c @ &KNormal::Int(_))
+            | Some(c @ &KNormal::Int32(_))
+            | Some(c @ &KNormal::Int64(_))
+            | Some(c @ &KNormal::Float(_))
+            | Some(c @ &KNormal::Bool(_)) => c.clone(),
+            _ => KNormal::Var(x),
+        },
+        KNormal::IntUnaryOp(op, x) => match env.get(&x) {
+            Some(&KNormal::Int(a)) => KNormal::Int(node::wrap_int(a.wrapping_neg())),
+            _ => KNormal::IntUnaryOp(op, x),
+        },
+        KNormal::FloatUnaryOp(op, x) => match env.get(&x) {
+            Some(&KNormal::Float(a)) => KNormal::Float(-a),
+            _ => KNormal::FloatUnaryOp(op, x),
+        },
+        KNormal::IntBinaryOp(op, x, y) => {
+            let folded = match (env.get(&x), env.get(&y)) {
+                (Some(&KNormal::Int(a)), Some(&KNormal::Int(b))) => fold_int_binop(&op, a, b),
+                _ => None,
+            };
+            match folded {
+                Some(i) => KNormal::Int(i),
+                None => KNormal::IntBinaryOp(op, x, y),
+            }
+        }
+        KNormal::FloatBinaryOp(op, x, y) => {
+            let folded = match (env.get(&x), env.get(&y)) {
+                (Some(&KNormal::Float(a)), Some(&KNormal::Float(b))) => {
+                    fold_float_binop(&op, a, b)
+                }
+                _ => None,
+            };
+            match folded {
+                Some(f) => KNormal::Float(f),
+                None => KNormal::FloatBinaryOp(op, x, y),
+            }
+        }
+        KNormal::CompBinaryOp(op, x, y) => {
+            let folded = match (env.get(&x), env.get(&y)) {
+                (Some(&KNormal::Int(a)), Some(&KNormal::Int(b)))
+                | (Some(&KNormal::Int64(a)), Some(&KNormal::Int64(b))) => {
+                    Some(fold_comp(&op, a, b))
+                }
+                (Some(&KNormal::Int32(a)), Some(&KNormal::Int32(b))) => Some(fold_comp(&op, a, b)),
+                (Some(&KNormal::Float(a)), Some(&KNormal::Float(b))) => {
+                    Some(fold_comp(&op, a, b))
+                }
+                (Some(&KNormal::Bool(a)), Some(&KNormal::Bool(b))) => Some(fold_comp(&op, a, b)),
+                _ if x == y && op == CompBinOps::PEq => Some(true),
+                _ => None,
+            };
+            match folded {
+                Some(b) => KNormal::Bool(b),
+                None => KNormal::CompBinaryOp(op, x, y),
+            }
+        }
+        KNormal::If(c, then, els) => match env.get(&c).cloned() {
+            Some(KNormal::Bool(true)) => g(*then, env),
+            Some(KNormal::Bool(false)) => g(*els, env),
+            _ => KNormal::If(c, Box::new(g(*then, env)), Box::new(g(*els, env))),
+        },
+        KNormal::LetExpr((x, t), e1, e2) => {
+            let e1 = g(*e1, env);
+            match e1 {
+                KNormal::Int(_)
+                | KNormal::Int32(_)
+                | KNormal::Int64(_)
+                | KNormal::Float(_)
+                | KNormal::Bool(_)
+                | KNormal::Tuple(_) => {
+                    env.insert(x.clone(), e1.clone());
+                }
+                _ => (),
+            }
+            KNormal::LetExpr((x, t), Box::new(e1), Box::new(g(*e2, env)))
+        }
+        KNormal::LetTupleExpr(xts, y, e) => match env.get(&y).cloned() {
+            // the tuple is known: bind each element directly
+            Some(KNormal::Tuple(ys)) => {
+                let body = g(*e, env);
+                xts.into_iter()
+                    .zip(ys.into_iter())
+                    .rev()
+                    .fold(body, |body, (xt, y)| {
+                        KNormal::LetExpr(xt, Box::new(KNormal::Var(y)), Box::new(body))
+                    })
+            }
+            _ => KNormal::LetTupleExpr(xts, y, Box::new(g(*e, env))),
+        },
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name,
+                params,
+                body,
+            },
+            e,
+        ) => KNormal::LetFuncExpr(
+            FuncDef {
+                name: name,
+                params: params,
+                body: Box::new(g(*body, env)),
+            },
+            Box::new(g(*e, env)),
+        ),
+        KNormal::While(cond, body) => {
+            KNormal::While(Box::new(g(*cond, env)), Box::new(g(*body, env)))
+        }
+        KNormal::For(x, a, b, dir, body) => KNormal::For(x, a, b, dir, Box::new(g(*body, env))),
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(g(*e, env))),
+        KNormal::TryWith(body, handlers) => KNormal::TryWith(
+            Box::new(g(*body, env)),
+            handlers
+                .into_iter()
+                .map(|handler| handler.map_body(|e| g(e, env)))
+                .collect(),
+        ),
+        e => e,
+    }
+}
+
+pub fn f(e: KNormal) -> KNormal {
+    g(e, &mut HashMap::new())
+}
@@ -0,0 +1,107 @@
+use std::boxed::Box;
+use std::collections::HashSet;
+
+use knormal;
+use knormal::{FuncDef, KNormal};
+use node::BinOps;
+
+// elimination of unused definitions whose bound expression has no side effect
+
+// nonzero holds the variables bound to non-zero integer constants. dividing by one can't raise
+fn effect(e: &KNormal, nonzero: &HashSet<String>) -> bool {
+    match *e {
+        KNormal::LetExpr(_, ref e1, ref e2) | KNormal::If(_, ref e1, ref e2) => {
+            effect(e1, nonzero) || effect(e2, nonzero)
+        }
+        KNormal::LetFuncExpr(_, ref e) | KNormal::LetTupleExpr(_, _, ref e) => effect(e, nonzero),
+        KNormal::TryWith(ref body, ref handlers) => {
+            effect(body, nonzero) || handlers.iter().any(|handler| effect(&handler.body, nonzero))
+        }
+        KNormal::IntBinaryOp(BinOps::IDiv, _, ref y)
+        | KNormal::IntBinaryOp(BinOps::IMod, _, ref y) => !nonzero.contains(y),
+        // a loop may not terminate, and an array access may raise
+        KNormal::App(_, _)
+        | KNormal::MakeArray(_, _)
+        | KNormal::Get(_, _)
+        | KNormal::Put(_, _, _)
+        | KNormal::Assign(_, _)
+        | KNormal::While(_, _)
+        | KNormal::For(_, _, _, _, _)
+        | KNormal::Raise(_, _)
+        | KNormal::LetDef(_, _) => true,
+        _ => false,
+    }
+}
+
+fn g(e: KNormal, nonzero: &mut HashSet<String>) -> KNormal {
+    match e {
+        KNormal::If(c, then, els) => {
+            KNormal::If(c, Box::new(g(*then, nonzero)), Box::new(g(*els, nonzero)))
+        }
+        KNormal::LetExpr((x, t), e1, e2) => {
+            let e1 = g(*e1, nonzero);
+            match e1 {
+                KNormal::Int(i) if i != 0 => {
+                    nonzero.insert(x.clone());
+                }
+                _ => (),
+            }
+            let e2 = g(*e2, nonzero);
+            if effect(&e1, nonzero) || knormal::fv(&e2).contains(&x) {
+                KNormal::LetExpr((x, t), Box::new(e1), Box::new(e2))
+            } else {
+                e2
+            }
+        }
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name: (x, t),
+                params,
+                body,
+            },
+            e,
+        ) => {
+            let e = g(*e, nonzero);
+            if knormal::fv(&e).contains(&x) {
+                KNormal::LetFuncExpr(
+                    FuncDef {
+                        name: (x, t),
+                        params: params,
+                        body: Box::new(g(*body, nonzero)),
+                    },
+                    Box::new(e),
+                )
+            } else {
+                e
+            }
+        }
+        KNormal::LetTupleExpr(xts, y, e) => {
+            let e = g(*e, nonzero);
+            let live = knormal::fv(&e);
+            if xts.iter().any(|&(ref x, _)| live.contains(x)) {
+                KNormal::LetTupleExpr(xts, y, Box::new(e))
+            } else {
+                e
+            }
+        }
+        KNormal::While(cond, body) => {
+            KNormal::While(Box::new(g(*cond, nonzero)), Box::new(g(*body, nonzero)))
+        }
+        KNormal::For(x, a, b, dir, body) => {
+            KNormal::For(x, a, b, dir, Box::new(g(*body, nonzero)))
+        }
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(g(*e, nonzero))),
+        KNormal::TryWith(body, handlers) => KNormal::TryWith(
+            Box::new(g(*body, nonzero)),
+            handlers
+                .into_iter()
+                .map(|handler| handler.map_body(|e| g(e, nonzero)))
+                .collect(),
+        ),
+        e => e,
+    }
+}
+
+pub fn f(e: KNormal) -> KNormal {
+    g(e, &mut HashSet::new())
+}
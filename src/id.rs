@@ -18,4 +18,15 @@ impl IdGen {
     pub fn get_type(&mut self) -> Type {
         Type::Var(self.get_id())
     }
+
+    // '.' can't appear in an identifier, so temporaries never clash with user variables
+    pub fn get_tmp_name(&mut self) -> String {
+        format!("tmp.{}", self.get_id())
+    }
+
+    // "x" and "x.3" both become "x.<fresh id>"
+    pub fn get_fresh_name(&mut self, name: &str) -> String {
+        let base = name.split('.').next().unwrap();
+        format!("{}.{}", base, self.get_id())
+    }
 }
@@ -0,0 +1,142 @@
+use std::boxed::Box;
+use std::collections::HashMap;
+
+use knormal::{FuncDef, KNormal};
+use typing::Type;
+use id::IdGen;
+use alpha;
+
+// inline expansion of functions whose body size doesn't exceed the threshold.
+// the input must be alpha-converted so that free variables of an inlined body can't be captured.
+
+pub fn size(e: &KNormal) -> usize {
+    match *e {
+        KNormal::If(_, ref e1, ref e2)
+        | KNormal::LetExpr(_, ref e1, ref e2)
+        | KNormal::While(ref e1, ref e2) => 1 + size(e1) + size(e2),
+        KNormal::For(_, _, _, _, ref e) | KNormal::LetDef(_, ref e) => 1 + size(e),
+        KNormal::LetFuncExpr(ref fundef, ref e2) => 1 + size(&fundef.body) + size(e2),
+        KNormal::LetTupleExpr(_, _, ref e) => 1 + size(e),
+        KNormal::TryWith(ref e, ref handlers) => {
+            handlers.iter().fold(1 + size(e), |acc, handler| acc + size(&handler.body))
+        }
+        _ => 1,
+    }
+}
+
+fn g(
+    e: KNormal,
+    env: &HashMap<String, (Vec<(String, Type)>, KNormal)>,
+    threshold: usize,
+    idgen: &mut IdGen,
+) -> KNormal {
+    match e {
+        KNormal::If(c, then, els) => KNormal::If(
+            c,
+            Box::new(g(*then, env, threshold, idgen)),
+            Box::new(g(*els, env, threshold, idgen)),
+        ),
+        KNormal::LetExpr(xt, e1, e2) => KNormal::LetExpr(
+            xt,
+            Box::new(g(*e1, env, threshold, idgen)),
+            Box::new(g(*e2, env, threshold, idgen)),
+        ),
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name: (x, t),
+                params,
+                body,
+            },
+            e,
+        ) => {
+            let mut newenv = env.clone();
+            if size(&body) <= threshold {
+                newenv.insert(x.clone(), (params.clone(), (*body).clone()));
+            }
+            KNormal::LetFuncExpr(
+                FuncDef {
+                    name: (x, t),
+                    params: params,
+                    body: Box::new(g(*body, &newenv, threshold, idgen)),
+                },
+                Box::new(g(*e, &newenv, threshold, idgen)),
+            )
+        }
+        KNormal::App(f, args) => match env.get(&f) {
+            Some(&(ref params, ref body)) if params.len() == args.len() => {
+                let mut alpha_env = HashMap::new();
+                for (&(ref z, _), y) in params.iter().zip(args.into_iter()) {
+                    alpha_env.insert(z.clone(), y);
+                }
+                alpha::g(body.clone(), &alpha_env, idgen)
+            }
+            _ => KNormal::App(f, args),
+        },
+        KNormal::LetTupleExpr(xts, y, e) => {
+            KNormal::LetTupleExpr(xts, y, Box::new(g(*e, env, threshold, idgen)))
+        }
+        KNormal::While(cond, body) => KNormal::While(
+            Box::new(g(*cond, env, threshold, idgen)),
+            Box::new(g(*body, env, threshold, idgen)),
+        ),
+        KNormal::For(x, a, b, dir, body) => {
+            KNormal::For(x, a, b, dir, Box::new(g(*body, env, threshold, idgen)))
+        }
+        KNormal::LetDef(xt, e) => KNormal::LetDef(xt, Box::new(g(*e, env, threshold, idgen))),
+        KNormal::TryWith(body, handlers) => KNormal::TryWith(
+            Box::new(g(*body, env, threshold, idgen)),
+            handlers
+                .into_iter()
+                .map(|handler| handler.map_body(|e| g(e, env, threshold, idgen)))
+                .collect(),
+        ),
+        e => e,
+    }
+}
+
+pub fn f(e: KNormal, threshold: usize, idgen: &mut IdGen) -> KNormal {
+    g(e, &HashMap::new(), threshold, idgen)
+}
+
+#[test]
+pub fn test_inline() {
+    use nom::IResult;
+    use parser::{module_item, tokens, uniquify};
+    use typing;
+    use knormal;
+
+    let mut idgen = IdGen::new();
+    let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
+    let mut f = |e: &str, threshold: usize| match module_item(&tokens(e).0) {
+        IResult::Done(_, node) => {
+            let uniquified = uniquify(node, &mut idgen);
+            let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+            let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+            self::f(knormalized, threshold, &mut idgen).to_string()
+        }
+        IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
+        IResult::Error(err) => panic!(format!("error: {:?}", err)),
+    };
+
+    let e = "let y = 1.0 in let dbl x = x +. y in let y = 2.0 in print_float (dbl y)";
+    assert_eq!(
+        f(e, 0),
+        "let y.12 : float = 1.0 in\n\
+         let rec dbl.13 (x.14 : float) : float =\n  \
+         x.14 +. y.12 in\n\
+         let y.15 : float = 2.0 in\n\
+         let tmp.16 : float = dbl.13 y.15 in\n\
+         print_float tmp.16"
+    );
+    // the inlined body still refers to the outer 'y', not the shadowing one
+    assert_eq!(
+        f(e, 1),
+        "let y.29 : float = 1.0 in\n\
+         let rec dbl.30 (x.31 : float) : float =\n  \
+         x.31 +. y.29 in\n\
+         let y.32 : float = 2.0 in\n\
+         let tmp.33 : float = y.32 +. y.29 in\n\
+         print_float tmp.33"
+    );
+}
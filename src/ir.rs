@@ -0,0 +1,513 @@
+extern crate llvm_sys as llvm;
+
+use self::llvm::core::*;
+use self::llvm::prelude::*;
+use self::llvm::execution_engine::*;
+
+extern crate libc;
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+// safe wrappers of the LLVM C API calls codegen makes. a name is copied into a C string that
+// lives through the call, and the context, execution engine and builders are disposed of by
+// their owners. the LLVMValueRef, LLVMTypeRef and LLVMBasicBlockRef handles passed to these
+// functions must come from one live Context, which CodeGen makes sure of by owning it
+
+fn c_str(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+pub struct Context(LLVMContextRef);
+
+impl Context {
+    pub fn new() -> Self {
+        Context(unsafe { LLVMContextCreate() })
+    }
+
+    pub fn module(&self, name: &str) -> Module {
+        let name = c_str(name);
+        Module(unsafe { LLVMModuleCreateWithNameInContext(name.as_ptr(), self.0) })
+    }
+
+    pub fn builder(&self) -> Builder {
+        Builder(unsafe { LLVMCreateBuilderInContext(self.0) })
+    }
+
+    pub fn void_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMVoidTypeInContext(self.0) }
+    }
+
+    pub fn i8_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMInt8TypeInContext(self.0) }
+    }
+
+    pub fn i32_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMInt32TypeInContext(self.0) }
+    }
+
+    pub fn i64_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMInt64TypeInContext(self.0) }
+    }
+
+    pub fn double_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMDoubleTypeInContext(self.0) }
+    }
+
+    pub fn struct_type(&self, fields: &[LLVMTypeRef]) -> LLVMTypeRef {
+        let mut fields = fields.to_vec();
+        unsafe { LLVMStructTypeInContext(self.0, fields.as_mut_ptr(), fields.len() as u32, 0) }
+    }
+
+    pub fn append_block(&self, fun: LLVMValueRef, name: &str) -> LLVMBasicBlockRef {
+        let name = c_str(name);
+        unsafe { LLVMAppendBasicBlockInContext(self.0, fun, name.as_ptr()) }
+    }
+
+    // adds a function attribute without a value, such as 'returns_twice'
+    pub fn add_fun_attr(&self, fun: LLVMValueRef, attr: &str) {
+        unsafe {
+            let kind = LLVMGetEnumAttributeKindForName(attr.as_ptr() as *const _, attr.len());
+            LLVMAddAttributeAtIndex(
+                fun,
+                llvm::LLVMAttributeFunctionIndex,
+                LLVMCreateEnumAttribute(self.0, kind, 0),
+            );
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { LLVMContextDispose(self.0) }
+    }
+}
+
+// a module belongs to the execution engine it is added to, which disposes of it
+#[derive(Clone, Copy)]
+pub struct Module(LLVMModuleRef);
+
+impl Module {
+    pub fn add_function(&self, name: &str, ty: LLVMTypeRef) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMAddFunction(self.0, name.as_ptr(), ty) }
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<LLVMValueRef> {
+        let name = c_str(name);
+        let fun = unsafe { LLVMGetNamedFunction(self.0, name.as_ptr()) };
+        if fun.is_null() {
+            None
+        } else {
+            Some(fun)
+        }
+    }
+
+    // a zero-initialized global variable. a name already taken gets a suffix
+    pub fn add_global(&self, name: &str, ty: LLVMTypeRef) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe {
+            let global = LLVMAddGlobal(self.0, ty, name.as_ptr());
+            LLVMSetInitializer(global, LLVMConstNull(ty));
+            global
+        }
+    }
+
+    pub fn dump(&self) {
+        unsafe { LLVMDumpModule(self.0) }
+    }
+
+    pub fn optimize(&self) {
+        unsafe {
+            let pm = LLVMCreatePassManager();
+            llvm::transforms::scalar::LLVMAddTailCallEliminationPass(pm);
+            llvm::transforms::scalar::LLVMAddReassociatePass(pm);
+            llvm::transforms::scalar::LLVMAddGVNPass(pm);
+            llvm::transforms::scalar::LLVMAddInstructionCombiningPass(pm);
+            llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
+            llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
+            llvm::transforms::scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
+            LLVMRunPassManager(pm, self.0);
+            LLVMDisposePassManager(pm);
+        }
+    }
+}
+
+pub struct ExecutionEngine(LLVMExecutionEngineRef);
+
+impl ExecutionEngine {
+    // an MCJIT engine that compiles module when one of its functions is first looked up
+    pub fn new(module: Module) -> Self {
+        unsafe {
+            LLVMLinkInMCJIT();
+            llvm::target::LLVM_InitializeAllTargetMCs();
+            llvm::target::LLVM_InitializeNativeTarget();
+            llvm::target::LLVM_InitializeNativeAsmPrinter();
+            llvm::target::LLVM_InitializeNativeAsmParser();
+
+            let mut ee = ptr::null_mut();
+            let mut error = ptr::null_mut();
+            if LLVMCreateExecutionEngineForModule(&mut ee, module.0, &mut error) != 0 {
+                panic!(format!(
+                    "cannot create an execution engine: {}",
+                    CStr::from_ptr(error).to_string_lossy()
+                ))
+            }
+            ExecutionEngine(ee)
+        }
+    }
+
+    pub fn add_module(&self, module: Module) {
+        unsafe { LLVMAddModule(self.0, module.0) }
+    }
+
+    // calls to fun jump to addr, which must be a function of fun's type
+    pub unsafe fn add_global_mapping(&self, fun: LLVMValueRef, addr: *mut libc::c_void) {
+        LLVMAddGlobalMapping(self.0, fun, addr)
+    }
+
+    // compiles the modules if they aren't yet
+    pub fn function_address(&self, name: &str) -> *mut libc::c_void {
+        let name = c_str(name);
+        unsafe { LLVMGetFunctionAddress(self.0, name.as_ptr()) as *mut libc::c_void }
+    }
+
+    // runs a function taking no arguments. the generated code is trusted to be memory safe
+    pub fn run_function(&self, fun: LLVMValueRef) {
+        unsafe {
+            let ret = LLVMRunFunction(self.0, fun, 0, ptr::null_mut());
+            LLVMDisposeGenericValue(ret);
+        }
+    }
+}
+
+impl Drop for ExecutionEngine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeExecutionEngine(self.0) }
+    }
+}
+
+pub struct Builder(LLVMBuilderRef);
+
+macro_rules! binary_ops {
+    ($($name:ident => $fun:ident),*) => (
+        $(
+            pub fn $name(&self, lhs: LLVMValueRef, rhs: LLVMValueRef, name: &str) -> LLVMValueRef {
+                let name = c_str(name);
+                unsafe { $fun(self.0, lhs, rhs, name.as_ptr()) }
+            }
+        )*
+    );
+}
+
+macro_rules! unary_ops {
+    ($($name:ident => $fun:ident),*) => (
+        $(
+            pub fn $name(&self, val: LLVMValueRef, name: &str) -> LLVMValueRef {
+                let name = c_str(name);
+                unsafe { $fun(self.0, val, name.as_ptr()) }
+            }
+        )*
+    );
+}
+
+macro_rules! cast_ops {
+    ($($name:ident => $fun:ident),*) => (
+        $(
+            pub fn $name(&self, val: LLVMValueRef, ty: LLVMTypeRef, name: &str) -> LLVMValueRef {
+                let name = c_str(name);
+                unsafe { $fun(self.0, val, ty, name.as_ptr()) }
+            }
+        )*
+    );
+}
+
+impl Builder {
+    binary_ops!(
+        add => LLVMBuildAdd,
+        sub => LLVMBuildSub,
+        mul => LLVMBuildMul,
+        sdiv => LLVMBuildSDiv,
+        srem => LLVMBuildSRem,
+        and => LLVMBuildAnd,
+        or => LLVMBuildOr,
+        xor => LLVMBuildXor,
+        shl => LLVMBuildShl,
+        ashr => LLVMBuildAShr,
+        lshr => LLVMBuildLShr,
+        fadd => LLVMBuildFAdd,
+        fsub => LLVMBuildFSub,
+        fmul => LLVMBuildFMul,
+        fdiv => LLVMBuildFDiv
+    );
+
+    unary_ops!(
+        neg => LLVMBuildNeg,
+        not => LLVMBuildNot,
+        fneg => LLVMBuildFNeg,
+        load => LLVMBuildLoad
+    );
+
+    cast_ops!(
+        zext => LLVMBuildZExt,
+        sext => LLVMBuildSExt,
+        trunc => LLVMBuildTrunc,
+        si_to_fp => LLVMBuildSIToFP,
+        fp_to_si => LLVMBuildFPToSI,
+        pointer_cast => LLVMBuildPointerCast
+    );
+
+    pub fn position_at_end(&self, bb: LLVMBasicBlockRef) {
+        unsafe { LLVMPositionBuilderAtEnd(self.0, bb) }
+    }
+
+    pub fn position_before(&self, inst: LLVMValueRef) {
+        unsafe { LLVMPositionBuilderBefore(self.0, inst) }
+    }
+
+    pub fn insert_block(&self) -> LLVMBasicBlockRef {
+        unsafe { LLVMGetInsertBlock(self.0) }
+    }
+
+    pub fn icmp(
+        &self,
+        pred: llvm::LLVMIntPredicate,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildICmp(self.0, pred, lhs, rhs, name.as_ptr()) }
+    }
+
+    pub fn fcmp(
+        &self,
+        pred: llvm::LLVMRealPredicate,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildFCmp(self.0, pred, lhs, rhs, name.as_ptr()) }
+    }
+
+    pub fn select(
+        &self,
+        cond: LLVMValueRef,
+        then: LLVMValueRef,
+        els: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildSelect(self.0, cond, then, els, name.as_ptr()) }
+    }
+
+    pub fn store(&self, val: LLVMValueRef, ptr: LLVMValueRef) -> LLVMValueRef {
+        unsafe { LLVMBuildStore(self.0, val, ptr) }
+    }
+
+    pub fn alloca(&self, ty: LLVMTypeRef, name: &str) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildAlloca(self.0, ty, name.as_ptr()) }
+    }
+
+    pub fn struct_gep(&self, ptr: LLVMValueRef, idx: u32, name: &str) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildStructGEP(self.0, ptr, idx, name.as_ptr()) }
+    }
+
+    pub fn gep(&self, ptr: LLVMValueRef, indices: &[LLVMValueRef], name: &str) -> LLVMValueRef {
+        let name = c_str(name);
+        let mut indices = indices.to_vec();
+        unsafe {
+            LLVMBuildGEP(
+                self.0,
+                ptr,
+                indices.as_mut_ptr(),
+                indices.len() as u32,
+                name.as_ptr(),
+            )
+        }
+    }
+
+    pub fn extract_value(&self, agg: LLVMValueRef, idx: u32, name: &str) -> LLVMValueRef {
+        let name = c_str(name);
+        unsafe { LLVMBuildExtractValue(self.0, agg, idx, name.as_ptr()) }
+    }
+
+    pub fn call(&self, fun: LLVMValueRef, args: &[LLVMValueRef], name: &str) -> LLVMValueRef {
+        let name = c_str(name);
+        let mut args = args.to_vec();
+        unsafe { LLVMBuildCall(self.0, fun, args.as_mut_ptr(), args.len() as u32, name.as_ptr()) }
+    }
+
+    pub fn br(&self, dest: LLVMBasicBlockRef) -> LLVMValueRef {
+        unsafe { LLVMBuildBr(self.0, dest) }
+    }
+
+    pub fn cond_br(
+        &self,
+        cond: LLVMValueRef,
+        then: LLVMBasicBlockRef,
+        els: LLVMBasicBlockRef,
+    ) -> LLVMValueRef {
+        unsafe { LLVMBuildCondBr(self.0, cond, then, els) }
+    }
+
+    pub fn ret(&self, val: LLVMValueRef) -> LLVMValueRef {
+        unsafe { LLVMBuildRet(self.0, val) }
+    }
+
+    pub fn unreachable(&self) -> LLVMValueRef {
+        unsafe { LLVMBuildUnreachable(self.0) }
+    }
+
+    pub fn phi(&self,
+               ty: LLVMTypeRef,
+               incoming: &[(LLVMValueRef, LLVMBasicBlockRef)])
+               -> LLVMValueRef {
+        let name = c_str("phi");
+        unsafe {
+            let phi = LLVMBuildPhi(self.0, ty, name.as_ptr());
+            for &(val, bb) in incoming {
+                LLVMAddIncoming(phi, vec![val].as_mut_ptr(), vec![bb].as_mut_ptr(), 1);
+            }
+            phi
+        }
+    }
+
+    // a pointer to a constant string in the module the builder is in
+    pub fn global_string_ptr(&self, s: &str, name: &str) -> LLVMValueRef {
+        let s = c_str(s);
+        let name = c_str(name);
+        unsafe { LLVMBuildGlobalStringPtr(self.0, s.as_ptr(), name.as_ptr()) }
+    }
+}
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeBuilder(self.0) }
+    }
+}
+
+pub fn pointer_type(ty: LLVMTypeRef) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(ty, 0) }
+}
+
+pub fn array_type(elem_ty: LLVMTypeRef, len: u32) -> LLVMTypeRef {
+    unsafe { LLVMArrayType(elem_ty, len) }
+}
+
+pub fn function_type(ret_ty: LLVMTypeRef, params: &[LLVMTypeRef]) -> LLVMTypeRef {
+    let mut params = params.to_vec();
+    unsafe { LLVMFunctionType(ret_ty, params.as_mut_ptr(), params.len() as u32, 0) }
+}
+
+// the type a pointer points to, or the element type of an array
+pub fn element_type(ty: LLVMTypeRef) -> LLVMTypeRef {
+    unsafe { LLVMGetElementType(ty) }
+}
+
+pub fn field_type(struct_ty: LLVMTypeRef, idx: u32) -> LLVMTypeRef {
+    unsafe { LLVMStructGetTypeAtIndex(struct_ty, idx) }
+}
+
+pub fn field_count(struct_ty: LLVMTypeRef) -> u32 {
+    unsafe { LLVMCountStructElementTypes(struct_ty) }
+}
+
+pub fn return_type(fun_ty: LLVMTypeRef) -> LLVMTypeRef {
+    unsafe { LLVMGetReturnType(fun_ty) }
+}
+
+pub fn size_of(ty: LLVMTypeRef) -> LLVMValueRef {
+    unsafe { LLVMSizeOf(ty) }
+}
+
+pub fn type_of(val: LLVMValueRef) -> LLVMTypeRef {
+    unsafe { LLVMTypeOf(val) }
+}
+
+pub fn const_int(ty: LLVMTypeRef, n: i64) -> LLVMValueRef {
+    unsafe { LLVMConstInt(ty, n as u64, 1) }
+}
+
+pub fn const_real(ty: LLVMTypeRef, f: f64) -> LLVMValueRef {
+    unsafe { LLVMConstReal(ty, f) }
+}
+
+pub fn const_null(ty: LLVMTypeRef) -> LLVMValueRef {
+    unsafe { LLVMConstNull(ty) }
+}
+
+pub fn const_int_to_ptr(val: LLVMValueRef, ty: LLVMTypeRef) -> LLVMValueRef {
+    unsafe { LLVMConstIntToPtr(val, ty) }
+}
+
+pub fn undef(ty: LLVMTypeRef) -> LLVMValueRef {
+    unsafe { LLVMGetUndef(ty) }
+}
+
+pub fn is_constant(val: LLVMValueRef) -> bool {
+    unsafe { LLVMIsConstant(val) != 0 }
+}
+
+// the value of an integer constant
+pub fn const_int_value(val: LLVMValueRef) -> Option<i64> {
+    unsafe {
+        if LLVMIsAConstantInt(val).is_null() {
+            None
+        } else {
+            Some(LLVMConstIntGetSExtValue(val))
+        }
+    }
+}
+
+pub fn set_tail_call(call: LLVMValueRef) {
+    unsafe { LLVMSetTailCall(call, 1) }
+}
+
+pub fn set_alignment(val: LLVMValueRef, bytes: u32) {
+    unsafe { LLVMSetAlignment(val, bytes) }
+}
+
+pub fn param(fun: LLVMValueRef, idx: u32) -> LLVMValueRef {
+    unsafe { LLVMGetParam(fun, idx) }
+}
+
+pub fn name_of(val: LLVMValueRef) -> String {
+    unsafe { CStr::from_ptr(LLVMGetValueName(val)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn entry_block(fun: LLVMValueRef) -> LLVMBasicBlockRef {
+    unsafe { LLVMGetEntryBasicBlock(fun) }
+}
+
+pub fn first_instruction(bb: LLVMBasicBlockRef) -> Option<LLVMValueRef> {
+    let inst = unsafe { LLVMGetFirstInstruction(bb) };
+    if inst.is_null() {
+        None
+    } else {
+        Some(inst)
+    }
+}
+
+// the address of a symbol of the process or a library loaded by load_library
+pub fn search_symbol(name: &str) -> Option<*mut libc::c_void> {
+    let name = c_str(name);
+    let addr = unsafe { llvm::support::LLVMSearchForAddressOfSymbol(name.as_ptr()) };
+    if addr.is_null() {
+        None
+    } else {
+        Some(addr)
+    }
+}
+
+// None loads the symbols of the process itself
+pub fn load_library(path: Option<&str>) -> bool {
+    let path = path.map(c_str);
+    let path_ptr = path.as_ref().map_or(ptr::null(), |path| path.as_ptr());
+    unsafe { llvm::support::LLVMLoadLibraryPermanently(path_ptr) == 0 }
+}
@@ -0,0 +1,658 @@
+use std::boxed::Box;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use node;
+use node::{BinOps, CompBinOps, Direction, Handler, NodeKind, UnaryOps};
+use typing;
+use typing::{Globals, Type};
+use id::IdGen;
+
+// K-normal form: every intermediate result is bound by a let and every operand is a variable,
+// so evaluation order is explicit (left to right) in the structure of the term.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KNormal {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    Int32(i32),
+    Int64(i64),
+    Float(f64),
+    String(String),
+    Var(String),
+    Tuple(Vec<String>),
+    IntUnaryOp(UnaryOps, String),
+    FloatUnaryOp(UnaryOps, String),
+    IntBinaryOp(BinOps, String, String),
+    FloatBinaryOp(BinOps, String, String),
+    CompBinaryOp(CompBinOps, String, String),
+    If(String, Box<KNormal>, Box<KNormal>), // cond, then, else
+    LetExpr((String, Type), Box<KNormal>, Box<KNormal>), // (name, ty), bound expr, body
+    LetTupleExpr(Vec<(String, Type)>, String, Box<KNormal>), // tuples, bound var, body
+    LetFuncExpr(FuncDef, Box<KNormal>), // function, body
+    App(String, Vec<String>),
+    MakeArray(String, String),
+    Get(String, String),
+    Put(String, String, String),
+    MakeRef(String),
+    Deref(String),
+    Assign(String, String),
+    While(Box<KNormal>, Box<KNormal>), // cond, body
+    For(String, String, String, Direction, Box<KNormal>), // var, from, to, direction, body
+    MakeExn(String, Option<String>), // constructor, argument
+    Raise(String, Type),             // exception, type of the 'raise' expression
+    TryWith(Box<KNormal>, Vec<Handler<KNormal>>),
+    LetDef((String, Type), Box<KNormal>), // a global variable, bound expr
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncDef {
+    pub name: (String, Type),
+    pub params: Vec<(String, Type)>,
+    pub body: Box<KNormal>,
+}
+
+// bindings collected while flattening operands; wrapped around the result in order
+struct Binds(Vec<((String, Type), KNormal)>);
+
+impl Binds {
+    fn new() -> Binds {
+        Binds(Vec::new())
+    }
+
+    fn bind(&mut self, e: (KNormal, Type), idgen: &mut IdGen) -> String {
+        match e {
+            (KNormal::Var(x), _) => x,
+            (e, ty) => {
+                let x = idgen.get_tmp_name();
+                self.0.push(((x.clone(), ty), e));
+                x
+            }
+        }
+    }
+
+    fn wrap(self, (e, ty): (KNormal, Type)) -> (KNormal, Type) {
+        let e = self.0.into_iter().rev().fold(e, |body, (xt, e)| {
+            KNormal::LetExpr(xt, Box::new(e), Box::new(body))
+        });
+        (e, ty)
+    }
+}
+
+fn lookup_ty(
+    name: &String,
+    env: &HashMap<String, Type>,
+    idgen: &mut IdGen,
+    globals: &Globals,
+) -> Type {
+    if let Some(t) = env.get(name) {
+        t.clone()
+    } else if let Some(t) = globals.values.get(name) {
+        typing::expand_all(&t.body, globals)
+    } else {
+        idgen.get_type()
+    }
+}
+
+// whether name is a function of globals rather than a local or a global variable
+fn is_global_fun(name: &String, env: &HashMap<String, Type>, globals: &Globals) -> bool {
+    if env.contains_key(name) || globals.vars.contains(name) {
+        return false;
+    }
+    match globals.values.get(name) {
+        Some(t) => match typing::expand_all(&t.body, globals) {
+            Type::Func(_, _) => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+// a global function used as a value is wrapped in a closure of type ty, so 'apply print_int 3'
+// becomes 'let f x = print_int x in apply f 3'. a builtin or an external has no closure of its own
+fn eta_expand(name: String, ty: Type, idgen: &mut IdGen) -> (KNormal, Type) {
+    let params = match ty {
+        Type::Func(ref params, _) => params
+            .iter()
+            .map(|t| (idgen.get_tmp_name(), t.clone()))
+            .collect::<Vec<_>>(),
+        _ => panic!(format!("'{}' isn't a function", name)),
+    };
+    let f = idgen.get_tmp_name();
+    let args = params.iter().map(|&(ref x, _)| x.clone()).collect();
+    (
+        KNormal::LetFuncExpr(
+            FuncDef {
+                name: (f.clone(), ty.clone()),
+                params: params,
+                body: Box::new(KNormal::App(name, args)),
+            },
+            Box::new(KNormal::Var(f)),
+        ),
+        ty,
+    )
+}
+
+// a use of name at type ty
+fn ident(
+    name: String,
+    ty: Type,
+    env: &HashMap<String, Type>,
+    idgen: &mut IdGen,
+    globals: &Globals,
+) -> (KNormal, Type) {
+    if is_global_fun(&name, env, globals) {
+        eta_expand(name, ty, idgen)
+    } else {
+        (KNormal::Var(name), ty)
+    }
+}
+
+// a function called by name is called directly rather than through a closure
+fn g_callee(
+    node: NodeKind,
+    env: &HashMap<String, Type>,
+    idgen: &mut IdGen,
+    globals: &Globals,
+) -> (KNormal, Type) {
+    match node {
+        NodeKind::Ident(name) => {
+            let ty = lookup_ty(&name, env, idgen, globals);
+            (KNormal::Var(name), ty)
+        }
+        NodeKind::Annot(e, ty) => match *e {
+            NodeKind::Ident(name) => (KNormal::Var(name), ty),
+            e => g(e, env, idgen, globals),
+        },
+        node => g(node, env, idgen, globals),
+    }
+}
+
+fn g(
+    node: NodeKind,
+    env: &HashMap<String, Type>,
+    idgen: &mut IdGen,
+    globals: &Globals,
+) -> (KNormal, Type) {
+    macro_rules! binop {
+        ($lhs:expr, $rhs:expr, $ty:expr, $k:expr) => ({
+            let mut binds = Binds::new();
+            let l = binds.bind(g(*$lhs, env, idgen, globals), idgen);
+            let r = binds.bind(g(*$rhs, env, idgen, globals), idgen);
+            binds.wrap(($k(l, r), $ty))
+        });
+    }
+    match node {
+        NodeKind::Unit => (KNormal::Unit, Type::Unit),
+        NodeKind::Bool(b) => (KNormal::Bool(b), Type::Bool),
+        NodeKind::Int(i) => (KNormal::Int(i), Type::Int),
+        NodeKind::Int32(i) => (KNormal::Int32(i), Type::Int32),
+        NodeKind::Int64(i) => (KNormal::Int64(i), Type::Int64),
+        NodeKind::Float(f) => (KNormal::Float(f), Type::Float),
+        NodeKind::String(s) => (KNormal::String(s), Type::String),
+        // a global function used as a value is expanded at the type of the use (see the Annot
+        // arm), not at its scheme, whose quantified variables are left in the body
+        NodeKind::Ident(name) => {
+            if is_global_fun(&name, env, globals) {
+                panic!(format!("the use of '{}' has no type", name));
+            }
+            let ty = lookup_ty(&name, env, idgen, globals);
+            (KNormal::Var(name), ty)
+        }
+        NodeKind::Tuple(es) => {
+            let mut binds = Binds::new();
+            let mut xs = vec![];
+            let mut tys = vec![];
+            for e in es {
+                let (e, ty) = g(e, env, idgen, globals);
+                tys.push(ty.clone());
+                xs.push(binds.bind((e, ty), idgen));
+            }
+            binds.wrap((KNormal::Tuple(xs), Type::Tuple(tys)))
+        }
+        NodeKind::IntUnaryOp(op, e) => {
+            let mut binds = Binds::new();
+            let x = binds.bind(g(*e, env, idgen, globals), idgen);
+            binds.wrap((KNormal::IntUnaryOp(op, x), Type::Int))
+        }
+        NodeKind::FloatUnaryOp(op, e) => {
+            let mut binds = Binds::new();
+            let x = binds.bind(g(*e, env, idgen, globals), idgen);
+            binds.wrap((KNormal::FloatUnaryOp(op, x), Type::Float))
+        }
+        NodeKind::IntBinaryOp(op, lhs, rhs) => binop!(lhs, rhs, Type::Int, |l, r| {
+            KNormal::IntBinaryOp(op, l, r)
+        }),
+        NodeKind::FloatBinaryOp(op, lhs, rhs) => binop!(lhs, rhs, Type::Float, |l, r| {
+            KNormal::FloatBinaryOp(op, l, r)
+        }),
+        NodeKind::CompBinaryOp(op, lhs, rhs) => binop!(lhs, rhs, Type::Bool, |l, r| {
+            KNormal::CompBinaryOp(op, l, r)
+        }),
+        NodeKind::IfExpr(cond, then, els) => {
+            let mut binds = Binds::new();
+            let c = binds.bind(g(*cond, env, idgen, globals), idgen);
+            let (then, ty) = g(*then, env, idgen, globals);
+            let (els, _) = g(*els, env, idgen, globals);
+            binds.wrap((KNormal::If(c, Box::new(then), Box::new(els)), ty))
+        }
+        NodeKind::LetExpr((name, ty), expr, body) => {
+            let (expr, _) = g(*expr, env, idgen, globals);
+            let mut newenv = env.clone();
+            newenv.insert(name.clone(), ty.clone());
+            let (body, body_ty) = g(*body, &newenv, idgen, globals);
+            (
+                KNormal::LetExpr((name, ty), Box::new(expr), Box::new(body)),
+                body_ty,
+            )
+        }
+        NodeKind::LetTupleExpr(xs, expr, body) => {
+            let mut binds = Binds::new();
+            let y = binds.bind(g(*expr, env, idgen, globals), idgen);
+            let mut newenv = env.clone();
+            for &(ref x, ref t) in xs.iter() {
+                newenv.insert(x.clone(), t.clone());
+            }
+            let (body, body_ty) = g(*body, &newenv, idgen, globals);
+            binds.wrap((KNormal::LetTupleExpr(xs, y, Box::new(body)), body_ty))
+        }
+        NodeKind::LetFuncExpr(
+            node::FuncDef {
+                name: (x, t),
+                params,
+            },
+            expr,
+            body,
+        ) => {
+            let mut env_p = env.clone();
+            env_p.insert(x.clone(), t.clone());
+            let mut env_body = env_p.clone();
+            for &(ref y, ref t) in params.iter() {
+                env_body.insert(y.clone(), t.clone());
+            }
+            let (expr, _) = g(*expr, &env_body, idgen, globals);
+            let (body, body_ty) = g(*body, &env_p, idgen, globals);
+            (
+                KNormal::LetFuncExpr(
+                    FuncDef {
+                        name: (x, t),
+                        params: params,
+                        body: Box::new(expr),
+                    },
+                    Box::new(body),
+                ),
+                body_ty,
+            )
+        }
+        NodeKind::Call(callee, args) => {
+            let mut binds = Binds::new();
+            let (callee, callee_ty) = g_callee(*callee, env, idgen, globals);
+            let ret_ty = match callee_ty {
+                Type::Func(_, ref ret_ty) => (**ret_ty).clone(),
+                _ => idgen.get_type(),
+            };
+            let f = binds.bind((callee, callee_ty), idgen);
+            let mut xs = vec![];
+            for arg in args {
+                xs.push(binds.bind(g(arg, env, idgen, globals), idgen));
+            }
+            binds.wrap((KNormal::App(f, xs), ret_ty))
+        }
+        NodeKind::MakeArray(len, init) => {
+            let mut binds = Binds::new();
+            let l = binds.bind(g(*len, env, idgen, globals), idgen);
+            let (init, init_ty) = g(*init, env, idgen, globals);
+            let i = binds.bind((init, init_ty.clone()), idgen);
+            binds.wrap((KNormal::MakeArray(l, i), Type::Array(Box::new(init_ty))))
+        }
+        NodeKind::Get(ary, idx) => {
+            let mut binds = Binds::new();
+            let (ary, ary_ty) = g(*ary, env, idgen, globals);
+            let elem_ty = match ary_ty {
+                Type::Array(ref t) => (**t).clone(),
+                _ => idgen.get_type(),
+            };
+            let a = binds.bind((ary, ary_ty), idgen);
+            let i = binds.bind(g(*idx, env, idgen, globals), idgen);
+            binds.wrap((KNormal::Get(a, i), elem_ty))
+        }
+        NodeKind::Put(ary, idx, val) => {
+            let mut binds = Binds::new();
+            let a = binds.bind(g(*ary, env, idgen, globals), idgen);
+            let i = binds.bind(g(*idx, env, idgen, globals), idgen);
+            let v = binds.bind(g(*val, env, idgen, globals), idgen);
+            binds.wrap((KNormal::Put(a, i, v), Type::Unit))
+        }
+        NodeKind::MakeRef(e) => {
+            let mut binds = Binds::new();
+            let (e, ty) = g(*e, env, idgen, globals);
+            let x = binds.bind((e, ty.clone()), idgen);
+            binds.wrap((KNormal::MakeRef(x), Type::Ref(Box::new(ty))))
+        }
+        NodeKind::Deref(e) => {
+            let mut binds = Binds::new();
+            let (e, ref_ty) = g(*e, env, idgen, globals);
+            let ty = match ref_ty {
+                Type::Ref(ref t) => (**t).clone(),
+                _ => idgen.get_type(),
+            };
+            let x = binds.bind((e, ref_ty), idgen);
+            binds.wrap((KNormal::Deref(x), ty))
+        }
+        NodeKind::Assign(e1, e2) => {
+            let mut binds = Binds::new();
+            let x = binds.bind(g(*e1, env, idgen, globals), idgen);
+            let y = binds.bind(g(*e2, env, idgen, globals), idgen);
+            binds.wrap((KNormal::Assign(x, y), Type::Unit))
+        }
+        // the condition is evaluated on every iteration, so it stays an expression
+        NodeKind::While(cond, body) => {
+            let (cond, _) = g(*cond, env, idgen, globals);
+            let (body, _) = g(*body, env, idgen, globals);
+            (KNormal::While(Box::new(cond), Box::new(body)), Type::Unit)
+        }
+        NodeKind::For(var, from, to, dir, body) => {
+            let mut binds = Binds::new();
+            let a = binds.bind(g(*from, env, idgen, globals), idgen);
+            let b = binds.bind(g(*to, env, idgen, globals), idgen);
+            let mut newenv = env.clone();
+            newenv.insert(var.clone(), Type::Int);
+            let (body, _) = g(*body, &newenv, idgen, globals);
+            binds.wrap((KNormal::For(var, a, b, dir, Box::new(body)), Type::Unit))
+        }
+        // the annotation has already been checked by typing. the one on a use of a name (see
+        // parser::uniquify) is the type it's used at, which a polymorphic name has nowhere else
+        NodeKind::Annot(e, ty) => match *e {
+            NodeKind::Ident(name) => ident(name, ty, env, idgen, globals),
+            e => g(e, env, idgen, globals),
+        },
+        // the value of a top-level 'let x = e' is stored in a global variable
+        NodeKind::LetDef((x, t), expr) => {
+            let (expr, _) = g(*expr, env, idgen, globals);
+            (KNormal::LetDef((x, t), Box::new(expr)), Type::Unit)
+        }
+        // a top-level function is bound like 'let f x = e in f'. it keeps its name through alpha
+        // conversion (see alpha::toplevel_fun), so the following module items can call it
+        NodeKind::LetFuncDef(
+            node::FuncDef {
+                name: (x, t),
+                params,
+            },
+            expr,
+        ) => {
+            let mut env_body = env.clone();
+            env_body.insert(x.clone(), t.clone());
+            for &(ref y, ref t) in params.iter() {
+                env_body.insert(y.clone(), t.clone());
+            }
+            let (expr, _) = g(*expr, &env_body, idgen, globals);
+            (
+                KNormal::LetFuncExpr(
+                    FuncDef {
+                        name: (x.clone(), t.clone()),
+                        params: params,
+                        body: Box::new(expr),
+                    },
+                    Box::new(KNormal::Var(x)),
+                ),
+                t,
+            )
+        }
+        NodeKind::TypeDef(_, _, _) | NodeKind::ExnDef(_, _) | NodeKind::External(_, _, _) => {
+            (KNormal::Unit, Type::Unit)
+        }
+        NodeKind::Constr(name, arg) => {
+            let mut binds = Binds::new();
+            let x = arg.map(|e| binds.bind(g(*e, env, idgen, globals), idgen));
+            binds.wrap((KNormal::MakeExn(name, x), Type::Exn))
+        }
+        NodeKind::Raise(e, ty) => {
+            let mut binds = Binds::new();
+            let x = binds.bind(g(*e, env, idgen, globals), idgen);
+            binds.wrap((KNormal::Raise(x, ty.clone()), ty))
+        }
+        // the body must stay inside the handler, so it isn't bound
+        NodeKind::TryWith(body, handlers) => {
+            let (body, ty) = g(*body, env, idgen, globals);
+            let handlers = handlers
+                .into_iter()
+                .map(|Handler { constr, var, body }| {
+                    let mut newenv = env.clone();
+                    if let Some((ref x, ref t)) = var {
+                        newenv.insert(x.clone(), t.clone());
+                    }
+                    Handler {
+                        constr: constr,
+                        var: var,
+                        body: Box::new(g(*body, &newenv, idgen, globals).0),
+                    }
+                })
+                .collect();
+            (KNormal::TryWith(Box::new(body), handlers), ty)
+        }
+    }
+}
+
+pub fn f(node: NodeKind, idgen: &mut IdGen, globals: &Globals) -> KNormal {
+    g(node, &HashMap::new(), idgen, globals).0
+}
+
+pub fn fv(e: &KNormal) -> HashSet<String> {
+    macro_rules! set {
+        ($($x:expr),*) => ({
+            let mut h = HashSet::new();
+            $(h.insert($x.clone());)*
+            h
+        })
+    }
+    match *e {
+        KNormal::Unit
+        | KNormal::Bool(_)
+        | KNormal::Int(_)
+        | KNormal::Int32(_)
+        | KNormal::Int64(_)
+        | KNormal::Float(_)
+        | KNormal::String(_)
+        | KNormal::MakeExn(_, None) => HashSet::new(),
+        KNormal::Var(ref x) => set!(x),
+        KNormal::Tuple(ref xs) => xs.iter().cloned().collect(),
+        KNormal::IntBinaryOp(_, ref x, ref y)
+        | KNormal::FloatBinaryOp(_, ref x, ref y)
+        | KNormal::CompBinaryOp(_, ref x, ref y)
+        | KNormal::MakeArray(ref x, ref y)
+        | KNormal::Get(ref x, ref y) => set!(x, y),
+        KNormal::Put(ref x, ref y, ref z) => set!(x, y, z),
+        KNormal::IntUnaryOp(_, ref x)
+        | KNormal::FloatUnaryOp(_, ref x)
+        | KNormal::MakeRef(ref x)
+        | KNormal::Deref(ref x)
+        | KNormal::MakeExn(_, Some(ref x))
+        | KNormal::Raise(ref x, _) => set!(x),
+        KNormal::Assign(ref x, ref y) => set!(x, y),
+        KNormal::While(ref cond, ref body) => &fv(cond) | &fv(body),
+        KNormal::For(ref x, ref a, ref b, _, ref body) => &set!(a, b) | &(&fv(body) - &set!(x)),
+        KNormal::If(ref c, ref e1, ref e2) => &(&set!(c) | &fv(e1)) | &fv(e2),
+        KNormal::TryWith(ref body, ref handlers) => {
+            handlers.iter().fold(fv(body), |acc, handler| {
+                let bound = handler.var.iter().map(|&(ref x, _)| x.clone()).collect();
+                &acc | &(&fv(&handler.body) - &bound)
+            })
+        }
+        KNormal::LetExpr((ref x, _), ref e1, ref e2) => &fv(e1) | &(&fv(e2) - &set!(x)),
+        KNormal::LetTupleExpr(ref xs, ref y, ref e) => {
+            let bound = xs.iter().map(|&(ref x, _)| x.clone()).collect();
+            &set!(y) | &(&fv(e) - &bound)
+        }
+        KNormal::LetFuncExpr(ref fundef, ref e) => {
+            let x = &fundef.name.0;
+            let params = fundef.params.iter().map(|&(ref y, _)| y.clone()).collect();
+            let zs = &fv(&fundef.body) - &params;
+            &(&zs | &fv(e)) - &set!(x)
+        }
+        KNormal::App(ref x, ref ys) => {
+            let mut s: HashSet<String> = ys.iter().cloned().collect();
+            s.insert(x.clone());
+            s
+        }
+        KNormal::LetDef(_, ref e) => fv(e),
+    }
+}
+
+impl KNormal {
+    fn fmt_sub(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = " ".repeat(indent);
+        match *self {
+            KNormal::Unit => write!(f, "()"),
+            KNormal::Bool(b) => write!(f, "{}", b),
+            KNormal::Int(i) => write!(f, "{}", i),
+            KNormal::Int32(i) => write!(f, "{}l", i),
+            KNormal::Int64(i) => write!(f, "{}L", i),
+            KNormal::Float(x) => write!(f, "{:?}", x),
+            KNormal::String(ref s) => write!(f, "{:?}", s),
+            KNormal::Var(ref x) => write!(f, "{}", x),
+            KNormal::Tuple(ref xs) => write!(f, "({})", xs.join(", ")),
+            KNormal::IntUnaryOp(ref op, ref x) | KNormal::FloatUnaryOp(ref op, ref x) => {
+                write!(f, "{}{}", node::unaryop_to_str(op), x)
+            }
+            KNormal::IntBinaryOp(ref op, ref x, ref y)
+            | KNormal::FloatBinaryOp(ref op, ref x, ref y) => {
+                write!(f, "{} {} {}", x, node::binop_to_str(op), y)
+            }
+            KNormal::CompBinaryOp(ref op, ref x, ref y) => {
+                write!(f, "{} {} {}", x, node::comp_binop_to_str(op), y)
+            }
+            KNormal::If(ref c, ref then, ref els) => {
+                try!(write!(f, "if {} then\n{}  ", c, pad));
+                try!(then.fmt_sub(f, indent + 2));
+                try!(write!(f, "\n{}else\n{}  ", pad, pad));
+                els.fmt_sub(f, indent + 2)
+            }
+            KNormal::LetExpr((ref x, ref t), ref e1, ref e2) => {
+                try!(write!(f, "let {} : {} = ", x, t.to_string()));
+                try!(e1.fmt_sub(f, indent + 2));
+                try!(write!(f, " in\n{}", pad));
+                e2.fmt_sub(f, indent)
+            }
+            KNormal::LetTupleExpr(ref xs, ref y, ref e) => {
+                try!(write!(
+                    f,
+                    "let ({}) = {} in\n{}",
+                    xs.iter()
+                        .map(|&(ref x, ref t)| format!("{} : {}", x, t.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    y,
+                    pad
+                ));
+                e.fmt_sub(f, indent)
+            }
+            KNormal::LetFuncExpr(ref fundef, ref e) => {
+                let (ref x, ref t) = fundef.name;
+                try!(write!(f, "let rec {}", x));
+                for &(ref y, ref t) in &fundef.params {
+                    try!(write!(f, " ({} : {})", y, t.to_string()));
+                }
+                let ret_ty = match *t {
+                    Type::Func(_, ref r) => r.to_string(),
+                    _ => t.to_string(),
+                };
+                try!(write!(f, " : {} =\n{}  ", ret_ty, pad));
+                try!(fundef.body.fmt_sub(f, indent + 2));
+                try!(write!(f, " in\n{}", pad));
+                e.fmt_sub(f, indent)
+            }
+            KNormal::App(ref x, ref ys) => write!(f, "{} {}", x, ys.join(" ")),
+            KNormal::MakeArray(ref x, ref y) => write!(f, "Array.create {} {}", x, y),
+            KNormal::Get(ref x, ref y) => write!(f, "{}.({})", x, y),
+            KNormal::Put(ref x, ref y, ref z) => write!(f, "{}.({}) <- {}", x, y, z),
+            KNormal::MakeRef(ref x) => write!(f, "ref {}", x),
+            KNormal::Deref(ref x) => write!(f, "!{}", x),
+            KNormal::Assign(ref x, ref y) => write!(f, "{} := {}", x, y),
+            KNormal::While(ref cond, ref body) => {
+                try!(write!(f, "while "));
+                try!(cond.fmt_sub(f, indent + 2));
+                try!(write!(f, " do\n{}  ", pad));
+                try!(body.fmt_sub(f, indent + 2));
+                write!(f, "\n{}done", pad)
+            }
+            KNormal::For(ref x, ref a, ref b, ref dir, ref body) => {
+                try!(write!(
+                    f,
+                    "for {} = {} {} {} do\n{}  ",
+                    x,
+                    a,
+                    node::direction_to_str(dir),
+                    b,
+                    pad
+                ));
+                try!(body.fmt_sub(f, indent + 2));
+                write!(f, "\n{}done", pad)
+            }
+            KNormal::MakeExn(ref name, None) => write!(f, "{}", name),
+            KNormal::MakeExn(ref name, Some(ref x)) => write!(f, "{} {}", name, x),
+            KNormal::Raise(ref x, _) => write!(f, "raise {}", x),
+            KNormal::TryWith(ref body, ref handlers) => {
+                try!(write!(f, "try\n{}  ", pad));
+                try!(body.fmt_sub(f, indent + 2));
+                try!(write!(f, "\n{}with", pad));
+                for handler in handlers {
+                    try!(write!(f, "\n{}| {} ->\n{}  ", pad, node::handler_pat_to_str(handler), pad));
+                    try!(handler.body.fmt_sub(f, indent + 2));
+                }
+                Ok(())
+            }
+            KNormal::LetDef((ref x, ref t), ref e) => {
+                try!(write!(f, "let {} : {} = ", x, t.to_string()));
+                e.fmt_sub(f, indent + 2)
+            }
+        }
+    }
+}
+
+impl fmt::Display for KNormal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_sub(f, 0)
+    }
+}
+
+#[test]
+pub fn test_knormalize() {
+    use nom::IResult;
+    use parser::{module_item, tokens, uniquify};
+    use typing;
+
+    let mut idgen = IdGen::new();
+    let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
+    let mut f = |e: &str| match module_item(&tokens(e).0) {
+        IResult::Done(_, node) => {
+            let uniquified = uniquify(node, &mut idgen);
+            let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+            f(infered, &mut idgen, &globals).to_string()
+        }
+        IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
+        IResult::Error(err) => panic!(format!("error: {:?}", err)),
+    };
+
+    assert_eq!(
+        f("let x = 1 in print_int (x + 2 * 3)"),
+        "let x : int = 1 in\n\
+         let tmp.7 : int = let tmp.6 : int = let tmp.4 : int = 2 in\n    \
+         let tmp.5 : int = 3 in\n    \
+         tmp.4 * tmp.5 in\n  \
+         x + tmp.6 in\n\
+         print_int tmp.7"
+    );
+    assert_eq!(
+        f("let f a b = if a < b then a else b in f 1 2"),
+        "let rec f (a : var(14)) (b : var(14)) : var(14) =\n  \
+         let tmp.20 : bool = a < b in\n  \
+         if tmp.20 then\n    \
+         a\n  \
+         else\n    \
+         b in\n\
+         let tmp.21 : int = 1 in\n\
+         let tmp.22 : int = 2 in\n\
+         f tmp.21 tmp.22"
+    );
+}
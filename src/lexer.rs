@@ -0,0 +1,590 @@
+use std::str::FromStr;
+
+use node;
+
+// the lexer turns a source into tokens carrying their positions. comments are kept as trivia
+// tokens, which the parser skips but tools like an editor can use
+
+// the bytes start..end of the source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Int(i64),
+    Int32(i32),
+    Int64(i64),
+    Float(f64),
+    String(String),
+    // a name starting with a lowercase letter or '_'
+    Ident(String),
+    // a name starting with a capital letter: a constructor or a module
+    Constr(String),
+    Keyword(&'static str),
+    Symbol(&'static str),
+    // '(* ... *)' including the delimiters. comments nest
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn is_trivia(&self) -> bool {
+        match self.kind {
+            TokenKind::Comment(_) => true,
+            _ => false,
+        }
+    }
+}
+
+// the reserved words of OCaml, even those the parser doesn't know, so 'and' is not a variable,
+// and 'ref' and 'raise', which the parser takes as keywords
+pub const KEYWORDS: &[&str] = &[
+    "and", "as", "assert", "asr", "begin", "class", "constraint", "do", "done", "downto", "else",
+    "end", "exception", "external", "false", "for", "fun", "function", "functor", "if", "in",
+    "include", "inherit", "initializer", "land", "lazy", "let", "lor", "lsl", "lsr", "lxor",
+    "match", "method", "mod", "module", "mutable", "new", "nonrec", "object", "of", "open", "or",
+    "private", "rec", "sig", "struct", "then", "to", "true", "try", "type", "val", "virtual",
+    "when", "while", "with", "ref", "raise",
+];
+
+// a symbol is the longest one matching, so '<-' is not '<' followed by '-'
+pub const SYMBOLS: &[&str] = &[
+    ";;", "->", "<-", ":=", "<>", "==", "!=", "<=", ">=", "+.", "-.", "*.", "/.", "(", ")", ",",
+    ";", ":", "|", "!", "'", ".", "=", "<", ">", "+", "-", "*", "/",
+];
+
+// an error at the bytes start..end of the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocatedError {
+    pub start: usize,
+    pub end: usize,
+    pub msg: String,
+}
+
+impl LocatedError {
+    // 'line 2, characters 4-10' as OCaml prints it, or 'lines 2-3, characters 4-30' for an
+    // error over several lines. the characters are counted from the start of the first line
+    pub fn location(&self, src: &str) -> String {
+        let line_start = src[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let first_line = src[..self.start].matches('\n').count() + 1;
+        let last_line = first_line + src[self.start..self.end].matches('\n').count();
+        let lines = if first_line == last_line {
+            format!("line {}", first_line)
+        } else {
+            format!("lines {}-{}", first_line, last_line)
+        };
+        format!(
+            "{}, characters {}-{}",
+            lines,
+            self.start - line_start,
+            self.end - line_start
+        )
+    }
+}
+
+// the tokens of src, comments included. a malformed token is reported and lexing goes on, so
+// every lexical error of the source is found at once
+pub fn lex(src: &str) -> (Vec<Token>, Vec<LocatedError>) {
+    let s = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut pos = 0;
+    while pos < s.len() {
+        let start = pos;
+        let kind = match s[pos] {
+            b' ' | b'\t' | b'\n' | b'\r' => {
+                pos += 1;
+                continue;
+            }
+            b'(' if s[pos..].starts_with(b"(*") => {
+                pos = scan_comment(s, pos);
+                if pos > s.len() {
+                    pos = s.len();
+                    errors.push(error(start, start + 2, "Comment not terminated"));
+                    continue;
+                }
+                TokenKind::Comment(src[start..pos].to_string())
+            }
+            b'*' if s[pos..].starts_with(b"*)") => {
+                pos += 2;
+                errors.push(error(start, pos, "This '*)' is not the end of a comment"));
+                continue;
+            }
+            b'"' => {
+                let (lit, end) = string_literal(s, pos);
+                pos = end;
+                match lit {
+                    Ok(lit) => TokenKind::String(lit),
+                    Err(err) => {
+                        errors.push(err);
+                        TokenKind::String("".to_string())
+                    }
+                }
+            }
+            c if c.is_ascii_digit() => {
+                pos += scan_number(&s[pos..]);
+                match number_literal(&src[start..pos], follows_unary_minus(&tokens)) {
+                    Ok(lit) => lit,
+                    Err(msg) => {
+                        errors.push(error(start, pos, &msg));
+                        TokenKind::Int(0)
+                    }
+                }
+            }
+            c if is_ident_char(c) => {
+                while pos < s.len() && (is_ident_char(s[pos]) || s[pos] == b'\'') {
+                    pos += 1;
+                }
+                let name = &src[start..pos];
+                match KEYWORDS.iter().find(|&&kw| kw == name) {
+                    Some(kw) => TokenKind::Keyword(kw),
+                    None if s[start].is_ascii_uppercase() => TokenKind::Constr(name.to_string()),
+                    None => TokenKind::Ident(name.to_string()),
+                }
+            }
+            _ => match SYMBOLS.iter().find(|sym| s[pos..].starts_with(sym.as_bytes())) {
+                Some(sym) => {
+                    pos += sym.len();
+                    TokenKind::Symbol(sym)
+                }
+                None => {
+                    let c = src[pos..].chars().next().unwrap();
+                    pos += c.len_utf8();
+                    errors.push(error(start, pos, &format!("Illegal character ({})", c)));
+                    continue;
+                }
+            },
+        };
+        tokens.push(Token {
+            kind: kind,
+            span: Span {
+                start: start,
+                end: pos,
+            },
+        });
+    }
+    (tokens, errors)
+}
+
+fn error(start: usize, end: usize, msg: &str) -> LocatedError {
+    LocatedError {
+        start: start,
+        end: end,
+        msg: msg.to_string(),
+    }
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+// the end of the comment starting at pos, or past the end of s if it isn't terminated. a string
+// literal in a comment is skipped, so '(* "*)" *)' is one comment as in OCaml
+fn scan_comment(s: &[u8], mut pos: usize) -> usize {
+    let mut level = 0;
+    while pos < s.len() {
+        if s[pos..].starts_with(b"(*") {
+            level += 1;
+            pos += 2;
+        } else if s[pos..].starts_with(b"*)") {
+            level -= 1;
+            pos += 2;
+            if level == 0 {
+                return pos;
+            }
+        } else if s[pos] == b'"' {
+            pos = string_literal(s, pos).1;
+        } else {
+            pos += 1;
+        }
+    }
+    s.len() + 1
+}
+
+// the string literal starting at pos with the escapes \n, \t, \\, \", the decimal \ddd and the
+// hexadecimal \xhh, and its end
+fn string_literal(s: &[u8], start: usize) -> (Result<String, LocatedError>, usize) {
+    let mut lit = Vec::new();
+    let mut pos = start + 1;
+    let mut err = None;
+    while pos < s.len() {
+        match s[pos] {
+            b'"' => {
+                let lit = match err {
+                    Some(err) => Err(err),
+                    None => String::from_utf8(lit).map_err(|_| {
+                        error(start, pos + 1, "String literal is not valid UTF-8")
+                    }),
+                };
+                return (lit, pos + 1);
+            }
+            b'\\' if pos + 1 < s.len() => {
+                if let Some((c, len)) = code_escape(&s[pos + 1..]) {
+                    match c {
+                        Some(c) => lit.push(c),
+                        None => {
+                            let msg = format!(
+                                "Illegal backslash escape in string or character (\\{})",
+                                String::from_utf8_lossy(&s[pos + 1..pos + 1 + len])
+                            );
+                            err = err.or(Some(error(pos, pos + 1 + len, &msg)));
+                        }
+                    }
+                    pos += 1 + len;
+                    continue;
+                }
+                lit.push(match s[pos + 1] {
+                    b'n' => b'\n',
+                    b't' => b'\t',
+                    b'\\' => b'\\',
+                    b'"' => b'"',
+                    c => {
+                        let msg = format!(
+                            "Illegal backslash escape in string or character (\\{})",
+                            c as char
+                        );
+                        err = err.or(Some(error(pos, pos + 2, &msg)));
+                        c
+                    }
+                });
+                pos += 2;
+            }
+            c => {
+                lit.push(c);
+                pos += 1;
+            }
+        }
+    }
+    (
+        Err(error(start, start + 1, "String literal not terminated")),
+        s.len(),
+    )
+}
+
+// the byte of the escape '\ddd' or '\xhh' after the backslash at the front of s, or None if it's
+// out of range, and its length
+fn code_escape(s: &[u8]) -> Option<(Option<u8>, usize)> {
+    let (radix, digits) = match s.first() {
+        Some(&b'x') => (16, &s[1..]),
+        _ => (10, s),
+    };
+    let len = if radix == 16 { 2 } else { 3 };
+    if digits.len() < len || !digits[..len].iter().all(|&c| (c as char).is_digit(radix)) {
+        return None;
+    }
+    let code = digits[..len]
+        .iter()
+        .fold(0, |n, &c| n * radix + (c as char).to_digit(radix).unwrap());
+    let c = if code < 256 { Some(code as u8) } else { None };
+    Some((c, s.len() - digits.len() + len))
+}
+
+// the digits at the front of s and the rest. a digit may be followed by digits and '_', which
+// are dropped. with leading_underscore, s may also start with '_' (as a fraction does)
+fn take_digits(s: &str, radix: u32, leading_underscore: bool) -> (String, &str) {
+    let first_ok = |c: char| c.is_digit(radix) || (leading_underscore && c == '_');
+    if !s.chars().next().map_or(false, first_ok) {
+        return ("".to_string(), s);
+    }
+    let end = s.find(|c: char| !c.is_digit(radix) && c != '_')
+        .unwrap_or(s.len());
+    (s[..end].chars().filter(|&c| c != '_').collect(), &s[end..])
+}
+
+// '[+-] digits' after 'e' or 'p'
+fn take_exponent(s: &str) -> Option<(i32, &str)> {
+    let (sign, s) = match s.chars().next() {
+        Some('-') => ("-", &s[1..]),
+        Some('+') => ("", &s[1..]),
+        _ => ("", s),
+    };
+    let (digits, rest) = take_digits(s, 10, false);
+    if digits.is_empty() {
+        return None;
+    }
+    // an exponent too large for an i32 only makes the float zero or infinite
+    let exp = i32::from_str(&format!("{}{}", sign, digits))
+        .unwrap_or(if sign == "-" { i32::min_value() } else { i32::max_value() });
+    Some((exp, rest))
+}
+
+// 'int [. frac] [e exp]' in decimal, or 'int [. frac] [p exp]' in hexadecimal where exp is a
+// power of 2
+fn float_literal(body: &str, radix: u32) -> Option<f64> {
+    let (int, rest) = take_digits(body, radix, false);
+    let (frac, rest) = if rest.starts_with('.') {
+        take_digits(&rest[1..], radix, true)
+    } else {
+        ("".to_string(), rest)
+    };
+    let exp_marks: &[char] = if radix == 10 { &['e', 'E'] } else { &['p', 'P'] };
+    let (exp, rest) = match rest.chars().next() {
+        Some(c) if exp_marks.contains(&c) => match take_exponent(&rest[1..]) {
+            Some(exp_rest) => exp_rest,
+            None => return None,
+        },
+        _ => (0, rest),
+    };
+    if int.is_empty() || !rest.is_empty() {
+        return None;
+    }
+    if radix == 10 {
+        return f64::from_str(&format!("{}.{}e{}", int, frac, exp)).ok();
+    }
+    let mantissa = int.chars()
+        .chain(frac.chars())
+        .fold(0.0, |m, c| m * 16.0 + c.to_digit(16).unwrap() as f64);
+    let exp = exp.saturating_sub(4 * frac.len() as i32);
+    Some(mantissa * 2f64.powi(exp))
+}
+
+// whether the next token is the operand of a unary '-', i.e. the last token is '-' and the one
+// before it can't end an operand. '- 4611686018427387904' is min_int but 'x - 4611686018427387904'
+// is out of range
+fn follows_unary_minus(tokens: &[Token]) -> bool {
+    let mut rev = tokens.iter().rev().filter(|t| !t.is_trivia());
+    match rev.next() {
+        Some(&Token { kind: TokenKind::Symbol("-"), .. }) => (),
+        _ => return false,
+    }
+    match rev.next().map(|t| &t.kind) {
+        Some(&TokenKind::Int(_))
+        | Some(&TokenKind::Int32(_))
+        | Some(&TokenKind::Int64(_))
+        | Some(&TokenKind::Float(_))
+        | Some(&TokenKind::String(_))
+        | Some(&TokenKind::Ident(_))
+        | Some(&TokenKind::Constr(_))
+        | Some(&TokenKind::Symbol(")"))
+        | Some(&TokenKind::Keyword("true"))
+        | Some(&TokenKind::Keyword("false"))
+        | Some(&TokenKind::Keyword("end"))
+        | Some(&TokenKind::Keyword("done")) => false,
+        _ => true,
+    }
+}
+
+// OCaml's numeric literals: decimal, '0x', '0o' and '0b' integers with an optional 'l' (int32)
+// or 'L' (int64) suffix, and decimal or hexadecimal floats. '_' may separate digits.
+// a hexadecimal, octal or binary integer may use the sign bit, so 0x7fffffffffffffff is -1.
+// a negated decimal integer may be one past the maximum, so that min_int can be written
+fn number_literal(s: &str, negated: bool) -> Result<TokenKind, String> {
+    let invalid = || format!("Invalid literal {}", s);
+    let (radix, body) = match s.get(..2) {
+        Some("0x") | Some("0X") => (16, &s[2..]),
+        Some("0o") | Some("0O") => (8, &s[2..]),
+        Some("0b") | Some("0B") => (2, &s[2..]),
+        _ => (10, s),
+    };
+    let is_float = match radix {
+        10 => body.contains(|c| c == '.' || c == 'e' || c == 'E'),
+        16 => body.contains(|c| c == '.' || c == 'p' || c == 'P'),
+        _ => false,
+    };
+    if is_float {
+        return float_literal(body, radix).map(TokenKind::Float).ok_or_else(invalid);
+    }
+
+    let (body, suffix) = match body.chars().last() {
+        Some(c) if c == 'l' || c == 'L' => (&body[..body.len() - 1], Some(c)),
+        _ => (body, None),
+    };
+    let (digits, rest) = take_digits(body, radix, false);
+    if digits.is_empty() || !rest.is_empty() {
+        return Err(invalid());
+    }
+    let (ty, bits) = match suffix {
+        Some('l') => ("int32", 32),
+        Some('L') => ("int64", 64),
+        _ => ("int", node::INT_BITS),
+    };
+    let out_of_range = || {
+        format!(
+            "Integer literal exceeds the range of representable integers of type {}",
+            ty
+        )
+    };
+    let max = if radix == 10 {
+        (1u64 << (bits - 1)) - if negated { 0 } else { 1 }
+    } else {
+        u64::max_value() >> (64 - bits)
+    };
+    let n = match u64::from_str_radix(&digits, radix) {
+        Ok(n) if n <= max => n,
+        _ => return Err(out_of_range()),
+    };
+    Ok(match suffix {
+        Some('l') => TokenKind::Int32(n as u32 as i32),
+        Some('L') => TokenKind::Int64(n as i64),
+        _ => TokenKind::Int(node::wrap_int(n as i64)),
+    })
+}
+
+// the extent of a numeric literal: a digit and the following letters, digits, '_' and '.', and
+// the sign of an exponent. whatever isn't a literal is reported by number_literal
+fn scan_number(input: &[u8]) -> usize {
+    let is_hex = input.len() > 1 && (input[1] == b'x' || input[1] == b'X');
+    let mut len = 1;
+    while len < input.len() {
+        let c = input[len];
+        let after_exp_mark = match input[len - 1] {
+            b'e' | b'E' => !is_hex,
+            b'p' | b'P' => is_hex,
+            _ => false,
+        };
+        let is_sign = after_exp_mark && (c == b'+' || c == b'-');
+        if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || is_sign {
+            len += 1;
+        } else {
+            break;
+        }
+    }
+    len
+}
+
+#[test]
+pub fn test_number_literal() {
+    use self::TokenKind::*;
+
+    let f = |s: &str| number_literal(s, false);
+    assert_eq!(f("0x1F"), Ok(Int(31)));
+    assert_eq!(f("0o17"), Ok(Int(15)));
+    assert_eq!(f("0b1010"), Ok(Int(10)));
+    assert_eq!(f("1_000_000"), Ok(Int(1000000)));
+    assert_eq!(f("0x7FFF_FFFF_FFFF_FFFF"), Ok(Int(-1)));
+    assert_eq!(f("0xFFFFFFFFl"), Ok(Int32(-1)));
+    assert_eq!(f("0x8000000000000000L"), Ok(Int64(i64::min_value())));
+    assert_eq!(f("1e10"), Ok(Float(1e10)));
+    assert_eq!(f("1."), Ok(Float(1.0)));
+    assert_eq!(f("1_0.2_5E-1"), Ok(Float(1.025)));
+    assert_eq!(f("0x1p-3"), Ok(Float(0.125)));
+    assert_eq!(f("0x1.8P1"), Ok(Float(3.0)));
+    assert_eq!(
+        f("4611686018427387904"),
+        Err("Integer literal exceeds the range of representable integers of type int".to_string())
+    );
+    assert_eq!(
+        f("2147483648l"),
+        Err("Integer literal exceeds the range of representable integers of type int32".to_string())
+    );
+    assert_eq!(number_literal("4611686018427387904", true), Ok(Int(node::MIN_INT)));
+    assert_eq!(number_literal("2147483648l", true), Ok(Int32(i32::min_value())));
+    assert_eq!(f("0x"), Err("Invalid literal 0x".to_string()));
+    assert_eq!(f("0x_1"), Err("Invalid literal 0x_1".to_string()));
+    assert_eq!(f("1e"), Err("Invalid literal 1e".to_string()));
+    assert_eq!(f("0b102"), Err("Invalid literal 0b102".to_string()));
+    assert_eq!(f("1.5l"), Err("Invalid literal 1.5l".to_string()));
+
+    // lexing goes on after a bad literal, which is reported with its location
+    let src = "let x = 1 in\n  x + 99999999999999999999";
+    let (tokens, errors) = lex(src);
+    assert_eq!(tokens.len(), 8);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].location(src), "line 2, characters 6-26");
+    let err = error(4, src.len(), "");
+    assert_eq!(err.location(src), "lines 1-2, characters 4-39");
+
+    // only the operand of a unary '-' may be one past the maximum
+    assert_eq!(lex("(- 4611686018427387904)").1, vec![]);
+    assert_eq!(lex("-2147483648l").1, vec![]);
+    assert_eq!(lex("x - 4611686018427387904").1.len(), 1);
+}
+
+#[test]
+pub fn test_lex() {
+    use self::TokenKind::*;
+
+    let kinds = |s: &str| {
+        let (tokens, errors) = lex(s);
+        assert_eq!(errors, vec![]);
+        tokens.into_iter().map(|t| t.kind).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kinds("let rec f x = x.(0) <- -1;;"),
+        vec![
+            Keyword("let"),
+            Keyword("rec"),
+            Ident("f".to_string()),
+            Ident("x".to_string()),
+            Symbol("="),
+            Ident("x".to_string()),
+            Symbol("."),
+            Symbol("("),
+            Int(0),
+            Symbol(")"),
+            Symbol("<-"),
+            Symbol("-"),
+            Int(1),
+            Symbol(";;"),
+        ]
+    );
+    assert_eq!(
+        kinds("Array.length records"),
+        vec![
+            Constr("Array".to_string()),
+            Symbol("."),
+            Ident("length".to_string()),
+            Ident("records".to_string()),
+        ]
+    );
+    // comments nest and are kept with their text. '(*' in a string doesn't start a comment and
+    // '*)' in a string in a comment doesn't end it
+    assert_eq!(
+        kinds("\"(*\" (* a (* b *) \"*)\" *) x"),
+        vec![
+            String("(*".to_string()),
+            Comment("(* a (* b *) \"*)\" *)".to_string()),
+            Ident("x".to_string()),
+        ]
+    );
+    // reserved words aren't variables, and a prime doesn't end a variable
+    assert_eq!(
+        kinds("let x' = f and 'a"),
+        vec![
+            Keyword("let"),
+            Ident("x'".to_string()),
+            Symbol("="),
+            Ident("f".to_string()),
+            Keyword("and"),
+            Symbol("'"),
+            Ident("a".to_string()),
+        ]
+    );
+    assert_eq!(
+        kinds("\"\\065\\x42\\195\\169\\0100\""),
+        vec![String("AB\u{e9}\n0".to_string())]
+    );
+    let (tokens, _) = lex("f  (* c *)\n  \"s\"");
+    assert_eq!(
+        tokens.iter().map(|t| (t.span.start, t.span.end)).collect::<Vec<_>>(),
+        vec![(0, 1), (3, 10), (13, 16)]
+    );
+
+    let errors = |s: &str| lex(s).1.into_iter().map(|e| e.msg).collect::<Vec<_>>();
+    assert_eq!(errors("x (* y"), vec!["Comment not terminated"]);
+    assert_eq!(errors("x *) y"), vec!["This '*)' is not the end of a comment"]);
+    assert_eq!(errors("\"abc"), vec!["String literal not terminated"]);
+    assert_eq!(
+        errors("\"\\q\" # 1"),
+        vec![
+            "Illegal backslash escape in string or character (\\q)",
+            "Illegal character (#)",
+        ]
+    );
+    assert_eq!(
+        errors("\"\\256\" \"\\xg\" \"\\255\""),
+        vec![
+            "Illegal backslash escape in string or character (\\256)",
+            "Illegal backslash escape in string or character (\\x)",
+            "String literal is not valid UTF-8",
+        ]
+    );
+}
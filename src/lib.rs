@@ -1,16 +1,27 @@
+pub mod lexer;
 #[macro_use]
 pub mod parser;
 pub mod node;
 pub mod typing;
+pub mod mono;
 pub mod id;
 pub mod codegen;
+mod ir;
+pub mod knormal;
+pub mod alpha;
+pub mod inline;
+pub mod beta;
+pub mod assoc;
+pub mod constfold;
+pub mod elim;
+pub mod optimize;
 pub mod closure;
+pub mod runtime;
+pub mod builtin;
+pub mod session;
 
 #[macro_use]
 extern crate nom;
 
 #[link(name = "ffi")]
 extern "C" {}
-
-#[macro_use]
-extern crate lazy_static;
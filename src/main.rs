@@ -1,5 +1,6 @@
 extern crate rcaml;
 use rcaml::parser;
+use rcaml::session::Session;
 
 extern crate clap;
 use clap::{App, Arg};
@@ -7,45 +8,31 @@ use clap::{App, Arg};
 extern crate ansi_term;
 use self::ansi_term::{Colour, Style};
 
-extern crate nom;
-
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 
 const VERSION_STR: &'static str = env!("CARGO_PKG_VERSION");
 
-pub fn run(e: &str) {
-    use rcaml::codegen;
-    use rcaml::typing;
-    use rcaml::id;
-    use rcaml::closure;
-    use std::collections::HashMap;
-    use nom::IResult;
-
-    let mut idgen = id::IdGen::new();
-    let mut tyenv = HashMap::new();
-    let mut progs = Vec::new();
-    let e = parser::remove_comments(e.as_bytes());
-    let mut code = e.as_str();
-
-    while code.len() > 0 {
-        match parser::module_item(code.as_bytes()) {
-            IResult::Done(remain, node) => {
-                let uniquified = parser::uniquify(node, &mut idgen);
-                let infered = typing::f(&uniquified, &mut tyenv, &mut idgen);
-                let closured = closure::f(infered);
-                progs.push(closured);
-                code = parser::to_str(remain);
-            }
-            IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
-            IResult::Error(err) => panic!(format!("error: {:?}", err)),
-        }
+// reports the errors the way ocamlopt does and exits
+fn report_errors(filename: &str, src: &str, errors: &[parser::LocatedError]) -> ! {
+    for err in errors {
+        eprintln!(
+            "File \"{}\", {}:\nError: {}",
+            filename,
+            err.location(src),
+            err.msg
+        );
     }
+    ::std::process::exit(2)
+}
 
-    unsafe {
-        let mut codegen = codegen::CodeGen::new(&mut tyenv);
-        codegen.gen(true, false, progs.clone()).unwrap();
-        codegen.run_module()
+pub fn run(filename: &str, e: &str, inline_threshold: usize, bounds_check: bool) {
+    let mut session = Session::new();
+    session.inline_threshold = inline_threshold;
+    session.bounds_check = bounds_check;
+    session.dump_module = true;
+    if let Err(errors) = session.run(e) {
+        report_errors(filename, e, &errors)
     }
 }
 
@@ -60,11 +47,39 @@ fn main() {
                 .long("version")
                 .help("Show version info"),
         )
+        .arg(
+            Arg::with_name("inline")
+                .long("inline")
+                .value_name("N")
+                .help("Inline functions whose body size is at most N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unsafe")
+                .long("unsafe")
+                .help("Don't check array bounds"),
+        )
+        .arg(
+            Arg::with_name("load")
+                .long("load")
+                .value_name("LIB")
+                .help("Load a shared library for 'external' declarations")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(Arg::with_name("FILE")
                 .help("Input file")
                 // .required(true)
                 .index(1))
-        .get_matches();
+        // '-unsafe' and '-inline' are spelled like ocamlopt's options
+        .get_matches_from(::std::env::args().map(|arg| {
+            if arg == "-unsafe" || arg == "-inline" {
+                format!("-{}", arg)
+            } else {
+                arg
+            }
+        }));
 
     if app.is_present("version") {
         println!("rcaml {}", VERSION_STR);
@@ -85,7 +100,16 @@ fn main() {
         file.read_to_string(&mut file_body)
             .ok()
             .expect("error while reading file");
-        run(file_body.trim());
+        for lib in app.values_of("load").into_iter().flat_map(|libs| libs) {
+            if let Err(msg) = rcaml::codegen::load_library(lib) {
+                eprintln!("{} {}", Colour::Red.bold().paint("error:"), msg);
+                ::std::process::exit(2)
+            }
+        }
+        let inline_threshold = app.value_of("inline")
+            .map(|n| n.parse().expect("inline threshold must be a number"))
+            .unwrap_or(0);
+        run(filename, &file_body, inline_threshold, !app.is_present("unsafe"));
     } else {
         parser::parse_and_show_simple_expr("5 / a3 + 11 * 10");
         parser::parse_and_show_simple_expr("5.2 /. 0.3");
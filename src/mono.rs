@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+
+use node::{FuncDef, Handler, NodeKind};
+use typing;
+use typing::{Globals, Type};
+
+// a top-level function whose type has type variables isn't compiled as it is, since values of
+// different types are passed differently. it's compiled once for each type it's used at
+// instead, under the name instance_name gives it: 'let id x = x;; id 1' uses 'id[int -> int]'.
+// so is a polymorphic top-level value such as 'let g = id', whose definition has no effects
+
+// a typed polymorphic top-level function or value and the types it has been compiled for
+pub struct PolyFun {
+    pub def: NodeKind,
+    pub instances: HashSet<Type>,
+}
+
+impl PolyFun {
+    pub fn new(def: NodeKind) -> Self {
+        PolyFun {
+            def: def,
+            instances: HashSet::new(),
+        }
+    }
+}
+
+pub fn is_poly(ty: &Type) -> bool {
+    match *ty {
+        Type::Var(_) => true,
+        Type::Func(ref params, ref ret) => params.iter().any(is_poly) || is_poly(ret),
+        Type::Tuple(ref ts) | Type::Con(_, ref ts) => ts.iter().any(is_poly),
+        Type::Array(ref t) | Type::Ref(ref t) => is_poly(t),
+        _ => false,
+    }
+}
+
+pub fn instance_name(name: &str, ty: &Type) -> String {
+    format!("{}[{}]", name, ty.to_string())
+}
+
+// the type variables of pat that make it ty
+fn match_ty(pat: &Type, ty: &Type, subst: &mut HashMap<usize, Type>) {
+    match (pat, ty) {
+        (&Type::Var(n), _) => {
+            subst.insert(n, ty.clone());
+        }
+        (&Type::Func(ref ps1, ref r1), &Type::Func(ref ps2, ref r2)) => {
+            for (p1, p2) in ps1.iter().zip(ps2) {
+                match_ty(p1, p2, subst)
+            }
+            match_ty(r1, r2, subst)
+        }
+        (&Type::Tuple(ref ts1), &Type::Tuple(ref ts2))
+        | (&Type::Con(_, ref ts1), &Type::Con(_, ref ts2)) => for (t1, t2) in ts1.iter().zip(ts2) {
+            match_ty(t1, t2, subst)
+        },
+        (&Type::Array(ref t1), &Type::Array(ref t2)) | (&Type::Ref(ref t1), &Type::Ref(ref t2)) => {
+            match_ty(t1, t2, subst)
+        }
+        _ => (),
+    }
+}
+
+// the polymorphic definition def ('let f ... = ...' or 'let x = ...') at the type ty, named
+// after it
+pub fn specialize(def: &NodeKind, ty: &Type, globals: &Globals) -> NodeKind {
+    let mut subst = HashMap::new();
+    match *def {
+        NodeKind::LetFuncDef(ref funcdef, _) => match_ty(&funcdef.name.1, ty, &mut subst),
+        NodeKind::LetDef((_, ref def_ty), _) => match_ty(def_ty, ty, &mut subst),
+        _ => panic!(format!("'{:?}' isn't a definition", def)),
+    }
+    match typing::subst_term(def, &subst, globals) {
+        NodeKind::LetFuncDef(FuncDef { name: (name, _), params }, body) => NodeKind::LetFuncDef(
+            FuncDef {
+                name: (instance_name(&name, ty), ty.clone()),
+                params: params,
+            },
+            body,
+        ),
+        NodeKind::LetDef((name, _), expr) => {
+            NodeKind::LetDef((instance_name(&name, ty), ty.clone()), expr)
+        }
+        _ => unreachable!(),
+    }
+}
+
+// renames the uses of the functions of polys in node to their instances at the types they're
+// used at, and adds those to found. the polymorphic local definitions of node are specialized
+// in place
+pub fn instances(
+    node: NodeKind,
+    polys: &HashMap<String, PolyFun>,
+    globals: &Globals,
+    found: &mut Vec<(String, Type)>,
+) -> NodeKind {
+    let names = polys.keys().cloned().collect();
+    g(node, &names, &HashSet::new(), found, globals)
+}
+
+// names is the polymorphic definitions to rename the uses of, and bound is the local names, which
+// hide them
+fn g(
+    node: NodeKind,
+    names: &HashSet<String>,
+    bound: &HashSet<String>,
+    found: &mut Vec<(String, Type)>,
+    globals: &Globals,
+) -> NodeKind {
+    macro_rules! g {
+        ($e:expr) => (Box::new(g(*$e, names, bound, found, globals)));
+        ($e:expr, $bound:expr) => (Box::new(g(*$e, names, $bound, found, globals)));
+    }
+    macro_rules! bind {
+        ($names:expr) => ({
+            let mut bound = bound.clone();
+            bound.extend($names);
+            bound
+        });
+    }
+
+    match node {
+        NodeKind::Annot(e, ty) => match *e {
+            NodeKind::Ident(name) => {
+                if names.contains(&name) && !bound.contains(&name) && !is_poly(&ty) {
+                    let instance = instance_name(&name, &ty);
+                    found.push((name, ty.clone()));
+                    NodeKind::Annot(Box::new(NodeKind::Ident(instance)), ty)
+                } else {
+                    NodeKind::Annot(Box::new(NodeKind::Ident(name)), ty)
+                }
+            }
+            e => NodeKind::Annot(g!(Box::new(e)), ty),
+        },
+        NodeKind::Tuple(es) => {
+            NodeKind::Tuple(es.into_iter().map(|e| g(e, names, bound, found, globals)).collect())
+        }
+        NodeKind::Call(e, args) => NodeKind::Call(
+            g!(e),
+            args.into_iter().map(|e| g(e, names, bound, found, globals)).collect(),
+        ),
+        NodeKind::LetExpr((name, ty), expr, body) => {
+            let body = g!(body, &bind!(vec![name.clone()]));
+            if is_poly(&ty) && typing::is_nonexpansive(&expr) {
+                let def = NodeKind::LetDef((name, ty), expr);
+                return local_instances(def, *body, names, bound, found, globals);
+            }
+            let expr = g!(expr);
+            NodeKind::LetExpr((name, ty), expr, body)
+        }
+        NodeKind::LetTupleExpr(xs, expr, body) => {
+            let expr = g!(expr);
+            let body = g!(body, &bind!(xs.iter().map(|&(ref x, _)| x.clone())));
+            NodeKind::LetTupleExpr(xs, expr, body)
+        }
+        NodeKind::LetFuncExpr(funcdef, expr, body) => {
+            let bound_body = bind!(vec![funcdef.name.0.clone()]);
+            if is_poly(&funcdef.name.1) {
+                let body = g(*body, names, &bound_body, found, globals);
+                let def = NodeKind::LetFuncDef(funcdef, expr);
+                return local_instances(def, body, names, bound, found, globals);
+            }
+            let expr = {
+                let params = funcdef.params.iter().map(|&(ref x, _)| x.clone());
+                let mut bound_expr = bound_body.clone();
+                bound_expr.extend(params);
+                g!(expr, &bound_expr)
+            };
+            let body = g!(body, &bound_body);
+            NodeKind::LetFuncExpr(funcdef, expr, body)
+        }
+        NodeKind::LetDef(xt, expr) => NodeKind::LetDef(xt, g!(expr)),
+        // the name isn't bound, so that a specialized function calls itself at its own type
+        NodeKind::LetFuncDef(funcdef, expr) => {
+            let expr = g!(expr, &bind!(funcdef.params.iter().map(|&(ref x, _)| x.clone())));
+            NodeKind::LetFuncDef(funcdef, expr)
+        }
+        NodeKind::IntUnaryOp(op, e) => NodeKind::IntUnaryOp(op, g!(e)),
+        NodeKind::FloatUnaryOp(op, e) => NodeKind::FloatUnaryOp(op, g!(e)),
+        NodeKind::IntBinaryOp(op, e1, e2) => NodeKind::IntBinaryOp(op, g!(e1), g!(e2)),
+        NodeKind::FloatBinaryOp(op, e1, e2) => NodeKind::FloatBinaryOp(op, g!(e1), g!(e2)),
+        NodeKind::CompBinaryOp(op, e1, e2) => NodeKind::CompBinaryOp(op, g!(e1), g!(e2)),
+        NodeKind::IfExpr(c, t, e) => NodeKind::IfExpr(g!(c), g!(t), g!(e)),
+        NodeKind::MakeArray(e1, e2) => NodeKind::MakeArray(g!(e1), g!(e2)),
+        NodeKind::Get(e1, e2) => NodeKind::Get(g!(e1), g!(e2)),
+        NodeKind::Put(e1, e2, e3) => NodeKind::Put(g!(e1), g!(e2), g!(e3)),
+        NodeKind::MakeRef(e) => NodeKind::MakeRef(g!(e)),
+        NodeKind::Deref(e) => NodeKind::Deref(g!(e)),
+        NodeKind::Assign(e1, e2) => NodeKind::Assign(g!(e1), g!(e2)),
+        NodeKind::While(cond, body) => NodeKind::While(g!(cond), g!(body)),
+        NodeKind::For(var, from, to, dir, body) => {
+            let from = g!(from);
+            let to = g!(to);
+            let body = g!(body, &bind!(vec![var.clone()]));
+            NodeKind::For(var, from, to, dir, body)
+        }
+        NodeKind::Constr(name, arg) => NodeKind::Constr(name, arg.map(|e| g!(e))),
+        NodeKind::Raise(e, ty) => NodeKind::Raise(g!(e), ty),
+        NodeKind::TryWith(body, handlers) => {
+            let body = g!(body);
+            let handlers = handlers
+                .into_iter()
+                .map(|handler| {
+                    let bound = bind!(handler.var.iter().map(|&(ref x, _)| x.clone()));
+                    Handler {
+                        constr: handler.constr,
+                        var: handler.var,
+                        body: g!(handler.body, &bound),
+                    }
+                })
+                .collect();
+            NodeKind::TryWith(body, handlers)
+        }
+        node => node,
+    }
+}
+
+// 'let x = def in body' with the local polymorphic definition def specialized like a top-level
+// one, at the types body uses it at. an instance that isn't used isn't defined at all
+fn local_instances(
+    def: NodeKind,
+    body: NodeKind,
+    names: &HashSet<String>,
+    bound: &HashSet<String>,
+    found: &mut Vec<(String, Type)>,
+    globals: &Globals,
+) -> NodeKind {
+    let name = match def {
+        NodeKind::LetFuncDef(ref funcdef, _) => funcdef.name.0.clone(),
+        NodeKind::LetDef((ref name, _), _) => name.clone(),
+        _ => unreachable!(),
+    };
+    let local = vec![name.clone()].into_iter().collect();
+    let mut uses = vec![];
+    let mut body = g(body, &local, &HashSet::new(), &mut uses, globals);
+    let mut tys = vec![];
+    for (_, ty) in uses {
+        if !tys.contains(&ty) {
+            tys.push(ty)
+        }
+    }
+    for ty in tys {
+        body = match specialize(&def, &ty, globals) {
+            // the instance calls itself at its own type, so under its own name
+            NodeKind::LetFuncDef(funcdef, expr) => {
+                let mut names = names.clone();
+                names.insert(name.clone());
+                let mut bound = bound.clone();
+                bound.extend(funcdef.params.iter().map(|&(ref x, _)| x.clone()));
+                bound.remove(&name);
+                let mut found_expr = vec![];
+                let expr = g(*expr, &names, &bound, &mut found_expr, globals);
+                found.extend(found_expr.into_iter().filter(|&(ref x, _)| *x != name));
+                NodeKind::LetFuncExpr(funcdef, Box::new(expr), Box::new(body))
+            }
+            NodeKind::LetDef(xt, expr) => {
+                let expr = g(*expr, names, bound, found, globals);
+                NodeKind::LetExpr(xt, Box::new(expr), Box::new(body))
+            }
+            _ => unreachable!(),
+        }
+    }
+    body
+}
@@ -5,12 +5,14 @@ use typing;
 pub enum NodeKind {
     Unit,
     Bool(bool),
-    Int(i32),
+    Int(i64), // always within the 63-bit range
+    Int32(i32),
+    Int64(i64),
     Float(f64),
+    String(String),
     Ident(String),
     Tuple(Vec<NodeKind>),
     Call(Box<NodeKind>, Vec<NodeKind>),
-    FuncDef((String, typing::Type), Vec<(String, typing::Type)>), // name, params
     LetExpr((String, typing::Type), Box<NodeKind>, Box<NodeKind>), // (name, ty), bound expr, body
     LetTupleExpr(Vec<(String, typing::Type)>, Box<NodeKind>, Box<NodeKind>), // (name, ty), bound expr, body
     LetFuncExpr(FuncDef, Box<NodeKind>, Box<NodeKind>), // (name, ty), bound expr, body
@@ -25,6 +27,63 @@ pub enum NodeKind {
     MakeArray(Box<NodeKind>, Box<NodeKind>),
     Get(Box<NodeKind>, Box<NodeKind>),
     Put(Box<NodeKind>, Box<NodeKind>, Box<NodeKind>),
+    MakeRef(Box<NodeKind>),
+    Deref(Box<NodeKind>),
+    Assign(Box<NodeKind>, Box<NodeKind>), // ref, new value
+    While(Box<NodeKind>, Box<NodeKind>), // cond, body
+    For(String, Box<NodeKind>, Box<NodeKind>, Direction, Box<NodeKind>), // var, from, to, direction, body
+    Annot(Box<NodeKind>, typing::Type),
+    TypeDef(String, Vec<String>, typing::Type), // name, params, body
+    ExnDef(String, Option<typing::Type>),        // name, argument
+    External(String, typing::Type, String),      // name, type, C symbol
+    Constr(String, Option<Box<NodeKind>>),       // exception constructor, argument
+    Raise(Box<NodeKind>, typing::Type),          // exception, type of the 'raise' expression
+    TryWith(Box<NodeKind>, Vec<Handler<NodeKind>>),
+}
+
+// a case of 'try ... with'. 'E x -> e' binds the argument of E to x, and a case without a
+// constructor ('x -> e' or '_ -> e') catches every exception
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Handler<E> {
+    pub constr: Option<String>,
+    pub var: Option<(String, typing::Type)>,
+    pub body: Box<E>,
+}
+
+impl<E> Handler<E> {
+    pub fn map_body<T, F: FnOnce(E) -> T>(self, f: F) -> Handler<T> {
+        Handler {
+            constr: self.constr,
+            var: self.var,
+            body: Box::new(f(*self.body)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upto,
+    Downto,
+}
+
+// 'E', 'E x', 'E _', 'x' or '_'
+pub fn handler_pat_to_str<E>(handler: &Handler<E>) -> String {
+    let var = match handler.var {
+        Some((ref x, _)) => x.as_str(),
+        None => "_",
+    };
+    match handler.constr {
+        Some(ref name) if handler.var.is_none() => name.clone(),
+        Some(ref name) => format!("{} {}", name, var),
+        None => var.to_string(),
+    }
+}
+
+pub fn direction_to_str(dir: &Direction) -> &'static str {
+    match *dir {
+        Direction::Upto => "to",
+        Direction::Downto => "downto",
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -42,6 +101,15 @@ impl NodeKind {
     }
 }
 
+// int is 63 bits wide and kept sign-extended in an i64. arithmetic wraps around at 63 bits
+pub const INT_BITS: u32 = 63;
+pub const MAX_INT: i64 = (1 << (INT_BITS - 1)) - 1;
+pub const MIN_INT: i64 = -(1 << (INT_BITS - 1));
+
+pub fn wrap_int(i: i64) -> i64 {
+    (i << (64 - INT_BITS)) >> (64 - INT_BITS)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum BinOps {
     IAdd,
@@ -71,6 +139,20 @@ pub fn str_to_binop(opstr: &str) -> (BinOps, bool) {
     }
 }
 
+pub fn binop_to_str(op: &BinOps) -> &'static str {
+    match *op {
+        BinOps::IAdd => "+",
+        BinOps::FAdd => "+.",
+        BinOps::ISub => "-",
+        BinOps::FSub => "-.",
+        BinOps::IMul => "*",
+        BinOps::FMul => "*.",
+        BinOps::IDiv => "/",
+        BinOps::FDiv => "/.",
+        BinOps::IMod => "mod",
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CompBinOps {
     SEq,
@@ -97,6 +179,19 @@ pub fn str_to_comp_binop(opstr: &str) -> CompBinOps {
     }
 }
 
+pub fn comp_binop_to_str(op: &CompBinOps) -> &'static str {
+    match *op {
+        CompBinOps::SEq => "=",
+        CompBinOps::SNe => "<>",
+        CompBinOps::PEq => "==",
+        CompBinOps::PNe => "!=",
+        CompBinOps::Lt => "<",
+        CompBinOps::Gt => ">",
+        CompBinOps::Le => "<=",
+        CompBinOps::Ge => ">=",
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum UnaryOps {
     INeg,
@@ -110,3 +205,10 @@ pub fn str_to_unaryop(opstr: &str) -> (UnaryOps, bool) {
         _ => panic!(),
     }
 }
+
+pub fn unaryop_to_str(op: &UnaryOps) -> &'static str {
+    match *op {
+        UnaryOps::INeg => "-",
+        UnaryOps::FNeg => "-.",
+    }
+}
@@ -0,0 +1,71 @@
+use knormal::KNormal;
+use id::IdGen;
+use beta;
+use assoc;
+use inline;
+use constfold;
+use elim;
+
+// upper bound on the number of optimization rounds
+const MAX_ITER: usize = 1000;
+
+// runs beta reduction, let flattening, inlining, constant folding and dead let elimination
+// until the term stops changing. the input must be alpha-converted.
+pub fn f(e: KNormal, inline_threshold: usize, idgen: &mut IdGen) -> KNormal {
+    let mut e = e;
+    for _ in 0..MAX_ITER {
+        let new_e = elim::f(constfold::f(inline::f(
+            assoc::f(beta::f(e.clone())),
+            inline_threshold,
+            idgen,
+        )));
+        if new_e == e {
+            break;
+        }
+        e = new_e;
+    }
+    e
+}
+
+#[test]
+pub fn test_optimize() {
+    use nom::IResult;
+    use std::collections::HashMap;
+    use parser::{module_item, tokens, uniquify};
+    use typing;
+    use knormal;
+    use alpha;
+
+    let mut idgen = IdGen::new();
+    let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
+    let mut f = |e: &str, threshold: usize| match module_item(&tokens(e).0) {
+        IResult::Done(_, node) => {
+            let uniquified = uniquify(node, &mut idgen);
+            let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+            let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+            self::f(knormalized, threshold, &mut idgen).to_string()
+        }
+        IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
+        IResult::Error(err) => panic!(format!("error: {:?}", err)),
+    };
+
+    // folding through lets, comparisons and a constant 'if'
+    assert_eq!(
+        f("let x = 1 + 2 * 3 in if x > 5 then print_int (x - 1) else print_int 0", 0),
+        "let tmp.23 : int = 6 in\nprint_int tmp.23"
+    );
+    // the unused pure binding and the '_' of a pure sequence disappear, 'y' is renamed to 'x'
+    assert_eq!(
+        f("let g x = let a = 2.0 in let y = x in 1.0 +. 2.0; print_int y in g 3", 0),
+        "let rec g.40 (x.41 : int) : unit =\n  \
+         print_int x.41 in\n\
+         let tmp.47 : int = 3 in\n\
+         g.40 tmp.47"
+    );
+    // inlined helpers are removed entirely
+    assert_eq!(
+        f("let dbl x = x +. x in let g y = print_float (dbl y) in g 1.5", 10),
+        "let tmp.69 : float = 3.0 in\nprint_float tmp.69"
+    );
+}
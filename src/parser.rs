@@ -1,86 +1,319 @@
-use nom::{digit, double, IResult};
+use nom::{ErrorKind, IResult, InputLength, Slice};
 
-use std::str;
-use std::str::FromStr;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::RangeTo;
 
 extern crate rand;
 use self::rand::Rng;
 
 use node;
-use node::NodeKind;
+use node::{Handler, NodeKind};
 use closure::Prog;
+use lexer;
+use lexer::{Span, Token, TokenKind};
+pub use lexer::LocatedError;
 
 use id::IdGen;
 
-use typing::{Type, TypeScheme};
+use typing::Type;
 
 use std::boxed::Box;
 
 // syntax reference: https://caml.inria.fr/pub/docs/manual-ocaml/language.html
 
-pub fn to_str(slice: &[u8]) -> &str {
-    str::from_utf8(slice).unwrap()
-}
-
-pub fn remove_comments(s: &[u8]) -> String {
-    let mut level = 0;
-    let mut pos = 0;
-    let mut ret = "".to_string();
-    let len = s.len();
-    while pos < len {
-        if pos < len - 1 && s[pos..(pos + 2)] == [b'(', b'*'] {
-            pos += 2;
-            level += 1;
-            continue;
-        }
-        if pos < len - 1 && s[pos..(pos + 2)] == [b'*', b')'] {
-            pos += 2;
-            if level <= 0 {
-                panic!("not found corresponding \"(*\"")
-            }
-            level -= 1;
-            continue;
+// the parser reads the tokens of lexer::lex without the comments
+
+// the tokens the parser reads, and the lexical errors
+pub fn tokens(src: &str) -> (Vec<Token>, Vec<LocatedError>) {
+    let (tokens, errors) = lexer::lex(src);
+    (tokens.into_iter().filter(|t| !t.is_trivia()).collect(), errors)
+}
+
+// the furthest position a parse failed at, as the number of tokens left there, and what the
+// grammar expected there. every alternative is tried before a parse fails, so the one that got
+// the furthest tells the most about the error
+struct Failures {
+    left: usize,
+    expected: Vec<&'static str>,
+}
+
+impl Failures {
+    fn new() -> Self {
+        Failures {
+            left: usize::max_value(),
+            expected: vec![],
+        }
+    }
+}
+
+// the input of the parsers: the tokens left, and the failures of the phrase being parsed, which
+// the parsers add to as they backtrack
+#[derive(Clone, Copy)]
+struct Input<'a> {
+    tokens: &'a [Token],
+    failures: &'a RefCell<Failures>,
+}
+
+impl<'a> Input<'a> {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn first(&self) -> Option<&'a Token> {
+        self.tokens.first()
+    }
+
+    fn advance(&self) -> Input<'a> {
+        Input {
+            tokens: &self.tokens[1..],
+            failures: self.failures,
+        }
+    }
+}
+
+impl<'a> InputLength for Input<'a> {
+    fn input_len(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+impl<'a> Slice<RangeTo<usize>> for Input<'a> {
+    fn slice(&self, range: RangeTo<usize>) -> Self {
+        Input {
+            tokens: &self.tokens[range],
+            failures: self.failures,
+        }
+    }
+}
+
+// the inputs of a phrase are suffixes of the same tokens
+impl<'a> PartialEq for Input<'a> {
+    fn eq(&self, other: &Input<'a>) -> bool {
+        self.tokens.len() == other.tokens.len()
+    }
+}
+
+fn expected(i: Input, what: Option<&'static str>) {
+    let mut failures = i.failures.borrow_mut();
+    if i.tokens.len() < failures.left {
+        *failures = Failures {
+            left: i.tokens.len(),
+            expected: vec![],
+        };
+    }
+    match what {
+        Some(what) if i.tokens.len() == failures.left && !failures.expected.contains(&what) => {
+            failures.expected.push(what)
         }
-        if level == 0 {
-            ret.push(s[pos] as char);
+        _ => {}
+    }
+}
+
+fn fail<O>(i: Input) -> IResult<Input, O> {
+    expected(i, None);
+    IResult::Error(error_position!(ErrorKind::Tag, i))
+}
+
+// the parser submac, which must match: what tells what it is in a syntax error
+macro_rules! expect (
+    ($i:expr, $submac:ident!( $($args:tt)* ), $what:expr) => ({
+        let i_ = $i;
+        match $submac!(i_, $($args)*) {
+            IResult::Error(err) => {
+                expected(i_, Some($what));
+                IResult::Error(err)
+            }
+            res => res,
         }
-        pos += 1;
+    });
+    ($i:expr, $f:expr, $what:expr) => (expect!($i, call!($f), $what));
+);
+
+fn keyword_token<'a>(i: Input<'a>, kw: &'static str) -> IResult<Input<'a>, ()> {
+    match i.first() {
+        Some(&Token {
+            kind: TokenKind::Keyword(k),
+            ..
+        }) if k == kw => IResult::Done(i.advance(), ()),
+        _ => fail(i),
+    }
+}
+
+fn symbol_token<'a>(i: Input<'a>, sym: &'static str) -> IResult<Input<'a>, ()> {
+    match i.first() {
+        Some(&Token {
+            kind: TokenKind::Symbol(s),
+            ..
+        }) if s == sym => IResult::Done(i.advance(), ()),
+        _ => fail(i),
     }
-    if level != 0 {
-        panic!("comments are not balanced")
+}
+
+// one of the binary operators ops, which are symbols or keywords like 'mod'
+fn operator<'a>(i: Input<'a>, ops: &[&'static str]) -> IResult<Input<'a>, &'static str> {
+    match i.first().map(|t| &t.kind) {
+        Some(&TokenKind::Symbol(op)) | Some(&TokenKind::Keyword(op)) if ops.contains(&op) => {
+            IResult::Done(i.advance(), op)
+        }
+        _ => fail(i),
     }
-    ret
 }
 
-named!(whitespace<()>, do_parse!(one_of!(" \t\n\r") >> ()));
+macro_rules! keyword (
+    ($i:expr, $kw:expr) => (call!($i, keyword_token, $kw));
+);
+
+macro_rules! symbol (
+    ($i:expr, $sym:expr) => (call!($i, symbol_token, $sym));
+);
+
+// the left-hand side of 'let'
+enum LetBinder {
+    Var((String, Type)),
+    Func(node::FuncDef),
+}
+
+named!(
+    type_annot<Input, Type>,
+    preceded!(symbol!(":"), expect!(type_expr, "a type"))
+);
 
-named!(opt_spaces<()>, do_parse!(many0!(whitespace) >> ()));
+named!(
+    param<Input, (String, Type)>,
+    alt!(
+        do_parse!(x: ident_s >> ((x, Type::Var(0))))
+            | delimited!(
+                symbol!("("),
+                do_parse!(x: ident_s >> t: type_annot >> ((x, t))),
+                expect!(symbol!(")"), "`)`")
+            )
+    )
+);
 
-named!(spaces<()>, do_parse!(many1!(whitespace) >> ()));
+named!(
+    let_binder<Input, LetBinder>,
+    alt!(
+        do_parse!(
+            name:   ident_s >> // TODO: not only identifier... (https://caml.inria.fr/pub/docs/manual-ocaml/patterns.html#pattern)
+            params: many1!(param)
+                >> ret: opt!(type_annot) >> ({
+                let ty = match ret {
+                    // the parameter types are tied to the function type by unification
+                    Some(ret) => Type::Func(
+                        params.iter().map(|_| Type::Var(0)).collect(),
+                        Box::new(ret),
+                    ),
+                    None => Type::Var(0),
+                };
+                LetBinder::Func(node::FuncDef {
+                    name: (name, ty),
+                    params: params,
+                })
+            })
+        )
+            | do_parse!(
+                symbol!("(") >> x: ident_s >> t: type_annot >> symbol!(")")
+                    >> (LetBinder::Var((x, t)))
+            )
+            | do_parse!(
+                x: ident_s >> t: opt!(type_annot)
+                    >> (LetBinder::Var((x, t.unwrap_or(Type::Var(0)))))
+            )
+    )
+);
 
+// type expressions. 'a -> b -> c' is a function of two parameters since functions aren't curried
 named!(
-    funcdef<NodeKind>,
+    type_expr<Input, Type>,
     do_parse!(
-        name:   ident >> // TODO: not only identifier... (https://caml.inria.fr/pub/docs/manual-ocaml/patterns.html#pattern)
-        params: many1!(do_parse!(spaces >> param: ident >> (param)))
-            >> (NodeKind::FuncDef(
-                (name.get_ident_name().unwrap(), Type::Var(0)),
-                (params
-                    .into_iter()
-                    .map(|param| (param.get_ident_name().unwrap(), Type::Var(0)))
-                    .collect())
-            ))
+        ts: separated_nonempty_list!(symbol!("->"), expect!(type_tuple, "a type")) >> ({
+            let mut ts = ts;
+            let ret = ts.pop().unwrap();
+            if ts.is_empty() {
+                ret
+            } else {
+                Type::Func(ts, Box::new(ret))
+            }
+        })
+    )
+);
+
+named!(
+    type_tuple<Input, Type>,
+    do_parse!(
+        init: type_app >> rest: many0!(preceded!(symbol!("*"), expect!(type_app, "a type")))
+            >> (if rest.is_empty() {
+                init
+            } else {
+                let mut ts = vec![init];
+                ts.extend(rest);
+                Type::Tuple(ts)
+            })
+    )
+);
+
+named!(
+    type_app<Input, Type>,
+    do_parse!(
+        init: type_atom
+            >> res: fold_many0!(type_constr, init, |t, constr| apply_type_constr(constr, vec![t]))
+            >> (res)
+    )
+);
+
+fn apply_type_constr(constr: String, mut args: Vec<Type>) -> Type {
+    match constr.as_str() {
+        "ref" if args.len() == 1 => Type::Ref(Box::new(args.pop().unwrap())),
+        "array" if args.len() == 1 => Type::Array(Box::new(args.pop().unwrap())),
+        _ => Type::Con(constr, args),
+    }
+}
+
+named!(
+    type_constr<Input, String>,
+    alt!(
+        do_parse!(keyword!("ref") >> ("ref".to_string())) | ident_s
+    )
+);
+
+// the builtin types are ordinary names, as in OCaml
+fn named_type(name: String) -> Type {
+    match name.as_str() {
+        "unit" => Type::Unit,
+        "bool" => Type::Bool,
+        "int" => Type::Int,
+        "int32" => Type::Int32,
+        "int64" => Type::Int64,
+        "float" => Type::Float,
+        "char" => Type::Char,
+        "string" => Type::String,
+        "exn" => Type::Exn,
+        _ => Type::Con(name, vec![]),
+    }
+}
+
+named!(
+    type_atom<Input, Type>,
+    alt!(
+        do_parse!(symbol!("'") >> name: ident_s >> (Type::Param(name)))
+            | do_parse!(
+                symbol!("(") >> args: separated_nonempty_list!(symbol!(","), type_expr)
+                    >> symbol!(")") >> name: expect!(type_constr, "a type constructor")
+                    >> (apply_type_constr(name, args))
+            )
+            | delimited!(symbol!("("), type_expr, expect!(symbol!(")"), "`)`"))
+            | map!(ident_s, named_type)
     )
 );
 
 named!(
-    pat<Vec<(String, Type)>>,
+    pat<Input, Vec<(String, Type)>>,
     do_parse!(
         init: ident_s
             >> res:
                 fold_many1!(
-                    ws!(preceded!(tag!(","), ident_s)),
+                    preceded!(symbol!(","), expect!(ident_s, "a variable")),
                     vec![(init, Type::Var(0))],
                     |mut acc: Vec<(String, Type)>, x| {
                         acc.push((x, Type::Var(0)));
@@ -90,70 +323,112 @@ named!(
     )
 );
 
-named!(expr<NodeKind>, alt!(expr_let | expr_semicolon));
+// precedence from the loosest: (let, try), ';', if, ('<-' ':='), ',', comparisons,
+// ('+' '-'), ('*' '/' mod), unary minus, (application, constructor application, raise), '.(',
+// '!'
+named!(expr<Input, NodeKind>, alt!(expr_let | expr_try | expr_semicolon));
+
+// an expression that can't contain a ';' unless it's inside a 'let' (branches of 'if')
+named!(expr_noseq<Input, NodeKind>, alt!(expr_let | expr_try | expr_if));
 
 named!(
-    expr_let<NodeKind>,
-    alt_complete!(
-        ws!(do_parse!(
-            tag!("let") >>
-        ws!(many0!(tag!("rec"))) >> // TODO: do not ignore rec
-        name: alt!(funcdef | ident) >> ws!(tag!("=")) >> exp: expr >> tag!("in")
-                >> body: expr >> (match name {
-                NodeKind::FuncDef(name, params) => NodeKind::LetFuncExpr(
-                    node::FuncDef {
-                        name: name,
-                        params: params,
-                    },
-                    Box::new(exp),
-                    Box::new(body)
-                ),
-                NodeKind::Ident(name) => {
-                    NodeKind::LetExpr((name, Type::Var(0)), Box::new(exp), Box::new(body))
+    expr_let<Input, NodeKind>,
+    alt!(
+        do_parse!(
+            keyword!("let") >>
+        opt!(keyword!("rec")) >> // TODO: do not ignore rec
+        binder: expect!(let_binder, "a let binder")
+                >> expect!(symbol!("="), "`=` after the let binder")
+                >> exp: expect!(expr, "an expression")
+                >> expect!(keyword!("in"), "`in` after let binding")
+                >> body: expect!(expr, "an expression after `in`") >> (match binder {
+                LetBinder::Func(funcdef) => {
+                    NodeKind::LetFuncExpr(funcdef, Box::new(exp), Box::new(body))
                 }
-                _ => panic!(),
+                LetBinder::Var(xt) => NodeKind::LetExpr(xt, Box::new(exp), Box::new(body)),
             })
-        ))
-            | ws!(do_parse!(
-                tag!("let") >> tag!("(") >> p: pat >> tag!(")") >> ws!(tag!("=")) >> exp: expr
-                    >> tag!("in") >> body: expr
+        )
+            | do_parse!(
+                keyword!("let") >> symbol!("(") >> p: pat >> expect!(symbol!(")"), "`)`")
+                    >> expect!(symbol!("="), "`=` after the let binder")
+                    >> exp: expect!(expr, "an expression")
+                    >> expect!(keyword!("in"), "`in` after let binding")
+                    >> body: expect!(expr, "an expression after `in`")
                     >> (NodeKind::LetTupleExpr(p, Box::new(exp), Box::new(body)))
-            ))
+            )
     )
 );
 
+// 'try e with E x -> e1 | _ -> e2'. like 'let', each handler extends as far as possible
 named!(
-    expr_semicolon<NodeKind>,
-    ws!(do_parse!(
+    expr_try<Input, NodeKind>,
+    do_parse!(
+        keyword!("try") >> body: expect!(expr, "an expression")
+            >> expect!(keyword!("with"), "`with` after the body of try") >> opt!(symbol!("|"))
+            >> handlers: separated_nonempty_list!(symbol!("|"), expect!(handler, "a handler"))
+            >> (NodeKind::TryWith(Box::new(body), handlers))
+    )
+);
+
+// a pattern is 'E', 'E x', 'E _', 'x' or '_'
+named!(
+    handler<Input, Handler<NodeKind>>,
+    do_parse!(
+        pat: alt!(
+            do_parse!(c: constr_s >> x: opt!(ident_s) >> ((Some(c), x)))
+                | do_parse!(x: ident_s >> ((None, Some(x))))
+        ) >> expect!(symbol!("->"), "`->` after the pattern")
+            >> body: expect!(expr, "an expression") >> ({
+            let (constr, var) = pat;
+            Handler {
+                constr: constr,
+                var: var.and_then(|x| if x == "_" { None } else { Some((x, Type::Var(0))) }),
+                body: Box::new(body),
+            }
+        })
+    )
+);
+
+// right associative, and a trailing ';' is allowed (but not ';;')
+named!(
+    expr_semicolon<Input, NodeKind>,
+    do_parse!(
         init: expr_if
-            >> res:
-                fold_many0!(
-                    do_parse!(op: tag!(";") >> rhs: expr >> (rhs)),
-                    init,
-                    |e1, e2: NodeKind| NodeKind::LetExpr(
-                        ("_".to_string(), Type::Var(0)),
-                        Box::new(e1),
-                        Box::new(e2)
-                    )
-                ) >> (res)
-    ))
+            >> rest: opt!(preceded!(symbol!(";"), opt!(expr))) >> (match rest {
+                Some(Some(rhs)) => NodeKind::LetExpr(
+                    ("_".to_string(), Type::Var(0)),
+                    Box::new(init),
+                    Box::new(rhs)
+                ),
+                _ => init,
+            })
+    )
 );
 
+// 'if c then e' is 'if c then e else ()'
 named!(
-    expr_if<NodeKind>,
-    alt_complete!(
-        ws!(do_parse!(
-            tag!("if") >> e1: expr >> tag!("then") >> e2: expr >> tag!("else") >> e3: expr
-                >> (NodeKind::IfExpr(Box::new(e1), Box::new(e2), Box::new(e3)))
-        )) | expr_assign
+    expr_if<Input, NodeKind>,
+    alt!(
+        do_parse!(
+            keyword!("if") >> e1: expect!(expr, "an expression")
+                >> expect!(keyword!("then"), "`then` after the condition")
+                >> e2: expect!(expr_noseq, "an expression")
+                >> e3: opt!(preceded!(keyword!("else"), expect!(expr_noseq, "an expression")))
+                >> (NodeKind::IfExpr(
+                    Box::new(e1),
+                    Box::new(e2),
+                    Box::new(e3.unwrap_or(NodeKind::Unit))
+                ))
+        ) | expr_assign
     )
 );
 
 named!(
-    expr_assign<NodeKind>,
-    alt_complete!(
-        ws!(do_parse!(
-            base_indices: array_index_list >> tag!("<-") >> e: expr_comma >> ({
+    expr_assign<Input, NodeKind>,
+    alt!(
+        do_parse!(
+            base_indices: array_index_list >> symbol!("<-")
+                >> e: expect!(expr_comma, "an expression") >> ({
                 let (mut base, mut indices) = base_indices;
                 let last = indices.pop().unwrap(); // indices.len() >= 1
                 for idx in indices {
@@ -161,83 +436,88 @@ named!(
                 }
                 NodeKind::Put(Box::new(base), Box::new(last), Box::new(e))
             })
-        )) | expr_comma
+        )
+            | do_parse!(
+                lhs: expr_comma
+                    >> rhs: opt!(preceded!(symbol!(":="), expect!(expr_assign, "an expression")))
+                    >> (match rhs {
+                        Some(rhs) => NodeKind::Assign(Box::new(lhs), Box::new(rhs)),
+                        None => lhs,
+                    })
+            )
     )
 );
 
 named!(
-    array_index_list<(NodeKind, Vec<NodeKind>)>,
-    ws!(do_parse!(
+    array_index_list<Input, (NodeKind, Vec<NodeKind>)>,
+    do_parse!(
         init: expr_prim
             >> res:
                 fold_many1!(
-                    ws!(do_parse!(
-                        char!('.') >> char!('(') >> res: expr >> char!(')') >> (res)
-                    )),
+                    do_parse!(
+                        symbol!(".") >> symbol!("(") >> res: expect!(expr, "an expression")
+                            >> expect!(symbol!(")"), "`)`") >> (res)
+                    ),
                     Vec::new(),
                     |mut acc: Vec<NodeKind>, index| {
                         acc.push(index);
                         acc
                     }
                 ) >> ((init, res))
-    ))
+    )
 );
 
 named!(
-    expr_comma<NodeKind>,
-    alt_complete!(
-        ws!(do_parse!(
+    expr_comma<Input, NodeKind>,
+    alt!(
+        do_parse!(
             init: expr_comp
                 >> res:
                     fold_many1!(
-                        do_parse!(tag!(",") >> rhs: expr_comp >> (rhs)),
+                        preceded!(symbol!(","), expect!(expr_comp, "an expression")),
                         vec![init],
                         |mut acc: Vec<NodeKind>, e| {
                             acc.push(e);
                             acc
                         }
                     ) >> (NodeKind::Tuple(res))
-        )) | expr_comp
+        ) | expr_comp
     )
 );
 
 named!(
-    expr_comp<NodeKind>,
-    ws!(do_parse!(
+    expr_comp<Input, NodeKind>,
+    do_parse!(
         init: expr_add_sub
             >> res:
                 fold_many0!(
                     do_parse!(
-                        op:
-                            alt!(
-                                tag!("<>") | tag!("==") | tag!("!=") | tag!("<=") | tag!(">=")
-                                    | tag!("<") | tag!(">")
-                                    | tag!("=")
-                            ) >> rhs: expr_add_sub >> (op, rhs)
+                        op: call!(operator, &["<>", "==", "!=", "<=", ">=", "<", ">", "="])
+                            >> rhs: expect!(expr_add_sub, "an expression") >> (op, rhs)
                     ),
                     init,
-                    |n1, (op, n2): (&[u8], NodeKind)| NodeKind::CompBinaryOp(
-                        node::str_to_comp_binop(str::from_utf8(op).unwrap()),
+                    |n1, (op, n2): (&str, NodeKind)| NodeKind::CompBinaryOp(
+                        node::str_to_comp_binop(op),
                         Box::new(n1),
                         Box::new(n2)
                     )
                 ) >> (res)
-    ))
+    )
 );
 
 named!(
-    expr_add_sub<NodeKind>,
-    ws!(do_parse!(
+    expr_add_sub<Input, NodeKind>,
+    do_parse!(
         init: expr_mul_div
             >> res:
                 fold_many0!(
                     do_parse!(
-                        op: alt!(tag!("+.") | tag!("-.") | tag!("+") | tag!("-"))
-                            >> rhs: expr_mul_div >> (op, rhs)
+                        op: call!(operator, &["+.", "-.", "+", "-"])
+                            >> rhs: expect!(expr_mul_div, "an expression") >> (op, rhs)
                     ),
                     init,
-                    |n1, (op, n2): (&[u8], NodeKind)| {
-                        let (op, is_int) = node::str_to_binop(to_str(op));
+                    |n1, (op, n2): (&str, NodeKind)| {
+                        let (op, is_int) = node::str_to_binop(op);
                         if is_int {
                             NodeKind::IntBinaryOp(op, Box::new(n1), Box::new(n2))
                         } else {
@@ -245,22 +525,22 @@ named!(
                         }
                     }
                 ) >> (res)
-    ))
+    )
 );
 
 named!(
-    expr_mul_div<NodeKind>,
-    ws!(do_parse!(
+    expr_mul_div<Input, NodeKind>,
+    do_parse!(
         init: expr_unary
             >> res:
                 fold_many0!(
                     do_parse!(
-                        op: alt!(tag!("mod") | tag!("*.") | tag!("/.") | tag!("*") | tag!("/"))
-                            >> rhs: expr_unary >> (op, rhs)
+                        op: call!(operator, &["mod", "*.", "/.", "*", "/"])
+                            >> rhs: expect!(expr_unary, "an expression") >> (op, rhs)
                     ),
                     init,
-                    |n1, (op, n2): (&[u8], NodeKind)| {
-                        let (op, is_int) = node::str_to_binop(to_str(op));
+                    |n1, (op, n2): (&str, NodeKind)| {
+                        let (op, is_int) = node::str_to_binop(op);
                         if is_int {
                             NodeKind::IntBinaryOp(op, Box::new(n1), Box::new(n2))
                         } else {
@@ -268,169 +548,497 @@ named!(
                         }
                     }
                 ) >> (res)
-    ))
+    )
 );
 
 named!(
-    expr_unary<NodeKind>,
-    ws!(alt!(
+    expr_unary<Input, NodeKind>,
+    alt!(
         do_parse!(
-            op: alt!(tag!("-.") | tag!("-")) >> e: expr_unary >> ({
-                let (op, is_int) = node::str_to_unaryop(str::from_utf8(op).unwrap());
-                if is_int {
-                    NodeKind::IntUnaryOp(op, Box::new(e))
-                } else {
-                    NodeKind::FloatUnaryOp(op, Box::new(e))
+            op: call!(operator, &["-.", "-"]) >> e: expect!(expr_unary, "an expression") >> ({
+                let (op, is_int) = node::str_to_unaryop(op);
+                // a negated literal is a constant, as in ocaml ('-1', '-2l', '-. 1.5')
+                match (e, is_int) {
+                    (NodeKind::Int(n), true) => NodeKind::Int(node::wrap_int(n.wrapping_neg())),
+                    (NodeKind::Int32(n), true) => NodeKind::Int32(n.wrapping_neg()),
+                    (NodeKind::Int64(n), true) => NodeKind::Int64(n.wrapping_neg()),
+                    (NodeKind::Float(f), _) => NodeKind::Float(-f),
+                    (e, true) => NodeKind::IntUnaryOp(op, Box::new(e)),
+                    (e, false) => NodeKind::FloatUnaryOp(op, Box::new(e)),
                 }
             })
         ) | expr_postfix
-    ))
+    )
 );
 
+// 'a.(i).(j)' binds tighter than application
 named!(
-    apply_postfix<Vec<NodeKind>>,
-    do_parse!(spaces >> args: separated_nonempty_list_complete!(spaces, expr_prim) >> (args))
+    expr_index<Input, NodeKind>,
+    do_parse!(
+        init: expr_prim
+            >> res:
+                fold_many0!(
+                    do_parse!(
+                        symbol!(".") >> symbol!("(") >> idx: expect!(expr, "an expression")
+                            >> expect!(symbol!(")"), "`)`") >> (idx)
+                    ),
+                    init,
+                    |ary, idx| NodeKind::Get(Box::new(ary), Box::new(idx))
+                ) >> (res)
+    )
 );
 
+// 'Array.create' and 'Array.make'
+fn array_create(i: Input) -> IResult<Input, ()> {
+    match qualified_name(i) {
+        IResult::Done(rest, ref name) if name == "Array.create" || name == "Array.make" => {
+            IResult::Done(rest, ())
+        }
+        _ => fail(i),
+    }
+}
+
 named!(
-    expr_postfix<NodeKind>,
-    alt_complete!(
-        ws!(do_parse!(
-            alt_complete!(tag!("Array.create") | tag!("Array.make")) >> res: many1!(ws!(expr_prim))
-                >> ({
-                    let mut res = res;
-                    if res.len() != 2 {
-                        panic!("The number of Array.create is wrong");
-                    }
-                    let res1 = res.pop().unwrap();
-                    let res0 = res.pop().unwrap();
-                    NodeKind::MakeArray(Box::new(res0), Box::new(res1))
+    expr_postfix<Input, NodeKind>,
+    alt!(
+        do_parse!(
+            array_create >> n: expect!(expr_prim, "an argument")
+                >> x: expect!(expr_prim, "an argument")
+                >> (NodeKind::MakeArray(Box::new(n), Box::new(x)))
+        )
+            | do_parse!(
+                keyword!("ref") >> e: expect!(expr_prim, "an argument")
+                    >> (NodeKind::MakeRef(Box::new(e)))
+            )
+            | do_parse!(
+                keyword!("raise") >> e: expect!(expr_prim, "an argument")
+                    >> (NodeKind::Raise(Box::new(e), Type::Var(0)))
+            )
+            | do_parse!(
+                c: constr_s >> arg: opt!(expr_prim) >> (NodeKind::Constr(c, arg.map(Box::new)))
+            ) | expr_loop
+            | do_parse!(
+                init: expr_index >> args: many0!(expr_index) >> (if args.is_empty() {
+                    init
+                } else {
+                    NodeKind::Call(Box::new(init), args)
                 })
-        ))
-            | ws!(do_parse!(
-                init: expr_prim >> folded: fold_many0!(apply_postfix, init, |lhs, pf| {
-                    NodeKind::Call(Box::new(lhs), pf)
-                }) >> (folded)
-            ))
+            )
     )
 );
 
-named!(expr_prim<NodeKind>, alt!(constant | parens | unit));
-
 named!(
-    integer<NodeKind>,
-    do_parse!(
-        i: map_res!(map_res!(digit, str::from_utf8), FromStr::from_str) >> (NodeKind::Int(i))
+    expr_loop<Input, NodeKind>,
+    alt!(
+        do_parse!(
+            keyword!("while") >> cond: expect!(expr, "an expression")
+                >> expect!(keyword!("do"), "`do` after the condition")
+                >> body: expect!(expr, "an expression")
+                >> expect!(keyword!("done"), "`done` after the body of the loop")
+                >> (NodeKind::While(Box::new(cond), Box::new(body)))
+        )
+            | do_parse!(
+                keyword!("for") >> var: expect!(ident_s, "a variable")
+                    >> expect!(symbol!("="), "`=` after the variable")
+                    >> from: expect!(expr, "an expression")
+                    >> dir:
+                        expect!(alt!(
+                            do_parse!(keyword!("to") >> (node::Direction::Upto))
+                                | do_parse!(keyword!("downto") >> (node::Direction::Downto))
+                        ), "`to` or `downto`") >> to: expect!(expr, "an expression")
+                    >> expect!(keyword!("do"), "`do` after the bounds")
+                    >> body: expect!(expr, "an expression")
+                    >> expect!(keyword!("done"), "`done` after the body of the loop")
+                    >> (NodeKind::For(var, Box::new(from), Box::new(to), dir, Box::new(body)))
+            )
     )
 );
 
 named!(
-    float<NodeKind>,
-    do_parse!(f: double >> (NodeKind::Float(f)))
+    expr_prim<Input, NodeKind>,
+    alt!(constant | parens | unit | deref | begin_end)
 );
 
-fn is_ident(x: &[u8]) -> bool {
-    let keywords = vec![
-        &b"let"[..],
-        &b"rec"[..],
-        &b"in"[..],
-        &b"true"[..],
-        &b"false"[..],
-        &b"if"[..],
-        &b"then"[..],
-        &b"else"[..],
-        &b"Array.create"[..],
-        &b"Array.make"[..],
-        &b"mod"[..],
-    ];
-    if x.len() == 0 || keywords.contains(&x) {
-        return false;
+named!(
+    deref<Input, NodeKind>,
+    do_parse!(
+        symbol!("!") >> e: expect!(expr_prim, "an argument") >> (NodeKind::Deref(Box::new(e)))
+    )
+);
+
+// literals were checked by the lexer
+fn number(i: Input) -> IResult<Input, NodeKind> {
+    let lit = match i.first().map(|t| &t.kind) {
+        Some(&TokenKind::Int(n)) => NodeKind::Int(n),
+        Some(&TokenKind::Int32(n)) => NodeKind::Int32(n),
+        Some(&TokenKind::Int64(n)) => NodeKind::Int64(n),
+        Some(&TokenKind::Float(f)) => NodeKind::Float(f),
+        _ => return fail(i),
+    };
+    IResult::Done(i.advance(), lit)
+}
+
+fn string_s(i: Input) -> IResult<Input, String> {
+    match i.first().map(|t| &t.kind) {
+        Some(&TokenKind::String(ref s)) => IResult::Done(i.advance(), s.clone()),
+        _ => fail(i),
     }
-    !(b'0' <= x[0] && x[0] <= b'9')
 }
-fn is_not_ident_u8(x: u8) -> bool {
-    !((b'0' <= x && x <= b'9') || (b'A' <= x && x <= b'Z') || (b'a' <= x && x <= b'z') || x == b'_')
+
+named!(string<Input, NodeKind>, map!(string_s, NodeKind::String));
+
+fn ident_s(i: Input) -> IResult<Input, String> {
+    match i.first().map(|t| &t.kind) {
+        Some(&TokenKind::Ident(ref x)) => IResult::Done(i.advance(), x.clone()),
+        _ => fail(i),
+    }
+}
+
+named!(ident<Input, NodeKind>, map!(ident_s, NodeKind::Ident));
+
+fn capitalized_s(i: Input) -> IResult<Input, String> {
+    match i.first().map(|t| &t.kind) {
+        Some(&TokenKind::Constr(ref c)) => IResult::Done(i.advance(), c.clone()),
+        _ => fail(i),
+    }
 }
 
 named!(
-    ident_s<String>,
-    do_parse!(
-        i: verify!(take_till!(is_not_ident_u8), is_ident)
-            >> (String::from_utf8(i.to_vec()).unwrap())
-    )
+    constr<Input, NodeKind>,
+    do_parse!(c: constr_s >> (NodeKind::Constr(c, None)))
 );
+
+// 'Array.length' is a constructor-like module name and a value name
 named!(
-    ident<NodeKind>,
+    qualified_name<Input, String>,
     do_parse!(
-        i: verify!(take_till!(is_not_ident_u8), is_ident)
-            >> (NodeKind::Ident(String::from_utf8(i.to_vec()).unwrap()))
+        m: capitalized_s >> symbol!(".") >> x: ident_s >> (format!("{}.{}", m, x))
     )
 );
 
+// 'Array.make' is not a value, it takes its arguments in expr_postfix
 named!(
-    bool_true<NodeKind>,
-    do_parse!(tag!("true") >> (NodeKind::Bool(true)))
+    qualified_ident<Input, NodeKind>,
+    do_parse!(not!(array_create) >> x: qualified_name >> (NodeKind::Ident(x)))
 );
 
 named!(
-    bool_false<NodeKind>,
-    do_parse!(tag!("false") >> (NodeKind::Bool(false)))
+    constr_s<Input, String>,
+    do_parse!(c: capitalized_s >> not!(symbol!(".")) >> (c))
 );
 
 named!(
-    constant<NodeKind>,
-    alt_complete!(float | integer | ident | bool_false | bool_true)
+    bool_true<Input, NodeKind>,
+    do_parse!(keyword!("true") >> (NodeKind::Bool(true)))
 );
 
 named!(
-    parens<NodeKind>,
-    delimited!(tag!("("), ws!(expr), tag!(")"))
+    bool_false<Input, NodeKind>,
+    do_parse!(keyword!("false") >> (NodeKind::Bool(false)))
 );
 
 named!(
-    unit<NodeKind>,
-    do_parse!(tag!("(") >> tag!(")") >> (NodeKind::Unit))
+    constant<Input, NodeKind>,
+    alt!(
+        number | string | qualified_ident | constr | ident | bool_false | bool_true
+    )
 );
 
-named!(opt_dscolon<()>, do_parse!(many0!(tag!(";;")) >> ()));
+// '(e : t)' is a type annotation
+named!(
+    parens<Input, NodeKind>,
+    delimited!(
+        symbol!("("),
+        do_parse!(
+            e: expr >> t: opt!(type_annot) >> (match t {
+                Some(t) => NodeKind::Annot(Box::new(e), t),
+                None => e,
+            })
+        ),
+        expect!(symbol!(")"), "`)`")
+    )
+);
+
+named!(
+    begin_end<Input, NodeKind>,
+    do_parse!(
+        keyword!("begin") >> e: opt!(expr) >> expect!(keyword!("end"), "`end`")
+            >> (e.unwrap_or(NodeKind::Unit))
+    )
+);
+
+named!(
+    unit<Input, NodeKind>,
+    do_parse!(symbol!("(") >> symbol!(")") >> (NodeKind::Unit))
+);
+
+named!(opt_dscolon<Input, ()>, do_parse!(many0!(symbol!(";;")) >> ()));
 
 #[macro_export]
-named!(pub module_item<NodeKind>,
-    ws!(do_parse!(
-        i: alt!(expr | definition) >> 
+named!(module_item_sub<Input, NodeKind>,
+    do_parse!(
+        i: alt!(expr | definition) >>
         opt_dscolon >> (i)
-    ))
+    )
 );
 
-named!(definition<NodeKind>, alt!(ws!(definition_let)));
+pub fn module_item(tokens: &[Token]) -> IResult<&[Token], NodeKind> {
+    parse(tokens, module_item_sub)
+}
+
+// runs parser on tokens, with failures of its own
+fn parse<O>(tokens: &[Token], parser: fn(Input) -> IResult<Input, O>) -> IResult<&[Token], O> {
+    let failures = RefCell::new(Failures::new());
+    let input = Input {
+        tokens: tokens,
+        failures: &failures,
+    };
+    match parser(input) {
+        IResult::Done(rest, o) => IResult::Done(&tokens[tokens.len() - rest.len()..], o),
+        IResult::Error(err) => IResult::Error(err),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
 
 named!(
-    definition_let<NodeKind>,
-    ws!(do_parse!(
-        tag!("let") >> name: alt!(funcdef | ident) >> ws!(tag!("=")) >> exp: expr >> (match name {
-            NodeKind::FuncDef(name, params) => NodeKind::LetFuncDef(
-                node::FuncDef {
-                    name: name,
-                    params: params,
-                },
-                Box::new(exp)
-            ),
-            NodeKind::Ident(name) => NodeKind::LetDef((name, Type::Var(0)), Box::new(exp)),
-            _ => panic!(),
+    definition<Input, NodeKind>,
+    alt!(definition_let | definition_type | definition_exception | definition_external)
+);
+
+named!(
+    type_params<Input, Vec<String>>,
+    alt!(
+        do_parse!(symbol!("'") >> name: ident_s >> (vec![name]))
+            | delimited!(
+                symbol!("("),
+                separated_nonempty_list!(symbol!(","), preceded!(symbol!("'"), ident_s)),
+                symbol!(")")
+            )
+    )
+);
+
+// 'type name = t' and 'type ('a, 'b) name = t' declare abbreviations
+named!(
+    definition_type<Input, NodeKind>,
+    do_parse!(
+        keyword!("type") >> params: opt!(type_params) >> name: expect!(ident_s, "a type name")
+            >> expect!(symbol!("="), "`=` after the type name")
+            >> body: expect!(type_expr, "a type")
+            >> (NodeKind::TypeDef(name, params.unwrap_or(vec![]), body))
+    )
+);
+
+// 'exception E' and 'exception E of t'
+named!(
+    definition_exception<Input, NodeKind>,
+    do_parse!(
+        keyword!("exception") >> name: expect!(constr_s, "a constructor name")
+            >> arg: opt!(preceded!(keyword!("of"), expect!(type_expr, "a type")))
+            >> (NodeKind::ExnDef(name, arg))
+    )
+);
+
+// 'external name : t = "symbol"' declares a C function
+named!(
+    definition_external<Input, NodeKind>,
+    do_parse!(
+        keyword!("external") >> name: expect!(ident_s, "a name")
+            >> expect!(symbol!(":"), "`:` after the name") >> ty: expect!(type_expr, "a type")
+            >> expect!(symbol!("="), "`=` after the type")
+            >> symbol: expect!(string_s, "a string naming the C function")
+            >> (NodeKind::External(name, ty, symbol))
+    )
+);
+
+named!(
+    definition_let<Input, NodeKind>,
+    do_parse!(
+        keyword!("let") >> opt!(keyword!("rec")) >> binder: expect!(let_binder, "a let binder")
+            >> expect!(symbol!("="), "`=` after the let binder")
+            >> exp: expect!(expr, "an expression") >> (match binder {
+            LetBinder::Func(funcdef) => NodeKind::LetFuncDef(funcdef, Box::new(exp)),
+            LetBinder::Var(xt) => NodeKind::LetDef(xt, Box::new(exp)),
         })
-    ))
+    )
 );
 
+// a top-level phrase of a program
+#[derive(Debug, PartialEq)]
+pub enum Phrase {
+    // a module item and the part of the source it spans
+    Item(NodeKind, Span),
+    // a phrase with a syntax error, and the name it defines if it starts with 'let name'
+    Broken(Option<String>),
+}
+
+// the phrases of a program and its syntax errors. after an error the parser skips to the next
+// ';;', 'let' or 'in' and goes on, so a program shows all its errors at once. what is parsed
+// after skipping to 'let' or 'in' may be the rest of the broken phrase, so it is only checked
+// for syntax errors until a definition or a ';;'
+pub fn phrases(tokens: &[Token]) -> (Vec<Phrase>, Vec<LocatedError>) {
+    let mut phrases = Vec::new();
+    let mut errors = Vec::new();
+    let mut code = tokens;
+    let mut in_broken = false;
+    while code.len() > 0 {
+        // failures of an earlier phrase don't explain an error in the next one
+        let failures = RefCell::new(Failures::new());
+        let input = Input {
+            tokens: code,
+            failures: &failures,
+        };
+        let parsed = match module_item_sub(input) {
+            IResult::Done(rest, node) => Some((&code[code.len() - rest.len()..], node)),
+            _ => None,
+        };
+        match parsed {
+            Some((rest, node)) if ends_phrase(code, rest) => {
+                let item = &code[..code.len() - rest.len()];
+                let is_definition = match node {
+                    NodeKind::LetDef(_, _)
+                    | NodeKind::LetFuncDef(_, _)
+                    | NodeKind::TypeDef(_, _, _)
+                    | NodeKind::ExnDef(_, _)
+                    | NodeKind::External(_, _, _) => true,
+                    _ => false,
+                };
+                in_broken = in_broken && !is_definition;
+                let dscolons = item.iter()
+                    .rev()
+                    .take_while(|t| t.kind == TokenKind::Symbol(";;"))
+                    .count();
+                if !in_broken {
+                    let span = Span {
+                        start: item[0].span.start,
+                        end: item[item.len() - dscolons - 1].span.end,
+                    };
+                    phrases.push(Phrase::Item(node, span));
+                }
+                in_broken = in_broken && dscolons == 0;
+                code = rest;
+            }
+            _ => {
+                let start = tokens.len() - code.len();
+                let failures = failures.borrow();
+                let pos = tokens.len() - failures.left.min(code.len());
+                errors.push(syntax_error(tokens, pos, failures.expected.clone()));
+                if !in_broken {
+                    phrases.push(Phrase::Broken(match (code.get(1), code.get(2)) {
+                        (Some(&Token { kind: TokenKind::Ident(ref name), .. }), _)
+                        | (
+                            Some(&Token { kind: TokenKind::Keyword("rec"), .. }),
+                            Some(&Token { kind: TokenKind::Ident(ref name), .. }),
+                        ) if code[0].kind == TokenKind::Keyword("let") =>
+                        {
+                            Some(name.clone())
+                        }
+                        _ => None,
+                    }));
+                }
+                let mut pos = pos;
+                in_broken = true;
+                while let Some(token) = tokens.get(pos) {
+                    match token.kind {
+                        TokenKind::Symbol(";;") => {
+                            in_broken = false;
+                            pos += 1;
+                            break;
+                        }
+                        TokenKind::Keyword("in") => {
+                            pos += 1;
+                            break;
+                        }
+                        TokenKind::Keyword("let") if pos > start => break,
+                        _ => pos += 1,
+                    }
+                }
+                code = &tokens[pos..];
+            }
+        }
+    }
+    (phrases, errors)
+}
+
+// an item is followed by ';;', the end of the source or a definition. if it is not, parsing
+// stopped at an error inside the phrase
+fn ends_phrase(code: &[Token], rest: &[Token]) -> bool {
+    match rest.first() {
+        None => true,
+        Some(token) => match token.kind {
+            TokenKind::Keyword("let")
+            | TokenKind::Keyword("type")
+            | TokenKind::Keyword("exception")
+            | TokenKind::Keyword("external") => true,
+            _ => code[code.len() - rest.len() - 1].kind == TokenKind::Symbol(";;"),
+        },
+    }
+}
+
+// 'Syntax error: expected `)` or an expression' at the token at pos, or at the end of the source
+fn syntax_error(tokens: &[Token], pos: usize, expected: Vec<&str>) -> LocatedError {
+    let span = match tokens.get(pos) {
+        Some(token) => token.span,
+        None => {
+            let end = tokens.last().map_or(0, |token| token.span.end);
+            Span {
+                start: end,
+                end: end,
+            }
+        }
+    };
+    let msg = match expected.len() {
+        0 => "Syntax error".to_string(),
+        1 => format!("Syntax error: expected {}", expected[0]),
+        n => format!(
+            "Syntax error: expected {} or {}",
+            expected[..n - 1].join(", "),
+            expected[n - 1]
+        ),
+    };
+    LocatedError {
+        start: span.start,
+        end: span.end,
+        msg: msg,
+    }
+}
+
+// gives fresh type variables to the binders of a module item. a type parameter like 'a stands
+// for the same variable everywhere in the item. every use of a name gets a fresh annotation too,
+// which typing fills with the type the name is used at
 pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
+    uniquify_sub(expr, idgen, &mut HashMap::new())
+}
+
+fn fresh_ty(ty: Type, idgen: &mut IdGen, tyvars: &mut HashMap<String, Type>) -> Type {
+    macro_rules! fresh_ty_seq {
+        ($seq:expr) => ($seq.into_iter().map(|t| fresh_ty(t, idgen, tyvars))
+                                  .collect::<Vec<_>>());
+    }
+    match ty {
+        Type::Var(_) => idgen.get_type(),
+        Type::Param(name) => tyvars
+            .entry(name)
+            .or_insert_with(|| idgen.get_type())
+            .clone(),
+        Type::Tuple(ts) => Type::Tuple(fresh_ty_seq!(ts)),
+        Type::Array(t) => Type::Array(Box::new(fresh_ty(*t, idgen, tyvars))),
+        Type::Ref(t) => Type::Ref(Box::new(fresh_ty(*t, idgen, tyvars))),
+        Type::Func(ps, r) => Type::Func(fresh_ty_seq!(ps), Box::new(fresh_ty(*r, idgen, tyvars))),
+        Type::Con(name, ts) => Type::Con(name, fresh_ty_seq!(ts)),
+        t => t,
+    }
+}
+
+fn uniquify_sub(
+    expr: NodeKind,
+    idgen: &mut IdGen,
+    tyvars: &mut HashMap<String, Type>,
+) -> NodeKind {
     match expr {
         NodeKind::LetExpr((name, ty), expr, body) => {
-            let ty = if let Type::Var(_) = ty {
-                idgen.get_type()
-            } else {
-                ty
-            };
-            let expr = uniquify(*expr, idgen);
-            let body = uniquify(*body, idgen);
+            let ty = fresh_ty(ty, idgen, tyvars);
+            let expr = uniquify_sub(*expr, idgen, tyvars);
+            let body = uniquify_sub(*body, idgen, tyvars);
             NodeKind::LetExpr((name, ty), Box::new(expr), Box::new(body))
         }
         NodeKind::LetFuncExpr(
@@ -441,22 +1049,13 @@ pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
             expr,
             body,
         ) => {
-            let t = if let Type::Var(_) = t {
-                idgen.get_type()
-            } else {
-                t
-            };
+            let t = fresh_ty(t, idgen, tyvars);
             for &mut (_, ref mut param_ty) in &mut params {
                 let entry = ::std::mem::replace(param_ty, Type::Unit);
-                let new_ty = if let Type::Var(_) = entry {
-                    idgen.get_type()
-                } else {
-                    entry
-                };
-                *param_ty = new_ty;
+                *param_ty = fresh_ty(entry, idgen, tyvars);
             }
-            let expr = Box::new(uniquify(*expr, idgen));
-            let body = Box::new(uniquify(*body, idgen));
+            let expr = Box::new(uniquify_sub(*expr, idgen, tyvars));
+            let body = Box::new(uniquify_sub(*body, idgen, tyvars));
             NodeKind::LetFuncExpr(
                 node::FuncDef {
                     name: (name, t),
@@ -468,23 +1067,18 @@ pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
         }
         NodeKind::LetTupleExpr(mut pat, expr, body) => {
             for i in 0..pat.len() {
-                if let Type::Var(_) = pat[i].1 {
-                    pat[i].1 = idgen.get_type();
-                }
+                let entry = ::std::mem::replace(&mut pat[i].1, Type::Unit);
+                pat[i].1 = fresh_ty(entry, idgen, tyvars);
             }
             NodeKind::LetTupleExpr(
                 pat,
-                Box::new(uniquify(*expr, idgen)),
-                Box::new(uniquify(*body, idgen)),
+                Box::new(uniquify_sub(*expr, idgen, tyvars)),
+                Box::new(uniquify_sub(*body, idgen, tyvars)),
             )
         }
         NodeKind::LetDef((name, ty), expr) => {
-            let ty = if let Type::Var(_) = ty {
-                idgen.get_type()
-            } else {
-                ty
-            };
-            let expr = uniquify(*expr, idgen);
+            let ty = fresh_ty(ty, idgen, tyvars);
+            let expr = uniquify_sub(*expr, idgen, tyvars);
             NodeKind::LetDef((name, ty), Box::new(expr))
         }
         NodeKind::LetFuncDef(
@@ -494,21 +1088,12 @@ pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
             },
             expr,
         ) => {
-            let t = if let Type::Var(_) = t {
-                idgen.get_type()
-            } else {
-                t
-            };
+            let t = fresh_ty(t, idgen, tyvars);
             for &mut (_, ref mut param_ty) in &mut params {
                 let entry = ::std::mem::replace(param_ty, Type::Unit);
-                let new_ty = if let Type::Var(_) = entry {
-                    idgen.get_type()
-                } else {
-                    entry
-                };
-                *param_ty = new_ty;
+                *param_ty = fresh_ty(entry, idgen, tyvars);
             }
-            let expr = Box::new(uniquify(*expr, idgen));
+            let expr = Box::new(uniquify_sub(*expr, idgen, tyvars));
             NodeKind::LetFuncDef(
                 node::FuncDef {
                     name: (name, t),
@@ -517,24 +1102,40 @@ pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
                 expr,
             )
         }
+        NodeKind::IntUnaryOp(op, e) => {
+            NodeKind::IntUnaryOp(op, Box::new(uniquify_sub(*e, idgen, tyvars)))
+        }
+        NodeKind::FloatUnaryOp(op, e) => {
+            NodeKind::FloatUnaryOp(op, Box::new(uniquify_sub(*e, idgen, tyvars)))
+        }
         NodeKind::IntBinaryOp(op, e1, e2) => {
-            let e1 = Box::new(uniquify(*e1, idgen));
-            let e2 = Box::new(uniquify(*e2, idgen));
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
             NodeKind::IntBinaryOp(op, e1, e2)
         }
         NodeKind::FloatBinaryOp(op, e1, e2) => {
-            let e1 = Box::new(uniquify(*e1, idgen));
-            let e2 = Box::new(uniquify(*e2, idgen));
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
             NodeKind::FloatBinaryOp(op, e1, e2)
         }
+        NodeKind::CompBinaryOp(op, e1, e2) => {
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
+            NodeKind::CompBinaryOp(op, e1, e2)
+        }
+        NodeKind::Tuple(mut es) => {
+            uniquify_seq(&mut es, idgen, tyvars);
+            NodeKind::Tuple(es)
+        }
         NodeKind::Call(e1, mut e2s) => {
             if let &NodeKind::Ident(_) = &*e1 {
-                let e1 = Box::new(uniquify(*e1, idgen));
-                uniquify_seq(&mut e2s, idgen);
+                let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+                uniquify_seq(&mut e2s, idgen, tyvars);
                 NodeKind::Call(e1, e2s)
             } else {
                 let rand_name: String = rand::thread_rng().gen_ascii_chars().take(8).collect();
-                uniquify_seq(&mut e2s, idgen);
+                let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+                uniquify_seq(&mut e2s, idgen, tyvars);
                 NodeKind::LetExpr(
                     (rand_name.clone(), idgen.get_type()),
                     e1,
@@ -543,34 +1144,76 @@ pub fn uniquify(expr: NodeKind, idgen: &mut IdGen) -> NodeKind {
             }
         }
         NodeKind::IfExpr(c, t, e) => NodeKind::IfExpr(
-            Box::new(uniquify(*c, idgen)),
-            Box::new(uniquify(*t, idgen)),
-            Box::new(uniquify(*e, idgen)),
+            Box::new(uniquify_sub(*c, idgen, tyvars)),
+            Box::new(uniquify_sub(*t, idgen, tyvars)),
+            Box::new(uniquify_sub(*e, idgen, tyvars)),
         ),
         NodeKind::MakeArray(e1, e2) => {
-            let e1 = Box::new(uniquify(*e1, idgen));
-            let e2 = Box::new(uniquify(*e2, idgen));
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
             NodeKind::MakeArray(e1, e2)
         }
         NodeKind::Get(e1, e2) => {
-            let e1 = Box::new(uniquify(*e1, idgen));
-            let e2 = Box::new(uniquify(*e2, idgen));
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
             NodeKind::Get(e1, e2)
         }
         NodeKind::Put(e1, e2, e3) => {
-            let e1 = Box::new(uniquify(*e1, idgen));
-            let e2 = Box::new(uniquify(*e2, idgen));
-            let e3 = Box::new(uniquify(*e3, idgen));
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
+            let e3 = Box::new(uniquify_sub(*e3, idgen, tyvars));
             NodeKind::Put(e1, e2, e3)
         }
+        NodeKind::MakeRef(e) => NodeKind::MakeRef(Box::new(uniquify_sub(*e, idgen, tyvars))),
+        NodeKind::Deref(e) => NodeKind::Deref(Box::new(uniquify_sub(*e, idgen, tyvars))),
+        NodeKind::Assign(e1, e2) => {
+            let e1 = Box::new(uniquify_sub(*e1, idgen, tyvars));
+            let e2 = Box::new(uniquify_sub(*e2, idgen, tyvars));
+            NodeKind::Assign(e1, e2)
+        }
+        NodeKind::While(cond, body) => {
+            let cond = Box::new(uniquify_sub(*cond, idgen, tyvars));
+            let body = Box::new(uniquify_sub(*body, idgen, tyvars));
+            NodeKind::While(cond, body)
+        }
+        NodeKind::For(var, from, to, dir, body) => {
+            let from = Box::new(uniquify_sub(*from, idgen, tyvars));
+            let to = Box::new(uniquify_sub(*to, idgen, tyvars));
+            let body = Box::new(uniquify_sub(*body, idgen, tyvars));
+            NodeKind::For(var, from, to, dir, body)
+        }
+        NodeKind::Annot(e, ty) => {
+            let e = Box::new(uniquify_sub(*e, idgen, tyvars));
+            NodeKind::Annot(e, fresh_ty(ty, idgen, tyvars))
+        }
+        NodeKind::Constr(name, arg) => {
+            NodeKind::Constr(name, arg.map(|e| Box::new(uniquify_sub(*e, idgen, tyvars))))
+        }
+        NodeKind::Raise(e, ty) => {
+            let e = Box::new(uniquify_sub(*e, idgen, tyvars));
+            NodeKind::Raise(e, fresh_ty(ty, idgen, tyvars))
+        }
+        NodeKind::TryWith(body, handlers) => {
+            let body = Box::new(uniquify_sub(*body, idgen, tyvars));
+            let handlers = handlers
+                .into_iter()
+                .map(|Handler { constr, var, body }| Handler {
+                    constr: constr,
+                    var: var.map(|(x, t)| (x, fresh_ty(t, idgen, tyvars))),
+                    body: Box::new(uniquify_sub(*body, idgen, tyvars)),
+                })
+                .collect();
+            NodeKind::TryWith(body, handlers)
+        }
+        NodeKind::Ident(name) => NodeKind::Annot(Box::new(NodeKind::Ident(name)), idgen.get_type()),
         x => x, // No Syntax inside
     }
 }
 
-fn uniquify_seq(seq: &mut Vec<NodeKind>, id_gen: &mut IdGen) {
+fn uniquify_seq(seq: &mut Vec<NodeKind>, idgen: &mut IdGen, tyvars: &mut HashMap<String, Type>) {
     for i in 0..seq.len() {
         let entry = ::std::mem::replace(&mut seq[i], NodeKind::Unit);
-        seq[i] = uniquify(entry, id_gen);
+        seq[i] = uniquify_sub(entry, idgen, tyvars);
     }
 }
 
@@ -578,7 +1221,7 @@ pub fn parse_and_show_simple_expr(e: &str) {
     println!(
         "expr: {}\n{}",
         e,
-        match expr(e.as_bytes()) {
+        match parse(&tokens(e).0, expr) {
             IResult::Done(_, expr_node) => format!("generated node: {:?}", expr_node),
             IResult::Incomplete(needed) => format!("imcomplete: {:?}", needed),
             IResult::Error(err) => format!("error: {:?}", err),
@@ -590,7 +1233,7 @@ pub fn parse_and_show_module_item(e: &str) {
     println!(
         "module-item: {}\n{}",
         e,
-        match module_item(e.as_bytes()) {
+        match module_item(&tokens(e).0) {
             IResult::Done(_, expr_node) => format!("generated node: {:?}", expr_node),
             IResult::Incomplete(needed) => format!("imcomplete: {:?}", needed),
             IResult::Error(err) => format!("error: {:?}", err),
@@ -606,69 +1249,76 @@ pub fn parse_module_items(e: &str) -> Vec<Prog> {
     use typing;
     use id;
     use codegen;
+    use knormal;
+    use alpha;
     use closure;
 
     let mut idgen = id::IdGen::new();
     let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
     let mut progs = Vec::new();
-    let e = remove_comments(e.as_bytes());
-    let mut code = e.as_str();
+    let (tokens, _) = tokens(e);
+    let mut code = &tokens[..];
 
     println!(
         "{}",
         Style::new()
             .underline()
             .bold()
-            .paint(format!("expression:\t{}", code))
+            .paint(format!("expression:\t{}", e))
     );
 
     while code.len() > 0 {
-        match module_item(code.as_bytes()) {
+        match module_item(code) {
             IResult::Done(remain, node) => {
                 let uniquified = uniquify(node, &mut idgen);
                 println!("{:?}", uniquified.clone());
-                let infered = typing::f(&uniquified, &mut tyenv, &mut idgen);
-                let closured = closure::f(infered);
+                let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+                let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+                println!("{}", knormalized);
+                let closured = closure::f(knormalized, &globals);
                 println!(
                     "{}",
                     Colour::Green
                         .bold()
-                        .paint(format!("program:\t{:?}", closured))
+                        .paint(format!("program:\n{}", closured))
                 );
                 progs.push(closured);
-                code = str::from_utf8(remain).unwrap();
+                code = remain;
             }
             IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
             IResult::Error(err) => panic!(format!("error: {:?}", err)),
         }
     }
 
-    unsafe {
-        let mut codegen = codegen::CodeGen::new(&mut tyenv);
-        codegen.gen(true, true, progs.clone()).unwrap();
-    }
+    let mut codegen = codegen::CodeGen::new(&globals, true);
+    codegen.gen(true, true, progs.clone()).unwrap();
     progs
 }
 
 pub fn do_parse_typing_closure(e: &str) -> (Vec<Prog>, HashMap<usize, Type>) {
     use typing;
     use id;
+    use knormal;
+    use alpha;
     use closure;
 
     let mut idgen = id::IdGen::new();
     let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
     let mut progs = Vec::new();
-    let e = remove_comments(e.as_bytes());
-    let mut code = e.as_str();
+    let (tokens, _) = tokens(e);
+    let mut code = &tokens[..];
 
     while code.len() > 0 {
-        match module_item(code.as_bytes()) {
+        match module_item(code) {
             IResult::Done(remain, node) => {
                 let uniquified = uniquify(node, &mut idgen);
-                let infered = typing::f(&uniquified, &mut tyenv, &mut idgen);
-                let closured = closure::f(infered);
+                let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+                let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+                let closured = closure::f(knormalized, &globals);
                 progs.push(closured);
-                code = str::from_utf8(remain).unwrap();
+                code = remain;
             }
             IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
             IResult::Error(err) => panic!(format!("error: {:?}", err)),
@@ -686,7 +1336,7 @@ pub fn parse_and_infer_type(e: &str) {
             .bold()
             .paint(format!("expression:\t{}", e))
     );
-    let node = match module_item(e.as_bytes()) {
+    let node = match module_item(&tokens(e).0) {
         IResult::Done(_, node) => node,
         _ => panic!(),
     };
@@ -695,6 +1345,7 @@ pub fn parse_and_infer_type(e: &str) {
     use id;
     let mut idgen = id::IdGen::new();
     let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
     let uniquified = uniquify(node, &mut idgen);
     println!(
         "{}",
@@ -706,7 +1357,7 @@ pub fn parse_and_infer_type(e: &str) {
         "{}",
         Colour::Green.bold().paint(format!(
             "infered:\t{:?}",
-            typing::f(&uniquified, &mut tyenv, &mut idgen)
+            typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals)
         ))
     );
 }
@@ -719,19 +1370,23 @@ pub fn parse_and_infer_type_and_closure_conv(e: &str) {
             .bold()
             .paint(format!("expression:\t{}", e))
     );
-    let node = match module_item(e.as_bytes()) {
+    let node = match module_item(&tokens(e).0) {
         IResult::Done(_, node) => node,
         _ => panic!(),
     };
     // sloppy impl of showing type-infered node
     use typing;
     use id;
+    use knormal;
+    use alpha;
     use closure;
     let mut idgen = id::IdGen::new();
     let mut tyenv = HashMap::new();
+    let mut globals = typing::Globals::new();
     let uniquified = uniquify(node, &mut idgen);
-    let infered = typing::f(&uniquified, &mut tyenv, &mut idgen);
-    let closured = closure::f(infered);
+    let infered = typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals).unwrap();
+    let knormalized = alpha::f(knormal::f(infered, &mut idgen, &globals), &mut idgen);
+    let closured = closure::f(knormalized, &globals);
     println!(
         "{}",
         Colour::Yellow
@@ -746,29 +1401,56 @@ pub fn parse_and_infer_type_and_closure_conv(e: &str) {
     );
 }
 
-use std::sync::Mutex;
-
-lazy_static! {
-    pub static ref EXTENV: Mutex<HashMap<String, TypeScheme>> = {
-        let mut extenv = HashMap::new();
-        extenv.insert("print_int".to_string(),
-                      TypeScheme::new(vec![], Type::Func(
-                                                vec![Type::Int],
-                                                Box::new(Type::Unit))));
-        extenv.insert("print_float".to_string(),
-                      TypeScheme::new(vec![], Type::Func(
-                                                vec![Type::Float],
-                                                Box::new(Type::Unit))));
-        extenv.insert("print_newline".to_string(),
-                      TypeScheme::new(vec![], Type::Func(
-                                                vec![Type::Unit],
-                                                Box::new(Type::Unit))));
-        extenv.insert("float_of_int".to_string(),
-                      TypeScheme::new(vec![], Type::Func(
-                                                vec![Type::Int],
-                                                Box::new(Type::Float))));
-        Mutex::new(extenv)
+#[test]
+pub fn test_phrases() {
+    let f = |src: &str| {
+        let (phrases, errors) = phrases(&tokens(src).0);
+        let kinds: Vec<_> = phrases
+            .into_iter()
+            .map(|p| match p {
+                Phrase::Item(_, span) => src[span.start..span.end].to_string(),
+                Phrase::Broken(name) => format!("broken {:?}", name),
+            })
+            .collect();
+        let msgs: Vec<_> = errors.into_iter().map(|e| e.msg).collect();
+        (kinds, msgs)
     };
+
+    assert_eq!(
+        f("let a = 1;; print_int a;;"),
+        (vec!["let a = 1".to_string(), "print_int a".to_string()], vec![])
+    );
+    // parsing goes on after ';;'
+    assert_eq!(
+        f("let a = (1 + ) in a;; let b = 2;;"),
+        (
+            vec!["broken Some(\"a\")".to_string(), "let b = 2".to_string()],
+            vec!["Syntax error: expected an expression".to_string()]
+        )
+    );
+    // and after 'let'
+    assert_eq!(
+        f("print_int (1 + ) let c = 3"),
+        (
+            vec!["broken None".to_string(), "let c = 3".to_string()],
+            vec!["Syntax error: expected an expression".to_string()]
+        )
+    );
+    // what follows 'in' is part of the broken phrase and is not returned
+    assert_eq!(
+        f("let a = (1 + ) in a + true;; let b = 2"),
+        (
+            vec!["broken Some(\"a\")".to_string(), "let b = 2".to_string()],
+            vec!["Syntax error: expected an expression".to_string()]
+        )
+    );
+    assert_eq!(
+        f("let f x = if x 1 else 2;; let g = f"),
+        (
+            vec!["broken Some(\"f\")".to_string(), "let g = f".to_string()],
+            vec!["Syntax error: expected `then` after the condition".to_string()]
+        )
+    );
 }
 
 #[test]
@@ -776,7 +1458,7 @@ pub fn test_parse_simple_expr() {
     use node::NodeKind::*;
     use node::BinOps::*;
 
-    let f = |e: &str| match expr(e.as_bytes()) {
+    let f = |e: &str| match parse(&tokens(e).0, expr) {
         IResult::Done(_, expr_node) => expr_node,
         IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
         IResult::Error(err) => panic!(format!("error: {:?}", err)),
@@ -797,16 +1479,153 @@ pub fn test_parse_simple_expr() {
     assert_eq!(
         f("5.3 *. 10.2"),
         FloatBinaryOp(FMul, Box::new(Float(5.3)), Box::new(Float(10.2)))
+    );
+    assert_eq!(f("4611686018427387903"), Int(4611686018427387903));
+    assert_eq!(f("2147483647l"), Int32(2147483647));
+    assert_eq!(f("9223372036854775807L"), Int64(9223372036854775807));
+    assert_eq!(
+        f("(x : int ref -> int * float array -> 'a)"),
+        Annot(
+            Box::new(Ident("x".to_string())),
+            Type::Func(
+                vec![
+                    Type::Ref(Box::new(Type::Int)),
+                    Type::Tuple(vec![Type::Int, Type::Array(Box::new(Type::Float))]),
+                ],
+                Box::new(Type::Param("a".to_string()))
+            )
+        )
+    );
+    // a parenthesized function type is not flattened into its enclosing one
+    assert_eq!(
+        f("(f : int -> (int -> unit))"),
+        Annot(
+            Box::new(Ident("f".to_string())),
+            Type::Func(
+                vec![Type::Int],
+                Box::new(Type::Func(vec![Type::Int], Box::new(Type::Unit)))
+            )
+        )
+    );
+    assert_eq!(
+        f("r := !r + 1"),
+        Assign(
+            Box::new(Ident("r".to_string())),
+            Box::new(IntBinaryOp(
+                IAdd,
+                Box::new(Deref(Box::new(Ident("r".to_string())))),
+                Box::new(Int(1))
+            ))
+        )
+    );
+    assert_eq!(
+        f("ref (refs, 1)"),
+        MakeRef(Box::new(Tuple(vec![Ident("refs".to_string()), Int(1)])))
+    );
+    assert_eq!(
+        f("for i = n downto 0 do print_int i done"),
+        For(
+            "i".to_string(),
+            Box::new(Ident("n".to_string())),
+            Box::new(Int(0)),
+            node::Direction::Downto,
+            Box::new(Call(
+                Box::new(Ident("print_int".to_string())),
+                vec![Ident("i".to_string())]
+            ))
+        )
+    );
+    assert_eq!(
+        f("while done_ do () done"),
+        While(Box::new(Ident("done_".to_string())), Box::new(Unit))
+    );
+    assert_eq!(
+        f("Array.length m.(i).(0)"),
+        Call(
+            Box::new(Ident("Array.length".to_string())),
+            vec![Get(
+                Box::new(Get(
+                    Box::new(Ident("m".to_string())),
+                    Box::new(Ident("i".to_string()))
+                )),
+                Box::new(Int(0))
+            )]
+        )
     )
 }
 
+// each pair is an expression and its fully parenthesized reading, following the precedence
+// table of the OCaml manual (https://caml.inria.fr/pub/docs/manual-ocaml/expr.html)
+#[test]
+pub fn test_precedence() {
+    let f = |e: &str| match parse(&tokens(e).0, expr) {
+        IResult::Done(remain, expr_node) => {
+            assert!(remain.is_empty(), "{:?} is left after parsing '{}'", remain, e);
+            expr_node
+        }
+        IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
+        IResult::Error(err) => panic!(format!("error: {:?}", err)),
+    };
+
+    let cases = vec![
+        // '!' binds tighter than application, application tighter than unary minus
+        ("f !r", "f (!r)"),
+        ("!r + 1", "(!r) + 1"),
+        ("- f x", "-(f x)"),
+        ("- a * b", "(-a) * b"),
+        // arithmetic
+        ("a + b * c", "a + (b * c)"),
+        ("a - b - c", "(a - b) - c"),
+        ("a / b mod c", "(a / b) mod c"),
+        ("a +. b *. c -. d", "(a +. (b *. c)) -. d"),
+        // comparisons are below arithmetic and left associative
+        ("a + b < c * d", "(a + b) < (c * d)"),
+        ("a = b = c", "(a = b) = c"),
+        // ',' is below comparisons, ':=' and '<-' are below ','
+        ("a < b, c", "(a < b), c"),
+        ("r := a, b", "r := (a, b)"),
+        ("r := s := 1", "r := (s := 1)"),
+        // 'if' is below ':=' and ','
+        ("if a then b else c, d", "if a then b else (c, d)"),
+        ("if a then r := 1 else r := 2", "if a then (r := 1) else (r := 2)"),
+        ("if a then if b then c else d", "if a then (if b then c else d)"),
+        ("if a then b", "if a then b else ()"),
+        // ';' is below 'if' and right associative
+        ("a; b; c", "a; (b; c)"),
+        ("r := a; b", "(r := a); b"),
+        ("a.(i) <- b; c", "(a.(i) <- b); c"),
+        ("if a then b; c", "(if a then b); c"),
+        ("if a then b else c; d", "(if a then b else c); d"),
+        ("a; b;", "a; b"),
+        // 'let' extends as far to the right as possible
+        ("let x = a; b in c; d", "let x = (a; b) in (c; d)"),
+        ("if a then let x = b in x; c", "if a then (let x = b in (x; c))"),
+        ("a; let x = b in c; d", "a; (let x = b in (c; d))"),
+        // begin ... end is a pair of parentheses
+        ("begin a; b end; c", "(a; b); c"),
+        ("begin end", "()"),
+        ("f begin a end", "f (a)"),
+        ("while a do b; c done; d", "(while a do (b; c) done); d"),
+    ];
+    for (e, parenthesized) in cases {
+        assert_eq!(f(e), f(parenthesized), "'{}'", e);
+    }
+
+    // a negated literal is a constant
+    assert_eq!(f("-7"), NodeKind::Int(-7));
+    assert_eq!(f("- 2l"), NodeKind::Int32(-2));
+    assert_eq!(f("-. 1.5"), NodeKind::Float(-1.5));
+    assert_eq!(f("-4611686018427387904"), NodeKind::Int(node::MIN_INT));
+    assert_eq!(f("-2147483648l"), NodeKind::Int32(::std::i32::MIN));
+}
+
 #[test]
 pub fn test_parse_module_item() {
     use node::NodeKind::*;
     use node::FuncDef;
     use node::BinOps::*;
 
-    let f = |e: &str| match module_item(e.as_bytes()) {
+    let f = |e: &str| match module_item(&tokens(e).0) {
         IResult::Done(_, expr_node) => expr_node,
         IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
         IResult::Error(err) => panic!(format!("error: {:?}", err)),
@@ -826,6 +1645,72 @@ pub fn test_parse_module_item() {
             ))
         )
     );
+    assert_eq!(
+        f("type ('k, 'v) assoc = ('k * 'v) array ref;;"),
+        TypeDef(
+            "assoc".to_string(),
+            vec!["k".to_string(), "v".to_string()],
+            Type::Ref(Box::new(Type::Array(Box::new(Type::Tuple(vec![
+                Type::Param("k".to_string()),
+                Type::Param("v".to_string()),
+            ])))))
+        )
+    );
+    assert_eq!(
+        f("let (m : (int, float pair) assoc) = m"),
+        LetDef(
+            (
+                "m".to_string(),
+                Type::Con(
+                    "assoc".to_string(),
+                    vec![
+                        Type::Int,
+                        Type::Con("pair".to_string(), vec![Type::Float]),
+                    ]
+                )
+            ),
+            Box::new(Ident("m".to_string()))
+        )
+    );
+    assert_eq!(
+        f("exception Error of int * string;;"),
+        ExnDef(
+            "Error".to_string(),
+            Some(Type::Tuple(vec![Type::Int, Type::String]))
+        )
+    );
+    assert_eq!(
+        f("external c_pow : float -> float -> float = \"pow\";;"),
+        External(
+            "c_pow".to_string(),
+            Type::Func(vec![Type::Float, Type::Float], Box::new(Type::Float)),
+            "pow".to_string()
+        )
+    );
+    assert_eq!(
+        f("try raise (Failure \"a\\n\") with Failure s -> s | _ -> \"(*\""),
+        TryWith(
+            Box::new(Raise(
+                Box::new(Constr(
+                    "Failure".to_string(),
+                    Some(Box::new(String("a\n".to_string())))
+                )),
+                Type::Var(0)
+            )),
+            vec![
+                Handler {
+                    constr: Some("Failure".to_string()),
+                    var: Some(("s".to_string(), Type::Var(0))),
+                    body: Box::new(Ident("s".to_string())),
+                },
+                Handler {
+                    constr: None,
+                    var: None,
+                    body: Box::new(String("(*".to_string())),
+                },
+            ]
+        )
+    );
 }
 
 #[test]
@@ -838,8 +1723,10 @@ pub fn test_type_infer() {
     use typing;
 
     let mut idgen = IdGen::new();
+    let mut globals = typing::Globals::new();
 
-    let mut f = |e: &str| match module_item(e.as_bytes()) {
+    // the type is printed with the weak variables known at the time
+    let mut f = |e: &str| match module_item(&tokens(e).0) {
         IResult::Done(remain, node) => {
             let uniquified = uniquify(node, &mut idgen);
             typing::g(
@@ -847,7 +1734,8 @@ pub fn test_type_infer() {
                 &HashMap::new(),
                 &mut HashMap::new(),
                 &mut idgen,
-            )
+                &mut globals,
+            ).map(|t| t.to_string_in(&globals))
         }
         IResult::Incomplete(needed) => panic!(format!("imcomplete: {:?}", needed)),
         IResult::Error(err) => panic!(format!("error: {:?}", err)),
@@ -859,4 +1747,75 @@ pub fn test_type_infer() {
             .to_string(),
         "((('1 -> '1) -> '2) -> '2)"
     );
+    assert_eq!(
+        f("let r = ref 1.5 in r := !r +. 1.0; r").unwrap().to_string(),
+        "float ref"
+    );
+    // a ref cell can't be used at two different types
+    assert!(f("let id x = x in let r = ref id in let a = !r 1 in !r true").is_err());
+    assert!(f("let id x = x in let a = id 1 in id true").is_ok());
+    // an expansive binding gets weak type variables, fixed by its first use
+    assert_eq!(
+        f("let id x = x in let g = id id in g").unwrap().to_string(),
+        "('_a -> '_a)"
+    );
+    assert_eq!(
+        f("let id x = x in let g = id id in let a = g 1 in g")
+            .unwrap()
+            .to_string(),
+        "(int -> int)"
+    );
+    assert!(f("let id x = x in let g = id id in let a = g 1 in g true").is_err());
+    // a variable occurring only in covariant positions is still generalized
+    assert!(f("let rec loop x = loop x in let v = loop 1 in let a = v + 1 in v +. 1.0").is_ok());
+    // type annotations
+    assert!(f("(1 : float)").is_err());
+    assert!(f("let (x : float) = 1 in x").is_err());
+    assert_eq!(
+        f("let x : float = 1.0 in (x : float)").unwrap().to_string(),
+        "float"
+    );
+    assert_eq!(
+        f("let f (x : 'a) : 'a = x + 1 in f").unwrap().to_string(),
+        "(int -> int)"
+    );
+    assert!(f("let f (x : int) y : float = x + y in f").is_err());
+    assert_eq!(
+        f("let apply (g : 'a -> 'b) x = g x in apply").unwrap().to_string(),
+        "(('1 -> '2) -> '1 -> '2)"
+    );
+    // type abbreviations
+    assert!(f("type 'a twice = 'a * 'a").is_ok());
+    assert!(f("type point = float twice").is_ok());
+    assert!(f("type bad = 'a * int").is_err());
+    assert!(f("type bad = undefined").is_err());
+    assert!(f("type bad = int twice twice twice").is_ok());
+    assert!(f("type bad = (int, int) twice").is_err());
+    assert_eq!(
+        f("let (p : point) = (1.0, 2.0) in p").unwrap().to_string(),
+        "point"
+    );
+    assert_eq!(
+        f("let swap (p : 'a twice) : 'a twice = let (a, b) = p in (b, a) in swap")
+            .unwrap()
+            .to_string(),
+        "('1 twice -> '1 twice)"
+    );
+    assert_eq!(
+        f("let (p : point) = (1.0, 2.0) in let (q : float twice) = p in q")
+            .unwrap()
+            .to_string(),
+        "float twice"
+    );
+    assert!(f("let (p : point) = (1, 2) in p").is_err());
+    assert!(f("let (p : int twice) = (1, 2) in let (q : point) = p in q").is_err());
+    // exceptions
+    assert!(f("exception Parse_error of string * int").is_ok());
+    assert!(f("(try raise Not_found with Parse_error p -> p | e -> raise e : string * int)").is_ok());
+    assert!(f("(try raise Not_found with Parse_error p -> p | e -> raise e : int)").is_err());
+    assert!(f("Parse_error \"x\"").is_err());
+    assert!(f("raise 1").is_err());
+    assert!(f("try 1 with Not_found x -> x").is_err());
+    assert!(f("try 1 with Failure s -> s").is_err());
+    assert!(f("raise (Undefined_exn 1)").is_err());
 }
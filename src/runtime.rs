@@ -0,0 +1,212 @@
+extern crate libc;
+
+use std::ffi::{CStr, CString};
+use std::io::Write;
+use std::io;
+use std::process;
+use std::cell::{Cell, RefCell};
+
+use typing::{ExnDef, Type};
+use typing;
+
+// exceptions are implemented with setjmp/longjmp. every 'try' pushes its jmp_buf while its body
+// runs, and 'raise' jumps to the innermost one. an exception is a heap block { i64 tag, payload }
+
+// large enough for the jmp_buf of any supported platform
+pub const JMP_BUF_SIZE: u32 = 512;
+
+extern "C" {
+    fn longjmp(env: *mut libc::c_void, val: libc::c_int) -> !;
+}
+
+// the state is per thread, since a 'raise' must jump to a 'try' of its own thread, and the
+// threads may run different programs
+thread_local! {
+    static HANDLERS: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+    static CURRENT_EXN: Cell<usize> = Cell::new(0);
+    // the exceptions of the running program, indexed by tag. used to print an uncaught one
+    static EXNS: RefCell<Vec<ExnDef>> = RefCell::new(Vec::new());
+}
+
+pub fn set_exns(exns: Vec<ExnDef>) {
+    EXNS.with(|e| *e.borrow_mut() = exns)
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_push_handler(buf: *mut libc::c_void) -> i32 {
+    HANDLERS.with(|h| h.borrow_mut().push(buf as usize));
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_pop_handler() -> i32 {
+    HANDLERS.with(|h| h.borrow_mut().pop());
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_current_exn() -> *mut libc::c_void {
+    CURRENT_EXN.with(|e| e.get()) as *mut libc::c_void
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_raise(exn: *mut libc::c_void) -> ! {
+    CURRENT_EXN.with(|e| e.set(exn as usize));
+    // the borrow must end before jumping since longjmp skips destructors
+    let handler = HANDLERS.with(|h| h.borrow_mut().pop());
+    match handler {
+        Some(buf) => unsafe { longjmp(buf as *mut libc::c_void, 1) },
+        None => {
+            let _ = io::stdout().flush();
+            eprintln!("Fatal error: exception {}", unsafe { exn_to_string(exn as *const u8) });
+            process::exit(2)
+        }
+    }
+}
+
+// raises 'Invalid_argument msg'. msg must be a static C string
+#[no_mangle]
+pub extern "C" fn rcaml_invalid_argument(msg: *const libc::c_char) -> ! {
+    let tag = typing::builtin_exn_tag("Invalid_argument");
+    unsafe {
+        let exn = libc::malloc(16) as *mut u8;
+        *(exn as *mut i64) = tag as i64;
+        *(exn.offset(8) as *mut *const libc::c_char) = msg;
+        rcaml_raise(exn as *mut libc::c_void)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rcaml_division_by_zero() -> ! {
+    let tag = typing::builtin_exn_tag("Division_by_zero");
+    unsafe {
+        let exn = libc::malloc(8) as *mut i64;
+        *exn = tag as i64;
+        rcaml_raise(exn as *mut libc::c_void)
+    }
+}
+
+// an array is a heap block { i64 length, elements }. the elements start at offset 8 since no
+// element type is aligned to more than 8 bytes
+const ARRAY_HEADER: usize = 8;
+
+#[no_mangle]
+pub extern "C" fn rcaml_array_make(len: i64, elem_size: i64) -> *mut libc::c_void {
+    if len < 0 {
+        rcaml_invalid_argument(b"Array.make\0".as_ptr() as *const libc::c_char)
+    }
+    unsafe {
+        let ary = libc::malloc(ARRAY_HEADER + len as usize * elem_size as usize) as *mut u8;
+        *(ary as *mut i64) = len as i64;
+        ary as *mut libc::c_void
+    }
+}
+
+unsafe fn array_len(ary: *const u8) -> i64 {
+    *(ary as *const i64)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rcaml_array_blit(
+    src: *const u8,
+    src_pos: i64,
+    dst: *mut u8,
+    dst_pos: i64,
+    len: i64,
+    elem_size: i64,
+) -> i32 {
+    if len < 0 || src_pos < 0 || src_pos > array_len(src) - len || dst_pos < 0
+        || dst_pos > array_len(dst) - len
+    {
+        rcaml_invalid_argument(b"Array.blit\0".as_ptr() as *const libc::c_char)
+    }
+    let elem_size = elem_size as usize;
+    // the ranges may overlap when src and dst are the same array
+    ::std::ptr::copy(
+        src.offset((ARRAY_HEADER + src_pos as usize * elem_size) as isize),
+        dst.offset((ARRAY_HEADER + dst_pos as usize * elem_size) as isize),
+        len as usize * elem_size,
+    );
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rcaml_array_copy(ary: *const u8, elem_size: i64) -> *mut libc::c_void {
+    let len = array_len(ary);
+    let copy = rcaml_array_make(len, elem_size);
+    rcaml_array_blit(ary, 0, copy as *mut u8, 0, len, elem_size);
+    copy
+}
+
+// Int32.to_string and Int64.to_string. strings are never freed
+#[no_mangle]
+pub extern "C" fn rcaml_int64_to_string(i: i64) -> *mut libc::c_char {
+    CString::new(i.to_string()).unwrap().into_raw()
+}
+
+// (size, alignment) of a value of the type inside a heap block
+fn layout(ty: &Type) -> (usize, usize) {
+    match *ty {
+        Type::Char => (1, 1),
+        Type::Unit | Type::Bool | Type::Int32 => (4, 4),
+        Type::Tuple(ref ts) => {
+            let (mut size, mut align) = (0, 1);
+            for t in ts {
+                let (s, a) = layout(t);
+                size = (size + a - 1) / a * a + s;
+                align = ::std::cmp::max(align, a);
+            }
+            ((size + align - 1) / align * align, align)
+        }
+        _ => (8, 8),
+    }
+}
+
+// formats a value the way the OCaml toplevel prints exception arguments
+unsafe fn value_to_string(p: *const u8, ty: &Type) -> String {
+    match *ty {
+        Type::Int => (*(p as *const i64)).to_string(),
+        Type::Bool => (*(p as *const i32) != 0).to_string(),
+        Type::Float => {
+            let f = *(p as *const f64);
+            if f.is_finite() && f == f.trunc() {
+                format!("{}.", f)
+            } else {
+                f.to_string()
+            }
+        }
+        Type::String => {
+            let s = CStr::from_ptr(*(p as *const *const libc::c_char));
+            format!("{:?}", s.to_string_lossy())
+        }
+        _ => "_".to_string(),
+    }
+}
+
+// the name of the exception exn, e.g. 'Not_found', and how it's printed, e.g. 'Failure("x")'
+pub unsafe fn describe_exn(exn: *const u8) -> (String, String) {
+    let tag = *(exn as *const i64) as usize;
+    let name = EXNS.with(|exns| exns.borrow()[tag].name.clone());
+    (name, exn_to_string(exn))
+}
+
+unsafe fn exn_to_string(exn: *const u8) -> String {
+    let tag = *(exn as *const i64) as usize;
+    let exndef = EXNS.with(|exns| exns.borrow()[tag].clone());
+    let payload = exn.offset(8);
+    match exndef.arg {
+        None => exndef.name.clone(),
+        Some(Type::Tuple(ref ts)) => {
+            let mut offset = 0;
+            let mut args = vec![];
+            for t in ts {
+                let (size, align) = layout(t);
+                offset = (offset + align - 1) / align * align;
+                args.push(value_to_string(payload.offset(offset as isize), t));
+                offset += size;
+            }
+            format!("{}({})", exndef.name, args.join(", "))
+        }
+        Some(ref t) => format!("{}({})", exndef.name, value_to_string(payload, t)),
+    }
+}
@@ -0,0 +1,798 @@
+extern crate libc;
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+use alpha;
+use closure;
+use codegen;
+use id;
+use knormal;
+use mono;
+use node;
+use node::NodeKind;
+use optimize;
+use parser;
+use runtime;
+use typing;
+use typing::{Type, TypeScheme};
+
+// compiles and runs programs that may call functions of the host program:
+//
+//     let mut session = Session::new();
+//     session.register_fn("now_ms", |()| -> i32 { ... });
+//     session.run("print_string (Int32.to_string (now_ms ()))")
+pub struct Session {
+    // functions whose body size is at most this are inlined
+    pub inline_threshold: usize,
+    // 'a.(i)' raises Invalid_argument when i is out of bounds
+    pub bounds_check: bool,
+    // prints the LLVM module to stderr before running it
+    pub dump_module: bool,
+    // the builtins and registered host functions every compilation starts from
+    globals: typing::Globals,
+    host_fns: Vec<HostFunc>,
+}
+
+// a Rust closure and the trampoline through which generated code calls it
+struct HostFunc {
+    name: String,
+    ty: Type,
+    trampoline: *mut libc::c_void,
+    env: *mut libc::c_void,
+    // owns the closure env points to. the modules calling it share it
+    closure: Rc<dyn Any>,
+}
+
+// a Rust type that stands for an rcaml type. Repr is how generated code passes it (see
+// Type::to_llvmty_sub)
+pub trait Value: Sized {
+    type Repr: Copy;
+    fn ty() -> Type;
+    fn from_repr(repr: Self::Repr) -> Self;
+    fn to_repr(self) -> Self::Repr;
+}
+
+impl Value for () {
+    type Repr = i32;
+    fn ty() -> Type {
+        Type::Unit
+    }
+    fn from_repr(_: i32) -> Self {}
+    fn to_repr(self) -> i32 {
+        0
+    }
+}
+
+impl Value for bool {
+    type Repr = i32;
+    fn ty() -> Type {
+        Type::Bool
+    }
+    fn from_repr(repr: i32) -> Self {
+        repr != 0
+    }
+    fn to_repr(self) -> i32 {
+        self as i32
+    }
+}
+
+// an int is 63 bits, so a larger i64 wraps around
+impl Value for i64 {
+    type Repr = i64;
+    fn ty() -> Type {
+        Type::Int
+    }
+    fn from_repr(repr: i64) -> Self {
+        repr
+    }
+    fn to_repr(self) -> i64 {
+        node::wrap_int(self)
+    }
+}
+
+impl Value for i32 {
+    type Repr = i32;
+    fn ty() -> Type {
+        Type::Int32
+    }
+    fn from_repr(repr: i32) -> Self {
+        repr
+    }
+    fn to_repr(self) -> i32 {
+        self
+    }
+}
+
+impl Value for f64 {
+    type Repr = f64;
+    fn ty() -> Type {
+        Type::Float
+    }
+    fn from_repr(repr: f64) -> Self {
+        repr
+    }
+    fn to_repr(self) -> f64 {
+        self
+    }
+}
+
+// a string returned to rcaml is never freed, like the other strings it makes
+impl Value for String {
+    type Repr = *const libc::c_char;
+    fn ty() -> Type {
+        Type::String
+    }
+    fn from_repr(repr: *const libc::c_char) -> Self {
+        unsafe { CStr::from_ptr(repr) }.to_string_lossy().into_owned()
+    }
+    fn to_repr(self) -> *const libc::c_char {
+        CString::new(self).unwrap().into_raw()
+    }
+}
+
+// a Value passed in a register, so that it crosses the C ABI unchanged. tuples aren't, since
+// generated code passes them as LLVM structs
+pub trait Scalar: Value {}
+
+impl Scalar for () {}
+impl Scalar for bool {}
+impl Scalar for i64 {}
+impl Scalar for i32 {}
+impl Scalar for f64 {}
+impl Scalar for String {}
+
+// a tuple is laid out as the LLVM struct of its elements
+macro_rules! tuple_value {
+    ($repr:ident, $($a:ident $i:tt),*) => {
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        pub struct $repr<$($a),*>($(pub $a),*);
+
+        impl<$($a: Value),*> Value for ($($a,)*) {
+            type Repr = $repr<$($a::Repr),*>;
+            fn ty() -> Type {
+                Type::Tuple(vec![$($a::ty()),*])
+            }
+            fn from_repr(repr: Self::Repr) -> Self {
+                ($($a::from_repr(repr.$i),)*)
+            }
+            fn to_repr(self) -> Self::Repr {
+                $repr($(self.$i.to_repr()),*)
+            }
+        }
+    };
+}
+
+tuple_value!(Repr2, A 0, B 1);
+tuple_value!(Repr3, A 0, B 1, C 2);
+tuple_value!(Repr4, A 0, B 1, C 2, D 3);
+
+// a closure callable from rcaml. it takes its parameters as one value: '|x: i64|' is
+// 'int -> _', '|()|' is 'unit -> _' and '|(x, y): (i64, f64)|' is 'int -> float -> _'. Args
+// only tells the implementations apart
+pub trait HostFn<Args>: 'static {
+    fn ty() -> Type;
+    // an extern "C" function that takes a pointer to the closure followed by the parameters
+    fn trampoline() -> *mut libc::c_void;
+}
+
+extern "C" fn call1<F, A, R>(f: *const F, a: A::Repr) -> R::Repr
+where
+    F: Fn(A) -> R,
+    A: Scalar,
+    R: Scalar,
+{
+    let f = unsafe { &*f };
+    f(A::from_repr(a)).to_repr()
+}
+
+impl<F, A, R> HostFn<(A,)> for F
+where
+    F: Fn(A) -> R + 'static,
+    A: Scalar,
+    R: Scalar,
+{
+    fn ty() -> Type {
+        Type::Func(vec![A::ty()], Box::new(R::ty()))
+    }
+    fn trampoline() -> *mut libc::c_void {
+        call1::<F, A, R> as *mut libc::c_void
+    }
+}
+
+macro_rules! host_fn_tuple {
+    ($call:ident, $($a:ident),*) => {
+        #[allow(non_snake_case)]
+        extern "C" fn $call<F, $($a,)* R>(f: *const F, $($a: $a::Repr),*) -> R::Repr
+        where
+            F: Fn(($($a,)*)) -> R,
+            $($a: Scalar,)*
+            R: Scalar,
+        {
+            let f = unsafe { &*f };
+            f(($($a::from_repr($a),)*)).to_repr()
+        }
+
+        impl<F, $($a,)* R> HostFn<($($a,)*)> for F
+        where
+            F: Fn(($($a,)*)) -> R + 'static,
+            $($a: Scalar,)*
+            R: Scalar,
+        {
+            fn ty() -> Type {
+                Type::Func(vec![$($a::ty()),*], Box::new(R::ty()))
+            }
+            fn trampoline() -> *mut libc::c_void {
+                $call::<F, $($a,)* R> as *mut libc::c_void
+            }
+        }
+    };
+}
+
+host_fn_tuple!(call2, A, B);
+host_fn_tuple!(call3, A, B, C);
+host_fn_tuple!(call4, A, B, C, D);
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            inline_threshold: 0,
+            bounds_check: true,
+            dump_module: false,
+            globals: typing::Globals::new(),
+            host_fns: vec![],
+        }
+    }
+
+    // makes f callable from the programs this session runs. a panic in f aborts the process
+    pub fn register_fn<Args, F: HostFn<Args>>(&mut self, name: &str, f: F) {
+        let ty = F::ty();
+        self.globals
+            .values
+            .insert(name.to_string(), TypeScheme::new(vec![], ty.clone()));
+        self.globals.vars.remove(name);
+        let closure = Rc::new(f);
+        let env = &*closure as *const F as *mut libc::c_void;
+        self.host_fns.push(HostFunc {
+            name: name.to_string(),
+            ty: ty,
+            trampoline: F::trampoline(),
+            env: env,
+            closure: closure,
+        });
+    }
+
+    // compiles the program src and runs it
+    pub fn run(&mut self, src: &str) -> Result<(), Vec<parser::LocatedError>> {
+        let mut module = try!(self.compile(src));
+        module.run();
+        Ok(())
+    }
+
+    // compiles the program src without running it. the errors are in the order of the source
+    pub fn compile(&mut self, src: &str) -> Result<Module, Vec<parser::LocatedError>> {
+        let mut globals = self.globals.clone();
+        let mut idgen = id::IdGen::new();
+        let mut tyenv = HashMap::new();
+        let mut progs = Vec::new();
+        let mut polys = HashMap::new();
+        let (tokens, mut errors) = parser::tokens(src);
+        let lexical_errors = errors.iter().map(|err| (err.start, err.end)).collect::<Vec<_>>();
+        let (phrases, syntax_errors) = parser::phrases(&tokens);
+        errors.extend(syntax_errors);
+
+        // every phrase that parsed is typechecked, but a program with errors goes no further
+        for phrase in phrases {
+            let (node, span) = match phrase {
+                parser::Phrase::Item(node, span) => (node, span),
+                parser::Phrase::Broken(name) => {
+                    // uses of what a broken phrase defines aren't errors
+                    if let Some(name) = name {
+                        bind_any_type(&mut globals, name, &mut idgen)
+                    }
+                    continue;
+                }
+            };
+            // a bad literal is read as 0, so typing its phrase could only add false errors
+            if lexical_errors.iter().any(|&(start, end)| start < span.end && span.start < end) {
+                if let Some(name) = defined_name(&node) {
+                    bind_any_type(&mut globals, name, &mut idgen)
+                }
+                continue;
+            }
+            let uniquified = parser::uniquify(node, &mut idgen);
+            let infered = match typing::f(&uniquified, &mut tyenv, &mut idgen, &mut globals) {
+                Ok(infered) => infered,
+                Err(err) => {
+                    errors.push(parser::LocatedError {
+                        start: span.start,
+                        end: span.end,
+                        msg: err.to_string(),
+                    });
+                    if let Some(name) = defined_name(&uniquified) {
+                        bind_any_type(&mut globals, name, &mut idgen)
+                    }
+                    continue;
+                }
+            };
+            // the symbol is looked up now, so that a missing one is reported like a type error
+            if let NodeKind::External(ref name, _, ref symbol) = infered {
+                if !codegen::symbol_defined(symbol) {
+                    errors.push(parser::LocatedError {
+                        start: span.start,
+                        end: span.end,
+                        msg: format!("The symbol {} of external {} is undefined", symbol, name),
+                    });
+                }
+            }
+            if !errors.is_empty() {
+                continue;
+            }
+            // a definition hides the polymorphic function or value of the same name
+            let poly = match infered {
+                NodeKind::LetDef((ref name, ref ty), ref expr) => {
+                    polys.remove(name);
+                    if mono::is_poly(ty) && typing::is_nonexpansive(expr) {
+                        Some(name.clone())
+                    } else {
+                        None
+                    }
+                }
+                NodeKind::External(ref name, _, _) => {
+                    polys.remove(name);
+                    None
+                }
+                NodeKind::LetFuncDef(node::FuncDef { name: (ref name, ref ty), .. }, _) => {
+                    polys.remove(name);
+                    if mono::is_poly(ty) {
+                        Some(name.clone())
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            if let Some(name) = poly {
+                polys.insert(name, mono::PolyFun::new(infered));
+                continue;
+            }
+            let toplevel_fun = match uniquified {
+                NodeKind::LetFuncDef(_, _) => true,
+                _ => false,
+            };
+            let node =
+                self.specialize_uses(infered, &mut polys, &mut idgen, &mut globals, &mut progs);
+            progs.push(self.lower(node, toplevel_fun, &mut idgen, &globals));
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|err| err.start);
+            return Err(errors);
+        }
+
+        let mut codegen = codegen::CodeGen::new(&globals, self.bounds_check);
+        for host_fn in &self.host_fns {
+            // the module keeps the closure alive
+            unsafe {
+                codegen.add_host_fn(&host_fn.name, &host_fn.ty, host_fn.trampoline, host_fn.env)
+            };
+        }
+        codegen.gen(self.dump_module, false, progs).unwrap();
+        Ok(Module {
+            codegen: codegen,
+            ran: Cell::new(false),
+            _host_fns: self.host_fns.iter().map(|f| f.closure.clone()).collect(),
+        })
+    }
+
+    // renames the uses of polymorphic functions in node to their instances. the instances not
+    // compiled yet are compiled before node, and before them the ones they use
+    fn specialize_uses(
+        &self,
+        node: NodeKind,
+        polys: &mut HashMap<String, mono::PolyFun>,
+        idgen: &mut id::IdGen,
+        globals: &mut typing::Globals,
+        progs: &mut Vec<closure::Prog>,
+    ) -> NodeKind {
+        let mut found = vec![];
+        let node = mono::instances(node, polys, globals, &mut found);
+        for (name, ty) in found {
+            if !polys.get_mut(&name).unwrap().instances.insert(ty.clone()) {
+                continue;
+            }
+            globals.values.insert(
+                mono::instance_name(&name, &ty),
+                TypeScheme::new(vec![], ty.clone()),
+            );
+            let def = mono::specialize(&polys[&name].def, &ty, globals);
+            let toplevel_fun = match def {
+                NodeKind::LetFuncDef(_, _) => true,
+                _ => {
+                    globals.vars.insert(mono::instance_name(&name, &ty));
+                    false
+                }
+            };
+            let def = self.specialize_uses(def, polys, idgen, globals, progs);
+            progs.push(self.lower(def, toplevel_fun, idgen, globals));
+        }
+        node
+    }
+
+    // the typed phrase node compiled down to closures
+    fn lower(
+        &self,
+        node: NodeKind,
+        toplevel_fun: bool,
+        idgen: &mut id::IdGen,
+        globals: &typing::Globals,
+    ) -> closure::Prog {
+        let knormalized = if toplevel_fun {
+            alpha::toplevel_fun(knormal::f(node, idgen, globals), idgen)
+        } else {
+            alpha::f(knormal::f(node, idgen, globals), idgen)
+        };
+        let knormalized = optimize::f(knormalized, self.inline_threshold, idgen);
+        let closured = closure::f(knormalized, globals);
+        if let Err(msg) = closure::validate(&closured, globals) {
+            panic!(format!("invalid closure-converted program: {}", msg))
+        }
+        closured
+    }
+}
+
+// the value a 'let' phrase defines
+fn defined_name(node: &NodeKind) -> Option<String> {
+    match *node {
+        NodeKind::LetDef((ref name, _), _)
+        | NodeKind::LetFuncDef(node::FuncDef { name: (ref name, _), .. }, _) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+fn bind_any_type(globals: &mut typing::Globals, name: String, idgen: &mut id::IdGen) {
+    let ty = idgen.get_type();
+    globals.values.insert(name, TypeScheme::new(vec![ty.clone()], ty));
+}
+
+// a compiled program. its top-level expressions run by run, and its top-level functions can be
+// called through get_fn
+pub struct Module {
+    codegen: codegen::CodeGen,
+    // whether the top-level expressions have run, which also initializes the top-level values
+    ran: Cell<bool>,
+    _host_fns: Vec<Rc<dyn Any>>,
+}
+
+// the parameters of a function called from Rust: () for 'unit -> _', (x,) for one parameter and
+// (x, y, ...) for several
+pub trait Params {
+    type Repr: Copy;
+    fn tys() -> Vec<Type>;
+    fn to_repr(self) -> Self::Repr;
+}
+
+impl Params for () {
+    type Repr = Repr1<i32>;
+    fn tys() -> Vec<Type> {
+        vec![Type::Unit]
+    }
+    fn to_repr(self) -> Self::Repr {
+        Repr1(0)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Repr1<A>(pub A);
+
+impl<A: Value> Params for (A,) {
+    type Repr = Repr1<A::Repr>;
+    fn tys() -> Vec<Type> {
+        vec![A::ty()]
+    }
+    fn to_repr(self) -> Self::Repr {
+        Repr1(self.0.to_repr())
+    }
+}
+
+macro_rules! tuple_params {
+    ($($a:ident),*) => {
+        impl<$($a: Value),*> Params for ($($a,)*) {
+            type Repr = <($($a,)*) as Value>::Repr;
+            fn tys() -> Vec<Type> {
+                vec![$($a::ty()),*]
+            }
+            fn to_repr(self) -> Self::Repr {
+                Value::to_repr(self)
+            }
+        }
+    };
+}
+
+tuple_params!(A, B);
+tuple_params!(A, B, C);
+tuple_params!(A, B, C, D);
+
+// an exception a function called from Rust raised and didn't handle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exn {
+    // the constructor, e.g. 'Not_found'
+    pub name: String,
+    // the exception as an uncaught one is printed, e.g. 'Failure("empty")'
+    pub message: String,
+}
+
+impl fmt::Display for Exn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// a top-level function of a Module, called with Rust values
+pub struct Func<'m, P, R> {
+    entry: extern "C" fn(*const libc::c_void, *mut libc::c_void) -> *const u8,
+    module: &'m Module,
+    _marker: PhantomData<fn(P) -> R>,
+}
+
+impl<'m, P: Params, R: Value> Func<'m, P, R> {
+    pub fn call(&self, params: P) -> Result<R, Exn> {
+        runtime::set_exns(self.module.codegen.exns().to_vec());
+        let params = params.to_repr();
+        let mut ret = mem::MaybeUninit::<R::Repr>::uninit();
+        let exn = (self.entry)(
+            &params as *const P::Repr as *const libc::c_void,
+            ret.as_mut_ptr() as *mut libc::c_void,
+        );
+        if !exn.is_null() {
+            let (name, message) = unsafe { runtime::describe_exn(exn) };
+            return Err(Exn {
+                name: name,
+                message: message,
+            });
+        }
+        Ok(R::from_repr(unsafe { ret.assume_init() }))
+    }
+}
+
+impl Module {
+    // runs the top-level expressions, unless get_fn has run them already
+    pub fn run(&mut self) {
+        self.init()
+    }
+
+    // the functions may read the top-level values, so the top-level expressions that define them
+    // run once before any function is called
+    fn init(&self) {
+        if !self.ran.get() {
+            self.ran.set(true);
+            self.codegen.run_module()
+        }
+    }
+
+    // the top-level function name, if its type is the one P and R stand for
+    pub fn get_fn<P: Params, R: Value>(&self, name: &str) -> Result<Func<P, R>, String> {
+        let expected = Type::Func(P::tys(), Box::new(R::ty()));
+        match self.codegen.toplevel_fun_ty(name) {
+            Some(ref ty) if *ty == expected => (),
+            Some(ty) => {
+                return Err(format!(
+                    "'{}' has type {} but is used as {}",
+                    name,
+                    ty.to_string(),
+                    expected.to_string()
+                ))
+            }
+            None => return Err(format!("no top-level function '{}'", name)),
+        }
+        self.init();
+        let entry = self.codegen.gen_entry(name);
+        Ok(Func {
+            entry: unsafe { mem::transmute(entry) },
+            module: self,
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[test]
+pub fn test_register_fn() {
+    use std::sync::{Arc, Mutex};
+
+    assert_eq!(
+        <fn(i64) -> bool as HostFn<(i64,)>>::ty(),
+        Type::Func(vec![Type::Int], Box::new(Type::Bool))
+    );
+    assert_eq!(
+        <fn((f64, String)) -> () as HostFn<(f64, String)>>::ty(),
+        Type::Func(vec![Type::Float, Type::String], Box::new(Type::Unit))
+    );
+
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut session = Session::new();
+    let log_ = log.clone();
+    session.register_fn("test_record", move |(x, s): (i64, String)| {
+        log_.lock().unwrap().push(format!("{} {}", x, s))
+    });
+    session.register_fn("test_twice", |x: f64| x * 2.);
+    session.register_fn("test_now", |()| -> i32 { 42 });
+    session
+        .run(
+            "test_record (int_of_float (test_twice 1.5)) \"a\";
+             test_record (Int32.to_int (test_now ())) \"b\"",
+        )
+        .unwrap();
+    assert_eq!(*log.lock().unwrap(), vec!["3 a", "42 b"]);
+}
+
+#[test]
+pub fn test_get_fn() {
+    let mut session = Session::new();
+    let mut module = session
+        .compile(
+            "let add x y = x + y;;
+             let rec fact n = if n = 0 then 1 else n * fact (n - 1);;
+             let norm p = let (x, y) = p in sqrt (x *. x +. y *. y);;
+             let div_mod x y = (x / y, x mod y, float_of_int x /. float_of_int y);;
+             let twice_fact n = 2 * fact n;;
+             let answer (u : unit) = 42;;",
+        )
+        .unwrap();
+    assert_eq!(module.get_fn::<(i64, i64), i64>("add").unwrap().call((1, 2)).unwrap(), 3);
+    assert_eq!(module.get_fn::<(i64,), i64>("fact").unwrap().call((10,)).unwrap(), 3628800);
+    assert_eq!(
+        module.get_fn::<((f64, f64),), f64>("norm").unwrap().call(((3., 4.),)).unwrap(),
+        5.
+    );
+    assert_eq!(
+        module.get_fn::<(i64, i64), (i64, i64, f64)>("div_mod").unwrap().call((7, 2)).unwrap(),
+        (3, 1, 3.5)
+    );
+    assert_eq!(module.get_fn::<(i64,), i64>("twice_fact").unwrap().call((3,)).unwrap(), 12);
+    assert_eq!(module.get_fn::<(), i64>("answer").unwrap().call(()).unwrap(), 42);
+    let add = module.get_fn::<(i64, i64), i64>("add").unwrap();
+    assert_eq!(add.entry as usize, module.get_fn::<(i64, i64), i64>("add").unwrap().entry as usize);
+    assert_eq!(
+        module.get_fn::<(f64,), i64>("fact").err().unwrap(),
+        "'fact' has type (int -> int) but is used as (float -> int)"
+    );
+    assert!(module.get_fn::<(), i64>("nothing").is_err());
+    module.run();
+
+    // the top-level values are initialized before the first call, and only once
+    let mut module = session
+        .compile(
+            "let k = 10;;
+             let a = Array.make 3 1;;
+             let runs = ref 0;;
+             runs := !runs + 1;;
+             let addk x = x + k;;
+             let geta i = a.(i);;
+             let count_runs (u : unit) = !runs;;",
+        )
+        .unwrap();
+    assert_eq!(module.get_fn::<(i64,), i64>("addk").unwrap().call((1,)), Ok(11));
+    assert_eq!(module.get_fn::<(i64,), i64>("geta").unwrap().call((2,)), Ok(1));
+    module.run();
+    assert_eq!(module.get_fn::<(), i64>("count_runs").unwrap().call(()), Ok(1));
+}
+
+#[test]
+pub fn test_call_exn() {
+    let mut session = Session::new();
+    let module = session
+        .compile(
+            "exception Empty of string * int;;
+             let div x y = x / y;;
+             let nth i = let a = Array.make 2 i in a.(i);;
+             let find n = if n > 0 then n else raise Not_found;;
+             let check n = if n < 0 then raise (Empty (\"negative\", n)) else n;;
+             let safe n = try find n with Not_found -> 0;;",
+        )
+        .unwrap();
+    let div = module.get_fn::<(i64, i64), i64>("div").unwrap();
+    assert_eq!(div.call((7, 2)), Ok(3));
+    let exn = div.call((1, 0)).err().unwrap();
+    assert_eq!(exn.name, "Division_by_zero");
+    assert_eq!(exn.to_string(), "Division_by_zero");
+    let exn = module.get_fn::<(i64,), i64>("nth").unwrap().call((2,)).err().unwrap();
+    assert_eq!(exn.message, "Invalid_argument(\"index out of bounds\")");
+    let exn = module.get_fn::<(i64,), i64>("find").unwrap().call((0,)).err().unwrap();
+    assert_eq!(exn.name, "Not_found");
+    let exn = module.get_fn::<(i64,), i64>("check").unwrap().call((-3,)).err().unwrap();
+    assert_eq!(exn.name, "Empty");
+    assert_eq!(exn.message, "Empty(\"negative\", -3)");
+    // the handlers of a call that raised are gone, so the next calls work as usual
+    assert_eq!(module.get_fn::<(i64,), i64>("find").unwrap().call((5,)), Ok(5));
+    assert_eq!(module.get_fn::<(i64,), i64>("safe").unwrap().call((0,)), Ok(0));
+    assert_eq!(div.call((9, 3)), Ok(3));
+}
+
+#[test]
+pub fn test_threads() {
+    use std::sync::{Arc, Barrier};
+
+    // each thread raises its own exceptions while the others do
+    let barrier = Arc::new(Barrier::new(8));
+    let threads = (0..8)
+        .map(|i| {
+            let barrier = barrier.clone();
+            ::std::thread::spawn(move || {
+                let mut session = Session::new();
+                let src = format!(
+                    "exception Odd{} of int;;
+                     let check n = if n mod 2 = 1 then raise (Odd{} (n + {})) else n;;
+                     let f n = try check n with Odd{} m -> m;;",
+                    i, i, i, i
+                );
+                let module = session.compile(&src).unwrap();
+                let f = module.get_fn::<(i64,), i64>("f").unwrap();
+                let check = module.get_fn::<(i64,), i64>("check").unwrap();
+                let mut sum = 0;
+                barrier.wait();
+                for n in 0..20000 {
+                    sum += f.call((n,)).unwrap();
+                    if let Err(exn) = check.call((n,)) {
+                        assert_eq!(exn.message, format!("Odd{}({})", i, n + i));
+                    }
+                }
+                sum
+            })
+        })
+        .collect::<Vec<_>>();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), 199990000 + 10000 * i as i64);
+    }
+}
+
+#[test]
+pub fn test_external() {
+    let mut session = Session::new();
+    // getpid returns a C int, which is an int32
+    let module = session
+        .compile(
+            "external getpid : unit -> int32 = \"getpid\";;
+             let pid (u : unit) = getpid ();;",
+        )
+        .unwrap();
+    assert_eq!(
+        module.get_fn::<(), i32>("pid").unwrap().call(()).unwrap(),
+        ::std::process::id() as i32
+    );
+    let errors = session
+        .compile("external f : int -> int = \"rcaml_no_such_symbol\";;\nf 1")
+        .err()
+        .unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!((errors[0].start, errors[0].end), (0, 48));
+    assert_eq!(errors[0].msg, "The symbol rcaml_no_such_symbol of external f is undefined");
+}
+
+#[test]
+pub fn test_isolation() {
+    let mut session = Session::new();
+    session.register_fn("double", |x: i64| x * 2);
+    let other = Session::new();
+    assert!(session.globals.values.contains_key("double"));
+    assert!(!other.globals.values.contains_key("double"));
+
+    // what a program declares doesn't outlive its compilation
+    let module = session
+        .compile(
+            "exception Stop;;
+             type point = int * int;;
+             let quad x = double (double x);;",
+        )
+        .unwrap();
+    assert_eq!(module.get_fn::<(i64,), i64>("quad").unwrap().call((3,)).unwrap(), 12);
+    assert!(!session.globals.values.contains_key("quad"));
+    assert!(!session.globals.tycons.contains_key("point"));
+    assert_eq!(session.globals.exn_tag("Stop"), None);
+}
@@ -1,64 +1,190 @@
 use std::boxed::Box;
 use std::collections::{HashMap, HashSet};
 
-use node::{FuncDef, NodeKind};
+use node::{FuncDef, Handler, NodeKind};
 use id;
 
-use parser::EXTENV;
+use builtin;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     Unit,
     Bool,
-    Int,
+    Int, // 63 bits as in OCaml
+    Int32,
+    Int64,
     Float,
     Char,
+    String,
+    Exn,
     Tuple(Vec<Type>),
     Array(Box<Type>),
+    Ref(Box<Type>),
     Func(Vec<Type>, Box<Type>), // (param types, return type, is type inference complete?)
     Var(usize),                 // id
+    Param(String),              // 'a written in the source. replaced by a Var before typing
+    Con(String, Vec<Type>),     // a declared type applied to its arguments, e.g. 'int pair'
+}
+
+// a type constructor declared by 'type (params) name = body'. the body refers to the
+// parameters as Type::Param
+#[derive(Debug, Clone)]
+pub struct TyCon {
+    pub params: Vec<String>,
+    pub body: Type,
+}
+
+// an exception constructor
+#[derive(Debug, Clone)]
+pub struct ExnDef {
+    pub name: String,
+    pub arg: Option<Type>,
+}
+
+fn builtin_exns() -> Vec<ExnDef> {
+    vec![
+        ("Not_found", None),
+        ("Failure", Some(Type::String)),
+        ("Invalid_argument", Some(Type::String)),
+        ("Division_by_zero", None),
+    ].into_iter()
+        .map(|(name, arg)| ExnDef {
+            name: name.to_string(),
+            arg: arg,
+        })
+        .collect()
+}
+
+// a C function declared by 'external'. its parameters and return value are passed as C scalars
+// (see CodeGen::gen_external): int is a long, so a C int is declared as int32, and int64 is an
+// int64_t, float a double, string a char *, bool an int and unit no value at all
+#[derive(Debug, Clone)]
+pub struct External {
+    pub symbol: String,
+    pub ty: Type,
+}
+
+// the tag of a builtin exception, which is the same in every program
+pub fn builtin_exn_tag(name: &str) -> usize {
+    builtin_exns().iter().position(|exn| exn.name == name).unwrap()
+}
+
+// what the module items of a program declare for the following ones. a Session owns one per
+// compilation, so compilations don't see each other's definitions
+#[derive(Clone)]
+pub struct Globals {
+    // the builtins, host functions, externals and top-level definitions
+    pub values: HashMap<String, TypeScheme>,
+    // the values in values bound by a top-level 'let x = e'. they live in global variables,
+    // whereas the others are functions called directly
+    pub vars: HashSet<String>,
+    // type variables left ungeneralized by the value restriction. they are printed as '_a and
+    // get fixed by the first use that unifies them with a concrete type
+    pub weakvars: HashSet<usize>,
+    pub tycons: HashMap<String, TyCon>,
+    // an exception's index is the tag stored in its values
+    pub exns: Vec<ExnDef>,
+    pub externals: HashMap<String, External>,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Globals {
+            values: builtin::all()
+                .into_iter()
+                .map(|b| (b.name, b.scheme))
+                .collect(),
+            vars: HashSet::new(),
+            weakvars: HashSet::new(),
+            tycons: HashMap::new(),
+            exns: builtin_exns(),
+            externals: HashMap::new(),
+        }
+    }
+
+    // the tag of the latest declaration of an exception
+    pub fn exn_tag(&self, name: &str) -> Option<usize> {
+        self.exns.iter().rposition(|exn| exn.name == name)
+    }
 }
 
 impl Type {
     pub fn to_string(&self) -> String {
-        self.to_string_sub(&mut 0, &mut HashMap::new())
+        self.to_string_sub(&mut 0, &mut HashMap::new(), &HashSet::new())
     }
 
-    pub fn to_string_sub(&self, i: &mut usize, m: &mut HashMap<usize, usize>) -> String {
+    // tells the weak type variables of globals apart
+    pub fn to_string_in(&self, globals: &Globals) -> String {
+        self.to_string_sub(&mut 0, &mut HashMap::new(), &globals.weakvars)
+    }
+
+    pub fn to_string_sub(
+        &self,
+        i: &mut usize,
+        m: &mut HashMap<usize, usize>,
+        weak: &HashSet<usize>,
+    ) -> String {
+        macro_rules! name { ($id:expr) => ({
+            let n = m.entry($id).or_insert_with(|| { *i += 1; *i }).clone();
+            if weak.contains(&$id) {
+                format!("\'_{}", (b'a' + ((n - 1) % 26) as u8) as char)
+            } else {
+                format!("\'{}", n)
+            }
+        }) };
         match self {
             &Type::Unit => "unit".to_string(),
             &Type::Bool => "bool".to_string(),
             &Type::Char => "char".to_string(),
             &Type::Int => "int".to_string(),
+            &Type::Int32 => "int32".to_string(),
+            &Type::Int64 => "int64".to_string(),
             &Type::Float => "float".to_string(),
+            &Type::String => "string".to_string(),
+            &Type::Exn => "exn".to_string(),
             &Type::Tuple(ref et) => format!(
                 "({})",
                 et.into_iter()
                     .fold(
                         "".to_string(),
-                        |acc, t| acc + t.to_string_sub(i, m).as_str() + " * "
+                        |acc, t| acc + t.to_string_sub(i, m, weak).as_str() + " * "
                     )
                     .trim_right_matches(" * ")
             ),
-            &Type::Array(ref et) => format!("[{}]", et.to_string_sub(i, m)),
+            &Type::Array(ref et) => format!("[{}]", et.to_string_sub(i, m, weak)),
+            &Type::Ref(ref et) => format!("{} ref", et.to_string_sub(i, m, weak)),
             &Type::Func(ref param_tys, ref ret_ty) => {
-                macro_rules! name { ($id:expr) => ( format!("\'{}", m.entry($id).or_insert_with(|| { *i += 1; *i }).clone()) ) };
                 format!(
                     "({})",
                     param_tys
                         .into_iter()
                         .fold("".to_string(), |acc, ts| acc + match *ts {
                             Type::Var(id) => name!(id),
-                            _ => ts.to_string_sub(i, m),
+                            _ => ts.to_string_sub(i, m, weak),
                         }.as_str()
                             + " -> ") + if let Type::Var(id) = **ret_ty {
                         name!(id)
                     } else {
-                        ret_ty.to_string_sub(i, m)
+                        ret_ty.to_string_sub(i, m, weak)
                     }.as_str()
                 )
             }
+            &Type::Var(id) if weak.contains(&id) => name!(id),
             &Type::Var(id) => format!("var({})", id),
+            &Type::Param(ref name) => format!("\'{}", name),
+            &Type::Con(ref name, ref args) => {
+                let mut args = args.iter()
+                    .map(|t| match *t {
+                        Type::Var(id) => name!(id),
+                        _ => t.to_string_sub(i, m, weak),
+                    })
+                    .collect::<Vec<_>>();
+                match args.len() {
+                    0 => name.clone(),
+                    1 => format!("{} {}", args.pop().unwrap(), name),
+                    _ => format!("({}) {}", args.join(", "), name),
+                }
+            }
         }
     }
 }
@@ -88,6 +214,173 @@ impl fmt::Debug for Type {
 #[derive(Debug)]
 pub enum TypeError {
     Unify(Type, Type),
+    UnboundType(String),
+    UnboundTypeParam(String),
+    TypeArity(String, usize, usize), // name, expected, given
+    UnboundExn(String),
+    ExnArity(String), // the constructor is applied to the wrong number of arguments
+    External(String), // the type of the external isn't a function on C scalars
+    UnboundValue(String),
+}
+
+// the messages follow OCaml's, without the location
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TypeError::Unify(ref t1, ref t2) => {
+                // the variables of both types are numbered together
+                let (mut i, mut m, weak) = (0, HashMap::new(), HashSet::new());
+                write!(
+                    f,
+                    "Type {} is not compatible with type {}",
+                    t1.to_string_sub(&mut i, &mut m, &weak),
+                    t2.to_string_sub(&mut i, &mut m, &weak)
+                )
+            }
+            TypeError::UnboundType(ref name) => write!(f, "Unbound type constructor {}", name),
+            TypeError::UnboundTypeParam(ref name) => write!(
+                f,
+                "The type variable '{} is unbound in this type declaration",
+                name
+            ),
+            TypeError::TypeArity(ref name, expected, given) => write!(
+                f,
+                "The type constructor {} expects {} argument(s), but is here applied to {} \
+                 argument(s)",
+                name, expected, given
+            ),
+            TypeError::UnboundExn(ref name) => write!(f, "Unbound constructor {}", name),
+            TypeError::ExnArity(ref name) => write!(
+                f,
+                "The constructor {} is applied to the wrong number of arguments",
+                name
+            ),
+            TypeError::External(ref name) => write!(
+                f,
+                "The type of external {} is not a function on C scalars",
+                name
+            ),
+            TypeError::UnboundValue(ref name) => write!(f, "Unbound value {}", name),
+        }
+    }
+}
+
+fn subst_params(ty: &Type, args: &HashMap<String, Type>) -> Type {
+    macro_rules! subst_params_seq {
+        ($seq:expr) => ($seq.iter().map(|t| subst_params(t, args)).collect::<Vec<_>>());
+    }
+    match *ty {
+        Type::Param(ref name) => args[name].clone(),
+        Type::Func(ref p, ref r) => {
+            Type::Func(subst_params_seq!(p), Box::new(subst_params(r, args)))
+        }
+        Type::Tuple(ref ts) => Type::Tuple(subst_params_seq!(ts)),
+        Type::Array(ref t) => Type::Array(Box::new(subst_params(t, args))),
+        Type::Ref(ref t) => Type::Ref(Box::new(subst_params(t, args))),
+        Type::Con(ref name, ref ts) => Type::Con(name.clone(), subst_params_seq!(ts)),
+        _ => ty.clone(),
+    }
+}
+
+// expands the abbreviation at the head of ty until it is not a Con
+pub fn expand(ty: &Type, globals: &Globals) -> Result<Type, TypeError> {
+    if let Type::Con(ref name, ref args) = *ty {
+        let tycon = match globals.tycons.get(name).cloned() {
+            Some(tycon) => tycon,
+            None => return Err(TypeError::UnboundType(name.clone())),
+        };
+        if tycon.params.len() != args.len() {
+            return Err(TypeError::TypeArity(
+                name.clone(),
+                tycon.params.len(),
+                args.len(),
+            ));
+        }
+        let args = tycon.params.into_iter().zip(args.iter().cloned()).collect();
+        expand(&subst_params(&tycon.body, &args), globals)
+    } else {
+        Ok(ty.clone())
+    }
+}
+
+// expands every abbreviation in ty. the later phases only see structural types
+pub fn expand_all(ty: &Type, globals: &Globals) -> Type {
+    macro_rules! expand_all_seq {
+        ($seq:expr) => ($seq.iter().map(|t| expand_all(t, globals)).collect::<Vec<_>>());
+    }
+    match *ty {
+        Type::Func(ref p, ref r) => Type::Func(expand_all_seq!(p), Box::new(expand_all(r, globals))),
+        Type::Tuple(ref ts) => Type::Tuple(expand_all_seq!(ts)),
+        Type::Array(ref t) => Type::Array(Box::new(expand_all(t, globals))),
+        Type::Ref(ref t) => Type::Ref(Box::new(expand_all(t, globals))),
+        Type::Con(_, _) => match expand(ty, globals) {
+            Ok(t) => expand_all(&t, globals),
+            Err(_) => ty.clone(),
+        },
+        _ => ty.clone(),
+    }
+}
+
+// checks that every constructor in ty is declared with the right arity and that every
+// parameter is one of params
+fn check_type(ty: &Type, params: &Vec<String>, globals: &Globals) -> Result<(), TypeError> {
+    match *ty {
+        Type::Param(ref name) if !params.contains(name) => {
+            Err(TypeError::UnboundTypeParam(name.clone()))
+        }
+        Type::Func(ref ts, ref t) => {
+            for t in ts {
+                try!(check_type(t, params, globals));
+            }
+            check_type(t, params, globals)
+        }
+        Type::Tuple(ref ts) => {
+            for t in ts {
+                try!(check_type(t, params, globals));
+            }
+            Ok(())
+        }
+        Type::Array(ref t) | Type::Ref(ref t) => check_type(t, params, globals),
+        Type::Con(_, ref ts) => {
+            for t in ts {
+                try!(check_type(t, params, globals));
+            }
+            expand(ty, globals).map(|_| ())
+        }
+        _ => Ok(()),
+    }
+}
+
+// the type variables of an annotation are fresh ones by now (see parser::uniquify), so only its
+// constructors are checked
+fn check_annot(ty: &Type, globals: &Globals) -> Result<(), TypeError> {
+    check_type(ty, &vec![], globals)
+}
+
+fn check_funcdef_annots(funcdef: &FuncDef, globals: &Globals) -> Result<(), TypeError> {
+    try!(check_annot(&funcdef.name.1, globals));
+    for &(_, ref t) in &funcdef.params {
+        try!(check_annot(t, globals));
+    }
+    Ok(())
+}
+
+// the body may only refer to constructors declared before, so abbreviations can't be cyclic
+pub fn declare_type(
+    name: &str,
+    params: &Vec<String>,
+    body: &Type,
+    globals: &mut Globals,
+) -> Result<(), TypeError> {
+    try!(check_type(body, params, globals));
+    globals.tycons.insert(
+        name.to_string(),
+        TyCon {
+            params: params.clone(),
+            body: body.clone(),
+        },
+    );
+    Ok(())
 }
 
 fn deref_ty(ty: &Type, tyenv: &HashMap<usize, Type>) -> Type {
@@ -99,6 +392,8 @@ fn deref_ty(ty: &Type, tyenv: &HashMap<usize, Type>) -> Type {
         Type::Func(ref p, ref r) => Type::Func(deref_ty_seq!(p), Box::new(deref_ty(r, tyenv))),
         Type::Tuple(ref ts) => Type::Tuple(deref_ty_seq!(ts)),
         Type::Array(ref t) => Type::Array(Box::new(deref_ty(t, tyenv))),
+        Type::Ref(ref t) => Type::Ref(Box::new(deref_ty(t, tyenv))),
+        Type::Con(ref name, ref ts) => Type::Con(name.clone(), deref_ty_seq!(ts)),
         Type::Var(ref n) => {
             if let Some(t) = tyenv.get(n) {
                 deref_ty(t, tyenv)
@@ -111,100 +406,161 @@ fn deref_ty(ty: &Type, tyenv: &HashMap<usize, Type>) -> Type {
     }
 }
 
-fn deref_term(node: &NodeKind, tyenv: &mut HashMap<usize, Type>) -> NodeKind {
+// the type annotations left in the term for the later phases, without abbreviations
+fn deref_expand(ty: &Type, tyenv: &HashMap<usize, Type>, globals: &Globals) -> Type {
+    expand_all(&deref_ty(ty, tyenv), globals)
+}
+
+fn deref_term(
+    node: &NodeKind,
+    tyenv: &mut HashMap<usize, Type>,
+    globals: &Globals,
+) -> NodeKind {
     macro_rules! deref_seq {
-        ($ary:expr) => ($ary.iter().map(|x| deref_term(x, tyenv)).collect::<Vec<_>>());
+        ($ary:expr) => ($ary.iter().map(|x| deref_term(x, tyenv, globals)).collect::<Vec<_>>());
     }
     match *node {
+        NodeKind::IntUnaryOp(ref op, ref e) => {
+            NodeKind::IntUnaryOp(op.clone(), Box::new(deref_term(&**e, tyenv, globals)))
+        }
+        NodeKind::FloatUnaryOp(ref op, ref e) => {
+            NodeKind::FloatUnaryOp(op.clone(), Box::new(deref_term(&**e, tyenv, globals)))
+        }
         NodeKind::IntBinaryOp(ref op, ref lhs, ref rhs) => NodeKind::IntBinaryOp(
             op.clone(),
-            Box::new(deref_term(&**lhs, tyenv)),
-            Box::new(deref_term(&**rhs, tyenv)),
+            Box::new(deref_term(&**lhs, tyenv, globals)),
+            Box::new(deref_term(&**rhs, tyenv, globals)),
         ),
         NodeKind::FloatBinaryOp(ref op, ref lhs, ref rhs) => NodeKind::FloatBinaryOp(
             op.clone(),
-            Box::new(deref_term(&**lhs, tyenv)),
-            Box::new(deref_term(&**rhs, tyenv)),
+            Box::new(deref_term(&**lhs, tyenv, globals)),
+            Box::new(deref_term(&**rhs, tyenv, globals)),
         ),
         NodeKind::CompBinaryOp(ref op, ref lhs, ref rhs) => NodeKind::CompBinaryOp(
             op.clone(),
-            Box::new(deref_term(&**lhs, tyenv)),
-            Box::new(deref_term(&**rhs, tyenv)),
+            Box::new(deref_term(&**lhs, tyenv, globals)),
+            Box::new(deref_term(&**rhs, tyenv, globals)),
         ),
         NodeKind::Tuple(ref es) => NodeKind::Tuple(deref_seq!(es)),
         NodeKind::Call(ref e, ref args) => {
-            NodeKind::Call(Box::new(deref_term(e, tyenv)), deref_seq!(args))
+            NodeKind::Call(Box::new(deref_term(e, tyenv, globals)), deref_seq!(args))
         }
         NodeKind::LetExpr((ref name, ref ty), ref expr, ref body) => NodeKind::LetExpr(
-            (name.clone(), deref_ty(ty, tyenv)),
-            Box::new(deref_term(&**expr, tyenv)),
-            Box::new(deref_term(&**body, tyenv)),
+            (name.clone(), deref_expand(ty, tyenv, globals)),
+            Box::new(deref_term(&**expr, tyenv, globals)),
+            Box::new(deref_term(&**body, tyenv, globals)),
         ),
         NodeKind::LetFuncExpr(ref funcdef, ref expr, ref body) => {
             let (ref name, ref ty) = funcdef.name;
             let params = &funcdef.params;
             NodeKind::LetFuncExpr(
                 FuncDef {
-                    name: (name.to_string(), deref_ty(ty, tyenv)),
+                    name: (name.to_string(), deref_expand(ty, tyenv, globals)),
                     params: params
                         .iter()
-                        .map(|&(ref x, ref t)| (x.clone(), deref_ty(t, tyenv)))
+                        .map(|&(ref x, ref t)| (x.clone(), deref_expand(t, tyenv, globals)))
                         .collect::<Vec<_>>(),
                 },
-                Box::new(deref_term(expr, tyenv)),
-                Box::new(deref_term(body, tyenv)),
+                Box::new(deref_term(expr, tyenv, globals)),
+                Box::new(deref_term(body, tyenv, globals)),
             )
         }
         NodeKind::LetTupleExpr(ref es, ref expr, ref body) => {
             let es = es.iter()
-                .map(|&(ref e, ref t)| (e.clone(), deref_ty(t, tyenv)))
+                .map(|&(ref e, ref t)| (e.clone(), deref_expand(t, tyenv, globals)))
                 .collect::<Vec<_>>();
             NodeKind::LetTupleExpr(
                 es,
-                Box::new(deref_term(expr, tyenv)),
-                Box::new(deref_term(body, tyenv)),
+                Box::new(deref_term(expr, tyenv, globals)),
+                Box::new(deref_term(body, tyenv, globals)),
             )
         }
         NodeKind::LetDef((ref name, ref ty), ref expr) => NodeKind::LetDef(
-            (name.clone(), deref_ty(ty, tyenv)),
-            Box::new(deref_term(&**expr, tyenv)),
+            (name.clone(), deref_expand(ty, tyenv, globals)),
+            Box::new(deref_term(&**expr, tyenv, globals)),
         ),
         NodeKind::LetFuncDef(ref funcdef, ref expr) => {
             let (ref name, ref ty) = funcdef.name;
             let params = &funcdef.params;
             NodeKind::LetFuncDef(
                 FuncDef {
-                    name: (name.to_string(), deref_ty(ty, tyenv)),
+                    name: (name.to_string(), deref_expand(ty, tyenv, globals)),
                     params: params
                         .iter()
-                        .map(|&(ref x, ref t)| (x.clone(), deref_ty(t, tyenv)))
+                        .map(|&(ref x, ref t)| (x.clone(), deref_expand(t, tyenv, globals)))
                         .collect::<Vec<_>>(),
                 },
-                Box::new(deref_term(expr, tyenv)),
+                Box::new(deref_term(expr, tyenv, globals)),
             )
         }
         NodeKind::IfExpr(ref cond, ref then_, ref else_) => NodeKind::IfExpr(
-            Box::new(deref_term(cond, tyenv)),
-            Box::new(deref_term(then_, tyenv)),
-            Box::new(deref_term(else_, tyenv)),
+            Box::new(deref_term(cond, tyenv, globals)),
+            Box::new(deref_term(then_, tyenv, globals)),
+            Box::new(deref_term(else_, tyenv, globals)),
         ),
         NodeKind::MakeArray(ref e1, ref e2) => NodeKind::MakeArray(
-            Box::new(deref_term(e1, tyenv)),
-            Box::new(deref_term(e2, tyenv)),
+            Box::new(deref_term(e1, tyenv, globals)),
+            Box::new(deref_term(e2, tyenv, globals)),
         ),
         NodeKind::Get(ref e1, ref e2) => NodeKind::Get(
-            Box::new(deref_term(e1, tyenv)),
-            Box::new(deref_term(e2, tyenv)),
+            Box::new(deref_term(e1, tyenv, globals)),
+            Box::new(deref_term(e2, tyenv, globals)),
         ),
         NodeKind::Put(ref e1, ref e2, ref e3) => NodeKind::Put(
-            Box::new(deref_term(e1, tyenv)),
-            Box::new(deref_term(e2, tyenv)),
-            Box::new(deref_term(e3, tyenv)),
+            Box::new(deref_term(e1, tyenv, globals)),
+            Box::new(deref_term(e2, tyenv, globals)),
+            Box::new(deref_term(e3, tyenv, globals)),
+        ),
+        NodeKind::MakeRef(ref e) => NodeKind::MakeRef(Box::new(deref_term(e, tyenv, globals))),
+        NodeKind::Deref(ref e) => NodeKind::Deref(Box::new(deref_term(e, tyenv, globals))),
+        NodeKind::Assign(ref e1, ref e2) => NodeKind::Assign(
+            Box::new(deref_term(e1, tyenv, globals)),
+            Box::new(deref_term(e2, tyenv, globals)),
+        ),
+        NodeKind::While(ref cond, ref body) => NodeKind::While(
+            Box::new(deref_term(cond, tyenv, globals)),
+            Box::new(deref_term(body, tyenv, globals)),
+        ),
+        NodeKind::For(ref var, ref from, ref to, ref dir, ref body) => NodeKind::For(
+            var.clone(),
+            Box::new(deref_term(from, tyenv, globals)),
+            Box::new(deref_term(to, tyenv, globals)),
+            dir.clone(),
+            Box::new(deref_term(body, tyenv, globals)),
+        ),
+        NodeKind::Annot(ref e, ref ty) => {
+            NodeKind::Annot(Box::new(deref_term(e, tyenv, globals)), deref_expand(ty, tyenv, globals))
+        }
+        NodeKind::Constr(ref name, ref arg) => NodeKind::Constr(
+            name.clone(),
+            arg.as_ref().map(|e| Box::new(deref_term(e, tyenv, globals))),
+        ),
+        NodeKind::Raise(ref e, ref ty) => {
+            NodeKind::Raise(Box::new(deref_term(e, tyenv, globals)), deref_expand(ty, tyenv, globals))
+        }
+        NodeKind::TryWith(ref body, ref handlers) => NodeKind::TryWith(
+            Box::new(deref_term(body, tyenv, globals)),
+            handlers
+                .iter()
+                .map(|handler| Handler {
+                    constr: handler.constr.clone(),
+                    var: handler
+                        .var
+                        .as_ref()
+                        .map(|&(ref x, ref t)| (x.clone(), deref_expand(t, tyenv, globals))),
+                    body: Box::new(deref_term(&handler.body, tyenv, globals)),
+                })
+                .collect(),
         ),
         _ => node.clone(),
     }
 }
 
+// the term with the type variables of subst replaced
+pub fn subst_term(node: &NodeKind, subst: &HashMap<usize, Type>, globals: &Globals) -> NodeKind {
+    deref_term(node, &mut subst.clone(), globals)
+}
+
 fn occur(r1: usize, ty: &Type) -> bool {
     macro_rules! occur_list {
         ($ls:expr) => ($ls.iter().any(|ty| occur(r1, ty)))
@@ -212,51 +568,87 @@ fn occur(r1: usize, ty: &Type) -> bool {
     match *ty {
         Type::Func(ref t2s, ref t2) => occur_list!(t2s) || occur(r1, t2),
         Type::Tuple(ref t2s) => occur_list!(t2s),
-        Type::Array(ref t2) => occur(r1, t2),
+        Type::Array(ref t2) | Type::Ref(ref t2) => occur(r1, t2),
+        Type::Con(_, ref t2s) => occur_list!(t2s),
         Type::Var(r2) => r1 == r2,
         _ => false,
     }
 }
 
-pub fn unify(t1: &Type, t2: &Type, tyenv: &mut HashMap<usize, Type>) -> Result<(), TypeError> {
+pub fn unify(
+    t1: &Type,
+    t2: &Type,
+    tyenv: &mut HashMap<usize, Type>,
+    globals: &mut Globals,
+) -> Result<(), TypeError> {
     match (t1, t2) {
         (&Type::Unit, &Type::Unit) => Ok(()),
         (&Type::Bool, &Type::Bool) => Ok(()),
         (&Type::Char, &Type::Char) => Ok(()),
         (&Type::Int, &Type::Int) => Ok(()),
+        (&Type::Int32, &Type::Int32) => Ok(()),
+        (&Type::Int64, &Type::Int64) => Ok(()),
         (&Type::Float, &Type::Float) => Ok(()),
+        (&Type::String, &Type::String) => Ok(()),
+        (&Type::Exn, &Type::Exn) => Ok(()),
         (&Type::Func(ref t1p, ref t1r), &Type::Func(ref t2p, ref t2r)) => {
             if t1p.len() != t2p.len() {
                 return Err(TypeError::Unify(t1.clone(), t2.clone()));
             }
             for (a, b) in t1p.iter().zip(t2p.iter()) {
-                try!(unify(a, b, tyenv));
+                try!(unify(a, b, tyenv, globals));
             }
-            unify(t1r, t2r, tyenv)
+            unify(t1r, t2r, tyenv, globals)
         }
-        (&Type::Array(ref t1), &Type::Array(ref t2)) => unify(t1, t2, tyenv),
+        (&Type::Array(ref t1), &Type::Array(ref t2)) => unify(t1, t2, tyenv, globals),
+        (&Type::Ref(ref t1), &Type::Ref(ref t2)) => unify(t1, t2, tyenv, globals),
         (&Type::Tuple(ref t1e), &Type::Tuple(ref t2e)) => {
             if t1e.len() != t2e.len() {
                 return Err(TypeError::Unify(t1.clone(), t2.clone()));
             }
             for (a, b) in t1e.iter().zip(t2e.iter()) {
-                try!(unify(a, b, tyenv));
+                try!(unify(a, b, tyenv, globals));
             }
             Ok(())
         }
         (&Type::Var(i1), &Type::Var(i2)) if i1 == i2 => Ok(()),
         (&Type::Var(ref i1), _) => {
             if let Some(t1sub) = tyenv.get(i1).cloned() {
-                unify(&t1sub, t2, tyenv)
+                unify(&t1sub, t2, tyenv, globals)
+            } else if let Some(t2sub) = var_n(t2).and_then(|i2| tyenv.get(&i2).cloned()) {
+                // binding i1 to a bound variable could make a cycle
+                unify(t1, &t2sub, tyenv, globals)
             } else {
                 if occur(*i1, t2) {
                     return Err(TypeError::Unify(t1.clone(), t2.clone()));
                 }
+                // whatever a weak variable is bound to can't be generalized either
+                if globals.weakvars.contains(i1) {
+                    let mut vars = vec![];
+                    unwrap_var(deref_ty(t2, tyenv), tyenv, &mut vars);
+                    globals.weakvars
+                        .extend(vars.iter().filter_map(var_n));
+                }
                 tyenv.insert(*i1, t2.clone());
                 Ok(())
             }
         }
-        (_, &Type::Var(_)) => unify(t2, t1, tyenv),
+        (_, &Type::Var(_)) => unify(t2, t1, tyenv, globals),
+        // keep the abbreviation when the arguments agree, so that it shows up in printed types
+        (&Type::Con(ref n1, ref a1), &Type::Con(ref n2, ref a2)) if n1 == n2 => {
+            let saved = tyenv.clone();
+            if a1.len() == a2.len()
+                && a1.iter()
+                    .zip(a2.iter())
+                    .all(|(a, b)| unify(a, b, tyenv, globals).is_ok())
+            {
+                return Ok(());
+            }
+            *tyenv = saved;
+            unify(&try!(expand(t1, globals)), &try!(expand(t2, globals)), tyenv, globals)
+        }
+        (&Type::Con(_, _), _) => unify(&try!(expand(t1, globals)), t2, tyenv, globals),
+        (_, &Type::Con(_, _)) => unify(t1, &try!(expand(t2, globals)), tyenv, globals),
         // TODO: implement more types
         _ => Err(TypeError::Unify(t1.clone(), t2.clone())),
     }
@@ -271,10 +663,21 @@ fn subst(ty: Type, tyenv: &mut HashMap<usize, Type>, map: HashMap<usize, Type>)
         });
     }
     match ty {
-        Type::Unit | Type::Bool | Type::Int | Type::Float | Type::Char => ty,
+        Type::Unit
+        | Type::Bool
+        | Type::Int
+        | Type::Int32
+        | Type::Int64
+        | Type::Float
+        | Type::Char
+        | Type::String
+        | Type::Exn
+        | Type::Param(_) => ty,
         Type::Func(params, ret) => Type::Func(seq!(params), Box::new(subst(*ret, tyenv, map))),
         Type::Array(et) => Type::Array(Box::new(subst(*et, tyenv, map))),
+        Type::Ref(et) => Type::Ref(Box::new(subst(*et, tyenv, map))),
         Type::Tuple(es) => Type::Tuple(seq!(es)),
+        Type::Con(name, es) => Type::Con(name, seq!(es)),
         Type::Var(id) => {
             if let Some(t) = map.get(&id).cloned() {
                 t
@@ -312,17 +715,55 @@ fn unwrap_var(ty: Type, tyenv: &mut HashMap<usize, Type>, freevars: &mut Vec<Typ
     }
 
     match ty {
-        Type::Unit | Type::Bool | Type::Int | Type::Float | Type::Char => (),
+        Type::Unit
+        | Type::Bool
+        | Type::Int
+        | Type::Int32
+        | Type::Int64
+        | Type::Float
+        | Type::Char
+        | Type::String
+        | Type::Exn
+        | Type::Param(_) => (),
         Type::Func(params, ret) => {
             seq!(params);
             unwrap_var(*ret, tyenv, freevars)
         }
-        Type::Array(et) => unwrap_var(*et, tyenv, freevars),
-        Type::Tuple(es) => seq!(es),
+        Type::Array(et) | Type::Ref(et) => unwrap_var(*et, tyenv, freevars),
+        Type::Tuple(es) | Type::Con(_, es) => seq!(es),
         Type::Var(_) => freevars.push(ty.clone()),
     }
 }
 
+// value restriction: the type of an expression that may allocate a mutable cell is only
+// partially generalized (see generalize_expansive), otherwise 'let r = ref id' would give r a
+// polymorphic type
+pub fn is_nonexpansive(node: &NodeKind) -> bool {
+    match *node {
+        NodeKind::Unit
+        | NodeKind::Bool(_)
+        | NodeKind::Int(_)
+        | NodeKind::Int32(_)
+        | NodeKind::Int64(_)
+        | NodeKind::Float(_)
+        | NodeKind::String(_)
+        | NodeKind::Ident(_)
+        | NodeKind::Constr(_, None) => true,
+        NodeKind::Tuple(ref es) => es.iter().all(is_nonexpansive),
+        NodeKind::Constr(_, Some(ref e)) => is_nonexpansive(e),
+        NodeKind::IntUnaryOp(_, ref e) | NodeKind::FloatUnaryOp(_, ref e) => is_nonexpansive(e),
+        NodeKind::IntBinaryOp(_, ref e1, ref e2)
+        | NodeKind::FloatBinaryOp(_, ref e1, ref e2)
+        | NodeKind::CompBinaryOp(_, ref e1, ref e2)
+        | NodeKind::LetExpr(_, ref e1, ref e2)
+        | NodeKind::LetTupleExpr(_, ref e1, ref e2) => is_nonexpansive(e1) && is_nonexpansive(e2),
+        NodeKind::LetFuncExpr(_, _, ref e) => is_nonexpansive(e),
+        NodeKind::Annot(ref e, _) => is_nonexpansive(e),
+        NodeKind::IfExpr(_, ref e1, ref e2) => is_nonexpansive(e1) && is_nonexpansive(e2),
+        _ => false,
+    }
+}
+
 fn generalize(
     ty: Type,
     env: &HashMap<String, TypeScheme>,
@@ -356,6 +797,42 @@ fn generalize(
     TypeScheme::new(newone, ty)
 }
 
+// relaxed value restriction: a type variable of an expansive expression is still generalized
+// if it only occurs in covariant positions. the others become weak.
+fn generalize_expansive(
+    ty: Type,
+    env: &HashMap<String, TypeScheme>,
+    tyenv: &mut HashMap<usize, Type>,
+    globals: &mut Globals,
+) -> TypeScheme {
+    let TypeScheme { tyvars, body } = generalize(ty, env, tyenv);
+    let mut noncovariant = vec![];
+    noncovariant_vars(&body, true, &mut noncovariant, globals);
+    let (weak, generic): (Vec<Type>, Vec<Type>) =
+        tyvars.into_iter().partition(|t| noncovariant.contains(t));
+    globals.weakvars.extend(weak.iter().filter_map(var_n));
+    TypeScheme::new(generic, body)
+}
+
+fn noncovariant_vars(ty: &Type, covariant: bool, vars: &mut Vec<Type>, globals: &Globals) {
+    match *ty {
+        Type::Func(ref params, ref ret) => {
+            for param in params {
+                noncovariant_vars(param, false, vars, globals)
+            }
+            noncovariant_vars(ret, covariant, vars, globals)
+        }
+        Type::Tuple(ref ts) => for t in ts {
+            noncovariant_vars(t, covariant, vars, globals)
+        },
+        // mutable containers are invariant
+        Type::Array(ref t) | Type::Ref(ref t) => noncovariant_vars(t, false, vars, globals),
+        Type::Con(_, _) => noncovariant_vars(&expand_all(ty, globals), covariant, vars, globals),
+        Type::Var(_) if !covariant => vars.push(ty.clone()),
+        _ => (),
+    }
+}
+
 fn var_n(ty: &Type) -> Option<usize> {
     if let &Type::Var(n) = ty {
         Some(n)
@@ -369,11 +846,12 @@ pub fn g(
     env: &HashMap<String, TypeScheme>,
     tyenv: &mut HashMap<usize, Type>,
     idgen: &mut id::IdGen,
+    globals: &mut Globals,
 ) -> Result<Type, TypeError> {
     macro_rules! g_seq {
         ($es:expr) => ({
             let mut argtys = Vec::new();
-            for e in $es.iter() { argtys.push(try!(g(e, env, tyenv, idgen))); }
+            for e in $es.iter() { argtys.push(try!(g(e, env, tyenv, idgen, globals))); }
             argtys
         });
     }
@@ -382,53 +860,70 @@ pub fn g(
         NodeKind::Unit => Ok(Type::Unit),
         NodeKind::Bool(_) => Ok(Type::Bool),
         NodeKind::Int(_) => Ok(Type::Int),
+        NodeKind::Int32(_) => Ok(Type::Int32),
+        NodeKind::Int64(_) => Ok(Type::Int64),
         NodeKind::Float(_) => Ok(Type::Float),
+        NodeKind::String(_) => Ok(Type::String),
         NodeKind::Ident(ref name) => {
             if let Some(t) = env.get(name).cloned() {
                 Ok(instantiate(t, tyenv, idgen))
-            } else if let Some(t) = EXTENV.lock().unwrap().get(name).cloned() {
+            } else if let Some(t) = globals.values.get(name).cloned() {
                 Ok(instantiate(t, tyenv, idgen))
             } else {
-                println!("{}", name);
-                panic!("TODO: implement")
+                Err(TypeError::UnboundValue(name.clone()))
             }
         }
         NodeKind::Tuple(ref es) => Ok(Type::Tuple(g_seq!(es))),
+        NodeKind::IntUnaryOp(_, ref e) => {
+            try!(unify(&try!(g(e, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            Ok(Type::Int)
+        }
+        NodeKind::FloatUnaryOp(_, ref e) => {
+            try!(unify(&try!(g(e, env, tyenv, idgen, globals)), &Type::Float, tyenv, globals));
+            Ok(Type::Float)
+        }
         NodeKind::IntBinaryOp(_, ref lhs, ref rhs) => {
-            try!(unify(&try!(g(lhs, env, tyenv, idgen)), &Type::Int, tyenv));
-            try!(unify(&try!(g(rhs, env, tyenv, idgen)), &Type::Int, tyenv));
+            try!(unify(&try!(g(lhs, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            try!(unify(&try!(g(rhs, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
             Ok(Type::Int)
         }
         NodeKind::FloatBinaryOp(_, ref lhs, ref rhs) => {
-            try!(unify(&try!(g(lhs, env, tyenv, idgen)), &Type::Float, tyenv));
-            try!(unify(&try!(g(rhs, env, tyenv, idgen)), &Type::Float, tyenv));
+            try!(unify(&try!(g(lhs, env, tyenv, idgen, globals)), &Type::Float, tyenv, globals));
+            try!(unify(&try!(g(rhs, env, tyenv, idgen, globals)), &Type::Float, tyenv, globals));
             Ok(Type::Float)
         }
         NodeKind::CompBinaryOp(_, ref lhs, ref rhs) => {
-            let a = try!(g(lhs, env, tyenv, idgen));
-            let b = try!(g(rhs, env, tyenv, idgen));
-            try!(unify(&a, &b, tyenv));
+            let a = try!(g(lhs, env, tyenv, idgen, globals));
+            let b = try!(g(rhs, env, tyenv, idgen, globals));
+            try!(unify(&a, &b, tyenv, globals));
             // println!("comp {:?}", tyenv);
             Ok(Type::Bool)
         }
         NodeKind::Call(ref callee, ref args) => {
             let ty = idgen.get_type();
-            let callee_ty = try!(g(callee, env, tyenv, idgen));
+            let callee_ty = try!(g(callee, env, tyenv, idgen, globals));
             let functy = Type::Func(g_seq!(args), Box::new(ty.clone()));
             // println!("call: {:?}", callee_ty);
             // println!("      {:?}", functy);
-            try!(unify(&callee_ty, &functy, tyenv));
+            try!(unify(&callee_ty, &functy, tyenv, globals));
             Ok(ty)
         }
         NodeKind::LetExpr((ref name, ref ty), ref expr, ref body) => {
-            let t = try!(g(expr, env, tyenv, idgen));
-            try!(unify(&t, ty, tyenv));
-            let p = generalize(t, env, tyenv);
+            try!(check_annot(ty, globals));
+            let t = try!(g(expr, env, tyenv, idgen, globals));
+            try!(unify(&t, ty, tyenv, globals));
+            // the binder's type keeps the abbreviations of its annotation
+            let p = if is_nonexpansive(expr) {
+                generalize(ty.clone(), env, tyenv)
+            } else {
+                generalize_expansive(ty.clone(), env, tyenv, globals)
+            };
             let mut newenv = env.clone();
             newenv.insert(name.clone(), p);
-            g(body, &newenv, tyenv, idgen)
+            g(body, &newenv, tyenv, idgen, globals)
         }
         NodeKind::LetFuncExpr(ref funcdef, ref expr, ref body) => {
+            try!(check_funcdef_annots(funcdef, globals));
             let (name, ty) = funcdef.name.clone();
             let params = &funcdef.params;
             let mut newenv = env.clone();
@@ -439,32 +934,43 @@ pub fn g(
             }
             let newty = Type::Func(
                 params.iter().map(|p| p.1.clone()).collect::<Vec<_>>(),
-                Box::new(try!(g(expr, &newenv_body, tyenv, idgen))),
+                Box::new(try!(g(expr, &newenv_body, tyenv, idgen, globals))),
             );
-            try!(unify(&ty, &newty, tyenv));
+            try!(unify(&ty, &newty, tyenv, globals));
             // println!("complete functy: {:?}", newty);
-            newenv.insert(name.clone(), generalize(newty, env, tyenv));
-            g(body, &newenv, tyenv, idgen)
+            newenv.insert(name.clone(), generalize(ty, env, tyenv));
+            g(body, &newenv, tyenv, idgen, globals)
         }
         NodeKind::LetTupleExpr(ref es, ref expr, ref body) => {
+            for &(_, ref t) in es {
+                try!(check_annot(t, globals));
+            }
             try!(unify(
-                &try!(g(expr, &env, tyenv, idgen)),
+                &try!(g(expr, &env, tyenv, idgen, globals)),
                 &Type::Tuple(es.iter().map(|e| e.1.clone()).collect::<Vec<_>>(),),
                 tyenv,
+                globals,
             ));
             let mut newenv = env.clone();
             for &(ref x, ref t) in es.iter() {
                 newenv.insert(x.to_string(), TypeScheme::new(vec![], t.clone()));
             }
-            g(body, &newenv, tyenv, idgen)
+            g(body, &newenv, tyenv, idgen, globals)
         }
         NodeKind::LetDef((ref name, ref ty), ref expr) => {
-            try!(unify(&try!(g(expr, env, tyenv, idgen)), ty, tyenv));
-            let t = generalize(ty.clone(), env, tyenv);
-            EXTENV.lock().unwrap().insert(name.clone(), t);
+            try!(check_annot(ty, globals));
+            try!(unify(&try!(g(expr, env, tyenv, idgen, globals)), ty, tyenv, globals));
+            let t = if is_nonexpansive(expr) {
+                generalize(ty.clone(), env, tyenv)
+            } else {
+                generalize_expansive(ty.clone(), env, tyenv, globals)
+            };
+            globals.values.insert(name.clone(), t);
+            globals.vars.insert(name.clone());
             Ok(Type::Unit)
         }
         NodeKind::LetFuncDef(ref funcdef, ref expr) => {
+            try!(check_funcdef_annots(funcdef, globals));
             let (name, ty) = funcdef.name.clone();
             let params = &funcdef.params;
             let mut newenv = env.clone();
@@ -476,55 +982,180 @@ pub fn g(
             let newty = deref_ty(
                 &Type::Func(
                     params.iter().map(|p| p.1.clone()).collect::<Vec<_>>(),
-                    Box::new(try!(g(expr, &newenv_body, tyenv, idgen))),
+                    Box::new(try!(g(expr, &newenv_body, tyenv, idgen, globals))),
                 ),
                 tyenv,
             );
-            try!(unify(&ty, &newty, tyenv));
-            EXTENV
-                .lock()
-                .unwrap()
-                .insert(name.clone(), generalize(newty, env, tyenv));
+            try!(unify(&ty, &newty, tyenv, globals));
+            globals.values
+                .insert(name.clone(), generalize(ty, env, tyenv));
+            globals.vars.remove(&name);
             Ok(Type::Unit)
         }
         NodeKind::IfExpr(ref cond, ref then_, ref else_) => {
-            try!(unify(&try!(g(cond, env, tyenv, idgen)), &Type::Bool, tyenv));
-            let t = try!(g(then_, env, tyenv, idgen));
-            let e = try!(g(else_, env, tyenv, idgen));
-            try!(unify(&t, &e, tyenv));
+            try!(unify(&try!(g(cond, env, tyenv, idgen, globals)), &Type::Bool, tyenv, globals));
+            let t = try!(g(then_, env, tyenv, idgen, globals));
+            let e = try!(g(else_, env, tyenv, idgen, globals));
+            try!(unify(&t, &e, tyenv, globals));
             Ok(t)
         }
         NodeKind::MakeArray(ref e1, ref e2) => {
-            try!(unify(&try!(g(e1, env, tyenv, idgen)), &Type::Int, tyenv));
-            let t = try!(g(e2, env, tyenv, idgen));
+            try!(unify(&try!(g(e1, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            let t = try!(g(e2, env, tyenv, idgen, globals));
             Ok(Type::Array(Box::new(t)))
         }
         NodeKind::Get(ref e1, ref e2) => {
             let t = idgen.get_type();
             try!(unify(
-                &try!(g(e1, env, tyenv, idgen)),
+                &try!(g(e1, env, tyenv, idgen, globals)),
                 &Type::Array(Box::new(t.clone())),
                 tyenv
-            ));
-            try!(unify(&try!(g(e2, env, tyenv, idgen)), &Type::Int, tyenv));
+            , globals));
+            try!(unify(&try!(g(e2, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
             Ok(t)
         }
         NodeKind::Put(ref e1, ref e2, ref e3) => {
-            let t = try!(g(e3, env, tyenv, idgen));
+            let t = try!(g(e3, env, tyenv, idgen, globals));
             try!(unify(
-                &try!(g(e1, env, tyenv, idgen)),
+                &try!(g(e1, env, tyenv, idgen, globals)),
                 &Type::Array(Box::new(t)),
                 tyenv
-            ));
-            try!(unify(&try!(g(e2, env, tyenv, idgen)), &Type::Int, tyenv));
+            , globals));
+            try!(unify(&try!(g(e2, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            Ok(Type::Unit)
+        }
+        NodeKind::MakeRef(ref e) => Ok(Type::Ref(Box::new(try!(g(e, env, tyenv, idgen, globals))))),
+        NodeKind::Deref(ref e) => {
+            let t = idgen.get_type();
+            try!(unify(
+                &try!(g(e, env, tyenv, idgen, globals)),
+                &Type::Ref(Box::new(t.clone())),
+                tyenv
+            , globals));
+            Ok(t)
+        }
+        NodeKind::Assign(ref e1, ref e2) => {
+            let t = try!(g(e2, env, tyenv, idgen, globals));
+            try!(unify(&try!(g(e1, env, tyenv, idgen, globals)), &Type::Ref(Box::new(t)), tyenv, globals));
+            Ok(Type::Unit)
+        }
+        NodeKind::While(ref cond, ref body) => {
+            try!(unify(&try!(g(cond, env, tyenv, idgen, globals)), &Type::Bool, tyenv, globals));
+            try!(unify(&try!(g(body, env, tyenv, idgen, globals)), &Type::Unit, tyenv, globals));
+            Ok(Type::Unit)
+        }
+        NodeKind::For(ref var, ref from, ref to, _, ref body) => {
+            try!(unify(&try!(g(from, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            try!(unify(&try!(g(to, env, tyenv, idgen, globals)), &Type::Int, tyenv, globals));
+            let mut newenv = env.clone();
+            newenv.insert(var.clone(), TypeScheme::new(vec![], Type::Int));
+            try!(unify(&try!(g(body, &newenv, tyenv, idgen, globals)), &Type::Unit, tyenv, globals));
+            Ok(Type::Unit)
+        }
+        // the annotation on a use of a name is a fresh variable (see parser::uniquify), which is
+        // resolved to the type of the name
+        NodeKind::Annot(ref e, ref ty) => {
+            try!(check_annot(ty, globals));
+            try!(unify(&try!(g(e, env, tyenv, idgen, globals)), ty, tyenv, globals));
+            Ok(deref_ty(ty, tyenv))
+        }
+        NodeKind::TypeDef(ref name, ref params, ref body) => {
+            try!(declare_type(name, params, body, globals));
+            Ok(Type::Unit)
+        }
+        NodeKind::ExnDef(ref name, ref arg) => {
+            if let Some(ref arg) = *arg {
+                try!(check_type(arg, &vec![], globals));
+            }
+            let arg = arg.as_ref().map(|arg| expand_all(arg, globals));
+            globals.exns.push(ExnDef {
+                name: name.clone(),
+                arg: arg,
+            });
+            Ok(Type::Unit)
+        }
+        NodeKind::External(ref name, ref ty, ref symbol) => {
+            try!(check_type(ty, &vec![], globals));
+            let ty = expand_all(ty, globals);
+            let is_scalar = |t: &Type| match *t {
+                Type::Unit | Type::Bool | Type::Int | Type::Int32 | Type::Int64 | Type::Float
+                | Type::String => true,
+                _ => false,
+            };
+            let ok = match ty {
+                Type::Func(ref params, ref ret) => params.iter().all(&is_scalar) && is_scalar(ret),
+                _ => false,
+            };
+            if !ok {
+                return Err(TypeError::External(name.clone()));
+            }
+            globals.values
+                .insert(name.clone(), TypeScheme::new(vec![], ty.clone()));
+            globals.vars.remove(name);
+            globals.externals.insert(
+                name.clone(),
+                External {
+                    symbol: symbol.clone(),
+                    ty: ty,
+                },
+            );
             Ok(Type::Unit)
         }
+        NodeKind::Constr(ref name, ref arg) => {
+            let exn_arg = match globals.exn_tag(name) {
+                Some(tag) => globals.exns[tag].arg.clone(),
+                None => return Err(TypeError::UnboundExn(name.clone())),
+            };
+            match (exn_arg, arg) {
+                (None, &None) => (),
+                (Some(t), &Some(ref e)) => try!(unify(&try!(g(e, env, tyenv, idgen, globals)), &t, tyenv, globals)),
+                _ => return Err(TypeError::ExnArity(name.clone())),
+            }
+            Ok(Type::Exn)
+        }
+        NodeKind::Raise(ref e, ref ty) => {
+            try!(unify(&try!(g(e, env, tyenv, idgen, globals)), &Type::Exn, tyenv, globals));
+            Ok(ty.clone())
+        }
+        NodeKind::TryWith(ref body, ref handlers) => {
+            let t = try!(g(body, env, tyenv, idgen, globals));
+            for handler in handlers {
+                let mut newenv = env.clone();
+                let var_ty = match handler.constr {
+                    Some(ref name) => match globals.exn_tag(name) {
+                        Some(tag) => globals.exns[tag].arg.clone(),
+                        None => return Err(TypeError::UnboundExn(name.clone())),
+                    },
+                    None => Some(Type::Exn),
+                };
+                match (var_ty, &handler.var) {
+                    (Some(t), &Some((ref x, ref ty))) => {
+                        try!(unify(ty, &t, tyenv, globals));
+                        newenv.insert(x.clone(), TypeScheme::new(vec![], t));
+                    }
+                    (None, &Some(_)) => {
+                        return Err(TypeError::ExnArity(handler.constr.clone().unwrap()))
+                    }
+                    (_, &None) => (),
+                }
+                try!(unify(&try!(g(&handler.body, &newenv, tyenv, idgen, globals)), &t, tyenv, globals));
+            }
+            Ok(t)
+        }
         _ => panic!(),
     }
 }
 
-pub fn f(node: &NodeKind, tyenv: &mut HashMap<usize, Type>, idgen: &mut id::IdGen) -> NodeKind {
-    let _infered_ty = g(node, &HashMap::new(), tyenv, idgen);
+pub fn f(
+    node: &NodeKind,
+    tyenv: &mut HashMap<usize, Type>,
+    idgen: &mut id::IdGen,
+    globals: &mut Globals,
+) -> Result<NodeKind, TypeError> {
     // TODO: infered_ty == Unit
-    deref_term(node, tyenv)
+    try!(g(node, &HashMap::new(), tyenv, idgen, globals).map_err(|err| match err {
+        TypeError::Unify(t1, t2) => TypeError::Unify(deref_ty(&t1, tyenv), deref_ty(&t2, tyenv)),
+        err => err,
+    }));
+    Ok(deref_term(node, tyenv, globals))
 }
@@ -0,0 +1,7 @@
+(* expect: index out of bounds|index out of bounds|Array.make *)
+let a = Array.make 3 1.5 in
+(try print_float a.(3) with Invalid_argument s -> print_string s);
+print_string "|";
+(try a.(-1) <- 2.0 with Invalid_argument s -> print_string s);
+print_string "|";
+try let b = Array.make (-1) 0 in print_int b.(0) with Invalid_argument s -> print_string s
@@ -0,0 +1,22 @@
+(* expect: 5 0149168 30 0,1,4,9,16, 16 1 *)
+let sq i = i * i in
+let a = Array.init 5 sq in
+print_int (Array.length a);
+print_string " ";
+let show x = print_int x in
+Array.iter show a;
+let half x = x / 2 in
+print_int (Array.map half a).(4);
+print_string " ";
+let add acc x = acc + x in
+print_int (Array.fold_left add 0 a);
+print_string " ";
+let b = Array.copy a in
+a.(0) <- 100;
+let show_comma x = print_int x; print_string "," in
+Array.iter show_comma b;
+print_string " ";
+Array.blit b 1 b 0 4;
+print_int b.(3);
+print_string " ";
+print_int b.(0)
@@ -0,0 +1,5 @@
+(* expect: 0 7 2 *)
+let a = Array.make 3 0 in
+a.(1) <- 7;
+a.(2) <- a.(1) - 5;
+print_int a.(0); print_string " "; print_int a.(1); print_string " "; print_int a.(2)
@@ -0,0 +1,8 @@
+(* expect: 3.5 2 *)
+let a = Array.make 2 (1, 2.5) in
+a.(1) <- (2, 3.5);
+let (i, f) = a.(1) in
+print_float f;
+print_string " ";
+let second (p : int * float) = let (i, _) = p in i in
+print_int (Array.map second a).(1)
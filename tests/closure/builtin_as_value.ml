@@ -0,0 +1,18 @@
+(* expect: 3 1.4142135623730951 5 2 2 3 *)
+let apply f (x : int) : unit = f x in
+let applyf f (x : float) : float = f x in
+apply print_int 3;
+print_string " ";
+print_float (applyf sqrt 2.);
+print_string " ";
+let h = print_int in
+h 5;
+print_string " ";
+let count f (arr : int array) : int = f arr in
+print_int (count Array.length (Array.make 2 0));
+print_string " ";
+(* without annotations, the builtin is used at the types of the uses of f *)
+let f = Array.length in
+print_int (f (Array.make 2 0));
+print_string " ";
+print_int (f (Array.make 3 "s"))
@@ -0,0 +1,5 @@
+(* expect: 720 *)
+let rec fact n =
+  let step m = m * fact (m - 1) in
+  if n < 1 then 1 else step n in
+print_int (fact 6)
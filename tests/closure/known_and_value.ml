@@ -0,0 +1,5 @@
+(* expect: 15 *)
+let apply f x = f (x + 0) + 0 in
+let k = 5 in
+let addk y = y + k in
+print_int (apply addk (addk 5))
@@ -0,0 +1,7 @@
+(* expect: 111 *)
+let f a =
+  let g b =
+    let h c = a + b + c in
+    h in
+  g in
+print_int (((f 100) 10) 1)
@@ -0,0 +1,5 @@
+(* expect: 55 *)
+let sum_to n =
+  let rec go i = if i > n then 0 else i + go (i + 1) in
+  go 1 in
+print_int (sum_to 10)
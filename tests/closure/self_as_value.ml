@@ -0,0 +1,4 @@
+(* expect: 3 *)
+let apply f x = f (x + 0) + 0 in
+let rec countdown n = if n < 1 then 0 else 1 + apply countdown (n - 1) in
+print_int (countdown 3)
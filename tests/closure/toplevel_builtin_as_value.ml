@@ -0,0 +1,7 @@
+(* expect: 6765 4 *)
+let rec fib n = if n < 2 then n else fib (n - 1) + fib (n - 2);;
+let apply f (x : int) : int = f x;;
+print_int (apply fib 20);;
+print_string " ";;
+let applyf f (x : float) : float = f x;;
+print_float (applyf sqrt 16.);;
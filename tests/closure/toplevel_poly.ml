@@ -0,0 +1,31 @@
+(* expect: 55 6765 3 2.5 s 7 5 16 200 1 11 7 2 2 3 2.5 *)
+let rec f n = if n < 2 then n else f (n - 1) + f (n - 2);;
+let apply g x = g x + 0;;
+let h n = apply f n;;
+print_int (h 10); print_string " ";;
+print_int (apply f 20); print_string " ";;
+let id x = x;;
+let pair x y = (x, y);;
+let fst2 p = let (a, b) = p in a;;
+let rec repeat n g x = if n = 0 then x else repeat (n - 1) g (g x);;
+let inc x = x + 1;;
+print_int (id 3); print_string " ";
+print_float (id 2.5); print_string " ";
+print_string (id "s"); print_string " ";
+print_int (fst2 (pair 7 "x")); print_string " ";
+print_int (repeat 5 inc 0); print_string " ";
+print_float (repeat 2 sqrt 65536.); print_string " ";;
+let id x = x * 100;;
+print_int (id 2); print_string " ";;
+let konst x = let k y = x in k;;
+let compose f g = let h x = f (g x) in h;;
+let dbl x = x * 2;;
+print_int ((konst 1) 2); print_string " ";
+print_int ((compose inc dbl) 5); print_string " ";;
+let first = fst2;;
+let len = Array.length;;
+print_int (first (7, 2.5)); print_string " ";
+print_int (len (Array.make 2 0)); print_string " ";
+print_int (len (Array.make 2 "s")); print_string " ";;
+let both x = let id y = y in (id x, id 2.5);;
+let (a, b) = both 3 in print_int a; print_string " "; print_float b
@@ -0,0 +1,7 @@
+(* the constructors of annotations are checked like those of declarations *)
+type 'a p = 'a * 'a;;
+let f (x : undefined_t) = x;;
+let g (x : p) = x;;
+let h x = (x : (int, int) p);;
+let k = let (y : int p) = (1, 2) in y;;
+print_int (let (a, b) = k in a + b)
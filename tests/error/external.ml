@@ -0,0 +1,2 @@
+external f : int -> int = "rcaml_no_such_symbol";;
+print_int (f 1)
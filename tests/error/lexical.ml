@@ -0,0 +1,7 @@
+(* a phrase with a bad literal isn't typechecked,
+   so only its lexical error is reported *)
+let big = 2147483648l;;
+print_float 0x.8p1;;
+print_string (Int32.to_string big);;
+let s = "s" +
+  1;;
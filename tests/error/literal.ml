@@ -0,0 +1,4 @@
+(* an int literal out of range
+   is reported with its location *)
+let x = 0x1F + 0o17 + 0b1010 + 1_000 in
+print_int (x + 4611686018427387904)
@@ -0,0 +1,11 @@
+(* every syntax and type error of a program
+   is reported, not only the first one *)
+let a = (1 + ) in
+print_int a;;
+let b = 1 in print_int b;;
+let c x = if x 1 else 2;;
+let d = "d" + c 1;;
+let f = Array.make 3;;
+let g = Array.make 1 (fun x -> x);;
+let h = c 1 and i = c 2;;
+let e = c 1 +
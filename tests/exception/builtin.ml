@@ -0,0 +1,9 @@
+(* expect: not found|boom|bad *)
+let find x = if x = 0 then raise Not_found else x in
+(try print_int (find 0) with Not_found -> print_string "not found");
+print_string "|";
+(try raise (Failure "boom") with Failure msg -> print_string msg);
+print_string "|";
+try raise (Invalid_argument "bad") with
+| Failure _ -> print_string "failure"
+| e -> (try raise e with Invalid_argument s -> print_string s)
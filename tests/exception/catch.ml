@@ -0,0 +1,9 @@
+(* expect: 42 *)
+exception Found of int
+;;
+try
+  for i = 0 to 100 do
+    if i * i > 1700 then raise (Found i)
+  done;
+  print_int 0
+with Found n -> print_int n
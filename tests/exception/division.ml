@@ -0,0 +1,16 @@
+(* expect: caught|caught|-7 1|0 0|4 1|unused *)
+let div x y : int = x / y in
+let rem x y : int = x mod y in
+let min_int = -4611686018427387904 in
+(try print_int (div 1 0) with Division_by_zero -> print_string "caught");
+print_string "|";
+(try print_int (rem 1 0) with Division_by_zero -> print_string "caught");
+print_string "|";
+print_int (div 7 (-1)); print_string " "; print_int (div min_int (-1) - min_int + 1);
+print_string "|";
+print_int (rem 7 (-1)); print_string " "; print_int (rem min_int (-1));
+print_string "|";
+print_int (div 9 2); print_string " "; print_int (9 mod 2);
+print_string "|";
+try (let n = 0 in let unused = 3 / n in print_string "not raised") with
+| Division_by_zero -> print_string "unused"
@@ -0,0 +1,10 @@
+(* expect: inner 3 outer 7 *)
+exception A of int
+exception B
+;;
+let f x : unit = if x > 5 then raise B else raise (A x) in
+try
+  (try f 3 with A n -> print_string "inner "; print_int n);
+  print_string " ";
+  try f 7 with A n -> print_string "wrong"
+with B -> print_string "outer "; print_int 7
@@ -0,0 +1,8 @@
+(* expect: 1000000 500000 *)
+(* a function with a 'try' still makes its other calls in tail position *)
+let rec loop n acc = if n = 0 then acc else loop (n - 1) (try acc + 1 with Not_found -> 0);;
+let rec count n acc =
+  if n = 0 then acc
+  else count (n - 1) (try if n mod 2 = 0 then raise Not_found else acc + 1 with Not_found -> acc);;
+print_int (loop 1000000 0); print_string " ";
+print_int (count 1000000 0)
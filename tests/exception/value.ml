@@ -0,0 +1,6 @@
+(* expect: 3.5 *)
+exception Stop of float * int
+;;
+let rec loop x : float = if x > 3.0 then raise (Stop (x, 1)) else loop (x +. 0.5) in
+let r = try loop 0.0; 0.0 with Stop p -> let (x, _) = p in x in
+print_float r
@@ -0,0 +1,17 @@
+(* expect: 42 1024 5 -123 7 true false 3 *)
+external c_abs : int -> int = "labs";;
+external pow : float -> float -> float = "pow";;
+external strlen : string -> int = "strlen";;
+external atoi : string -> int32 = "atoi";;
+external atoll : string -> int64 = "atoll";;
+external isdigit : int32 -> bool = "isdigit";;
+external abs : int32 -> int32 = "abs";;
+
+print_int (c_abs (-42)); print_string " ";
+print_float (pow 2. 10.); print_string " ";
+print_int (strlen "hello"); print_string " ";
+print_string (Int32.to_string (atoi "-123")); print_string " ";
+print_string (Int64.to_string (atoll "7")); print_string " ";
+print_string (if isdigit 55l then "true" else "false"); print_string " ";
+print_string (if isdigit 97l then "true" else "false"); print_string " ";
+print_int (Int32.to_int (abs (-3l)))
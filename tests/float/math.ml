@@ -0,0 +1,11 @@
+(* expect: 1.4142135623730951 1 2.718281828459045 2.302585092994046 0.7853981633974483 0.7853981633974483 2 3 3|9 -2 1.5 3|3 -2 4|true true true true true *)
+let pr x = print_float x; print_string " " in
+let pb b = print_string (if b then " true" else " false") in
+pr (sqrt 2.); pr (cos 0.); pr (exp 1.); pr (log 10.); pr (atan 1.); pr (atan2 1. 1.);
+pr (floor 2.7); pr (ceil 2.2); print_float (abs_float (-3.)); print_string "|";
+pr (fsqr 3.); pr (fneg 2.); pr (fhalf 3.); print_float (fabs (-3.)); print_string "|";
+print_int (int_of_float 3.9); print_string " ";
+print_int (truncate (-2.5)); print_string " ";
+print_float (float 3 +. float_of_int 1); print_string "|";
+print_string (if fless 1. 2. then "true" else "false");
+pb (fispos 1.); pb (fisneg (-. 1.)); pb (fiszero 0.); pb (fequal (sin 0.) 0.)
@@ -0,0 +1,11 @@
+(* expect: 2147483647 -2147483648 -2147483648 7 -1 2147483648 6 2147483647 *)
+let max = 2147483647l in
+let pr x = print_string (Int32.to_string x); print_string " " in
+pr max;
+pr (Int32.add max 1l);
+pr (Int32.neg (Int32.add max 1l));
+pr (Int32.div 15l 2l);
+pr (Int32.rem (-7l) 2l);
+print_int (Int32.to_int max + 1); print_string " ";
+pr (Int32.logand 14l (Int32.shift_left 3l 1));
+print_string (Int32.to_string (Int32.of_int 2147483647))
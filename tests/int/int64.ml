@@ -0,0 +1,11 @@
+(* expect: 9223372036854775807 -9223372036854775808 -1 42 12 3.5 caught true *)
+let max = 9223372036854775807L in
+let pr x = print_string (Int64.to_string x); print_string " " in
+pr max;
+pr (Int64.add max 1L);
+print_int (Int64.to_int max); print_string " ";
+pr (Int64.of_int 42);
+pr (Int64.mul (Int64.shift_right 48L 2) 1L);
+print_float (Int64.to_float 7L /. 2.); print_string " ";
+(try pr (Int64.div 1L (Int64.sub 1L 1L)) with Division_by_zero -> print_string "caught ");
+print_string (if Int64.of_int 3 < 4L then "true" else "false")
@@ -0,0 +1,10 @@
+(* expect: -7 7 -4611686018427387904 -4611686018427387904 -6 -2.5 *)
+let f x = -x in
+let g y = -. y in
+let min_int = -4611686018427387904 in
+print_int (-7); print_string " ";
+print_int (f (-7)); print_string " ";
+print_int (f min_int); print_string " ";
+print_int (- (min_int + 0)); print_string " ";
+print_int (- f 3 * -2); print_string " ";
+print_float (g 2.5)
@@ -0,0 +1,10 @@
+(* expect: 102334155 4611686018427387903 -4611686018427387904 -4611686018427387904 -2 *)
+let rec fib n : int = if n < 2 then n else fib (n - 1) + fib (n - 2) in
+let max_int = 4611686018427387903 in
+let succ x : int = x + 1 in
+let twice x : int = x * 2 in
+print_int (fib 40); print_string " ";
+print_int max_int; print_string " ";
+print_int (succ max_int); print_string " ";
+print_int (twice (-2305843009213693952)); print_string " ";
+print_int (twice max_int)
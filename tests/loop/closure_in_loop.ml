@@ -0,0 +1,5 @@
+(* expect: 246 *)
+for i = 1 to 3 do
+  let dbl x = x * i in
+  print_int (dbl 2)
+done
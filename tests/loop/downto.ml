@@ -0,0 +1,2 @@
+(* expect: 54321 *)
+for i = 5 downto 1 do print_int i done
@@ -0,0 +1,4 @@
+(* expect: 7 *)
+for i = 1 to 0 do print_int i done;
+for i = 0 downto 1 do print_int i done;
+print_int 7
@@ -0,0 +1,6 @@
+(* expect: 5050 *)
+let sum = ref 0 in
+for i = 1 to 100 do
+  sum := !sum + i
+done;
+print_int !sum
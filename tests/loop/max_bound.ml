@@ -0,0 +1,4 @@
+(* expect: 3 *)
+let n = ref 0 in
+for i = 2147483645 to 2147483647 do n := !n + 1 done;
+print_int !n
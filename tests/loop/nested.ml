@@ -0,0 +1,4 @@
+(* expect: 112123 *)
+for i = 1 to 3 do
+  for j = 1 to i do print_int j done
+done
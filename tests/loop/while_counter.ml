@@ -0,0 +1,6 @@
+(* expect: 0123456789 *)
+let i = ref 0 in
+while !i < 10 do
+  print_int !i;
+  i := !i + 1
+done
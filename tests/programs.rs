@@ -0,0 +1,222 @@
+use std::fs;
+use std::process::Command;
+
+// every program under tests/<dir> starts with '(* expect: <stdout> *)'
+fn expected_output(src: &str) -> String {
+    let first_line = src.lines().next().unwrap_or("");
+    let prefix = "(* expect: ";
+    let suffix = " *)";
+    if !first_line.starts_with(prefix) || !first_line.ends_with(suffix) {
+        panic!("missing '(* expect: ... *)' header")
+    }
+    first_line[prefix.len()..first_line.len() - suffix.len()].to_string()
+}
+
+fn run_programs(dir: &str) {
+    let mut paths = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "ml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(paths.len() > 0);
+
+    for path in paths {
+        let src = fs::read_to_string(&path).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{} failed", path.display());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            expected_output(&src),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[test]
+pub fn test_closure_programs() {
+    run_programs("tests/closure");
+}
+
+#[test]
+pub fn test_ref_programs() {
+    run_programs("tests/ref");
+}
+
+#[test]
+pub fn test_loop_programs() {
+    run_programs("tests/loop");
+}
+
+#[test]
+pub fn test_syntax_programs() {
+    run_programs("tests/syntax");
+}
+
+#[test]
+pub fn test_array_programs() {
+    run_programs("tests/array");
+}
+
+#[test]
+pub fn test_int_programs() {
+    run_programs("tests/int");
+}
+
+#[test]
+pub fn test_float_programs() {
+    run_programs("tests/float");
+}
+
+#[test]
+pub fn test_ffi_programs() {
+    run_programs("tests/ffi");
+}
+
+#[test]
+pub fn test_exception_programs() {
+    run_programs("tests/exception");
+}
+
+// an uncaught exception is reported on stderr after flushing stdout
+#[test]
+pub fn test_uncaught_exception() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/uncaught/failure.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "12");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.lines().last(),
+        Some("Fatal error: exception Failure(\"boom\")")
+    );
+}
+
+// '-unsafe' only drops the bounds checks
+#[test]
+pub fn test_unsafe_flag() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("-unsafe")
+        .arg("tests/array/builtins.ml")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let src = fs::read_to_string("tests/array/builtins.ml").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected_output(&src));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("bounds.error"));
+}
+
+// '-inline <n>' is accepted like ocamlopt's option and keeps the output
+#[test]
+pub fn test_inline_flag() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("-inline")
+        .arg("10")
+        .arg("tests/closure/nested.ml")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let src = fs::read_to_string("tests/closure/nested.ml").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected_output(&src));
+}
+
+// a bad literal is a compile error reported like ocamlopt does
+#[test]
+pub fn test_literal_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/error/literal.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "File \"tests/error/literal.ml\", line 4, characters 15-34:\n\
+         Error: Integer literal exceeds the range of representable integers of type int\n"
+    );
+}
+
+#[test]
+pub fn test_lexical_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/error/lexical.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "File \"tests/error/lexical.ml\", line 3, characters 10-21:\n\
+         Error: Integer literal exceeds the range of representable integers of type int32\n\
+         File \"tests/error/lexical.ml\", line 4, characters 12-18:\n\
+         Error: Invalid literal 0x.8p1\n\
+         File \"tests/error/lexical.ml\", lines 6-7, characters 0-17:\n\
+         Error: Type string is not compatible with type int\n"
+    );
+}
+
+#[test]
+pub fn test_annotation_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/error/annotation.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "File \"tests/error/annotation.ml\", line 3, characters 0-27:\n\
+         Error: Unbound type constructor undefined_t\n\
+         File \"tests/error/annotation.ml\", line 4, characters 0-17:\n\
+         Error: The type constructor p expects 1 argument(s), but is here applied to 0 \
+         argument(s)\n\
+         File \"tests/error/annotation.ml\", line 5, characters 0-28:\n\
+         Error: The type constructor p expects 1 argument(s), but is here applied to 2 \
+         argument(s)\n"
+    );
+}
+
+#[test]
+pub fn test_syntax_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/error/syntax.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "File \"tests/error/syntax.ml\", line 3, characters 13-14:\n\
+         Error: Syntax error: expected an expression\n\
+         File \"tests/error/syntax.ml\", line 6, characters 17-21:\n\
+         Error: Syntax error: expected `then` after the condition\n\
+         File \"tests/error/syntax.ml\", line 7, characters 0-17:\n\
+         Error: Type string is not compatible with type int\n\
+         File \"tests/error/syntax.ml\", line 8, characters 20-22:\n\
+         Error: Syntax error: expected an argument\n\
+         File \"tests/error/syntax.ml\", line 9, characters 22-25:\n\
+         Error: Syntax error\n\
+         File \"tests/error/syntax.ml\", line 10, characters 12-15:\n\
+         Error: Syntax error: expected `in` after let binding\n\
+         File \"tests/error/syntax.ml\", line 11, characters 13-13:\n\
+         Error: Syntax error: expected an expression\n"
+    );
+}
+
+#[test]
+pub fn test_undefined_external() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rcaml"))
+        .arg("tests/error/external.ml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "File \"tests/error/external.ml\", line 1, characters 0-48:\n\
+         Error: The symbol rcaml_no_such_symbol of external f is undefined\n"
+    );
+}
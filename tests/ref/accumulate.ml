@@ -0,0 +1,5 @@
+(* expect: 5050 *)
+let sum = ref 0 in
+let rec go i = if i > 100 then () else (sum := !sum + i; go (i + 1)) in
+go 1;
+print_int !sum
@@ -0,0 +1,9 @@
+(* expect: 12 *)
+let make_counter step =
+  let c = ref 0 in
+  let next k = c := !c + step * k; !c in
+  next in
+let next = make_counter 4 in
+let a = next 1 in
+let b = next 1 in
+print_int (a + b)
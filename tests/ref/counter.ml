@@ -0,0 +1,5 @@
+(* expect: 3 *)
+let r = ref 0 in
+let incr x = x := !x + 1 in
+incr r; incr r; incr r;
+print_int !r
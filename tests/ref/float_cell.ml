@@ -0,0 +1,4 @@
+(* expect: 2.5 *)
+let x = ref 1.0 in
+x := !x +. 1.5;
+print_float !x
@@ -0,0 +1,7 @@
+(* expect: 42 *)
+let scale (k : int) (x : int) : int = k * x in
+let (base : int) = 20 in
+let r : int ref = ref (scale 2 base) in
+let bump (c : int ref) = c := !c + (1 : int) in
+bump r; bump r;
+print_int !r
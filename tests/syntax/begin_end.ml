@@ -0,0 +1,8 @@
+(* expect: 1234 *)
+let r = ref 0 in
+let step u = begin r := !r + u; print_int !r end in
+if !r = 0 then begin step 1; step 1 end else step 100;
+step 1;
+begin
+  step 1;
+end
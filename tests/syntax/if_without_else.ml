@@ -0,0 +1,4 @@
+(* expect: 135 *)
+for i = 1 to 5 do
+  if i mod 2 = 1 then print_int i
+done
@@ -0,0 +1,13 @@
+(* expect: 31 15 10 1000000 -1 10000000000 1 0.125 3 AB 2 *)
+print_int 0x1F; print_string " ";
+print_int 0o17; print_string " ";
+print_int 0b1010; print_string " ";
+print_int 1_000_000; print_string " ";
+print_int 0x7FFF_FFFF_FFFF_FFFF; print_string " ";
+print_float 1e10; print_string " ";
+print_float 1.; print_string " ";
+print_float 0x1p-3; print_string " ";
+print_float 0x1.8p1; print_string " ";
+print_string "\065\x42 ";
+let x' = 2 in print_int x'
+
@@ -0,0 +1,6 @@
+(* expect: 240 7 *)
+let rec fact n = if n = 0 then 1 else n * fact (n - 1);;
+let twice n = 2 * fact n;;
+let add3 x y z = x + y + z;;
+print_int (twice 5); print_string " ";
+print_int (add3 1 2 4)
@@ -0,0 +1,17 @@
+(* expect: 10 32 132 3 2.5 hi *)
+let a = 10;;
+print_int a; print_string " ";;
+let b = a * 2 + 1;;
+let f x = x + a + b;;
+print_int (f 1); print_string " ";;
+(* f keeps reading the 'a' it was defined with *)
+let a = 100;;
+print_int (f 1 + a); print_string " ";;
+let r = ref 0;;
+let incr n = r := !r + n;;
+incr 1; incr 2;;
+print_int !r; print_string " ";;
+let t = (1, 2.5);;
+let (p, q) = t in print_float q; print_string " ";;
+let s = "hi";;
+print_string s
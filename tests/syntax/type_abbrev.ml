@@ -0,0 +1,7 @@
+(* expect: 30 *)
+type 'a pair = 'a * 'a
+type counter = int ref
+let sum (p : int pair) : int = let (a, b) = p in a + b in
+let (c : counter) = ref 0 in
+c := sum (10, 20);
+print_int !c
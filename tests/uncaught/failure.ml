@@ -0,0 +1,2 @@
+let check x = if x > 2 then raise (Failure "boom") else print_int x in
+for i = 1 to 5 do check i done